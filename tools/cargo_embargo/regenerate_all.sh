@@ -18,9 +18,21 @@
 # report of the results, including any errors, warnings or changes to Android.bp files.
 #
 # Should be run from under external/rust/crates.
+#
+# Set CARGO_EMBARGO_CACHE_DIR to reuse cargo output from previous runs where the inputs haven't
+# changed, and CARGO_EMBARGO_SANDBOX to run cargo without network access or write access to the
+# crates.
 
 set -e
 
+extra_args=()
+if [[ -n "$CARGO_EMBARGO_CACHE_DIR" ]]; then
+  extra_args=(--cache-dir "$CARGO_EMBARGO_CACHE_DIR")
+fi
+if [[ -n "$CARGO_EMBARGO_SANDBOX" ]]; then
+  extra_args+=(--sandbox)
+fi
+
 report="cargo_embargo_report.html"
 
 cat > $report <<END
@@ -50,9 +62,16 @@ for config in */cargo_embargo.json; do
   crate=$(dirname $config)
   echo "Trying $crate..."
   echo "<tr><td><code>$crate</code></td>" >> $report
-  if (cd $crate && cargo_embargo generate cargo_embargo.json) 2> cargo_embargo.err; then
+  if (cd $crate && cargo_embargo "${extra_args[@]}" generate cargo_embargo.json) 2> cargo_embargo.err; then
     (cd $crate && git diff Android.bp > Android.bp.diff)
-    if grep "WARNING" cargo_embargo.err; then
+    if grep "WARNING: sandbox violation" cargo_embargo.err; then
+      echo '<td class="error">Sandbox violation</td>' >> $report
+      echo '<td><details><summary>' >> $report
+      grep -m 1 "WARNING: sandbox violation" cargo_embargo.err >> $report
+      echo '</summary>' >> $report
+      sed 's/$/<br\/>/g' < cargo_embargo.err >> $report
+      echo '</details></td>' >> $report
+    elif grep "WARNING" cargo_embargo.err; then
       echo '<td class="error">Warning</td>' >> $report
       echo '<td><details><summary>' >> $report
       grep -m 1 "WARNING" cargo_embargo.err >> $report
@@ -78,6 +97,13 @@ for config in */cargo_embargo.json; do
       fi
       echo '</td>' >> $report
     fi
+  elif grep "WARNING: sandbox violation" cargo_embargo.err; then
+    echo '<td class="error">Sandbox violation</td>' >> $report
+    echo '<td><details open><summary>' >> $report
+    grep -m 1 "WARNING: sandbox violation" cargo_embargo.err >> $report
+    echo '</summary>' >> $report
+    sed 's/$/<br\/>/g' < cargo_embargo.err >> $report
+    echo '</details></td>' >> $report
   else
     echo '<td class="error">Error</td>' >> $report
     echo '<td><details open>' >> $report
@@ -0,0 +1,567 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Heuristics used by `autoconfig` to choose config options from the crates found by cargo and the
+//! package sources.
+//!
+//! Each function which changes the config also adds a note explaining why, which `autoconfig`
+//! writes as comments at the top of the config file.
+
+use crate::build_script::BuildScript;
+use crate::cargo::{Crate, CrateType, ExternType};
+use crate::config::{PackageVariantConfig, VariantConfig};
+use crate::override_module_name;
+use crate::rename_registry::rename_registry;
+use crate::tree_index::TreeIndex;
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Component, Path, PathBuf};
+
+/// Directories which are never searched for source files.
+const SKIPPED_DIRS: [&str; 4] = [".git", "out", "target", "target.tmp"];
+
+/// Whether a crate can be built without `std`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NoStd {
+    /// The crate is always `no_std`.
+    Always { alloc: bool },
+    /// The crate is `no_std` when some feature (usually `std`) is disabled.
+    Optional { alloc: bool },
+}
+
+/// Returns whether the main source file of the given crate declares it to be `no_std`.
+pub fn no_std_support(crate_: &Crate) -> Result<Option<NoStd>> {
+    let path = crate_.package_dir.join(&crate_.main_src);
+    let source = read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+    let alloc = source.lines().any(|line| line.trim_start().starts_with("extern crate alloc"));
+    for line in source.lines().map(str::trim) {
+        if line == "#![no_std]" {
+            return Ok(Some(NoStd::Always { alloc }));
+        } else if line.starts_with("#![cfg_attr(") && line.contains("no_std") {
+            return Ok(Some(NoStd::Optional { alloc }));
+        }
+    }
+    Ok(None)
+}
+
+/// Sets `no_std` and `alloc` for packages whose libraries are always `no_std`.
+///
+/// Returns a new variant to build packages which can optionally be `no_std` that way, if there are
+/// any.
+pub fn configure_no_std(
+    cfg: &mut VariantConfig,
+    crates: &[Crate],
+    notes: &mut Vec<String>,
+) -> Result<Option<VariantConfig>> {
+    let mut optional_no_std = BTreeMap::new();
+    for crate_ in crates.iter().filter(|crate_| crate_.types.iter().any(|t| t.is_library())) {
+        match no_std_support(crate_)? {
+            Some(NoStd::Always { alloc }) => {
+                let package_cfg = package_entry(cfg, &crate_.package_name);
+                package_cfg.no_std = true;
+                package_cfg.alloc = alloc;
+                notes.push(format!(
+                    "{} is declared no_std{}, so it is built without std.",
+                    crate_.package_name,
+                    if alloc { " and uses alloc" } else { "" }
+                ));
+            }
+            Some(NoStd::Optional { alloc }) => {
+                optional_no_std.insert(crate_.package_name.clone(), alloc);
+            }
+            None => {}
+        }
+    }
+    if optional_no_std.is_empty() {
+        return Ok(None);
+    }
+
+    // The main variant is for Android.bp, so build the no_std version for rules.mk, where it is
+    // needed.
+    let mut no_std_variant = VariantConfig {
+        tests: false,
+        features: Some(vec![]),
+        generate_androidbp: false,
+        generate_rulesmk: true,
+        ..cfg.clone()
+    };
+    for (package_name, alloc) in optional_no_std {
+        let package_cfg = package_entry(&mut no_std_variant, &package_name);
+        package_cfg.no_std = true;
+        package_cfg.alloc = alloc;
+        notes.push(format!(
+            "{package_name} can be built as no_std{}, so a second variant builds it without default \
+             features for rules.mk.",
+            if alloc { " with alloc" } else { "" }
+        ));
+    }
+    Ok(Some(no_std_variant))
+}
+
+/// Sets `device_supported: false` for packages which can only be built for the host.
+///
+/// These are packages with only proc-macro crates, and packages which depend on a module in the
+/// tree which is host-only.
+pub fn configure_host_only(
+    cfg: &mut VariantConfig,
+    crates: &[Crate],
+    tree_index: Option<&TreeIndex>,
+    notes: &mut Vec<String>,
+) {
+    let mut packages: BTreeMap<&str, Vec<&Crate>> = BTreeMap::new();
+    for crate_ in crates {
+        packages.entry(&crate_.package_name).or_default().push(crate_);
+    }
+    for (package_name, package_crates) in packages {
+        let non_test_crates: Vec<&Crate> = package_crates
+            .into_iter()
+            .filter(|crate_| !crate_.types.iter().any(|t| t.is_test()))
+            .collect();
+        if non_test_crates.is_empty() {
+            continue;
+        }
+        let reason = if non_test_crates
+            .iter()
+            .all(|crate_| crate_.types.iter().all(|t| *t == CrateType::ProcMacro))
+        {
+            Some("only has proc-macro crates".to_string())
+        } else if let Some(tree_index) = tree_index {
+            let package_cfg = cfg.package.get(package_name).cloned().unwrap_or_default();
+            non_test_crates.iter().flat_map(|crate_| &crate_.externs).find_map(|extern_dep| {
+                let module_name = dep_module_name(cfg, &package_cfg, &extern_dep.lib_name)?;
+                let module = tree_index.get(&module_name)?;
+                (extern_dep.extern_type == ExternType::Rust && module.host_only)
+                    .then(|| format!("depends on the host-only module {module_name}"))
+            })
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            package_entry(cfg, package_name).device_supported = false;
+            notes.push(format!("{package_name} {reason}, so it is only built for the host."));
+        }
+    }
+}
+
+/// Sets `bindgen` for packages whose build script only generates bindings with bindgen, so that a
+/// `rust_bindgen` module generates them instead.
+pub fn configure_bindgen(
+    cfg: &mut VariantConfig,
+    build_scripts: &BTreeMap<String, BuildScript>,
+    notes: &mut Vec<String>,
+) {
+    for (package_name, build_script) in build_scripts {
+        if build_script.bindgen.is_some() && build_script.replaceable_by_modules() {
+            package_entry(cfg, package_name).bindgen = build_script.bindgen.clone();
+            notes.push(format!(
+                "The build script of {package_name} generates bindings with bindgen, so bindgen is \
+                 set to generate them with a rust_bindgen module. Check its options, and add any \
+                 header_libs which the wrapper header needs."
+            ));
+        }
+    }
+}
+
+/// Sets `protobuf` for packages whose build script only generates code from `.proto` files, so that
+/// a generated module does so instead.
+pub fn configure_protobuf(
+    cfg: &mut VariantConfig,
+    build_scripts: &BTreeMap<String, BuildScript>,
+    notes: &mut Vec<String>,
+) {
+    for (package_name, build_script) in build_scripts {
+        if let Some(protobuf) = &build_script.protobuf {
+            if build_script.replaceable_by_modules() {
+                package_entry(cfg, package_name).protobuf = Some(protobuf.clone());
+                notes.push(format!(
+                    "The build script of {package_name} generates code from {}, so protobuf is \
+                     set to generate it with a module instead. Check that the crate includes the \
+                     generated files by the names the module gives them.",
+                    protobuf.protos.join(", ")
+                ));
+            }
+        }
+    }
+}
+
+/// Sets `copy_out` and `run_cargo` for packages with a build script whose output is included from
+/// `OUT_DIR`.
+pub fn configure_copy_out(
+    cfg: &mut VariantConfig,
+    crates: &[Crate],
+    build_scripts: &BTreeMap<String, BuildScript>,
+    notes: &mut Vec<String>,
+) -> Result<()> {
+    let packages: BTreeMap<&str, &Path> = crates
+        .iter()
+        .map(|crate_| (crate_.package_name.as_str(), crate_.package_dir.as_path()))
+        .collect();
+    for (package_name, package_dir) in packages {
+        let has_source_modules = cfg.package.get(package_name).is_some_and(|package_cfg| {
+            package_cfg.bindgen.is_some() || package_cfg.protobuf.is_some()
+        });
+        let Some(build_script) = build_scripts.get(package_name) else {
+            continue;
+        };
+        if has_source_modules {
+            continue;
+        }
+        // The build script itself refers to `OUT_DIR` to write to it.
+        let build_script_files = build_script
+            .source_files()?
+            .into_iter()
+            .map(|path| path.canonicalize().unwrap_or(path))
+            .collect();
+        if uses_out_dir(package_dir, &build_script_files)? {
+            package_entry(cfg, package_name).copy_out = true;
+            cfg.run_cargo = true;
+            notes.push(format!(
+                "{package_name} includes files generated by its build script from OUT_DIR, so \
+                 copy_out is set and cargo must be run."
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether any Rust source file under the given directory, other than the `skipped` files,
+/// refers to `OUT_DIR`.
+fn uses_out_dir(dir: &Path, skipped: &BTreeSet<PathBuf>) -> Result<bool> {
+    for entry in read_dir(dir).with_context(|| format!("failed to read directory {dir:?}"))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if !SKIPPED_DIRS.contains(&&*entry.file_name().to_string_lossy())
+                && uses_out_dir(&path, skipped)?
+            {
+                return Ok(true);
+            }
+        } else if path.extension().is_some_and(|extension| extension == "rs")
+            && !skipped.contains(&path)
+            && read_to_string(&path)?.contains(r#"env!("OUT_DIR")"#)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Checks that the module for each dependency exists in the tree.
+///
+/// If a missing module is available under a versioned name then a `module_name_overrides` entry is
+/// added. If a missing module is only used by tests then it is added to `dep_blocklist`.
+pub fn configure_deps(
+    cfg: &mut VariantConfig,
+    crates: &[Crate],
+    tree_index: &TreeIndex,
+    notes: &mut Vec<String>,
+) {
+    // Crates from this package don't need to be in the tree yet.
+    let own_crates: BTreeSet<&str> = crates.iter().map(|crate_| crate_.name.as_str()).collect();
+    // The packages which use each missing module, and whether they use it only from tests.
+    let mut missing: BTreeMap<String, BTreeMap<String, bool>> = BTreeMap::new();
+    for crate_ in crates {
+        let is_test = crate_.types.iter().any(|t| t.is_test());
+        let package_cfg = cfg.package.get(&crate_.package_name).cloned().unwrap_or_default();
+        for extern_dep in &crate_.externs {
+            if own_crates.contains(extern_dep.lib_name.as_str()) {
+                continue;
+            }
+            let Some(module_name) = dep_module_name(cfg, &package_cfg, &extern_dep.lib_name) else {
+                continue;
+            };
+            if tree_index.get(&module_name).is_none() {
+                let test_only = missing
+                    .entry(module_name)
+                    .or_default()
+                    .entry(crate_.package_name.clone())
+                    .or_insert(true);
+                *test_only &= is_test;
+            }
+        }
+    }
+
+    for (module_name, packages) in missing {
+        let versions = tree_index.versions_of(&module_name);
+        if let [version] = versions.as_slice() {
+            notes.push(format!(
+                "{module_name} isn't in the tree, but {version} is, so it is used instead."
+            ));
+            cfg.module_name_overrides.insert(module_name, version.to_string());
+            continue;
+        }
+        if !versions.is_empty() {
+            notes.push(format!(
+                "{module_name} isn't in the tree. Add a module_name_overrides entry for one of {}.",
+                versions.join(", ")
+            ));
+            continue;
+        }
+        for (package_name, test_only) in packages {
+            if test_only {
+                package_entry(cfg, &package_name).dep_blocklist.push(module_name.clone());
+                notes.push(format!(
+                    "{module_name} isn't in the tree and is only used by tests of {package_name}, \
+                     so it is blocklisted. Tests which need it may fail to build."
+                ));
+            } else {
+                notes.push(format!(
+                    "{module_name} isn't in the tree, but is needed by {package_name}. It must be \
+                     imported before {package_name} can be built."
+                ));
+            }
+        }
+    }
+}
+
+/// Returns the name of the module for the given library dependency, or `None` if it is
+/// blocklisted.
+fn dep_module_name(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    lib_name: &str,
+) -> Option<String> {
+    override_module_name(
+        &format!("lib{lib_name}"),
+        &package_cfg.dep_blocklist,
+        &cfg.module_name_overrides,
+        &rename_registry().rename_map,
+    )
+}
+
+/// Adds `test_data` entries for files in the package which tests appear to read at runtime.
+pub fn configure_test_data(
+    cfg: &mut VariantConfig,
+    crates: &[Crate],
+    notes: &mut Vec<String>,
+) -> Result<()> {
+    for crate_ in crates.iter().filter(|crate_| crate_.types.iter().any(|t| t.is_test())) {
+        let data = test_data_files(crate_)?;
+        if data.is_empty() {
+            continue;
+        }
+        let main_src = crate_.main_src.to_string_lossy().into_owned();
+        notes.push(format!(
+            "{main_src} refers to {}, so they are added as test data.",
+            data.join(", ")
+        ));
+        package_entry(cfg, &crate_.package_name).test_data.insert(main_src, data);
+    }
+    Ok(())
+}
+
+/// Returns the files and directories in the package which the given test crate appears to use at
+/// runtime, found from string literals in its main source file which name them.
+///
+/// Directories are returned as globs of their contents. Files included at compile time with
+/// `include_str!` or `include_bytes!` are skipped.
+pub fn test_data_files(crate_: &Crate) -> Result<Vec<String>> {
+    static STRING_LITERAL: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"(include_(?:str|bytes)!\s*\(\s*)?"([^"\\]+)""#).unwrap());
+
+    let path = crate_.package_dir.join(&crate_.main_src);
+    let source = read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+    let mut data = BTreeSet::new();
+    for captures in STRING_LITERAL.captures_iter(&source) {
+        if captures.get(1).is_some() {
+            continue;
+        }
+        // Paths joined to `CARGO_MANIFEST_DIR` with `concat!` start with a slash.
+        let literal = captures[2].trim_start_matches('/');
+        let relative_path = Path::new(literal);
+        if literal.is_empty()
+            || !relative_path.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            continue;
+        }
+        let full_path = crate_.package_dir.join(relative_path);
+        if full_path.is_dir() {
+            data.insert(format!("{}/**/*", literal.trim_end_matches('/')));
+        } else if full_path.is_file() && full_path.extension() != Some(OsStr::new("rs")) {
+            data.insert(literal.to_string());
+        }
+    }
+    Ok(data.into_iter().collect())
+}
+
+/// Returns the config for the given package in the variant, adding a default one if necessary.
+fn package_entry<'a>(
+    cfg: &'a mut VariantConfig,
+    package_name: &str,
+) -> &'a mut PackageVariantConfig {
+    cfg.package.entry(package_name.to_string()).or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_script::BuildScriptKind;
+    use crate::cargo::Extern;
+    use std::fs::{create_dir_all, write};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn make_crate(package_dir: &Path, main_src: &str, crate_type: CrateType) -> Crate {
+        Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            types: vec![crate_type],
+            package_dir: package_dir.to_owned(),
+            main_src: PathBuf::from(main_src),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detect_no_std() -> Result<()> {
+        let package_dir = tempdir()?;
+        create_dir_all(package_dir.path().join("src"))?;
+        let crate_ = make_crate(package_dir.path(), "src/lib.rs", CrateType::Lib);
+
+        write(package_dir.path().join("src/lib.rs"), "#![no_std]\n")?;
+        assert_eq!(no_std_support(&crate_)?, Some(NoStd::Always { alloc: false }));
+        write(
+            package_dir.path().join("src/lib.rs"),
+            "#![cfg_attr(not(feature = \"std\"), no_std)]\nextern crate alloc;\n",
+        )?;
+        assert_eq!(no_std_support(&crate_)?, Some(NoStd::Optional { alloc: true }));
+
+        let mut cfg = VariantConfig::default();
+        let mut notes = Vec::new();
+        let no_std_variant = configure_no_std(&mut cfg, &[crate_], &mut notes)?.unwrap();
+        assert_eq!(cfg, VariantConfig::default());
+        assert!(!no_std_variant.generate_androidbp);
+        assert!(no_std_variant.generate_rulesmk);
+        assert!(no_std_variant.package["foo"].no_std);
+        assert!(no_std_variant.package["foo"].alloc);
+        assert_eq!(notes.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_proc_macro_only() {
+        let crates = [
+            make_crate(Path::new("/foo"), "src/lib.rs", CrateType::ProcMacro),
+            make_crate(Path::new("/foo"), "tests/test.rs", CrateType::Test),
+        ];
+        let mut cfg = VariantConfig::default();
+        let mut notes = Vec::new();
+        configure_host_only(&mut cfg, &crates, None, &mut notes);
+        assert!(!cfg.package["foo"].device_supported);
+        assert_eq!(notes.len(), 1);
+    }
+
+    #[test]
+    fn missing_deps() {
+        let mut tree_index = TreeIndex::default();
+        tree_index.add_blueprint(
+            Path::new("external/rust/crates"),
+            "rust_library {\n    name: \"libbar_0_4\",\n}\n",
+        );
+        let mut lib = make_crate(Path::new("/foo"), "src/lib.rs", CrateType::Lib);
+        lib.externs.push(Extern {
+            name: "bar".to_string(),
+            lib_name: "bar".to_string(),
+            extern_type: ExternType::Rust,
+            version: None,
+        });
+        let mut test = make_crate(Path::new("/foo"), "tests/test.rs", CrateType::Test);
+        test.externs.push(Extern {
+            name: "baz".to_string(),
+            lib_name: "baz".to_string(),
+            extern_type: ExternType::Rust,
+            version: None,
+        });
+        let mut cfg = VariantConfig::default();
+        let mut notes = Vec::new();
+        configure_deps(&mut cfg, &[lib, test], &tree_index, &mut notes);
+        assert_eq!(
+            cfg.module_name_overrides,
+            [("libbar".to_string(), "libbar_0_4".to_string())].into_iter().collect()
+        );
+        assert_eq!(cfg.package["foo"].dep_blocklist, vec!["libbaz".to_string()]);
+        assert_eq!(notes.len(), 2);
+    }
+
+    #[test]
+    fn detect_copy_out() -> Result<()> {
+        let package_dir = tempdir()?;
+        let package_dir = package_dir.path().canonicalize()?;
+        create_dir_all(package_dir.join("build"))?;
+        create_dir_all(package_dir.join("src"))?;
+        write(package_dir.join("build/main.rs"), "mod out;\nfn main() {}\n")?;
+        write(package_dir.join("build/out.rs"), r#"const OUT_DIR: &str = env!("OUT_DIR");"#)?;
+        write(package_dir.join("src/lib.rs"), "mod build;\n")?;
+        let crates = [make_crate(&package_dir, "src/lib.rs", CrateType::Lib)];
+        let build_scripts: BTreeMap<String, BuildScript> = [(
+            "foo".to_string(),
+            BuildScript {
+                src_path: package_dir.join("build/main.rs"),
+                kind: BuildScriptKind::Codegen,
+                reasons: vec![],
+                evaluable: false,
+                bindgen: None,
+                protobuf: None,
+            },
+        )]
+        .into_iter()
+        .collect();
+
+        // The build script's own use of `OUT_DIR` doesn't count.
+        let mut cfg = VariantConfig { run_cargo: false, ..Default::default() };
+        let mut notes = Vec::new();
+        configure_copy_out(&mut cfg, &crates, &build_scripts, &mut notes)?;
+        assert!(!cfg.run_cargo);
+        assert!(notes.is_empty());
+
+        // A module of the crate called `build.rs` isn't the build script.
+        write(
+            package_dir.join("src/build.rs"),
+            r#"include!(concat!(env!("OUT_DIR"), "/generated.rs"));"#,
+        )?;
+        configure_copy_out(&mut cfg, &crates, &build_scripts, &mut notes)?;
+        assert!(cfg.run_cargo);
+        assert!(cfg.package["foo"].copy_out);
+        assert_eq!(notes.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn find_test_data() -> Result<()> {
+        let package_dir = tempdir()?;
+        create_dir_all(package_dir.path().join("tests/data"))?;
+        write(package_dir.path().join("tests/data/input.txt"), "")?;
+        write(package_dir.path().join("tests/golden.txt"), "")?;
+        write(package_dir.path().join("tests/inline.txt"), "")?;
+        write(
+            package_dir.path().join("tests/test.rs"),
+            r#"
+            const INLINE: &str = include_str!("inline.txt");
+            fn golden() -> &'static str { concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden.txt") }
+            fn data_dir() -> &'static str { "tests/data" }
+            fn other() -> &'static str { "not a file" }
+            "#,
+        )?;
+        let crate_ = make_crate(package_dir.path(), "tests/test.rs", CrateType::Test);
+        assert_eq!(
+            test_data_files(&crate_)?,
+            vec!["tests/data/**/*".to_string(), "tests/golden.txt".to_string()]
+        );
+        Ok(())
+    }
+}
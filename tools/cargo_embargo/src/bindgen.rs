@@ -0,0 +1,277 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `rust_bindgen` modules to generate the bindings which a package's build script would otherwise
+//! generate with bindgen.
+
+use crate::bp::BpModule;
+use crate::config::{BindgenConfig, PackageVariantConfig, VariantConfig};
+use crate::override_module_name;
+use crate::rename_registry::rename_registry;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// The default `source_stem`, for the common `out_path.join("bindings.rs")`.
+const DEFAULT_SOURCE_STEM: &str = "bindings";
+
+/// Methods of `bindgen::Builder` which take no argument or a boolean, and the equivalent bindgen
+/// flag.
+const BUILDER_FLAGS: [(&str, &str); 8] = [
+    (r"derive_default\(\s*true\s*\)", "--with-derive-default"),
+    (r"derive_eq\(\s*true\s*\)", "--with-derive-eq"),
+    (r"derive_hash\(\s*true\s*\)", "--with-derive-hash"),
+    (r"derive_partialeq\(\s*true\s*\)", "--with-derive-partialeq"),
+    (r"generate_comments\(\s*false\s*\)", "--no-doc-comments"),
+    (r"layout_tests\(\s*false\s*\)", "--no-layout-tests"),
+    (r"size_t_is_usize\(\s*true\s*\)", "--size_t-is-usize"),
+    (r"use_core\(\s*\)", "--use-core"),
+];
+
+/// Methods of `bindgen::Builder` which take a string, and the equivalent bindgen flag.
+const BUILDER_STRING_FLAGS: [(&str, &str); 9] = [
+    ("blocklist_function", "--blocklist-function"),
+    ("blocklist_item", "--blocklist-item"),
+    ("blocklist_type", "--blocklist-type"),
+    ("ctypes_prefix", "--ctypes-prefix"),
+    ("opaque_type", "--opaque-type"),
+    ("constified_enum_module", "--constified-enum-module"),
+    ("newtype_enum", "--newtype-enum"),
+    ("raw_line", "--raw-line"),
+    ("rustified_enum", "--rustified-enum"),
+];
+
+/// Returns the name of the `rust_bindgen` module for the given package, before any renaming.
+fn bindgen_module_name(package_name: &str) -> String {
+    format!("lib{}_bindgen", package_name.replace('-', "_"))
+}
+
+/// Generates the `rust_bindgen` module for the given package, if it has a `bindgen` config and
+/// the module isn't blocklisted.
+pub fn bindgen_module(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_name: &str,
+) -> Option<BpModule> {
+    let bindgen_cfg = package_cfg.bindgen.as_ref()?;
+    let module_name = override_module_name(
+        &bindgen_module_name(package_name),
+        &cfg.module_blocklist,
+        &cfg.module_name_overrides,
+        &rename_registry().rename_map,
+    )?;
+    let mut m = source_module("rust_bindgen", module_name, cfg, package_cfg);
+    m.props.set("crate_name", format!("{}_bindgen", package_name.replace('-', "_")));
+    m.props.set("wrapper_src", bindgen_cfg.wrapper_src.clone());
+    m.props.set(
+        "source_stem",
+        bindgen_cfg.source_stem.clone().unwrap_or_else(|| DEFAULT_SOURCE_STEM.to_string()),
+    );
+    let bindgen_flags: Vec<String> = [
+        ("--allowlist-function", &bindgen_cfg.allowlist_functions),
+        ("--allowlist-type", &bindgen_cfg.allowlist_types),
+        ("--allowlist-var", &bindgen_cfg.allowlist_vars),
+    ]
+    .into_iter()
+    .flat_map(|(flag, patterns)| patterns.iter().map(move |pattern| format!("{flag}={pattern}")))
+    .chain(bindgen_cfg.bindgen_flags.iter().cloned())
+    .collect();
+    m.props.set_if_nonempty("bindgen_flags", bindgen_flags);
+    m.props.set_if_nonempty("cflags", bindgen_cfg.cflags.clone());
+    m.props.set_if_nonempty("header_libs", bindgen_cfg.header_libs.clone());
+    m.props.set_if_nonempty("static_libs", bindgen_cfg.static_libs.clone());
+    m.props.set_if_nonempty("shared_libs", bindgen_cfg.shared_libs.clone());
+    Some(m)
+}
+
+/// Returns a new module of the given type (or its `_host` variant for host-only packages) with the
+/// given name, which generates sources for the package's crates, so is available wherever they are.
+pub fn source_module(
+    module_type: &str,
+    module_name: String,
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+) -> BpModule {
+    let host = if package_cfg.device_supported { "" } else { "_host" };
+    let mut m = BpModule::new(module_type.to_string() + host);
+    m.props.set("name", module_name);
+    if let Some(defaults) = &cfg.global_defaults {
+        m.props.set("defaults", vec![defaults.clone()]);
+    }
+    if package_cfg.host_supported && package_cfg.device_supported {
+        m.props.set("host_supported", true);
+    }
+    if package_cfg.device_supported {
+        for (property, enabled) in [
+            ("product_available", cfg.product_available),
+            ("vendor_available", cfg.vendor_available),
+        ] {
+            if enabled {
+                m.props.set(property, true);
+            }
+        }
+        m.props.set_if_nonempty("apex_available", cfg.apex_available.clone());
+        if let Some(min_sdk_version) = &cfg.min_sdk_version {
+            m.props.set("min_sdk_version", min_sdk_version.clone());
+        }
+    }
+    m
+}
+
+/// Finds the bindgen options which the given build script source uses, or returns `None` if it
+/// doesn't use a single wrapper header given as a string literal.
+pub fn bindgen_config_from_build_script(source: &str) -> Option<BindgenConfig> {
+    static HEADER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"\.header\(\s*"([^"]+)"\s*\)"#).unwrap());
+    static ALLOWLIST: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r#"\.(?:allowlist|whitelist)_(function|type|var)\(\s*"([^"]+)"\s*\)"#).unwrap()
+    });
+    static CLANG_ARG: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"\.clang_arg\(\s*"([^"]+)"\s*\)"#).unwrap());
+    static SOURCE_STEM: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"join\(\s*"([\w-]+)\.rs"\s*\)"#).unwrap());
+
+    if !source.contains(".header(") {
+        return None;
+    }
+    let headers: Vec<_> = HEADER.captures_iter(source).collect();
+    let [header] = headers.as_slice() else {
+        return None;
+    };
+    let mut bindgen_cfg = BindgenConfig {
+        wrapper_src: header[1].to_string(),
+        source_stem: SOURCE_STEM
+            .captures(source)
+            .map(|captures| captures[1].to_string())
+            .filter(|source_stem| source_stem != DEFAULT_SOURCE_STEM),
+        ..Default::default()
+    };
+    for captures in ALLOWLIST.captures_iter(source) {
+        let pattern = unescape(&captures[2]);
+        match &captures[1] {
+            "function" => bindgen_cfg.allowlist_functions.push(pattern),
+            "type" => bindgen_cfg.allowlist_types.push(pattern),
+            _ => bindgen_cfg.allowlist_vars.push(pattern),
+        }
+    }
+    for (method, flag) in BUILDER_FLAGS {
+        if Regex::new(&format!(r"\.{method}")).unwrap().is_match(source) {
+            bindgen_cfg.bindgen_flags.push(flag.to_string());
+        }
+    }
+    for (method, flag) in BUILDER_STRING_FLAGS {
+        let regex = Regex::new(&format!(r#"\.{method}\(\s*"([^"]+)"\s*\)"#)).unwrap();
+        for captures in regex.captures_iter(source) {
+            bindgen_cfg.bindgen_flags.push(format!("{flag}={}", unescape(&captures[1])));
+        }
+    }
+    bindgen_cfg.cflags =
+        CLANG_ARG.captures_iter(source).map(|captures| unescape(&captures[1])).collect();
+    Some(bindgen_cfg)
+}
+
+/// Removes the escaping of backslashes from the contents of a Rust string literal.
+fn unescape(literal: &str) -> String {
+    literal.replace(r"\\", r"\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_from_build_script() {
+        let source = r#"
+            use std::env;
+            use std::path::PathBuf;
+
+            fn main() {
+                println!("cargo:rustc-link-lib=foo");
+                let bindings = bindgen::Builder::default()
+                    .header("wrapper.h")
+                    .clang_arg("-Iinclude")
+                    .allowlist_function("foo_.*")
+                    .allowlist_type("foo_t")
+                    .allowlist_var("FOO_\\w+")
+                    .blocklist_type("max_align_t")
+                    .derive_default(true)
+                    .layout_tests(false)
+                    .generate()
+                    .expect("Unable to generate bindings");
+                let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+                bindings.write_to_file(out_path.join("foo_bindings.rs")).unwrap();
+            }
+        "#;
+        assert_eq!(
+            bindgen_config_from_build_script(source),
+            Some(BindgenConfig {
+                wrapper_src: "wrapper.h".to_string(),
+                source_stem: Some("foo_bindings".to_string()),
+                allowlist_functions: vec!["foo_.*".to_string()],
+                allowlist_types: vec!["foo_t".to_string()],
+                allowlist_vars: vec![r"FOO_\w+".to_string()],
+                bindgen_flags: vec![
+                    "--with-derive-default".to_string(),
+                    "--no-layout-tests".to_string(),
+                    "--blocklist-type=max_align_t".to_string(),
+                ],
+                cflags: vec!["-Iinclude".to_string()],
+                ..Default::default()
+            })
+        );
+
+        // The header isn't a literal.
+        assert_eq!(
+            bindgen_config_from_build_script(
+                "fn main() { bindgen::Builder::default().header(header_path()).generate(); }"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn generate_module() {
+        let cfg = VariantConfig {
+            apex_available: vec!["//apex_available:platform".to_string()],
+            ..Default::default()
+        };
+        let mut package_cfg = PackageVariantConfig::default();
+        assert_eq!(bindgen_module(&cfg, &package_cfg, "foo-sys"), None);
+
+        package_cfg.bindgen = Some(BindgenConfig {
+            wrapper_src: "wrapper.h".to_string(),
+            allowlist_functions: vec!["foo_.*".to_string()],
+            bindgen_flags: vec!["--no-layout-tests".to_string()],
+            header_libs: vec!["libfoo_headers".to_string()],
+            ..Default::default()
+        });
+        let mut contents = String::new();
+        bindgen_module(&cfg, &package_cfg, "foo-sys").unwrap().write(&mut contents).unwrap();
+        assert_eq!(
+            contents,
+            r#"rust_bindgen {
+name: "libfoo_sys_bindgen",
+host_supported: true,
+crate_name: "foo_sys_bindgen",
+wrapper_src: "wrapper.h",
+source_stem: "bindings",
+bindgen_flags: ["--allowlist-function=foo_.*", "--no-layout-tests"],
+header_libs: ["libfoo_headers"],
+apex_available: ["//apex_available:platform"],
+product_available: true,
+vendor_available: true,
+}
+"#
+        );
+    }
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
@@ -109,6 +109,13 @@ impl BpProperties {
             "stem",
             "host_supported",
             "crate_name",
+            "wrapper_src",
+            "protos",
+            "source_stem",
+            "proto_flags",
+            "bindgen_flags",
+            "cflags",
+            "header_libs",
             "cargo_env_compat",
             "cargo_pkg_version",
             "crate_root",
@@ -186,6 +193,12 @@ impl BpValue {
     }
 }
 
+impl From<BpProperties> for BpValue {
+    fn from(x: BpProperties) -> Self {
+        BpValue::Object(x)
+    }
+}
+
 impl From<bool> for BpValue {
     fn from(x: bool) -> Self {
         BpValue::Bool(x)
@@ -215,3 +228,261 @@ impl<T: Into<BpValue>> From<Vec<T>> for BpValue {
         BpValue::List(x.into_iter().map(|x| x.into()).collect())
     }
 }
+
+/// Parses the modules defined in the given Blueprint file contents.
+///
+/// Top-level variable assignments are evaluated and substituted into module properties, but other
+/// features of Blueprint such as `soong_namespace` are not understood. Integer values are not
+/// supported.
+pub fn parse_blueprint(contents: &str) -> Result<Vec<BpModule>> {
+    BlueprintParser { chars: contents.chars().collect(), pos: 0, variables: BTreeMap::new() }
+        .parse_file()
+}
+
+/// A simple recursive descent parser for Blueprint files.
+struct BlueprintParser {
+    chars: Vec<char>,
+    pos: usize,
+    variables: BTreeMap<String, BpValue>,
+}
+
+impl BlueprintParser {
+    fn parse_file(mut self) -> Result<Vec<BpModule>> {
+        let mut modules = Vec::new();
+        while self.peek().is_some() {
+            let ident = self.parse_ident()?;
+            match self.peek() {
+                Some('{') => {
+                    let props = self.parse_object()?;
+                    modules.push(BpModule { module_type: ident, props });
+                }
+                Some('=') => {
+                    self.expect('=')?;
+                    let value = self.parse_value()?;
+                    self.variables.insert(ident, value);
+                }
+                Some('+') => {
+                    self.expect('+')?;
+                    self.expect('=')?;
+                    let value = self.parse_value()?;
+                    let existing = self
+                        .variables
+                        .remove(&ident)
+                        .ok_or_else(|| self.error(&format!("undefined variable {ident:?}")))?;
+                    let value = self.concat(existing, value)?;
+                    self.variables.insert(ident, value);
+                }
+                _ => bail!(self.error("expected module or assignment")),
+            }
+        }
+        Ok(modules)
+    }
+
+    /// Skips whitespace and comments, and returns the next character if any.
+    fn peek(&mut self) -> Option<char> {
+        loop {
+            match (self.chars.get(self.pos), self.chars.get(self.pos + 1)) {
+                (Some(c), _) if c.is_whitespace() => self.pos += 1,
+                (Some('/'), Some('/')) => {
+                    while self.chars.get(self.pos).is_some_and(|c| *c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    self.pos += 2;
+                    while self.pos < self.chars.len()
+                        && !(self.chars[self.pos] == '*'
+                            && self.chars.get(self.pos + 1) == Some(&'/'))
+                    {
+                        self.pos += 1;
+                    }
+                    self.pos += 2;
+                }
+                (c, _) => return c.copied(),
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        if self.peek() != Some(expected) {
+            bail!(self.error(&format!("expected {expected:?}")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        let line =
+            self.chars[..self.pos.min(self.chars.len())].iter().filter(|c| **c == '\n').count();
+        anyhow!("line {}: {message}", line + 1)
+    }
+
+    fn parse_ident(&mut self) -> Result<String> {
+        self.peek();
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!(self.error("expected identifier"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Parses a value, including any concatenations with `+`.
+    fn parse_value(&mut self) -> Result<BpValue> {
+        let mut value = self.parse_single_value()?;
+        while self.peek() == Some('+') {
+            self.pos += 1;
+            let other = self.parse_single_value()?;
+            value = self.concat(value, other)?;
+        }
+        Ok(value)
+    }
+
+    fn parse_single_value(&mut self) -> Result<BpValue> {
+        match self.peek() {
+            Some('"') => Ok(BpValue::String(self.parse_string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                while self.peek() != Some(']') {
+                    values.push(self.parse_value()?);
+                    if self.peek() != Some(']') {
+                        self.expect(',')?;
+                    }
+                }
+                self.pos += 1;
+                Ok(BpValue::List(values))
+            }
+            Some('{') => Ok(BpValue::Object(self.parse_object()?)),
+            Some(c) if c.is_alphabetic() || c == '_' => match self.parse_ident()?.as_str() {
+                "true" => Ok(BpValue::Bool(true)),
+                "false" => Ok(BpValue::Bool(false)),
+                variable => self
+                    .variables
+                    .get(variable)
+                    .cloned()
+                    .ok_or_else(|| self.error(&format!("undefined variable {variable:?}"))),
+            },
+            _ => bail!(self.error("expected value")),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.get(self.pos) {
+                Some('"') => break,
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.chars.get(self.pos) {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some(c) => s.push(*c),
+                        None => bail!(self.error("unterminated string")),
+                    }
+                }
+                Some(c) => s.push(*c),
+                None => bail!(self.error("unterminated string")),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(s)
+    }
+
+    fn parse_object(&mut self) -> Result<BpProperties> {
+        self.expect('{')?;
+        let mut props = BpProperties::new();
+        while self.peek() != Some('}') {
+            let key = self.parse_ident()?;
+            self.expect(':')?;
+            let value = self.parse_value()?;
+            props.map.insert(key, value);
+            if self.peek() != Some('}') {
+                self.expect(',')?;
+            }
+        }
+        self.pos += 1;
+        Ok(props)
+    }
+
+    fn concat(&self, a: BpValue, b: BpValue) -> Result<BpValue> {
+        match (a, b) {
+            (BpValue::String(a), BpValue::String(b)) => Ok(BpValue::String(a + &b)),
+            (BpValue::List(mut a), BpValue::List(b)) => {
+                a.extend(b);
+                Ok(BpValue::List(a))
+            }
+            (BpValue::Object(mut a), BpValue::Object(b)) => {
+                for (key, value) in b.map {
+                    let value = match a.map.remove(&key) {
+                        Some(existing) => self.concat(existing, value)?,
+                        None => value,
+                    };
+                    a.map.insert(key, value);
+                }
+                Ok(BpValue::Object(a))
+            }
+            _ => bail!(self.error("can't concatenate values of different types")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_write() {
+        let modules = parse_blueprint(
+            r#"
+// Comment
+package {
+    default_applicable_licenses: ["foo_license"],
+}
+
+common_libs = ["libbar"]
+
+/* Block
+   comment */
+rust_library {
+    name: "libfoo",
+    crate_name: "foo",
+    host_supported: true,
+    rustlibs: common_libs + [
+        "libbaz", // Trailing comment
+    ],
+    target: {
+        android: {
+            cfgs: ["android"],
+        },
+    },
+}
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].module_type, "package");
+        let mut bp = String::new();
+        modules[1].write(&mut bp).unwrap();
+        assert_eq!(
+            bp,
+            "rust_library {\n\
+             name: \"libfoo\",\n\
+             host_supported: true,\n\
+             crate_name: \"foo\",\n\
+             rustlibs: [\"libbar\", \"libbaz\"],\n\
+             target: {\n\
+             android: {\n\
+             cfgs: [\"android\"],\n\
+             },\n\
+             },\n\
+             }\n"
+        );
+        assert!(parse_blueprint("rust_library {\n    name: \"libfoo\"\n").is_err());
+    }
+}
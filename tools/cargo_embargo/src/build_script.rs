@@ -0,0 +1,562 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Classification of package build scripts, to decide whether cargo must be run to get their
+//! effects or whether `cargo metadata` is enough.
+
+use crate::bindgen::bindgen_config_from_build_script;
+use crate::cargo::metadata::{PackageMetadata, TargetKind, WorkspaceMetadata};
+use crate::config::{BindgenConfig, ProtobufConfig, ProtobufGenerator, VariantConfig};
+use crate::probe::{evaluate_probes, RustcVersion};
+use crate::protobuf::protobuf_config_from_build_script;
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+/// What a build script does which affects the build, from least to most involved.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum BuildScriptKind {
+    /// Nothing which `cargo metadata` misses, e.g. only `cargo:rerun-if-changed`.
+    NoOp,
+    /// Probes the compiler and sets cfgs with `cargo:rustc-cfg`.
+    CfgProbe,
+    /// Sets environment variables or other rustc flags.
+    EnvEmitting,
+    /// Generates source files in `OUT_DIR`.
+    Codegen,
+    /// Compiles or links native code.
+    NativeCompile,
+}
+
+impl Display for BuildScriptKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoOp => "does nothing which cargo metadata misses",
+            Self::CfgProbe => "probes the compiler to set cfgs",
+            Self::EnvEmitting => "sets environment variables or rustc flags",
+            Self::Codegen => "generates source files",
+            Self::NativeCompile => "compiles or links native code",
+        })
+    }
+}
+
+/// Build-dependencies which show what a build script does.
+const BUILD_DEPENDENCIES: [(&str, BuildScriptKind); 21] = [
+    ("autocfg", BuildScriptKind::CfgProbe),
+    ("rustc_version", BuildScriptKind::CfgProbe),
+    ("rustversion", BuildScriptKind::CfgProbe),
+    ("version_check", BuildScriptKind::CfgProbe),
+    ("vergen", BuildScriptKind::EnvEmitting),
+    ("bindgen", BuildScriptKind::Codegen),
+    ("built", BuildScriptKind::Codegen),
+    ("cbindgen", BuildScriptKind::Codegen),
+    ("lalrpop", BuildScriptKind::Codegen),
+    ("phf_codegen", BuildScriptKind::Codegen),
+    ("prost-build", BuildScriptKind::Codegen),
+    ("protobuf-codegen", BuildScriptKind::Codegen),
+    ("protoc-rust", BuildScriptKind::Codegen),
+    ("tonic-build", BuildScriptKind::Codegen),
+    ("cc", BuildScriptKind::NativeCompile),
+    ("cmake", BuildScriptKind::NativeCompile),
+    ("cxx-build", BuildScriptKind::NativeCompile),
+    ("nasm-rs", BuildScriptKind::NativeCompile),
+    ("pkg-config", BuildScriptKind::NativeCompile),
+    ("system-deps", BuildScriptKind::NativeCompile),
+    ("vcpkg", BuildScriptKind::NativeCompile),
+];
+
+/// Patterns in the source of a build script which show what it does.
+static SOURCE_PATTERNS: Lazy<[(Regex, BuildScriptKind, &str); 5]> = Lazy::new(|| {
+    [
+        (
+            Regex::new(r"cargo::?rustc-link-(lib|search)").unwrap(),
+            BuildScriptKind::NativeCompile,
+            "links native libraries",
+        ),
+        (Regex::new(r"\bOUT_DIR\b").unwrap(), BuildScriptKind::Codegen, "uses OUT_DIR"),
+        (
+            Regex::new(r"cargo::?rustc-(env|flags|link-arg)").unwrap(),
+            BuildScriptKind::EnvEmitting,
+            "emits `cargo:rustc-env` or rustc flags",
+        ),
+        (
+            Regex::new(r"cargo::?rustc-cfg").unwrap(),
+            BuildScriptKind::CfgProbe,
+            "emits `cargo:rustc-cfg`",
+        ),
+        (
+            Regex::new(r"rustc_version|RUSTC_VERSION|env::var(_os)?\(\s*.RUSTC.\s*\)").unwrap(),
+            BuildScriptKind::CfgProbe,
+            "checks the rustc version",
+        ),
+    ]
+});
+
+/// What a package's build script does, and why cargo_embargo thinks so.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BuildScript {
+    /// The main source file of the build script, from cargo metadata.
+    pub src_path: PathBuf,
+    pub kind: BuildScriptKind,
+    /// The evidence for `kind`, e.g. "has a build-dependency on cc".
+    pub reasons: Vec<String>,
+    /// Whether the cfgs it sets can be found by evaluating its probes statically, without running
+    /// it.
+    pub evaluable: bool,
+    /// The options it passes to bindgen, if it generates bindings and they could be found.
+    pub bindgen: Option<BindgenConfig>,
+    /// The `.proto` files it generates code from, if it uses prost or protobuf-codegen and any could
+    /// be found.
+    pub protobuf: Option<ProtobufConfig>,
+}
+
+impl BuildScript {
+    /// Returns whether cargo must be run to get the effects of the build script.
+    pub fn needs_cargo(&self) -> bool {
+        self.kind > BuildScriptKind::NoOp && !self.evaluable
+    }
+
+    /// Returns whether generated `rust_bindgen` or protobuf modules can replace everything the build
+    /// script does.
+    pub fn replaceable_by_modules(&self) -> bool {
+        (self.bindgen.is_some() || self.protobuf.is_some()) && self.kind <= BuildScriptKind::Codegen
+    }
+
+    /// Returns the source files of the build script.
+    pub fn source_files(&self) -> Result<Vec<PathBuf>> {
+        build_script_files(&self.src_path)
+    }
+
+    /// Returns a description of what the build script does and why, for notes and warnings.
+    pub fn describe(&self) -> String {
+        if self.reasons.is_empty() {
+            self.kind.to_string()
+        } else {
+            format!("{} (it {})", self.kind, self.reasons.join(", "))
+        }
+    }
+}
+
+/// Classifies the build scripts of the workspace members in the given `cargo metadata` output.
+///
+/// Returns the classification for each package name with a build script.
+pub fn classify_build_scripts(cargo_metadata: &str) -> Result<BTreeMap<String, BuildScript>> {
+    let metadata: WorkspaceMetadata =
+        serde_json::from_str(cargo_metadata).context("failed to parse cargo metadata")?;
+    let mut build_scripts = BTreeMap::new();
+    for package in &metadata.packages {
+        if !metadata.workspace_members.contains(&package.id) {
+            continue;
+        }
+        if let Some(build_script) = classify_build_script(package)? {
+            build_scripts.insert(package.name.clone(), build_script);
+        }
+    }
+    Ok(build_scripts)
+}
+
+/// Classifies the build script of the given package from its build-dependencies and source, or
+/// returns `None` if it doesn't have one.
+fn classify_build_script(package: &PackageMetadata) -> Result<Option<BuildScript>> {
+    let Some(target) =
+        package.targets.iter().find(|target| target.kind.contains(&TargetKind::CustomBuild))
+    else {
+        return Ok(None);
+    };
+    let mut kind = BuildScriptKind::NoOp;
+    let mut reasons = Vec::new();
+    let mut uses_bindgen = false;
+    let mut protobuf_generator = None;
+    for dependency in
+        package.dependencies.iter().filter(|dependency| dependency.kind.as_deref() == Some("build"))
+    {
+        if let Some((_, dependency_kind)) =
+            BUILD_DEPENDENCIES.iter().find(|(name, _)| *name == dependency.name)
+        {
+            kind = kind.max(*dependency_kind);
+            reasons.push(format!("has a build-dependency on {}", dependency.name));
+            uses_bindgen |= dependency.name == "bindgen";
+            match dependency.name.as_str() {
+                "prost-build" | "tonic-build" => {
+                    protobuf_generator = Some(ProtobufGenerator::Prost)
+                }
+                "protobuf-codegen" | "protoc-rust" => {
+                    protobuf_generator = Some(ProtobufGenerator::Protobuf)
+                }
+                _ => {}
+            }
+        }
+    }
+    let source = build_script_source(&target.src_path)?;
+    for (pattern, pattern_kind, reason) in SOURCE_PATTERNS.iter() {
+        if pattern.is_match(&source) {
+            kind = kind.max(*pattern_kind);
+            reasons.push(reason.to_string());
+        }
+    }
+    // Whether a build script can be evaluated doesn't depend on the version.
+    let evaluable = kind == BuildScriptKind::CfgProbe
+        && evaluate_probes(&source, RustcVersion::default()).is_some();
+    let bindgen = uses_bindgen.then(|| bindgen_config_from_build_script(&source)).flatten();
+    let protobuf = protobuf_generator.and_then(|generator| {
+        let package_dir = Path::new(&package.manifest_path).parent()?;
+        protobuf_config_from_build_script(&source, package_dir, generator)
+    });
+    Ok(Some(BuildScript {
+        src_path: target.src_path.clone(),
+        kind,
+        reasons,
+        evaluable,
+        bindgen,
+        protobuf,
+    }))
+}
+
+/// Statically evaluates the probes in the build scripts of the workspace members in the given
+/// `cargo metadata` output against the version of rustc returned by `rustc_version`, which is
+/// only called if there is a build script to evaluate.
+///
+/// Returns the cfgs set by each package's build script, each with a description of where it came
+/// from. Packages whose build script can't be evaluated are left out.
+pub fn static_cfgs(
+    cargo_metadata: &str,
+    rustc_version: impl Fn() -> Result<RustcVersion>,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    let metadata: WorkspaceMetadata =
+        serde_json::from_str(cargo_metadata).context("failed to parse cargo metadata")?;
+    let mut static_cfgs = BTreeMap::new();
+    let mut known_version = None;
+    for package in &metadata.packages {
+        if !metadata.workspace_members.contains(&package.id) {
+            continue;
+        }
+        let Some(target) =
+            package.targets.iter().find(|target| target.kind.contains(&TargetKind::CustomBuild))
+        else {
+            continue;
+        };
+        if !classify_build_script(package)?.is_some_and(|build_script| build_script.evaluable) {
+            continue;
+        }
+        let version = match known_version {
+            Some(version) => version,
+            None => *known_version.insert(rustc_version()?),
+        };
+        let Some(cfgs) = evaluate_probes(&build_script_source(&target.src_path)?, version) else {
+            continue;
+        };
+        let cfgs = cfgs
+            .into_iter()
+            .map(|(cfg, probe)| {
+                let origin = if let Some(probe) = probe {
+                    format!(
+                        "the probe `{probe}` in the build script of {}, evaluated against rustc \
+                         {version}",
+                        package.name
+                    )
+                } else {
+                    format!("`cargo:rustc-cfg` in the build script of {}", package.name)
+                };
+                (cfg, origin)
+            })
+            .collect();
+        static_cfgs.insert(package.name.clone(), cfgs);
+    }
+    Ok(static_cfgs)
+}
+
+/// Returns the source of the build script with the given main source file.
+fn build_script_source(src_path: &Path) -> Result<String> {
+    let mut source = String::new();
+    for path in build_script_files(src_path)? {
+        source += &read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+    }
+    Ok(source)
+}
+
+/// Returns the source files of the build script with the given main source file. If it is in its
+/// own directory, e.g. `build/main.rs`, these include the other Rust files there.
+fn build_script_files(src_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![src_path.to_owned()];
+    let Some(dir) = src_path.parent() else {
+        return Ok(files);
+    };
+    if dir.join("Cargo.toml").exists() {
+        return Ok(files);
+    }
+    for entry in read_dir(dir).with_context(|| format!("failed to read directory {dir:?}"))? {
+        let path = entry?.path();
+        if path != src_path && path.extension().is_some_and(|extension| extension == "rs") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Returns a description of each package whose build script has effects which the given variant
+/// config will miss.
+pub fn check_config(
+    cfg: &VariantConfig,
+    build_scripts: &BTreeMap<String, BuildScript>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (package_name, build_script) in build_scripts {
+        let package_cfg = cfg.package.get(package_name);
+        let copy_out = package_cfg.is_some_and(|package_cfg| package_cfg.copy_out);
+        let has_bindgen = package_cfg.is_some_and(|package_cfg| package_cfg.bindgen.is_some());
+        let has_protobuf = package_cfg.is_some_and(|package_cfg| package_cfg.protobuf.is_some());
+        if (has_bindgen || has_protobuf) && build_script.kind <= BuildScriptKind::Codegen {
+            continue;
+        }
+        let hint = if build_script.bindgen.is_some() && !has_bindgen {
+            " Consider setting `bindgen` to generate a `rust_bindgen` module instead."
+        } else if build_script.protobuf.is_some() && !has_protobuf {
+            " Consider setting `protobuf` to generate the code from its `.proto` files instead."
+        } else {
+            ""
+        };
+        if !cfg.run_cargo && build_script.needs_cargo() {
+            if build_script.kind == BuildScriptKind::CfgProbe && !cfg.extra_cfg.is_empty() {
+                continue;
+            }
+            problems.push(format!(
+                "The build script of {package_name} {}, but run_cargo is false so its effects \
+                 will be missing.{hint}",
+                build_script.describe()
+            ));
+        } else if build_script.kind == BuildScriptKind::Codegen && !copy_out {
+            problems.push(format!(
+                "The build script of {package_name} {}, but copy_out isn't set for it.{hint}",
+                build_script.describe()
+            ));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::metadata::{DependencyMetadata, TargetMetadata};
+    use crate::config::PackageVariantConfig;
+    use std::fs::{create_dir, write};
+    use tempfile::tempdir;
+
+    fn package_with_build_script(src_path: &Path, build_dependencies: &[&str]) -> PackageMetadata {
+        PackageMetadata {
+            name: "foo".to_string(),
+            dependencies: build_dependencies
+                .iter()
+                .map(|name| DependencyMetadata {
+                    name: name.to_string(),
+                    kind: Some("build".to_string()),
+                    optional: false,
+                    target: None,
+                    rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
+                })
+                .collect(),
+            targets: vec![TargetMetadata {
+                kind: vec![TargetKind::CustomBuild],
+                name: "build-script-build".to_string(),
+                src_path: src_path.to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn classify() -> Result<()> {
+        let package_dir = tempdir()?;
+        write(package_dir.path().join("Cargo.toml"), "")?;
+        let build_rs = package_dir.path().join("build.rs");
+
+        assert_eq!(classify_build_script(&PackageMetadata::default())?, None);
+
+        write(&build_rs, "fn main() {\n    println!(\"cargo:rerun-if-changed=build.rs\");\n}\n")?;
+        let build_script = classify_build_script(&package_with_build_script(&build_rs, &[]))?;
+        assert_eq!(
+            build_script,
+            Some(BuildScript {
+                src_path: build_rs.clone(),
+                kind: BuildScriptKind::NoOp,
+                reasons: vec![],
+                evaluable: false,
+                bindgen: None,
+                protobuf: None,
+            })
+        );
+        assert!(!build_script.unwrap().needs_cargo());
+
+        write(
+            &build_rs,
+            "fn main() {\n    autocfg::new().emit_has_path(\"std::ptr::NonNull\");\n}\n",
+        )?;
+        let build_script =
+            classify_build_script(&package_with_build_script(&build_rs, &["autocfg"]))?.unwrap();
+        assert_eq!(build_script.kind, BuildScriptKind::CfgProbe);
+        assert_eq!(
+            build_script.describe(),
+            "probes the compiler to set cfgs (it has a build-dependency on autocfg)"
+        );
+        assert!(build_script.needs_cargo());
+
+        // Version probes can be evaluated statically, so cargo needn't be run.
+        write(
+            &build_rs,
+            "fn main() {\n    if autocfg::new().probe_rustc_version(1, 70) {\n        \
+             autocfg::emit(\"has_foo\");\n    }\n}\n",
+        )?;
+        let build_script =
+            classify_build_script(&package_with_build_script(&build_rs, &["autocfg"]))?.unwrap();
+        assert_eq!(build_script.kind, BuildScriptKind::CfgProbe);
+        assert!(build_script.evaluable);
+        assert!(!build_script.needs_cargo());
+
+        write(
+            &build_rs,
+            "fn main() {\n    cc::Build::new().file(\"foo.c\").compile(\"foo\");\n}\n",
+        )?;
+        let build_script =
+            classify_build_script(&package_with_build_script(&build_rs, &["cc", "autocfg"]))?
+                .unwrap();
+        assert_eq!(build_script.kind, BuildScriptKind::NativeCompile);
+
+        // A build script in its own directory is classified from all its source files.
+        let build_dir = package_dir.path().join("build");
+        create_dir(&build_dir)?;
+        write(build_dir.join("main.rs"), "mod generate;\nfn main() {\n    generate::run();\n}\n")?;
+        write(
+            build_dir.join("generate.rs"),
+            "pub fn run() {\n    let out_dir = std::env::var(\"OUT_DIR\").unwrap();\n}\n",
+        )?;
+        let build_script =
+            classify_build_script(&package_with_build_script(&build_dir.join("main.rs"), &[]))?
+                .unwrap();
+        assert_eq!(
+            build_script,
+            BuildScript {
+                src_path: build_dir.join("main.rs"),
+                kind: BuildScriptKind::Codegen,
+                reasons: vec!["uses OUT_DIR".to_string()],
+                evaluable: false,
+                bindgen: None,
+                protobuf: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_variant_config() {
+        let build_scripts: BTreeMap<String, BuildScript> = [
+            (
+                "probe".to_string(),
+                BuildScript {
+                    src_path: PathBuf::new(),
+                    kind: BuildScriptKind::CfgProbe,
+                    reasons: vec![],
+                    evaluable: false,
+                    bindgen: None,
+                    protobuf: None,
+                },
+            ),
+            (
+                "gen".to_string(),
+                BuildScript {
+                    src_path: PathBuf::new(),
+                    kind: BuildScriptKind::Codegen,
+                    reasons: vec![],
+                    evaluable: false,
+                    bindgen: None,
+                    protobuf: None,
+                },
+            ),
+            (
+                "noop".to_string(),
+                BuildScript {
+                    src_path: PathBuf::new(),
+                    kind: BuildScriptKind::NoOp,
+                    reasons: vec![],
+                    evaluable: false,
+                    bindgen: None,
+                    protobuf: None,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let cfg = VariantConfig { run_cargo: false, ..Default::default() };
+        assert_eq!(
+            check_config(&cfg, &build_scripts),
+            vec![
+                "The build script of gen generates source files, but run_cargo is false so its \
+                 effects will be missing."
+                    .to_string(),
+                "The build script of probe probes the compiler to set cfgs, but run_cargo is false \
+                 so its effects will be missing."
+                    .to_string(),
+            ]
+        );
+
+        let cfg = VariantConfig::default();
+        assert_eq!(
+            check_config(&cfg, &build_scripts),
+            vec!["The build script of gen generates source files, but copy_out isn't set for it."
+                .to_string()]
+        );
+    }
+
+    #[test]
+    fn check_variant_config_bindgen() {
+        let bindgen_cfg =
+            BindgenConfig { wrapper_src: "wrapper.h".to_string(), ..Default::default() };
+        let build_scripts: BTreeMap<String, BuildScript> = [(
+            "foo-sys".to_string(),
+            BuildScript {
+                src_path: PathBuf::new(),
+                kind: BuildScriptKind::Codegen,
+                reasons: vec![],
+                evaluable: false,
+                bindgen: Some(bindgen_cfg.clone()),
+                protobuf: None,
+            },
+        )]
+        .into_iter()
+        .collect();
+
+        let mut cfg = VariantConfig { run_cargo: false, ..Default::default() };
+        assert_eq!(
+            check_config(&cfg, &build_scripts),
+            vec!["The build script of foo-sys generates source files, but run_cargo is false so \
+                  its effects will be missing. Consider setting `bindgen` to generate a \
+                  `rust_bindgen` module instead."
+                .to_string()]
+        );
+
+        cfg.package.insert(
+            "foo-sys".to_string(),
+            PackageVariantConfig { bindgen: Some(bindgen_cfg), ..Default::default() },
+        );
+        assert_eq!(check_config(&cfg, &build_scripts), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Content-addressed cache of cargo output.
+//!
+//! Running cargo is by far the slowest part of cargo_embargo. The cache stores the `cargo.metadata`
+//! and `cargo.out` for each variant in a directory named after a hash of everything which might
+//! affect them: the manifests, lockfile, build scripts and cargo config files of the package, the
+//! paths of its Rust source files, the variant config, the cargo and rustc versions, and relevant
+//! environment variables. If none of these have changed then cargo doesn't need to be run again.
+
+use crate::config::VariantConfig;
+use crate::fingerprint::Fingerprint;
+use crate::CargoOutput;
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::env;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, rename, write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Bump this when the cargo commands run by `generate_cargo_out` change, to invalidate old entries.
+const CACHE_FORMAT_VERSION: &str = "3";
+
+/// Files which affect the output of cargo if they appear anywhere in the package tree.
+const INPUT_FILE_NAMES: [&str; 2] = ["Cargo.toml", "Cargo.lock"];
+
+/// Directories of a package in which cargo looks for the sources of its targets. Other Rust source
+/// files in the package are build scripts or their modules.
+const TARGET_SOURCE_DIRS: [&str; 4] = ["benches", "examples", "src", "tests"];
+
+/// Directories which are never searched for input files.
+const SKIPPED_DIRS: [&str; 4] = [".git", ".repo", "target", "target.tmp"];
+
+/// Environment variables which affect the output of cargo, in addition to those starting with
+/// `CARGO` or `RUST` below.
+const INPUT_ENV_VARS: [&str; 5] = ["AR", "CC", "CFLAGS", "CXX", "CXXFLAGS"];
+
+/// Environment variables which start with `CARGO` or `RUST` but don't affect the output of cargo.
+const IGNORED_ENV_VARS: [&str; 2] = ["RUST_BACKTRACE", "RUST_LOG"];
+
+/// A cache of cargo output, stored in a directory.
+pub struct CargoCache {
+    dir: PathBuf,
+}
+
+impl CargoCache {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_owned() }
+    }
+
+    /// Returns the cached cargo output for the given key, if any.
+    pub fn get(&self, key: &str) -> Result<Option<CargoOutput>> {
+        let entry = self.dir.join(key);
+        if !entry.is_dir() {
+            return Ok(None);
+        }
+        Ok(Some(CargoOutput {
+            cargo_metadata: read_to_string(entry.join("cargo.metadata"))
+                .with_context(|| format!("failed to read cache entry {entry:?}"))?,
+            cargo_out: read_to_string(entry.join("cargo.out"))
+                .with_context(|| format!("failed to read cache entry {entry:?}"))?,
+            fuzz_metadata: serde_json::from_str(
+                &read_to_string(entry.join("fuzz.metadata"))
+                    .with_context(|| format!("failed to read cache entry {entry:?}"))?,
+            )
+            .with_context(|| format!("failed to parse cache entry {entry:?}"))?,
+        }))
+    }
+
+    /// Stores the given cargo output in the cache under the given key.
+    ///
+    /// The entry is written to a temporary directory first and then renamed into place, so that a
+    /// concurrent or interrupted run never sees a partial entry.
+    pub fn put(&self, key: &str, cargo_output: &CargoOutput) -> Result<()> {
+        create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create cache directory {:?}", self.dir))?;
+        let entry = self.dir.join(key);
+        let partial_entry = self.dir.join(format!("{key}.tmp-{}", std::process::id()));
+        create_dir_all(&partial_entry)?;
+        write(partial_entry.join("cargo.metadata"), &cargo_output.cargo_metadata)?;
+        write(partial_entry.join("cargo.out"), &cargo_output.cargo_out)?;
+        write(
+            partial_entry.join("fuzz.metadata"),
+            serde_json::to_string(&cargo_output.fuzz_metadata)?,
+        )?;
+        if rename(&partial_entry, &entry).is_err() {
+            // Another run probably stored the same entry first, so ours isn't needed.
+            remove_dir_all(&partial_entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether the output of cargo for the given variant may be cached.
+///
+/// Build script outputs for `copy_out` are read from the cargo target directory, which isn't
+/// cached, so variants using it must always run cargo.
+pub fn is_cacheable(cfg: &VariantConfig) -> bool {
+    !(cfg.run_cargo && cfg.package.values().any(|package_cfg| package_cfg.copy_out))
+}
+
+/// Returns the versions of the cargo and rustc binaries on the `PATH`.
+pub fn toolchain_version() -> Result<String> {
+    let mut version = String::new();
+    for tool in ["cargo", "rustc"] {
+        let output = Command::new(tool)
+            .arg("-vV")
+            .output()
+            .with_context(|| format!("failed to run {tool} -vV"))?;
+        version += &String::from_utf8_lossy(&output.stdout);
+    }
+    Ok(version)
+}
+
+/// Computes the cache key for running cargo for the given variant on the package in
+/// `package_dir`.
+pub fn cache_key(
+    cfg: &VariantConfig,
+    package_dir: &Path,
+    toolchain_version: &str,
+    env_vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<String> {
+    let mut fingerprint = Fingerprint::new();
+    fingerprint.update_str(CACHE_FORMAT_VERSION);
+    // The metadata contains absolute paths, so the same package in another location needs a
+    // different entry.
+    fingerprint.update_str(&package_dir.to_string_lossy());
+    fingerprint.update_str(&serde_json::to_string(cfg)?);
+    fingerprint.update_str(toolchain_version);
+
+    let mut env_vars: Vec<_> = env_vars
+        .into_iter()
+        .filter(|(name, _)| {
+            (name.starts_with("CARGO")
+                || name.starts_with("RUST")
+                || INPUT_ENV_VARS.contains(&&**name))
+                && !IGNORED_ENV_VARS.contains(&&**name)
+        })
+        .collect();
+    env_vars.sort();
+    for (name, value) in env_vars {
+        fingerprint.update_str(&name);
+        fingerprint.update_str(&value);
+    }
+
+    let mut input_files = InputFiles::default();
+    find_input_files(package_dir, false, &mut input_files)?;
+    // The names of the tests listed by `cargo test -- --list` depend on the contents of the sources.
+    if cfg.run_cargo && cfg.tests {
+        input_files.contents.append(&mut input_files.sources);
+    }
+    input_files.contents.sort();
+    input_files.contents.dedup();
+    input_files.sources.sort();
+    // Cargo finds targets from the paths of source files, so those matter even if their contents
+    // don't.
+    for source in &input_files.sources {
+        fingerprint
+            .update_str(&source.strip_prefix(package_dir).unwrap_or(source).to_string_lossy());
+    }
+
+    let mut input_files = input_files.contents;
+    for ancestor in package_dir.ancestors() {
+        input_files.push(ancestor.join(".cargo/config"));
+        input_files.push(ancestor.join(".cargo/config.toml"));
+    }
+    if let Some(cargo_home) = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")))
+    {
+        input_files.push(cargo_home.join("config"));
+        input_files.push(cargo_home.join("config.toml"));
+    }
+    for input_file in input_files {
+        fingerprint.update_file(&input_file)?;
+    }
+
+    Ok(fingerprint.finish())
+}
+
+/// Files in the package tree which may affect the output of cargo.
+#[derive(Debug, Default)]
+struct InputFiles {
+    /// Files whose contents affect the output of cargo.
+    contents: Vec<PathBuf>,
+    /// Rust source files of targets, whose paths affect the output of cargo.
+    sources: Vec<PathBuf>,
+}
+
+/// Adds all files under `dir` which may affect the output of cargo to `files`. `target_sources` is
+/// whether `dir` contains the sources of targets rather than build scripts.
+///
+/// Symlinked directories are not followed, to avoid cycles and files outside the package tree.
+fn find_input_files(dir: &Path, target_sources: bool, files: &mut InputFiles) -> Result<()> {
+    let is_package = dir.join("Cargo.toml").is_file();
+    if is_package {
+        if let Some(build_script) = custom_build_script(&dir.join("Cargo.toml"))? {
+            // The build script may include other modules from its directory or below.
+            match build_script.parent() {
+                Some(build_dir) if build_dir != dir => {
+                    find_rust_files(build_dir, &mut files.contents)?
+                }
+                _ => files.contents.push(build_script),
+            }
+        }
+    }
+    for entry in read_dir(dir).with_context(|| format!("failed to read directory {dir:?}"))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if file_name == ".cargo" {
+                files.contents.push(entry.path().join("config"));
+                files.contents.push(entry.path().join("config.toml"));
+            } else if !SKIPPED_DIRS.contains(&file_name) {
+                let target_sources = if is_package {
+                    TARGET_SOURCE_DIRS.contains(&file_name)
+                } else {
+                    target_sources
+                };
+                find_input_files(&entry.path(), target_sources, files)?;
+            }
+        } else if INPUT_FILE_NAMES.contains(&file_name) {
+            files.contents.push(entry.path());
+        } else if file_name.ends_with(".rs") {
+            if target_sources {
+                files.sources.push(entry.path());
+            } else {
+                files.contents.push(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends the paths of all Rust source files under `dir` to `files`.
+fn find_rust_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in read_dir(dir).with_context(|| format!("failed to read directory {dir:?}"))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_rust_files(&path, files)?;
+        } else if path.extension().is_some_and(|extension| extension == "rs") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of the build script set by the `build` key of the given manifest, if it is
+/// somewhere other than the default `build.rs`.
+fn custom_build_script(manifest_path: &Path) -> Result<Option<PathBuf>> {
+    static BUILD: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"(?m)^\s*build\s*=\s*"([^"]+)""#).unwrap());
+    let manifest = read_to_string(manifest_path)
+        .with_context(|| format!("failed to read {manifest_path:?}"))?;
+    Ok(BUILD
+        .captures(&manifest)
+        .and_then(|captures| captures.get(1))
+        .map(|build_script| build_script.as_str())
+        .filter(|build_script| *build_script != "build.rs")
+        .and_then(|build_script| Some(manifest_path.parent()?.join(build_script))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn put_and_get() -> Result<()> {
+        let cache_dir = tempdir()?;
+        let cache = CargoCache::new(cache_dir.path());
+        let cargo_output = CargoOutput {
+            cargo_metadata: "metadata".to_string(),
+            cargo_out: "out".to_string(),
+            fuzz_metadata: [("foo".to_string(), "fuzz metadata".to_string())].into(),
+        };
+
+        assert_eq!(cache.get("key")?, None);
+        cache.put("key", &cargo_output)?;
+        assert_eq!(cache.get("key")?, Some(cargo_output.clone()));
+        // Storing the same entry again is fine.
+        cache.put("key", &cargo_output)?;
+        assert_eq!(cache.get("key")?, Some(cargo_output));
+        Ok(())
+    }
+
+    #[test]
+    fn key_depends_on_inputs() -> Result<()> {
+        let package_dir = tempdir()?;
+        let package_dir = package_dir.path();
+        write(package_dir.join("Cargo.toml"), "[package]\nname = \"foo\"\n")?;
+        create_dir_all(package_dir.join("src"))?;
+        write(package_dir.join("src/lib.rs"), "")?;
+        let cfg = VariantConfig::default();
+        let key = || cache_key(&cfg, package_dir, "cargo 1.0", []);
+
+        let original_key = key()?;
+        assert_eq!(key()?, original_key);
+
+        // The contents of the sources of targets don't affect the key, unless tests are listed.
+        write(package_dir.join("src/lib.rs"), "pub fn foo() {}")?;
+        assert_eq!(key()?, original_key);
+        let test_cfg = VariantConfig { tests: true, ..Default::default() };
+        let test_key = cache_key(&test_cfg, package_dir, "cargo 1.0", [])?;
+        write(package_dir.join("src/lib.rs"), "#[test]\nfn foo() {}")?;
+        assert_ne!(cache_key(&test_cfg, package_dir, "cargo 1.0", [])?, test_key);
+
+        // Their paths do, as cargo finds targets from them.
+        create_dir_all(package_dir.join("tests"))?;
+        write(package_dir.join("tests/foo.rs"), "")?;
+        let key_with_test = key()?;
+        assert_ne!(key_with_test, original_key);
+
+        // So do the contents of build scripts and their modules, wherever they are.
+        write(package_dir.join("build.rs"), "fn main() {}")?;
+        assert_ne!(key()?, key_with_test);
+        write(
+            package_dir.join("Cargo.toml"),
+            "[package]\nname = \"foo\"\nbuild = \"build/main.rs\"\n",
+        )?;
+        create_dir_all(package_dir.join("build"))?;
+        write(package_dir.join("build/main.rs"), "mod gen;\nfn main() {}")?;
+        write(package_dir.join("build/gen.rs"), "")?;
+        let original_key = key()?;
+        write(package_dir.join("build/gen.rs"), "pub fn gen() {}")?;
+        assert_ne!(key()?, original_key);
+        let original_key = key()?;
+
+        // Manifests, lockfiles, variant config, toolchain and environment all do.
+        write(package_dir.join("Cargo.lock"), "")?;
+        let key_with_lock = key()?;
+        assert_ne!(key_with_lock, original_key);
+        assert_ne!(cache_key(&cfg, package_dir, "cargo 2.0", [])?, key_with_lock);
+        assert_ne!(
+            cache_key(
+                &VariantConfig { tests: true, ..Default::default() },
+                package_dir,
+                "cargo 1.0",
+                []
+            )?,
+            key_with_lock
+        );
+        assert_ne!(
+            cache_key(
+                &cfg,
+                package_dir,
+                "cargo 1.0",
+                [("RUSTFLAGS".to_string(), "--cfg foo".to_string())]
+            )?,
+            key_with_lock
+        );
+        assert_eq!(
+            cache_key(&cfg, package_dir, "cargo 1.0", [("HOME".to_string(), "/".to_string())])?,
+            key_with_lock
+        );
+        Ok(())
+    }
+}
@@ -18,6 +18,7 @@ pub mod cargo_out;
 pub mod metadata;
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 /// Combined representation of --crate-type and --test flags.
@@ -37,6 +38,8 @@ pub enum CrateType {
     Test,
     // "--cfg test" without --test. (Assume it is a test with the harness disabled.
     TestNoHarness,
+    // A binary of the package's cargo-fuzz package, built as a fuzzer.
+    Fuzz,
 }
 
 impl CrateType {
@@ -95,6 +98,66 @@ pub struct Crate {
     pub main_src: PathBuf,    // relative to package_dir
     /// Whether it is a test crate which doesn't actually contain any tests or benchmarks.
     pub empty_test: bool,
+    /// The names of the tests and benchmarks in a test crate, if known.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub test_names: Vec<String>,
+    /// Information about the package from its `Cargo.toml`, such as the license.
+    #[serde(default, skip_serializing_if = "PackageInfo::is_empty")]
+    pub package_info: PackageInfo,
+    /// Where the values of the other fields came from.
+    #[serde(skip)]
+    pub origins: Origins,
+}
+
+impl Crate {
+    /// Returns whether the crate has the same values as the given one, regardless of where they
+    /// came from.
+    pub fn same_values(&self, other: &Crate) -> bool {
+        let without_origins =
+            |crate_: &Crate| Crate { origins: Origins::default(), ..crate_.clone() };
+        without_origins(self) == without_origins(other)
+    }
+}
+
+/// Information about a package from the `[package]` section of its `Cargo.toml`, used for license
+/// modules and `METADATA` files.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PackageInfo {
+    /// The SPDX license expression, e.g. `MIT OR Apache-2.0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// The path to a non-standard license file, relative to the package directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_file: Option<String>,
+    /// A short description of the package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The URL of the source repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+}
+
+impl PackageInfo {
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Where the values of some fields of a `Crate` came from, for `cargo_embargo explain`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Origins(BTreeMap<(String, String), String>);
+
+impl Origins {
+    /// Records the origin of the given value of the given `Crate` field, unless one is already
+    /// recorded.
+    pub fn record(&mut self, field: &str, value: &str, origin: impl Into<String>) {
+        self.0.entry((field.to_string(), value.to_string())).or_insert_with(|| origin.into());
+    }
+
+    /// Returns the origin of the given value of the given `Crate` field, if known.
+    pub fn get(&self, field: &str, value: &str) -> Option<&str> {
+        self.0.get(&(field.to_string(), value.to_string())).map(String::as_str)
+    }
 }
 
 /// A dependency of a Rust crate.
@@ -103,6 +166,10 @@ pub struct Extern {
     pub name: String,
     pub lib_name: String,
     pub extern_type: ExternType,
+    /// The resolved version of the dependency. This is only set for crates listed in
+    /// `versioned_crates`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
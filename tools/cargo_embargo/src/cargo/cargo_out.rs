@@ -31,13 +31,17 @@ use std::path::PathBuf;
 /// the rustc invocations.
 ///
 /// Ignores crates outside the current directory and build script crates.
-pub fn parse_cargo_out(cargo_output: &CargoOutput) -> Result<Vec<Crate>> {
+pub fn parse_cargo_out(
+    cargo_output: &CargoOutput,
+    versioned_crates: &[String],
+) -> Result<Vec<Crate>> {
     let metadata = serde_json::from_str(&cargo_output.cargo_metadata)
         .context("failed to parse cargo metadata")?;
     parse_cargo_out_str(
         &cargo_output.cargo_out,
         &metadata,
         env::current_dir().unwrap().canonicalize().unwrap(),
+        versioned_crates,
     )
 }
 
@@ -45,10 +49,13 @@ pub fn parse_cargo_out(cargo_output: &CargoOutput) -> Result<Vec<Crate>> {
 /// based on the rustc invocations.
 ///
 /// Ignores crates outside `base_directory` and build script crates.
+///
+/// Externs on any of `versioned_crates` get the version of the crate which rustc was given.
 fn parse_cargo_out_str(
     cargo_out: &str,
     metadata: &WorkspaceMetadata,
     base_directory: impl AsRef<Path>,
+    versioned_crates: &[String],
 ) -> Result<Vec<Crate>> {
     let cargo_out = CargoOut::parse(cargo_out).context("failed to parse cargo.out")?;
     debug!("Parsed cargo output: {:?}", cargo_out);
@@ -56,10 +63,18 @@ fn parse_cargo_out_str(
     assert!(cargo_out.cc_invocations.is_empty(), "cc not supported yet");
     assert!(cargo_out.ar_invocations.is_empty(), "ar not supported yet");
 
-    let mut crates = Vec::new();
+    let mut all_crates = Vec::new();
+    // Output filename stem => crate version
+    let mut output_versions = BTreeMap::new();
     for rustc in cargo_out.rustc_invocations.iter() {
-        let c = Crate::from_rustc_invocation(rustc, metadata, &cargo_out.tests)
+        let (c, output_filename) = Crate::from_rustc_invocation(rustc, metadata, &cargo_out.tests)
             .with_context(|| format!("failed to process rustc invocation: {rustc}"))?;
+        output_versions.insert(output_filename, c.version.clone());
+        all_crates.push((c, rustc));
+    }
+
+    let mut crates = Vec::new();
+    for (mut c, rustc) in all_crates {
         // Ignore build.rs crates.
         if c.name.starts_with("build_script_") {
             continue;
@@ -68,17 +83,39 @@ fn parse_cargo_out_str(
         if !c.package_dir.starts_with(&base_directory) {
             continue;
         }
+        if c.externs.iter().any(|e| versioned_crates.contains(&e.lib_name)) {
+            let extern_outputs = extern_outputs(rustc);
+            for e in c.externs.iter_mut().filter(|e| versioned_crates.contains(&e.lib_name)) {
+                e.version = extern_outputs
+                    .get(e.name.as_str())
+                    .and_then(|output| output_versions.get(*output).cloned().flatten());
+            }
+        }
         crates.push(c);
     }
     crates.dedup();
     Ok(crates)
 }
 
-/// Whether a test target contains any tests or benchmarks.
+/// Returns the output filename stems of the `--extern`s of the given rustc invocation, by extern
+/// name.
+fn extern_outputs(rustc: &str) -> BTreeMap<&str, &str> {
+    static REGEX: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r#"--extern ['"]?(\w+)=\S*/lib([^-/.]+(?:-[0-9a-f]+)?)\.(?:rlib|so|rmeta)"#)
+            .unwrap()
+    });
+    REGEX
+        .captures_iter(rustc)
+        .map(|captures| (captures.get(1).unwrap().as_str(), captures.get(2).unwrap().as_str()))
+        .collect()
+}
+
+/// Whether a test target contains any tests or benchmarks, and their names.
 #[derive(Debug)]
 struct TestContents {
     tests: bool,
     benchmarks: bool,
+    names: Vec<String>,
 }
 
 /// Raw-ish data extracted from cargo.out file.
@@ -121,6 +158,7 @@ impl CargoOut {
         let mut result = CargoOut::default();
         let mut in_tests = false;
         let mut cur_test_key = None;
+        let mut cur_test_names = Vec::new();
         let mut lines_iter = contents.lines().enumerate();
         while let Some((n, line)) = lines_iter.next() {
             if line.starts_with("warning: ") {
@@ -206,16 +244,26 @@ impl CargoOut {
                 Lazy::new(|| Regex::new(r"^\s*Running (?:unittests )?(.*) \(.*/(.*)\)$").unwrap());
             static CARGO_TEST_LIST_END_PAT: Lazy<Regex> =
                 Lazy::new(|| Regex::new(r"^(\d+) tests?, (\d+) benchmarks$").unwrap());
+            // Example: tests::parse_empty: test
+            static CARGO_TEST_LIST_NAME_PAT: Lazy<Regex> =
+                Lazy::new(|| Regex::new(r"^(\S+): (?:test|bench)$").unwrap());
             if let Some(captures) = CARGO_TEST_LIST_START_PAT.captures(line) {
                 cur_test_key =
                     Some((captures.get(2).unwrap().as_str(), captures.get(1).unwrap().as_str()));
+                cur_test_names.clear();
             } else if let Some((output_filename, main_src)) = cur_test_key {
-                if let Some(captures) = CARGO_TEST_LIST_END_PAT.captures(line) {
+                if let Some(name) = match1(&CARGO_TEST_LIST_NAME_PAT, line) {
+                    cur_test_names.push(name);
+                } else if let Some(captures) = CARGO_TEST_LIST_END_PAT.captures(line) {
                     let num_tests = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
                     let num_benchmarks = captures.get(2).unwrap().as_str().parse::<u32>().unwrap();
                     result.tests.entry(output_filename.to_owned()).or_default().insert(
                         PathBuf::from(main_src),
-                        TestContents { tests: num_tests != 0, benchmarks: num_benchmarks != 0 },
+                        TestContents {
+                            tests: num_tests != 0,
+                            benchmarks: num_benchmarks != 0,
+                            names: std::mem::take(&mut cur_test_names),
+                        },
                     );
                     cur_test_key = None;
                 }
@@ -233,7 +281,7 @@ impl Crate {
         rustc: &str,
         metadata: &WorkspaceMetadata,
         tests: &BTreeMap<String, BTreeMap<PathBuf, TestContents>>,
-    ) -> Result<Crate> {
+    ) -> Result<(Crate, String)> {
         let mut out = Crate::default();
         let mut extra_filename = String::new();
 
@@ -261,12 +309,15 @@ impl Crate {
                 "--cfg" => {
                     // example: feature=\"sink\"
                     let arg = arg_iter.next().unwrap();
+                    let origin = format!("`--cfg {arg}` in the rustc invocation");
                     if let Some(feature) =
                         arg.strip_prefix("feature=\"").and_then(|s| s.strip_suffix('\"'))
                     {
                         out.features.push(feature.to_string());
+                        out.origins.record("features", feature, origin);
                     } else {
                         out.cfgs.push(arg.to_string());
+                        out.origins.record("cfgs", arg, origin);
                     }
                 }
                 "--extern" => {
@@ -293,10 +344,16 @@ impl Crate {
                             } else {
                                 bail!("Unexpected extension for extern filename {}", filename);
                             };
+                        out.origins.record(
+                            "externs",
+                            name,
+                            format!("`--extern {arg}` in the rustc invocation"),
+                        );
                         out.externs.push(Extern {
                             name: name.to_string(),
                             lib_name: lib_name.as_str().to_string(),
                             extern_type,
+                            version: None,
                         });
                     } else if arg != "proc_macro" {
                         panic!("No filename for {}", arg);
@@ -323,20 +380,44 @@ impl Crate {
                         && arg != "prefer-dynamic"
                     {
                         out.codegens.push(arg.to_string());
+                        out.origins.record(
+                            "codegens",
+                            arg,
+                            format!("`-C {arg}` in the rustc invocation"),
+                        );
                     }
                     if let Some(x) = arg.strip_prefix("extra-filename=") {
                         extra_filename = x.to_string();
                     }
                 }
-                "--cap-lints" => out.cap_lints = arg_iter.next().unwrap().to_string(),
+                _ if arg.starts_with("--edition=") => {
+                    out.edition = arg.strip_prefix("--edition=").unwrap().to_string();
+                    out.origins.record(
+                        "edition",
+                        &out.edition,
+                        format!("`{arg}` in the rustc invocation"),
+                    );
+                }
+                "--cap-lints" => {
+                    out.cap_lints = arg_iter.next().unwrap().to_string();
+                    out.origins.record(
+                        "cap_lints",
+                        &out.cap_lints,
+                        format!("`--cap-lints {}` in the rustc invocation", out.cap_lints),
+                    );
+                }
                 "-l" => {
                     let arg = arg_iter.next().unwrap();
+                    let origin = format!("`-l {arg}` in the rustc invocation");
                     if let Some(lib) = arg.strip_prefix("static=") {
                         out.static_libs.push(lib.to_string());
+                        out.origins.record("static_libs", lib, origin);
                     } else if let Some(lib) = arg.strip_prefix("dylib=") {
                         out.shared_libs.push(lib.to_string());
+                        out.origins.record("shared_libs", lib, origin);
                     } else {
                         out.shared_libs.push(arg.to_string());
+                        out.origins.record("shared_libs", arg, origin);
                     }
                 }
                 _ if !arg.starts_with('-') => {
@@ -355,7 +436,6 @@ impl Crate {
                 }
                 _ if arg.starts_with("--error-format=") => {}
                 _ if arg.starts_with("--emit=") => {}
-                _ if arg.starts_with("--edition=") => {}
                 _ if arg.starts_with("--json=") => {}
                 _ if arg.starts_with("-Aclippy") => {}
                 _ if arg.starts_with("--allow=clippy") => {}
@@ -412,15 +492,26 @@ impl Crate {
             })?;
         out.package_name.clone_from(&package_metadata.name);
         out.version = Some(package_metadata.version.clone());
-        out.edition.clone_from(&package_metadata.edition);
+        out.package_info.clone_from(&package_metadata.info);
+        // Cargo only passes `--edition` for editions after 2015, so this is the target's edition
+        // rather than the package's.
+        if out.edition.is_empty() {
+            out.edition = "2015".to_string();
+            out.origins.record(
+                "edition",
+                &out.edition,
+                "the lack of `--edition` in the rustc invocation",
+            );
+        }
 
         let output_filename = out.name.clone() + &extra_filename;
         if let Some(test_contents) = tests.get(&output_filename).and_then(|m| m.get(&out.main_src))
         {
             out.empty_test = !test_contents.tests && !test_contents.benchmarks;
+            out.test_names.clone_from(&test_contents.names);
         }
 
-        Ok(out)
+        Ok((out, output_filename))
     }
 }
 
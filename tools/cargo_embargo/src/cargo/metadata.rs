@@ -14,11 +14,12 @@
 
 //! Types for parsing cargo.metadata JSON files.
 
-use super::{Crate, CrateType, Extern, ExternType};
+use super::{Crate, CrateType, Extern, ExternType, PackageInfo};
 use crate::config::VariantConfig;
 use anyhow::{bail, Context, Result};
 use serde::Deserialize;
-use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, VecDeque};
 use std::path::{Path, PathBuf};
 
 /// `cfg` strings for dependencies which should be considered enabled. It would be better to parse
@@ -37,6 +38,43 @@ const ENABLED_CFGS: [&str; 6] = [
 pub struct WorkspaceMetadata {
     pub packages: Vec<PackageMetadata>,
     pub workspace_members: Vec<String>,
+    /// The resolved dependency graph. This is missing if `--no-deps` was used.
+    #[serde(default)]
+    pub resolve: Option<ResolveMetadata>,
+    /// The `[workspace.metadata]` table from the workspace's `Cargo.toml`, if any.
+    #[serde(default)]
+    pub metadata: Option<Value>,
+}
+
+/// The `resolve` section of `cargo metadata` output.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct ResolveMetadata {
+    pub nodes: Vec<NodeMetadata>,
+}
+
+/// A package in the resolved dependency graph.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct NodeMetadata {
+    pub id: String,
+    pub deps: Vec<NodeDepMetadata>,
+}
+
+/// A resolved dependency of a package.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct NodeDepMetadata {
+    /// The name of the dependency's library as seen by the dependent, with any rename applied.
+    pub name: String,
+    /// The package ID of the dependency.
+    pub pkg: String,
+    #[serde(default)]
+    pub dep_kinds: Vec<DepKindMetadata>,
+}
+
+/// The kind and target of a resolved dependency, i.e. which section of `Cargo.toml` it came from.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct DepKindMetadata {
+    pub kind: Option<String>,
+    pub target: Option<String>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
@@ -49,6 +87,11 @@ pub struct PackageMetadata {
     pub features: BTreeMap<String, Vec<String>>,
     pub id: String,
     pub targets: Vec<TargetMetadata>,
+    #[serde(flatten)]
+    pub info: PackageInfo,
+    /// The `[package.metadata]` table from the package's `Cargo.toml`, if any.
+    #[serde(default)]
+    pub metadata: Option<Value>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
@@ -58,6 +101,19 @@ pub struct DependencyMetadata {
     pub optional: bool,
     pub target: Option<String>,
     pub rename: Option<String>,
+    /// The version requirement, e.g. `^1.0`.
+    #[serde(default)]
+    pub req: String,
+    /// The features of the dependency which the dependent enables.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Whether the dependent enables the default features of the dependency.
+    #[serde(default = "default_true")]
+    pub uses_default_features: bool,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl DependencyMetadata {
@@ -87,6 +143,39 @@ pub struct TargetMetadata {
     pub name: String,
     pub src_path: PathBuf,
     pub test: bool,
+    /// Features which must be enabled for the target to be built.
+    #[serde(default, rename = "required-features")]
+    pub required_features: Vec<String>,
+}
+
+impl TargetMetadata {
+    /// Returns the required features of the target which aren't enabled by the given resolved
+    /// features of its package.
+    fn missing_features(&self, package: &PackageMetadata, features: &[String]) -> Vec<&str> {
+        self.required_features
+            .iter()
+            .map(String::as_str)
+            .filter(|required| {
+                if let Some((dependency, _)) = required.split_once('/') {
+                    // Only check that the dependency is enabled, as the features of dependencies
+                    // aren't known here.
+                    let dependency = dependency.trim_end_matches('?');
+                    !features.iter().any(|feature| {
+                        feature == dependency || feature.strip_prefix("dep:") == Some(dependency)
+                    }) && !package.dependencies.iter().any(|package_dependency| {
+                        !package_dependency.optional
+                            && package_dependency
+                                .rename
+                                .as_ref()
+                                .unwrap_or(&package_dependency.name)
+                                == dependency
+                    })
+                } else {
+                    !features.iter().any(|feature| feature == required)
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
@@ -104,28 +193,109 @@ pub enum TargetKind {
     Test,
 }
 
-pub fn parse_cargo_metadata_str(cargo_metadata: &str, cfg: &VariantConfig) -> Result<Vec<Crate>> {
+/// Parses the given `cargo metadata` output into crates for the given variant config.
+///
+/// `static_cfgs` are the cfgs which each package's build script sets, found without running it,
+/// each with a description of where it came from.
+pub fn parse_cargo_metadata_str(
+    cargo_metadata: &str,
+    cfg: &VariantConfig,
+    static_cfgs: &BTreeMap<String, BTreeMap<String, String>>,
+) -> Result<Vec<Crate>> {
     let metadata =
         serde_json::from_str(cargo_metadata).context("failed to parse cargo metadata")?;
-    parse_cargo_metadata(&metadata, &cfg.features, &cfg.extra_cfg, cfg.tests)
+    parse_cargo_metadata(
+        &metadata,
+        &cfg.features,
+        &cfg.extra_cfg,
+        static_cfgs,
+        cfg.tests,
+        &cfg.versioned_crates,
+    )
+}
+
+/// Extracts cargo_embargo config options from the `cargo_embargo` tables in the
+/// `[workspace.metadata]` and `[package.metadata]` of the workspace members.
+///
+/// The result has the same structure as a JSON config: workspace options are at the top level, and
+/// package options are under `package` keyed by the package name.
+pub fn config_from_cargo_metadata(cargo_metadata: &str) -> Result<Map<String, Value>> {
+    let metadata: WorkspaceMetadata =
+        serde_json::from_str(cargo_metadata).context("failed to parse cargo metadata")?;
+    let mut config = embargo_metadata(&metadata.metadata, "workspace")?;
+    let mut packages = Map::new();
+    for package in &metadata.packages {
+        if !metadata.workspace_members.contains(&package.id) {
+            continue;
+        }
+        let package_config = embargo_metadata(&package.metadata, &package.name)?;
+        if !package_config.is_empty() {
+            packages.insert(package.name.clone(), package_config.into());
+        }
+    }
+    if !packages.is_empty() {
+        if config.contains_key("package") {
+            bail!("`package` is not allowed in [workspace.metadata.cargo_embargo]");
+        }
+        config.insert("package".to_string(), packages.into());
+    }
+    Ok(config)
+}
+
+/// Returns the `cargo_embargo` table of the given `metadata` table, or an empty map if there is
+/// none.
+fn embargo_metadata(metadata: &Option<Value>, owner: &str) -> Result<Map<String, Value>> {
+    match metadata.as_ref().and_then(|metadata| metadata.get("cargo_embargo")) {
+        None => Ok(Map::new()),
+        Some(Value::Object(table)) => Ok(table.clone()),
+        Some(_) => bail!("metadata.cargo_embargo of {owner} is not a table"),
+    }
 }
 
 fn parse_cargo_metadata(
     metadata: &WorkspaceMetadata,
-    features: &Option<Vec<String>>,
+    chosen_features: &Option<Vec<String>>,
     cfgs: &[String],
+    static_cfgs: &BTreeMap<String, BTreeMap<String, String>>,
     include_tests: bool,
+    versioned_crates: &[String],
 ) -> Result<Vec<Crate>> {
+    let graph = PackageGraph::new(&metadata.packages, metadata.resolve.as_ref());
+    let unified = metadata
+        .resolve
+        .is_some()
+        .then(|| unify_features(metadata, &graph, chosen_features, cfgs, include_tests))
+        .transpose()?;
     let mut crates = Vec::new();
     for package in &metadata.packages {
         if !metadata.workspace_members.contains(&package.id) {
             continue;
         }
 
-        let features = resolve_features(features, &package.features, &package.dependencies);
+        let features =
+            unified.as_ref().and_then(|unified| unified.get(package)).cloned().unwrap_or_else(
+                || resolve_features(chosen_features, &package.features, &package.dependencies),
+            );
+        // Features which other packages enabled on this one.
+        let enabled_by: BTreeMap<&str, &str> = unified
+            .iter()
+            .flat_map(|unified| &unified.enabled_by)
+            .filter(|((id, _), _)| *id == package.id)
+            .map(|((_, feature), dependent)| (feature.as_str(), dependent.as_str()))
+            .collect();
         let features_without_deps: Vec<String> =
             features.clone().into_iter().filter(|feature| !feature.starts_with("dep:")).collect();
         let package_dir = package_dir_from_id(&package.id)?;
+        // Cfgs set by the build script, other than those already in `extra_cfg`.
+        let package_static_cfgs: BTreeMap<String, String> = static_cfgs
+            .get(&package.name)
+            .into_iter()
+            .flatten()
+            .filter(|(cfg, _)| !cfgs.contains(cfg))
+            .map(|(cfg, origin)| (cfg.clone(), origin.clone()))
+            .collect();
+        let crate_cfgs: Vec<String> =
+            cfgs.iter().chain(package_static_cfgs.keys()).cloned().collect();
 
         for target in &package.targets {
             let target_kinds = target
@@ -149,6 +319,28 @@ fn parse_cargo_metadata(
                 // Only binaries, libraries and integration tests are supported.
                 continue;
             }
+            // Cargo ignores `required-features` on libraries.
+            let missing_features =
+                if target.kind.iter().any(|kind| {
+                    [TargetKind::Bin, TargetKind::Example, TargetKind::Test].contains(kind)
+                }) {
+                    target.missing_features(package, &features)
+                } else {
+                    Vec::new()
+                };
+            if !missing_features.is_empty() {
+                // Cargo doesn't build such targets either, and they would fail to compile.
+                eprintln!(
+                    "WARNING: skipping target {} of {} because its required features aren't \
+                     enabled: {}",
+                    target.name,
+                    package.name,
+                    missing_features.join(", ")
+                );
+                continue;
+            }
+            let edition =
+                if target.edition.is_empty() { &package.edition } else { &target.edition };
             let main_src = split_src_path(&target.src_path, &package_dir);
             // Hypens are not allowed in crate names. See
             // https://github.com/rust-lang/rfcs/blob/master/text/0940-hyphens-considered-harmful.md
@@ -162,64 +354,140 @@ fn parse_cargo_metadata(
             // Don't generate an entry for integration tests, they will be covered by the test case
             // below.
             if target_kinds != [TargetKind::Test] {
-                crates.push(Crate {
+                let mut crate_ = Crate {
                     name: target_name.clone(),
                     package_name: package.name.to_owned(),
                     version: Some(package.version.to_owned()),
+                    package_info: package.info.clone(),
                     types: target.crate_types.clone(),
                     features: features_without_deps.clone(),
-                    edition: package.edition.to_owned(),
+                    edition: edition.to_owned(),
                     package_dir: package_dir.clone(),
                     main_src: main_src.to_owned(),
                     target: target_triple.clone(),
                     externs: get_externs(
                         package,
-                        &metadata.packages,
+                        &graph,
                         &features,
                         cfgs,
                         &target_kinds,
                         false,
+                        versioned_crates,
                     )?,
-                    cfgs: cfgs.to_owned(),
+                    cfgs: crate_cfgs.clone(),
                     ..Default::default()
-                });
+                };
+                record_origins(
+                    &mut crate_,
+                    package,
+                    chosen_features.is_some(),
+                    &enabled_by,
+                    &package_static_cfgs,
+                );
+                crates.push(crate_);
             }
             // This includes both unit tests and integration tests.
             if target.test && include_tests {
-                crates.push(Crate {
+                let mut crate_ = Crate {
                     name: target_name,
                     package_name: package.name.to_owned(),
                     version: Some(package.version.to_owned()),
+                    package_info: package.info.clone(),
                     types: vec![CrateType::Test],
                     features: features_without_deps.clone(),
-                    edition: package.edition.to_owned(),
+                    edition: edition.to_owned(),
                     package_dir: package_dir.clone(),
                     main_src: main_src.to_owned(),
                     target: target_triple.clone(),
                     externs: get_externs(
                         package,
-                        &metadata.packages,
+                        &graph,
                         &features,
                         cfgs,
                         &target_kinds,
                         true,
+                        versioned_crates,
                     )?,
-                    cfgs: cfgs.to_owned(),
+                    cfgs: crate_cfgs.clone(),
                     ..Default::default()
-                });
+                };
+                record_origins(
+                    &mut crate_,
+                    package,
+                    chosen_features.is_some(),
+                    &enabled_by,
+                    &package_static_cfgs,
+                );
+                crates.push(crate_);
             }
         }
     }
     Ok(crates)
 }
 
+/// Records where the edition, features, cfgs and externs of the given crate came from.
+///
+/// `enabled_by` maps features which other packages enabled on the crate's package to the package
+/// which enabled them, and `static_cfgs` maps cfgs set by its build script to their origins.
+fn record_origins(
+    crate_: &mut Crate,
+    package: &PackageMetadata,
+    features_chosen: bool,
+    enabled_by: &BTreeMap<&str, &str>,
+    static_cfgs: &BTreeMap<String, String>,
+) {
+    let edition_origin = if crate_.edition == package.edition {
+        format!("the edition in the Cargo.toml of {}", package.name)
+    } else {
+        format!("the edition of the {} target in the Cargo.toml of {}", crate_.name, package.name)
+    };
+    crate_.origins.record("edition", &crate_.edition, edition_origin);
+    for feature in &crate_.features {
+        let origin = if let Some(dependent) = enabled_by.get(feature.as_str()) {
+            format!("feature unification with the dependency of {dependent} on {}", package.name)
+        } else if features_chosen {
+            "the `features` config option (or a feature it enables)".to_string()
+        } else {
+            format!("the default features of {}", package.name)
+        };
+        crate_.origins.record("features", feature, origin);
+    }
+    for cfg in &crate_.cfgs {
+        let origin = static_cfgs.get(cfg).map_or("the `extra_cfg` config option", String::as_str);
+        crate_.origins.record("cfgs", cfg, origin);
+    }
+    for extern_ in &crate_.externs {
+        let dependency = package.dependencies.iter().find(|dependency| {
+            dependency.rename.as_deref().unwrap_or(&dependency.name).replace('-', "_")
+                == extern_.name
+        });
+        let origin = if let Some(dependency) = dependency {
+            let mut origin = format!(
+                "{}dependency on {} {} in the Cargo.toml of {}",
+                if dependency.kind.as_deref() == Some("dev") { "dev-" } else { "" },
+                dependency.name,
+                dependency.req,
+                package.name,
+            );
+            if let Some(target) = &dependency.target {
+                origin += &format!(" for target {target}");
+            }
+            origin
+        } else {
+            format!("the library target of {}", package.name)
+        };
+        crate_.origins.record("externs", &extern_.name, origin);
+    }
+}
+
 fn get_externs(
     package: &PackageMetadata,
-    packages: &[PackageMetadata],
+    graph: &PackageGraph,
     features: &[String],
     cfgs: &[String],
     target_kinds: &[TargetKind],
     test: bool,
+    versioned_crates: &[String],
 ) -> Result<Vec<Extern>> {
     let mut externs = package
         .dependencies
@@ -230,7 +498,7 @@ fn get_externs(
                 && dependency.kind.as_deref() != Some("build")
                 && (dependency.kind.is_none() || test)
             {
-                Some(make_extern(packages, dependency))
+                Some(make_extern(graph, package, dependency, versioned_crates))
             } else {
                 None
             }
@@ -243,10 +511,12 @@ fn get_externs(
         for target in &package.targets {
             if target.kind.contains(&TargetKind::Lib) {
                 let lib_name = target.name.replace('-', "_");
+                let version = versioned_crates.contains(&lib_name).then(|| package.version.clone());
                 externs.push(Extern {
                     name: lib_name.clone(),
                     lib_name,
                     extern_type: ExternType::Rust,
+                    version,
                 });
             }
         }
@@ -257,9 +527,33 @@ fn get_externs(
     Ok(externs)
 }
 
-fn make_extern(packages: &[PackageMetadata], dependency: &DependencyMetadata) -> Result<Extern> {
-    let Some(package) = packages.iter().find(|package| package.name == dependency.name) else {
-        bail!("package {} not found in metadata", dependency.name);
+/// Makes an extern for the given dependency of the given package.
+///
+/// The dependency is looked up by package ID in the resolved dependency graph if there is one, so
+/// that the right package is used even if there are several versions or sources of it. Otherwise
+/// it is looked up by name, preferring a version which matches the requirement.
+fn make_extern(
+    graph: &PackageGraph,
+    dependent: &PackageMetadata,
+    dependency: &DependencyMetadata,
+    versioned_crates: &[String],
+) -> Result<Extern> {
+    let resolved = graph.resolve_dependency(dependent, dependency);
+    let package = if let Some((_, package)) = resolved {
+        package
+    } else {
+        // There may be several versions of the package, so prefer one which matches the
+        // requirement.
+        let mut candidates =
+            graph.packages.iter().filter(|package| package.name == dependency.name);
+        let Some(package) = candidates
+            .clone()
+            .find(|package| version_matches(&package.version, &dependency.req))
+            .or_else(|| candidates.next())
+        else {
+            bail!("package {} not found in metadata", dependency.name);
+        };
+        package
     };
     let Some(target) = package.targets.iter().find(|target| {
         target.kind.contains(&TargetKind::Lib) || target.kind.contains(&TargetKind::ProcMacro)
@@ -267,8 +561,13 @@ fn make_extern(packages: &[PackageMetadata], dependency: &DependencyMetadata) ->
         bail!("Package {} didn't have any library or proc-macro targets", dependency.name);
     };
     let lib_name = target.name.replace('-', "_");
-    let name =
-        if let Some(rename) = &dependency.rename { rename.clone() } else { lib_name.clone() };
+    let name = if let Some((dep, _)) = resolved {
+        dep.name.clone()
+    } else if let Some(rename) = &dependency.rename {
+        rename.clone()
+    } else {
+        lib_name.clone()
+    };
 
     // Check whether the package is a proc macro.
     let extern_type =
@@ -278,7 +577,57 @@ fn make_extern(packages: &[PackageMetadata], dependency: &DependencyMetadata) ->
             ExternType::Rust
         };
 
-    Ok(Extern { name, lib_name, extern_type })
+    let version = versioned_crates.contains(&lib_name).then(|| package.version.clone());
+
+    Ok(Extern { name, lib_name, extern_type, version })
+}
+
+/// Returns whether the given version satisfies the given Cargo version requirement, e.g. `^1.2` or
+/// `>=0.4, <0.6`. An empty requirement matches any version.
+///
+/// Pre-release and build metadata are ignored.
+fn version_matches(version: &str, req: &str) -> bool {
+    let Some(version) = parse_version(version) else {
+        return false;
+    };
+    req.split(',').map(str::trim).filter(|comparator| !comparator.is_empty()).all(|comparator| {
+        let (op, partial) = match comparator.find(|c: char| c.is_ascii_digit() || c == '*') {
+            Some(index) => (comparator[..index].trim(), &comparator[index..]),
+            None => return false,
+        };
+        // The parts of the version given in the comparator, up to the first wildcard.
+        let parts: Vec<u64> = partial
+            .split('.')
+            .map_while(|part| part.split(['-', '+']).next().unwrap().parse().ok())
+            .take(3)
+            .collect();
+        let prefix = &version[..parts.len()];
+        let parts = parts.as_slice();
+        match op {
+            "" | "^" => {
+                // Everything up to and including the first non-zero part must match.
+                let significant =
+                    parts.iter().position(|part| *part != 0).map_or(parts.len(), |i| i + 1);
+                version[..significant] == parts[..significant] && prefix >= parts
+            }
+            "~" => {
+                let significant = parts.len().min(2);
+                version[..significant] == parts[..significant] && prefix >= parts
+            }
+            "=" => prefix == parts,
+            ">" => prefix > parts,
+            ">=" => prefix >= parts,
+            "<" => prefix < parts,
+            "<=" => prefix <= parts,
+            _ => false,
+        }
+    })
+}
+
+/// Parses the major, minor and patch parts of the given version.
+fn parse_version(version: &str) -> Option<[u64; 3]> {
+    let mut parts = version.split(['-', '+']).next()?.split('.').map(|part| part.parse().ok());
+    Some([parts.next()??, parts.next()??, parts.next()??])
 }
 
 /// Given a Cargo package ID, returns the path.
@@ -307,6 +656,197 @@ fn split_src_path<'a>(src_path: &'a Path, package_dir: &Path) -> &'a Path {
     }
 }
 
+/// The packages from `cargo metadata` output, and the resolved dependency graph between them if it
+/// is available.
+struct PackageGraph<'a> {
+    packages: &'a [PackageMetadata],
+    by_id: BTreeMap<&'a str, &'a PackageMetadata>,
+    nodes: BTreeMap<&'a str, &'a NodeMetadata>,
+}
+
+impl<'a> PackageGraph<'a> {
+    fn new(packages: &'a [PackageMetadata], resolve: Option<&'a ResolveMetadata>) -> Self {
+        Self {
+            packages,
+            by_id: packages.iter().map(|package| (package.id.as_str(), package)).collect(),
+            nodes: resolve
+                .iter()
+                .flat_map(|resolve| &resolve.nodes)
+                .map(|node| (node.id.as_str(), node))
+                .collect(),
+        }
+    }
+
+    /// Returns the resolved dependency and package which the given dependency of the given package
+    /// resolved to, or `None` if it isn't in the resolved graph.
+    fn resolve_dependency(
+        &self,
+        package: &PackageMetadata,
+        dependency: &DependencyMetadata,
+    ) -> Option<(&'a NodeDepMetadata, &'a PackageMetadata)> {
+        self.nodes.get(package.id.as_str())?.deps.iter().find_map(|dep| {
+            let dependency_package = *self.by_id.get(dep.pkg.as_str())?;
+            (dependency_package.name == dependency.name
+                && dep.dep_kinds.iter().any(|dep_kind| {
+                    dep_kind.kind == dependency.kind && dep_kind.target == dependency.target
+                })
+                && dependency.rename.iter().all(|rename| rename.replace('-', "_") == dep.name))
+            .then_some((dep, dependency_package))
+        })
+    }
+}
+
+/// Whether a package is built for the device or for the host (as a proc macro, a build dependency
+/// or a dependency of one of them). Features are unified separately for each, as by version 2 of
+/// Cargo's feature resolver.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Platform {
+    Target,
+    Host,
+}
+
+/// The features enabled for each package, unified across the dependency graph.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct UnifiedFeatures {
+    /// The features requested for each package ID and platform, before adding the features which
+    /// they enable.
+    requested: BTreeMap<(String, Platform), Vec<String>>,
+    /// All features enabled for each package ID and platform, including `dep:` features.
+    features: BTreeMap<(String, Platform), Vec<String>>,
+    /// The dependent package which first enabled each feature of each package ID, for features
+    /// which weren't enabled by the package itself.
+    enabled_by: BTreeMap<(String, String), String>,
+}
+
+impl UnifiedFeatures {
+    /// Returns the features enabled for the given workspace member.
+    fn get(&self, package: &PackageMetadata) -> Option<&Vec<String>> {
+        self.features.get(&(package.id.clone(), root_platform(package)))
+    }
+}
+
+fn is_proc_macro(package: &PackageMetadata) -> bool {
+    package.targets.iter().any(|target| target.kind.contains(&TargetKind::ProcMacro))
+}
+
+/// Returns the platform which the given workspace member is built for.
+fn root_platform(package: &PackageMetadata) -> Platform {
+    if is_proc_macro(package) {
+        Platform::Host
+    } else {
+        Platform::Target
+    }
+}
+
+/// Unifies features across the resolved dependency graph, as Cargo's version 2 feature resolver
+/// does when building all workspace members with the given chosen features.
+///
+/// Dependencies get the union of the features which their dependents enable on them, including
+/// `dep/feature` and `dep?/feature` entries in the features of the dependents. Build dependencies,
+/// proc macros and their dependencies are unified separately, and dev-dependencies are only
+/// included for workspace members if `include_tests` is set.
+fn unify_features(
+    metadata: &WorkspaceMetadata,
+    graph: &PackageGraph,
+    chosen_features: &Option<Vec<String>>,
+    cfgs: &[String],
+    include_tests: bool,
+) -> Result<UnifiedFeatures> {
+    let mut unified = UnifiedFeatures::default();
+    let mut queue = VecDeque::new();
+    let root_features = chosen_features.clone().unwrap_or_else(|| vec!["default".to_string()]);
+    for member in &metadata.workspace_members {
+        let package =
+            graph.by_id.get(member.as_str()).context("workspace member not in packages")?;
+        let platform = root_platform(package);
+        request_features(&mut unified, &mut queue, package, platform, &root_features, None);
+    }
+
+    while let Some((id, platform)) = queue.pop_front() {
+        let package = graph.by_id[id];
+        let features = unified.features[&(id.to_string(), platform)].clone();
+        let is_member = metadata.workspace_members.contains(&package.id);
+        for dependency in &package.dependencies {
+            let kind = dependency.kind.as_deref();
+            if (kind == Some("dev") && !(is_member && include_tests))
+                || !dependency.enabled(&features, cfgs)
+            {
+                continue;
+            }
+            let Some((_, dependency_package)) = graph.resolve_dependency(package, dependency)
+            else {
+                continue;
+            };
+            let dependency_platform = if platform == Platform::Host
+                || kind == Some("build")
+                || is_proc_macro(dependency_package)
+            {
+                Platform::Host
+            } else {
+                Platform::Target
+            };
+            let mut dependency_features = dependency.features.clone();
+            if dependency.uses_default_features {
+                dependency_features.push("default".to_string());
+            }
+            let name = dependency.rename.as_ref().unwrap_or(&dependency.name);
+            for feature in &features {
+                for entry in package.features.get(feature).into_iter().flatten() {
+                    if let Some((dependency_name, dependency_feature)) = entry.split_once('/') {
+                        if dependency_name.trim_end_matches('?') == name {
+                            dependency_features.push(dependency_feature.to_string());
+                        }
+                    }
+                }
+            }
+            request_features(
+                &mut unified,
+                &mut queue,
+                dependency_package,
+                dependency_platform,
+                &dependency_features,
+                Some(&package.name),
+            );
+        }
+    }
+    Ok(unified)
+}
+
+/// Adds the given features to those requested for the given package on the given platform, and if
+/// that enables any new features then queues the package to have its dependencies updated.
+fn request_features<'a>(
+    unified: &mut UnifiedFeatures,
+    queue: &mut VecDeque<(&'a str, Platform)>,
+    package: &'a PackageMetadata,
+    platform: Platform,
+    features: &[String],
+    dependent: Option<&str>,
+) {
+    let key = (package.id.clone(), platform);
+    let requested = unified.requested.entry(key.clone()).or_default();
+    let is_new = !unified.features.contains_key(&key);
+    let old_len = requested.len();
+    requested.extend(features.iter().cloned());
+    requested.sort();
+    requested.dedup();
+    if !is_new && requested.len() == old_len {
+        return;
+    }
+    let resolved =
+        resolve_features(&Some(requested.clone()), &package.features, &package.dependencies);
+    if let Some(dependent) = dependent {
+        let old_features = unified.features.get(&key).cloned().unwrap_or_default();
+        for feature in resolved.iter().filter(|feature| !old_features.contains(feature)) {
+            unified
+                .enabled_by
+                .entry((package.id.clone(), feature.clone()))
+                .or_insert_with(|| dependent.to_string());
+        }
+    }
+    unified.features.insert(key, resolved);
+    queue.push_back((&package.id, platform));
+}
+
 /// Given a set of chosen features, and the feature dependencies from a package's metadata, returns
 /// the full set of features which should be enabled.
 fn resolve_features(
@@ -363,6 +903,7 @@ fn add_feature_and_dependencies(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cargo::Origins;
     use crate::config::Config;
     use crate::tests::testdata_directories;
     use googletest::matchers::eq;
@@ -427,6 +968,9 @@ mod tests {
                 optional: true,
                 target: None,
                 rename: None,
+                req: String::new(),
+                features: vec![],
+                uses_default_features: true,
             },
             DependencyMetadata {
                 name: "optionaldep2".to_string(),
@@ -434,6 +978,9 @@ mod tests {
                 optional: true,
                 target: None,
                 rename: None,
+                req: String::new(),
+                features: vec![],
+                uses_default_features: true,
             },
             DependencyMetadata {
                 name: "requireddep".to_string(),
@@ -441,6 +988,9 @@ mod tests {
                 optional: false,
                 target: None,
                 rename: None,
+                req: String::new(),
+                features: vec![],
+                uses_default_features: true,
             },
         ];
         assert_eq!(
@@ -460,6 +1010,9 @@ mod tests {
                     optional: false,
                     target: None,
                     rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
                 DependencyMetadata {
                     name: "unixlib".to_string(),
@@ -467,6 +1020,9 @@ mod tests {
                     optional: false,
                     target: Some("cfg(unix)".to_string()),
                     rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
                 DependencyMetadata {
                     name: "windowslib".to_string(),
@@ -474,6 +1030,9 @@ mod tests {
                     optional: false,
                     target: Some("cfg(windows)".to_string()),
                     rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
             ],
             features: [].into_iter().collect(),
@@ -511,17 +1070,20 @@ mod tests {
             },
         ];
         assert_eq!(
-            get_externs(&package, &packages, &[], &[], &[], false).unwrap(),
+            get_externs(&package, &PackageGraph::new(&packages, None), &[], &[], &[], false, &[])
+                .unwrap(),
             vec![
                 Extern {
                     name: "alwayslib".to_string(),
                     lib_name: "alwayslib".to_string(),
-                    extern_type: ExternType::Rust
+                    extern_type: ExternType::Rust,
+                    version: None,
                 },
                 Extern {
                     name: "unixlib".to_string(),
                     lib_name: "unixlib".to_string(),
-                    extern_type: ExternType::Rust
+                    extern_type: ExternType::Rust,
+                    version: None,
                 },
             ]
         );
@@ -538,6 +1100,9 @@ mod tests {
                     optional: false,
                     target: Some("cfg(foo)".to_string()),
                     rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
                 DependencyMetadata {
                     name: "barlib".to_string(),
@@ -545,6 +1110,9 @@ mod tests {
                     optional: false,
                     target: Some("cfg(bar)".to_string()),
                     rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
             ],
             features: [].into_iter().collect(),
@@ -573,11 +1141,21 @@ mod tests {
             },
         ];
         assert_eq!(
-            get_externs(&package, &packages, &[], &["foo".to_string()], &[], false).unwrap(),
+            get_externs(
+                &package,
+                &PackageGraph::new(&packages, None),
+                &[],
+                &["foo".to_string()],
+                &[],
+                false,
+                &[]
+            )
+            .unwrap(),
             vec![Extern {
                 name: "foolib".to_string(),
                 lib_name: "foolib".to_string(),
-                extern_type: ExternType::Rust
+                extern_type: ExternType::Rust,
+                version: None,
             },]
         );
     }
@@ -593,6 +1171,9 @@ mod tests {
                     optional: false,
                     target: None,
                     rename: Some("foo2".to_string()),
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
                 DependencyMetadata {
                     name: "bar".to_string(),
@@ -600,6 +1181,9 @@ mod tests {
                     optional: true,
                     target: None,
                     rename: None,
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
                 DependencyMetadata {
                     name: "bar".to_string(),
@@ -607,6 +1191,9 @@ mod tests {
                     optional: true,
                     target: None,
                     rename: Some("baz".to_string()),
+                    req: String::new(),
+                    features: vec![],
+                    uses_default_features: true,
                 },
             ],
             ..Default::default()
@@ -633,32 +1220,54 @@ mod tests {
             },
         ];
         assert_eq!(
-            get_externs(&package, &packages, &["dep:bar".to_string()], &[], &[], false).unwrap(),
+            get_externs(
+                &package,
+                &PackageGraph::new(&packages, None),
+                &["dep:bar".to_string()],
+                &[],
+                &[],
+                false,
+                &[]
+            )
+            .unwrap(),
             vec![
                 Extern {
                     name: "bar".to_string(),
                     lib_name: "bar".to_string(),
-                    extern_type: ExternType::Rust
+                    extern_type: ExternType::Rust,
+                    version: None,
                 },
                 Extern {
                     name: "foo2".to_string(),
                     lib_name: "foo".to_string(),
-                    extern_type: ExternType::Rust
+                    extern_type: ExternType::Rust,
+                    version: None,
                 },
             ]
         );
         assert_eq!(
-            get_externs(&package, &packages, &["dep:baz".to_string()], &[], &[], false).unwrap(),
+            get_externs(
+                &package,
+                &PackageGraph::new(&packages, None),
+                &["dep:baz".to_string()],
+                &[],
+                &[],
+                false,
+                &[]
+            )
+            .unwrap(),
             vec![
                 Extern {
                     name: "baz".to_string(),
                     lib_name: "bar".to_string(),
-                    extern_type: ExternType::Rust
+                    extern_type: ExternType::Rust,
+                    version: None,
                 },
                 Extern {
                     name: "foo2".to_string(),
                     lib_name: "foo".to_string(),
-                    extern_type: ExternType::Rust
+                    extern_type: ExternType::Rust,
+                    version: None,
                 },
             ]
         );
@@ -680,6 +1289,12 @@ mod tests {
             c
         }
 
+        /// Clear the origins, which aren't stored in `crates.json`. They are checked by other
+        /// tests.
+        fn clear_origins(c: Crate) -> Crate {
+            Crate { origins: Origins::default(), ..c }
+        }
+
         for testdata_directory_path in testdata_directories() {
             let cfg = Config::from_json_str(
                 &read_to_string(testdata_directory_path.join("cargo_embargo.json"))
@@ -710,14 +1325,403 @@ mod tests {
                             .with_context(|| format!("Failed to open {:?}", cargo_metadata_path))
                             .unwrap(),
                         variant_cfg,
+                        &BTreeMap::new(),
                     )
                     .unwrap()
                     .into_iter()
                     .map(normalize_package_dir)
+                    .map(clear_origins)
                     .collect::<Vec<Crate>>()
                 })
                 .collect::<Vec<Vec<Crate>>>();
             assert_that!(format!("{crates:#?}"), eq(format!("{expected_crates:#?}")));
         }
     }
+
+    #[test]
+    fn version_requirements() {
+        assert!(version_matches("1.0.109", ""));
+        assert!(version_matches("1.0.109", "1.0"));
+        assert!(version_matches("1.2.0", "^1.0.100"));
+        assert!(!version_matches("2.0.1", "^1.0"));
+        assert!(version_matches("0.4.3", "0.4.1"));
+        assert!(!version_matches("0.5.0", "0.4.1"));
+        assert!(!version_matches("0.0.4", "^0.0.3"));
+        assert!(version_matches("1.2.9", "~1.2.3"));
+        assert!(!version_matches("1.3.0", "~1.2.3"));
+        assert!(version_matches("1.2.3", "=1.2.3"));
+        assert!(version_matches("0.7.1", ">=0.6, <0.8"));
+        assert!(!version_matches("0.8.0", ">=0.6, <0.8"));
+        assert!(version_matches("3.1.4", "3.*"));
+        assert!(version_matches("1.0.0-alpha.1", "1"));
+    }
+
+    #[test]
+    fn config_from_metadata() {
+        let config = config_from_cargo_metadata(
+            r#"{
+                "packages": [
+                    {
+                        "name": "foo",
+                        "version": "1.0.0",
+                        "edition": "2021",
+                        "manifest_path": "/path/to/foo/Cargo.toml",
+                        "dependencies": [],
+                        "features": {},
+                        "id": "foo 1.0.0 (path+file:///path/to/foo)",
+                        "targets": [],
+                        "metadata": {
+                            "cargo_embargo": { "device_supported": false },
+                            "docs.rs": { "all-features": true }
+                        }
+                    },
+                    {
+                        "name": "bar",
+                        "version": "1.0.0",
+                        "edition": "2021",
+                        "manifest_path": "/path/to/bar/Cargo.toml",
+                        "dependencies": [],
+                        "features": {},
+                        "id": "bar 1.0.0 (path+file:///path/to/bar)",
+                        "targets": [],
+                        "metadata": null
+                    }
+                ],
+                "workspace_members": [
+                    "foo 1.0.0 (path+file:///path/to/foo)",
+                    "bar 1.0.0 (path+file:///path/to/bar)"
+                ],
+                "metadata": { "cargo_embargo": { "tests": true } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Value::from(config),
+            serde_json::json!({
+                "tests": true,
+                "package": { "foo": { "device_supported": false } }
+            })
+        );
+    }
+
+    #[test]
+    fn unify_features_across_workspace() {
+        fn package(name: &str, kind: &str, features: Value, dependencies: Value) -> Value {
+            serde_json::json!({
+                "name": name,
+                "version": "1.0.0",
+                "edition": "2021",
+                "manifest_path": format!("/ws/{name}/Cargo.toml"),
+                "dependencies": dependencies,
+                "features": features,
+                "id": format!("path+file:///ws/{name}#1.0.0"),
+                "targets": [{
+                    "crate_types": [kind],
+                    "doc": true,
+                    "doctest": false,
+                    "edition": "2021",
+                    "kind": [kind],
+                    "name": name,
+                    "src_path": format!("/ws/{name}/src/lib.rs"),
+                    "test": false,
+                }],
+            })
+        }
+        fn dependency(name: &str, kind: Option<&str>, optional: bool, features: &[&str]) -> Value {
+            serde_json::json!({
+                "name": name,
+                "kind": kind,
+                "optional": optional,
+                "req": "^1",
+                "features": features,
+            })
+        }
+        fn node(name: &str, deps: &[(&str, Option<&str>)]) -> Value {
+            let deps: Vec<Value> = deps
+                .iter()
+                .map(|(dep, kind)| {
+                    serde_json::json!({
+                        "name": dep,
+                        "pkg": format!("path+file:///ws/{dep}#1.0.0"),
+                        "dep_kinds": [{ "kind": kind, "target": null }],
+                    })
+                })
+                .collect();
+            serde_json::json!({ "id": format!("path+file:///ws/{name}#1.0.0"), "deps": deps })
+        }
+
+        let metadata: WorkspaceMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [
+                package(
+                    "app",
+                    "lib",
+                    serde_json::json!({ "all": ["core/logging"] }),
+                    serde_json::json!([
+                        dependency("core", None, false, &["extra"]),
+                        dependency("tester", Some("dev"), false, &[]),
+                    ]),
+                ),
+                package(
+                    "core",
+                    "lib",
+                    serde_json::json!({
+                        "default": [],
+                        "extra": ["log?/std"],
+                        "logging": ["dep:log"],
+                        "testing": [],
+                        "unused": [],
+                    }),
+                    serde_json::json!([dependency("log", None, true, &[])]),
+                ),
+                package(
+                    "macros",
+                    "proc-macro",
+                    serde_json::json!({}),
+                    serde_json::json!([dependency("core", None, false, &["unused"])]),
+                ),
+                package(
+                    "tester",
+                    "lib",
+                    serde_json::json!({}),
+                    serde_json::json!([dependency("core", None, false, &["testing"])]),
+                ),
+                package(
+                    "log",
+                    "lib",
+                    serde_json::json!({ "std": [], "kv": [] }),
+                    serde_json::json!([]),
+                ),
+            ],
+            "workspace_members": [
+                "path+file:///ws/app#1.0.0",
+                "path+file:///ws/core#1.0.0",
+                "path+file:///ws/macros#1.0.0",
+            ],
+            "resolve": {
+                "nodes": [
+                    node("app", &[("core", None), ("tester", Some("dev"))]),
+                    node("core", &[("log", None)]),
+                    node("macros", &[("core", None)]),
+                    node("tester", &[("core", None)]),
+                    node("log", &[]),
+                ],
+            },
+        }))
+        .unwrap();
+
+        let features_of = |crates: &[Crate], name: &str| {
+            crates.iter().find(|crate_| crate_.name == name).unwrap().features.clone()
+        };
+        let chosen = Some(vec!["all".to_string()]);
+        let crates =
+            parse_cargo_metadata(&metadata, &chosen, &[], &BTreeMap::new(), false, &[]).unwrap();
+        assert_eq!(features_of(&crates, "app"), vec!["all"]);
+        assert_eq!(features_of(&crates, "core"), vec!["default", "extra", "logging"]);
+        assert_eq!(features_of(&crates, "macros"), Vec::<String>::new());
+        let core = crates.iter().find(|crate_| crate_.name == "core").unwrap();
+        assert_eq!(
+            core.origins.get("features", "logging"),
+            Some("feature unification with the dependency of app on core")
+        );
+        assert_eq!(core.externs.len(), 1);
+
+        let graph = PackageGraph::new(&metadata.packages, metadata.resolve.as_ref());
+        let unified = unify_features(&metadata, &graph, &chosen, &[], true).unwrap();
+        let log_id = "path+file:///ws/log#1.0.0".to_string();
+        assert_eq!(unified.features[&(log_id, Platform::Target)], vec!["std"]);
+        let core_id = "path+file:///ws/core#1.0.0".to_string();
+        assert_eq!(
+            unified.features[&(core_id.clone(), Platform::Target)],
+            vec!["default", "dep:log", "extra", "logging", "testing"]
+        );
+        assert_eq!(unified.features[&(core_id, Platform::Host)], vec!["default", "unused"]);
+    }
+
+    #[test]
+    fn externs_from_resolve() {
+        fn rand(version: &str) -> Value {
+            serde_json::json!({
+                "name": "rand",
+                "version": version,
+                "edition": "2018",
+                "manifest_path": format!("/registry/rand-{version}/Cargo.toml"),
+                "dependencies": [],
+                "features": {},
+                "id": format!("registry+https://github.com/rust-lang/crates.io-index#rand@{version}"),
+                "targets": [{
+                    "crate_types": ["lib"],
+                    "doc": true,
+                    "doctest": true,
+                    "edition": "2018",
+                    "kind": ["lib"],
+                    "name": "rand",
+                    "src_path": format!("/registry/rand-{version}/src/lib.rs"),
+                    "test": true,
+                }],
+            })
+        }
+        let metadata: WorkspaceMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [
+                rand("0.7.3"),
+                rand("0.6.5"),
+                {
+                    "name": "app",
+                    "version": "1.0.0",
+                    "edition": "2021",
+                    "manifest_path": "/ws/app/Cargo.toml",
+                    "dependencies": [
+                        { "name": "rand", "kind": null, "optional": false, "req": "*" },
+                        {
+                            "name": "rand",
+                            "kind": null,
+                            "optional": false,
+                            "rename": "rand07",
+                            "req": "^0.7",
+                        },
+                    ],
+                    "features": {},
+                    "id": "path+file:///ws/app#1.0.0",
+                    "targets": [{
+                        "crate_types": ["lib"],
+                        "doc": true,
+                        "doctest": false,
+                        "edition": "2021",
+                        "kind": ["lib"],
+                        "name": "app",
+                        "src_path": "/ws/app/src/lib.rs",
+                        "test": false,
+                    }],
+                },
+            ],
+            "workspace_members": ["path+file:///ws/app#1.0.0"],
+            "resolve": {
+                "nodes": [{
+                    "id": "path+file:///ws/app#1.0.0",
+                    "deps": [
+                        {
+                            "name": "rand",
+                            "pkg": "registry+https://github.com/rust-lang/crates.io-index#rand@0.6.5",
+                            "dep_kinds": [{ "kind": null, "target": null }],
+                        },
+                        {
+                            "name": "rand07",
+                            "pkg": "registry+https://github.com/rust-lang/crates.io-index#rand@0.7.3",
+                            "dep_kinds": [{ "kind": null, "target": null }],
+                        },
+                    ],
+                }],
+            },
+        }))
+        .unwrap();
+
+        let crates = parse_cargo_metadata(
+            &metadata,
+            &None,
+            &[],
+            &BTreeMap::new(),
+            false,
+            &["rand".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            crates[0].externs,
+            vec![
+                Extern {
+                    name: "rand".to_string(),
+                    lib_name: "rand".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: Some("0.6.5".to_string()),
+                },
+                Extern {
+                    name: "rand07".to_string(),
+                    lib_name: "rand".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: Some("0.7.3".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn target_required_features_and_edition() {
+        fn target(kind: &str, name: &str, edition: &str, required_features: &[&str]) -> Value {
+            serde_json::json!({
+                "crate_types": [kind],
+                "doc": true,
+                "doctest": false,
+                "edition": edition,
+                "kind": [kind],
+                "name": name,
+                "src_path": format!("/ws/app/src/{name}.rs"),
+                "test": kind != "lib",
+                "required-features": required_features,
+            })
+        }
+        let metadata: WorkspaceMetadata = serde_json::from_value(serde_json::json!({
+            "packages": [{
+                "name": "app",
+                "version": "1.0.0",
+                "edition": "2021",
+                "manifest_path": "/ws/app/Cargo.toml",
+                "dependencies": [
+                    { "name": "serde", "kind": null, "optional": true, "req": "1" },
+                ],
+                "features": { "default": ["cli"], "cli": [], "json": ["dep:serde"] },
+                "id": "path+file:///ws/app#1.0.0",
+                "targets": [
+                    target("lib", "lib", "2021", &["json"]),
+                    target("bin", "cli", "2018", &["cli"]),
+                    target("bin", "export", "2021", &["json"]),
+                    target("bin", "serialize", "2021", &["serde/derive"]),
+                    target("test", "integration", "2021", &["cli", "json"]),
+                ],
+            }, {
+                "name": "serde",
+                "version": "1.0.0",
+                "edition": "2018",
+                "manifest_path": "/registry/serde/Cargo.toml",
+                "dependencies": [],
+                "features": {},
+                "id": "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0",
+                "targets": [{
+                    "crate_types": ["lib"],
+                    "doc": true,
+                    "doctest": true,
+                    "edition": "2018",
+                    "kind": ["lib"],
+                    "name": "serde",
+                    "src_path": "/registry/serde/src/lib.rs",
+                    "test": true,
+                }],
+            }],
+            "workspace_members": ["path+file:///ws/app#1.0.0"],
+        }))
+        .unwrap();
+
+        let crates =
+            parse_cargo_metadata(&metadata, &None, &[], &BTreeMap::new(), true, &[]).unwrap();
+        assert_eq!(
+            crates
+                .iter()
+                .map(|crate_| (crate_.name.as_str(), crate_.types[0], crate_.edition.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("lib", CrateType::Lib, "2021"),
+                ("cli", CrateType::Bin, "2018"),
+                ("cli", CrateType::Test, "2018"),
+            ]
+        );
+        assert_eq!(
+            crates[1].origins.get("edition", "2018"),
+            Some("the edition of the cli target in the Cargo.toml of app")
+        );
+
+        let chosen = Some(vec!["json".to_string()]);
+        let crates =
+            parse_cargo_metadata(&metadata, &chosen, &[], &BTreeMap::new(), false, &[]).unwrap();
+        assert_eq!(
+            crates.iter().map(|crate_| crate_.name.as_str()).collect::<Vec<_>>(),
+            vec!["lib", "export", "serialize"]
+        );
+    }
 }
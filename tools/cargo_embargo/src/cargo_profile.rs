@@ -0,0 +1,297 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The environment and arguments used to invoke cargo for a variant.
+//!
+//! By default cargo inherits the user's environment. In hermetic mode it instead gets a private
+//! `CARGO_HOME` which replaces crates.io with the vendored crates, runs offline, and only sees an
+//! explicit allowlist of environment variables, so that the results don't depend on who runs it.
+
+use crate::config::VariantConfig;
+use crate::sandbox::Sandbox;
+use anyhow::{Context, Result};
+use std::env;
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+use std::process::Command;
+
+/// Environment variables which are always passed through to cargo, even in hermetic mode.
+const ALWAYS_ALLOWED_ENV_VARS: [&str; 1] = ["PATH"];
+
+/// How to invoke cargo for a particular variant. Not to be confused with cargo's own build
+/// profiles.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CargoProfile {
+    /// Whether to clear the environment before adding `env`.
+    hermetic: bool,
+    /// Environment variables to set for cargo.
+    env: Vec<(String, String)>,
+    /// Arguments to pass to every cargo command, before the subcommand.
+    args: Vec<String>,
+    /// The sandbox to run cargo in, if any.
+    sandbox: Option<Sandbox>,
+}
+
+impl CargoProfile {
+    /// Creates the profile for the given variant, building for `target`.
+    ///
+    /// In hermetic mode this writes the private cargo config under `intermediates_dir`.
+    pub fn new(cfg: &VariantConfig, intermediates_dir: &Path, target: &str) -> Result<Self> {
+        Self::with_env(cfg, intermediates_dir, target, env::vars())
+    }
+
+    fn with_env(
+        cfg: &VariantConfig,
+        intermediates_dir: &Path,
+        target: &str,
+        inherited_env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut profile = Self { hermetic: cfg.hermetic, ..Default::default() };
+        let mut inherited_env: Vec<(String, String)> = inherited_env.into_iter().collect();
+
+        if cfg.hermetic {
+            inherited_env.retain(|(name, _)| {
+                ALWAYS_ALLOWED_ENV_VARS.contains(&name.as_str()) || cfg.env_allowlist.contains(name)
+            });
+            let cargo_home = intermediates_dir.join("cargo_home");
+            create_dir_all(&cargo_home)
+                .with_context(|| format!("failed to create {cargo_home:?}"))?;
+            write(cargo_home.join("config.toml"), hermetic_cargo_config(cfg)?)
+                .context("failed to write hermetic cargo config")?;
+            // The private `CARGO_HOME` replaces any inherited one.
+            inherited_env.retain(|(name, _)| name != "CARGO_HOME");
+            profile.env.push(("CARGO_HOME".to_string(), cargo_home.to_string_lossy().into_owned()));
+
+            // Without a lockfile cargo must be allowed to create one, but it still mustn't use
+            // the network.
+            if Path::new("Cargo.lock").exists() {
+                profile.args.push("--frozen".to_string());
+            } else {
+                profile.args.push("--offline".to_string());
+            }
+        }
+
+        let mut rustflags = cfg.rustflags.clone();
+        for cfg_flag in &cfg.extra_cfg {
+            rustflags.push("--cfg".to_string());
+            rustflags.push(cfg_flag.clone());
+        }
+        if !rustflags.is_empty() {
+            // Cargo ignores rustflags from its config files if either of these is set, so if the
+            // user has set one then append to it. Otherwise add to `target.<triple>.rustflags` in
+            // the config, where arrays from different sources are concatenated. Cargo then ignores
+            // `build.rustflags`, which stable cargo can't report, so that is overridden.
+            if let Some((_, value)) =
+                inherited_env.iter_mut().find(|(name, _)| name == "CARGO_ENCODED_RUSTFLAGS")
+            {
+                for flag in rustflags {
+                    if !value.is_empty() {
+                        value.push('\x1f');
+                    }
+                    value.push_str(&flag);
+                }
+            } else if let Some((_, value)) =
+                inherited_env.iter_mut().find(|(name, _)| name == "RUSTFLAGS")
+            {
+                for flag in rustflags {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    value.push_str(&flag);
+                }
+            } else {
+                profile.args.push("--config".to_string());
+                profile.args.push(format!(
+                    "target.{target}.rustflags={}",
+                    serde_json::to_string(&rustflags)?
+                ));
+            }
+        }
+
+        if cfg.hermetic {
+            inherited_env.append(&mut profile.env);
+            profile.env = inherited_env;
+        } else {
+            // Only set the variables we changed, the rest are inherited anyway.
+            profile.env.extend(
+                inherited_env
+                    .into_iter()
+                    .filter(|(name, _)| name == "RUSTFLAGS" || name == "CARGO_ENCODED_RUSTFLAGS"),
+            );
+        }
+        profile.env.sort();
+        Ok(profile)
+    }
+
+    /// Runs cargo in the given sandbox.
+    pub fn sandboxed(self, sandbox: Sandbox) -> Self {
+        Self { sandbox: Some(sandbox), ..self }
+    }
+
+    /// Returns whether cargo runs in a sandbox.
+    pub fn is_sandboxed(&self) -> bool {
+        self.sandbox.is_some()
+    }
+
+    /// Returns a new `cargo` command using this profile, to which the subcommand and its
+    /// arguments should be added.
+    pub fn command(&self) -> Result<Command> {
+        let mut command = self.unsandboxed_command();
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.apply(&mut command)?;
+        }
+        Ok(command)
+    }
+
+    /// Like `command`, but never runs cargo in the sandbox. This should only be used for commands
+    /// which don't run build scripts or need the network.
+    pub fn unsandboxed_command(&self) -> Command {
+        let mut command = Command::new("cargo");
+        if self.hermetic {
+            command.env_clear();
+        }
+        command.envs(self.env.iter().map(|(name, value)| (name, value))).args(&self.args);
+        command
+    }
+
+    /// Returns all the environment variables cargo will see when run with this profile.
+    pub fn effective_env(&self) -> Vec<(String, String)> {
+        if self.hermetic {
+            return self.env.clone();
+        }
+        let mut effective_env: Vec<(String, String)> = env::vars()
+            .filter(|(name, _)| !self.env.iter().any(|(set_name, _)| set_name == name))
+            .collect();
+        effective_env.extend(self.env.iter().cloned());
+        effective_env.sort();
+        effective_env
+    }
+}
+
+/// Returns the contents of the cargo config file to use for the given hermetic variant.
+fn hermetic_cargo_config(cfg: &VariantConfig) -> Result<String> {
+    let mut config = "[net]\noffline = true\n".to_string();
+    if let Some(vendored_crates_dir) = &cfg.vendored_crates_dir {
+        let vendored_crates_dir = vendored_crates_dir
+            .canonicalize()
+            .with_context(|| format!("vendored_crates_dir {vendored_crates_dir:?} not found"))?;
+        // A JSON string is also a valid TOML basic string.
+        config += &format!(
+            "\n[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n\
+             [source.vendored-sources]\ndirectory = {}\n",
+            serde_json::to_string(&vendored_crates_dir.to_string_lossy())?
+        );
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    const TARGET: &str = "x86_64-unknown-linux-gnu";
+
+    fn user_env() -> Vec<(String, String)> {
+        [("PATH", "/bin"), ("HOME", "/home/user"), ("CARGO_HOME", "/home/user/.cargo")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn default_profile_inherits_environment() -> Result<()> {
+        let intermediates_dir = tempdir()?;
+        let profile = CargoProfile::with_env(
+            &VariantConfig::default(),
+            intermediates_dir.path(),
+            TARGET,
+            user_env(),
+        )?;
+        assert_eq!(profile, CargoProfile::default());
+        Ok(())
+    }
+
+    #[test]
+    fn rustflags_added_to_config() -> Result<()> {
+        let intermediates_dir = tempdir()?;
+        let cfg = VariantConfig {
+            rustflags: vec!["-Cforce-frame-pointers".to_string()],
+            extra_cfg: vec!["foo".to_string()],
+            ..Default::default()
+        };
+        let profile = CargoProfile::with_env(&cfg, intermediates_dir.path(), TARGET, user_env())?;
+        assert_eq!(
+            profile.args,
+            vec![
+                "--config".to_string(),
+                r#"target.x86_64-unknown-linux-gnu.rustflags=["-Cforce-frame-pointers","--cfg","foo"]"#
+                    .to_string()
+            ]
+        );
+        assert!(profile.env.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rustflags_merged_with_user_rustflags() -> Result<()> {
+        let intermediates_dir = tempdir()?;
+        let cfg = VariantConfig { extra_cfg: vec!["foo".to_string()], ..Default::default() };
+        let mut env = user_env();
+        env.push(("RUSTFLAGS".to_string(), "-Dwarnings".to_string()));
+        let profile = CargoProfile::with_env(&cfg, intermediates_dir.path(), TARGET, env)?;
+        assert!(profile.args.is_empty());
+        assert_eq!(
+            profile.env,
+            vec![("RUSTFLAGS".to_string(), "-Dwarnings --cfg foo".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hermetic_profile() -> Result<()> {
+        let intermediates_dir = tempdir()?;
+        let vendored_dir = tempdir()?;
+        let cfg = VariantConfig {
+            hermetic: true,
+            vendored_crates_dir: Some(vendored_dir.path().to_owned()),
+            env_allowlist: vec!["HOME".to_string()],
+            ..Default::default()
+        };
+        let mut env = user_env();
+        env.push(("SECRET".to_string(), "value".to_string()));
+        let profile = CargoProfile::with_env(&cfg, intermediates_dir.path(), TARGET, env)?;
+
+        let cargo_home = intermediates_dir.path().join("cargo_home");
+        assert!(profile.hermetic);
+        assert_eq!(
+            profile.env,
+            vec![
+                ("CARGO_HOME".to_string(), cargo_home.to_string_lossy().into_owned()),
+                ("HOME".to_string(), "/home/user".to_string()),
+                ("PATH".to_string(), "/bin".to_string()),
+            ]
+        );
+        assert_eq!(profile.effective_env(), profile.env);
+        let config = read_to_string(cargo_home.join("config.toml"))?;
+        assert!(config.contains("offline = true"));
+        assert!(config.contains("replace-with = \"vendored-sources\""));
+        assert!(config.contains(&format!(
+            "directory = \"{}\"",
+            vendored_dir.path().canonicalize()?.to_string_lossy()
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Genrules to generate the C headers of packages' FFI libraries with cbindgen, and checking of
+//! checked-in copies of the headers.
+
+use crate::bp::BpModule;
+use crate::cargo::Crate;
+use crate::config::{CbindgenConfig, Config, PackageVariantConfig, VariantConfig};
+use crate::override_module_name;
+use crate::rename_registry::rename_registry;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::tempdir;
+
+/// The default cbindgen config file, which also enables header generation without a `cbindgen`
+/// config.
+const DEFAULT_CONFIG: &str = "cbindgen.toml";
+
+/// Arguments passed to cbindgen both by the genrule and by `check-headers`, so that they generate
+/// the same header. The language and other options come from the config file.
+const CBINDGEN_ARGS: [&str; 1] = ["--quiet"];
+
+/// A package's C library crate, and how to generate its header.
+struct Header<'a> {
+    crate_: &'a Crate,
+    /// The cbindgen config file, relative to the package directory.
+    config: PathBuf,
+    /// The file name of the header.
+    name: String,
+}
+
+impl<'a> Header<'a> {
+    /// Returns the header to generate for the C library among the given crates of a package, if
+    /// there is one and the package has a `cbindgen` config or a `cbindgen.toml`.
+    fn find(
+        package_cfg: &PackageVariantConfig,
+        crates: impl IntoIterator<Item = &'a Crate>,
+    ) -> Option<Self> {
+        let crate_ = crates
+            .into_iter()
+            .find(|crate_| crate_.types.iter().any(|crate_type| crate_type.is_c_library()))?;
+        let cbindgen_cfg = package_cfg.cbindgen.clone().or_else(|| {
+            crate_.package_dir.join(DEFAULT_CONFIG).is_file().then(CbindgenConfig::default)
+        })?;
+        Some(Self {
+            crate_,
+            config: cbindgen_cfg.config.unwrap_or_else(|| DEFAULT_CONFIG.into()),
+            name: cbindgen_cfg.header.unwrap_or_else(|| format!("{}.h", crate_.name)),
+        })
+    }
+
+    /// Returns the paths relative to the package directory where a checked-in copy of the header
+    /// may be.
+    fn checked_in_paths(&self, package_cfg: &PackageVariantConfig) -> Vec<PathBuf> {
+        if let Some(path) = package_cfg
+            .cbindgen
+            .as_ref()
+            .and_then(|cbindgen_cfg| cbindgen_cfg.checked_in_header.clone())
+        {
+            vec![path]
+        } else {
+            package_cfg.exported_c_header_dir.iter().map(|dir| dir.join(&self.name)).collect()
+        }
+    }
+
+    /// Runs cbindgen in the package directory the same way as the genrule, and returns the header.
+    fn generate(&self) -> Result<String> {
+        let out_dir = tempdir()?;
+        let out_path = out_dir.path().join(&self.name);
+        let output = Command::new("cbindgen")
+            .current_dir(&self.crate_.package_dir)
+            .arg("--config")
+            .arg(&self.config)
+            .args(CBINDGEN_ARGS)
+            .arg("--output")
+            .arg(&out_path)
+            .arg(&self.crate_.main_src)
+            .output()
+            .context("failed to run cbindgen, is it installed?")?;
+        if !output.status.success() {
+            bail!(
+                "cbindgen failed for {}:\n{}",
+                self.crate_.package_name,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        read_to_string(&out_path).with_context(|| format!("failed to read {out_path:?}"))
+    }
+}
+
+/// Returns the name of the genrule which generates the header of the given C library crate, if it
+/// has one and it isn't blocklisted.
+pub fn header_module_name(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    crate_: &Crate,
+) -> Option<String> {
+    let header = Header::find(package_cfg, [crate_])?;
+    override_module_name(
+        &format!("gen_{}_header", header.crate_.name),
+        &cfg.module_blocklist,
+        &cfg.module_name_overrides,
+        &rename_registry().rename_map,
+    )
+}
+
+/// Generates the genrule which generates the header of the C library among the given crates of a
+/// package with cbindgen, if it has one.
+pub fn header_module(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    crates: &[Crate],
+) -> Option<BpModule> {
+    let header = Header::find(package_cfg, crates)?;
+    let module_name = header_module_name(cfg, package_cfg, header.crate_)?;
+    let config = header.config.to_string_lossy().into_owned();
+    let sources = header.crate_.main_src.parent().unwrap_or(Path::new("")).join("**/*.rs");
+
+    let mut m = BpModule::new("genrule".to_string());
+    m.props.set("name", module_name);
+    m.props.set("srcs", vec![header.crate_.main_src.to_string_lossy().into_owned()]);
+    m.props.set(
+        "cmd",
+        format!(
+            "$(location cbindgen) --config $(location {config}) {} --output $(out) $(in)",
+            CBINDGEN_ARGS.join(" ")
+        ),
+    );
+    m.props.set("out", vec![header.name]);
+    m.props.set("tools", vec!["cbindgen"]);
+    // cbindgen follows the `mod` items of the crate root to the other source files.
+    m.props.set("tool_files", vec![config, sources.to_string_lossy().into_owned()]);
+    Some(m)
+}
+
+/// Generates the headers of the C libraries with cbindgen, and compares them with their
+/// checked-in copies. Prints whether each is up to date, and returns whether they all are.
+pub fn check_headers(cfg: &Config, crates: &[Vec<Crate>]) -> Result<bool> {
+    let def = PackageVariantConfig::default();
+    let mut checked = BTreeSet::new();
+    let mut up_to_date = true;
+    for (variant_cfg, variant_crates) in cfg.variants.iter().zip(crates) {
+        let mut packages: BTreeMap<&str, Vec<&Crate>> = BTreeMap::new();
+        for crate_ in variant_crates {
+            packages.entry(&crate_.package_name).or_default().push(crate_);
+        }
+        for (package_name, package_crates) in packages {
+            let package_cfg = variant_cfg.package.get(package_name).unwrap_or(&def);
+            let Some(header) = Header::find(package_cfg, package_crates) else {
+                continue;
+            };
+            let package_dir = &header.crate_.package_dir;
+            let Some(path) = header
+                .checked_in_paths(package_cfg)
+                .into_iter()
+                .find(|path| package_dir.join(path).is_file())
+            else {
+                println!("{package_name}: no checked-in copy of {}", header.name);
+                continue;
+            };
+            if !checked.insert(package_dir.join(&path)) {
+                continue;
+            }
+            let checked_in = read_to_string(package_dir.join(&path))
+                .with_context(|| format!("failed to read {path:?}"))?;
+            if checked_in == header.generate()? {
+                println!("{}: up to date", path.display());
+            } else {
+                println!("{}: out of date with the header generated by cbindgen", path.display());
+                up_to_date = false;
+            }
+        }
+    }
+    Ok(up_to_date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::CrateType;
+    use std::fs::write;
+
+    #[test]
+    fn generate_module() -> Result<()> {
+        let package_dir = tempdir()?;
+        let cfg = VariantConfig::default();
+        let mut package_cfg = PackageVariantConfig::default();
+        let crates = vec![
+            Crate {
+                name: "foo_ffi".to_string(),
+                types: vec![CrateType::CDyLib, CrateType::StaticLib],
+                package_dir: package_dir.path().to_path_buf(),
+                main_src: "src/lib.rs".into(),
+                ..Default::default()
+            },
+            Crate {
+                name: "foo".to_string(),
+                types: vec![CrateType::Bin],
+                package_dir: package_dir.path().to_path_buf(),
+                main_src: "src/main.rs".into(),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(header_module(&cfg, &package_cfg, &crates), None);
+        assert_eq!(header_module_name(&cfg, &package_cfg, &crates[0]), None);
+
+        // A cbindgen.toml enables it with the default options.
+        write(package_dir.path().join(DEFAULT_CONFIG), "language = \"C\"\n")?;
+        let mut contents = String::new();
+        header_module(&cfg, &package_cfg, &crates).unwrap().write(&mut contents)?;
+        assert_eq!(
+            contents,
+            r#"genrule {
+name: "gen_foo_ffi_header",
+srcs: ["src/lib.rs"],
+cmd: "$(location cbindgen) --config $(location cbindgen.toml) --quiet --output $(out) $(in)",
+out: ["foo_ffi.h"],
+tool_files: ["cbindgen.toml", "src/**/*.rs"],
+tools: ["cbindgen"],
+}
+"#
+        );
+        assert_eq!(header_module_name(&cfg, &package_cfg, &crates[1]), None);
+
+        package_cfg.cbindgen = Some(CbindgenConfig {
+            config: Some("ffi/cbindgen.toml".into()),
+            header: Some("foo.h".to_string()),
+            ..Default::default()
+        });
+        let m = header_module(&cfg, &package_cfg, &crates).unwrap();
+        assert_eq!(m.props.get_string("name"), "gen_foo_ffi_header");
+        let mut contents = String::new();
+        m.write(&mut contents)?;
+        assert!(contents.contains("--config $(location ffi/cbindgen.toml)"));
+        assert!(contents.contains("out: [\"foo.h\"],\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn checked_in_paths() {
+        let crate_ = Crate { name: "foo_ffi".to_string(), ..Default::default() };
+        let header =
+            Header { crate_: &crate_, config: DEFAULT_CONFIG.into(), name: "foo.h".into() };
+        let mut package_cfg = PackageVariantConfig {
+            exported_c_header_dir: vec!["include".into(), "ffi/include".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            header.checked_in_paths(&package_cfg),
+            vec![PathBuf::from("include/foo.h"), PathBuf::from("ffi/include/foo.h")]
+        );
+
+        package_cfg.cbindgen =
+            Some(CbindgenConfig { checked_in_header: Some("foo.h".into()), ..Default::default() });
+        assert_eq!(header.checked_in_paths(&package_cfg), vec![PathBuf::from("foo.h")]);
+    }
+}
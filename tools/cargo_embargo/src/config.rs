@@ -52,6 +52,10 @@ fn is_false(value: &bool) -> bool {
     !*value
 }
 
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
 /// Options that apply to everything.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -60,6 +64,10 @@ pub struct Config {
     /// Package specific config options across all variants.
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub package: BTreeMap<String, PackageConfig>,
+    /// Whether to add a comment to generated files recording the inputs they were generated from,
+    /// so that the `status` subcommand can tell whether they are out of date.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub provenance: bool,
 }
 
 /// Inserts entries from `defaults` into `variant` if neither it nor `ignored_fields` contain
@@ -76,25 +84,79 @@ fn add_defaults_to_variant(
     }
 }
 
+/// Inserts top-level and package entries from `defaults` into `config` if it doesn't already have
+/// them.
+fn merge_defaults(config: &mut Map<String, Value>, defaults: &Map<String, Value>) -> Result<()> {
+    for (key, value) in defaults {
+        if key != "package" {
+            config.entry(key).or_insert_with(|| value.clone());
+            continue;
+        }
+        let default_packages =
+            value.as_object().context("Failed to parse config: package is not an object")?;
+        let packages = config
+            .entry("package")
+            .or_insert_with(|| Map::new().into())
+            .as_object_mut()
+            .context("Failed to parse config: package is not an object")?;
+        for (package_name, default_package_config) in default_packages {
+            let package_config = packages
+                .entry(package_name)
+                .or_insert_with(|| Map::new().into())
+                .as_object_mut()
+                .context("Failed to parse config: package is not an object")?;
+            add_defaults_to_variant(
+                package_config,
+                default_package_config
+                    .as_object()
+                    .context("Failed to parse config: package is not an object")?,
+                &[],
+            );
+        }
+    }
+    Ok(())
+}
+
 impl Config {
     /// Names of all fields in [`Config`] other than `variants` (which is treated specially).
-    const FIELD_NAMES: [&'static str; 1] = ["package"];
+    const FIELD_NAMES: [&'static str; 2] = ["package", "provenance"];
 
     /// Parses an instance of this config from the given JSON file.
-    pub fn from_file(filename: &Path) -> Result<Self> {
+    ///
+    /// Options from `defaults` are used where the file doesn't set them, as for
+    /// [`Config::from_json_str_with_defaults`]. The file may be missing if `defaults` isn't empty.
+    pub fn from_file_with_defaults(filename: &Path, defaults: &Map<String, Value>) -> Result<Self> {
+        if !defaults.is_empty() && !filename.exists() {
+            return Self::from_json_str_with_defaults("{}", defaults);
+        }
         let json_string = std::fs::read_to_string(filename)
             .with_context(|| format!("failed to read file: {:?}", filename))?;
-        Self::from_json_str(&json_string)
+        Self::from_json_str_with_defaults(&json_string, defaults)
     }
 
     /// Parses an instance of this config from a string of JSON.
+    #[cfg(test)]
     pub fn from_json_str(json_str: &str) -> Result<Self> {
+        Self::from_json_str_with_defaults(json_str, &Map::new())
+    }
+
+    /// Parses an instance of this config from a string of JSON, using options from `defaults`
+    /// where the JSON doesn't set them.
+    ///
+    /// `defaults` has the same structure as the JSON, e.g. from `Cargo.toml` metadata. Top-level
+    /// options and options for each package are merged individually, but `variants` from
+    /// `defaults` are only used if the JSON doesn't have any.
+    pub fn from_json_str_with_defaults(
+        json_str: &str,
+        defaults: &Map<String, Value>,
+    ) -> Result<Self> {
         // Ignore comments.
         let json_str: String =
             json_str.lines().filter(|l| !l.trim_start().starts_with("//")).collect();
         // First parse into untyped map.
         let mut config: Map<String, Value> =
             serde_json::from_str(&json_str).context("failed to parse config")?;
+        merge_defaults(&mut config, defaults)?;
 
         // Flatten variants. First, get the variants from the config file.
         let mut variants = match config.remove("variants") {
@@ -306,6 +368,15 @@ pub struct VariantConfig {
     /// the project being processed.
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub module_name_overrides: BTreeMap<String, String>,
+    /// Crates which exist in the tree in more than one version. Library modules for these crates,
+    /// and references to them, get a suffix for their version, e.g. "libsyn_1" or "libnom_0_7".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versioned_crates: Vec<String>,
+    /// Suffix to add to the names of all Android.bp modules generated for this variant, and to
+    /// references to libraries generated from other crates in the same variant, e.g. "_nostd".
+    /// rules.mk modules are named by their directory, so aren't affected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module_suffix: Option<String>,
     /// Package specific config options.
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub package: BTreeMap<String, PackageVariantConfig>,
@@ -325,6 +396,21 @@ pub struct VariantConfig {
     /// from the cargo metadata.
     #[serde(default = "default_true", skip_serializing_if = "is_true")]
     pub run_cargo: bool,
+    /// Extra flags to pass to rustc when running cargo. These are merged with any `RUSTFLAGS` from
+    /// the environment or `target.<triple>.rustflags` from cargo config files, rather than
+    /// replacing them, but they override `build.rustflags`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rustflags: Vec<String>,
+    /// Whether to run cargo hermetically: offline, with a private `CARGO_HOME`, and with only the
+    /// environment variables in `env_allowlist` (plus `PATH`).
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub hermetic: bool,
+    /// Directory of vendored crates to use instead of crates.io when `hermetic` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendored_crates_dir: Option<PathBuf>,
+    /// Environment variables to pass through to cargo when `hermetic` is set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env_allowlist: Vec<String>,
     /// Generate an Android.bp build file for this variant if true.
     #[serde(default = "default_true", skip_serializing_if = "is_true")]
     pub generate_androidbp: bool,
@@ -350,12 +436,18 @@ impl Default for VariantConfig {
             vendor_ramdisk_available: false,
             min_sdk_version: None,
             module_name_overrides: Default::default(),
+            versioned_crates: Default::default(),
+            module_suffix: None,
             package: Default::default(),
             cfg_blocklist: Default::default(),
             extra_cfg: Default::default(),
             module_blocklist: Default::default(),
             module_visibility: Default::default(),
             run_cargo: true,
+            rustflags: Default::default(),
+            hermetic: false,
+            vendored_crates_dir: None,
+            env_allowlist: Default::default(),
             generate_androidbp: true,
             generate_rulesmk: false,
         }
@@ -376,11 +468,16 @@ pub struct PackageConfig {
     /// Patch file to apply after rules.mk is generated.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rulesmk_patch: Option<PathBuf>,
+    /// Create `MODULE_LICENSE_*` and `METADATA` files for the package from its `Cargo.toml`, if
+    /// they don't already exist.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub add_metadata_files: bool,
 }
 
 impl PackageConfig {
     /// Names of all the fields on `PackageConfig`.
-    const FIELD_NAMES: [&'static str; 3] = ["add_toplevel_block", "patch", "rulesmk_patch"];
+    const FIELD_NAMES: [&'static str; 4] =
+        ["add_toplevel_block", "patch", "rulesmk_patch", "add_metadata_files"];
 }
 
 /// Options that apply to everything in a package (i.e. everything associated with a particular
@@ -403,9 +500,8 @@ pub struct PackageVariantConfig {
     /// Generate "rust_library_rlib" instead of "rust_library".
     #[serde(default, skip_serializing_if = "is_false")]
     pub force_rlib: bool,
-    /// Whether to disable "unit_test" for "rust_test" modules.
-    // TODO: Should probably be a list of modules or crates. A package might have a mix of unit and
-    // integration tests.
+    /// Whether to disable "unit_test" for "rust_test" modules. This can be overridden for
+    /// individual tests in `test_targets`.
     #[serde(default, skip_serializing_if = "is_false")]
     pub no_presubmit: bool,
     /// File with content to append to the end of each generated module.
@@ -426,12 +522,32 @@ pub struct PackageVariantConfig {
     /// relative to the crate root.
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub test_data: BTreeMap<String, Vec<String>>,
+    /// Options for individual test targets. The key is the test source filename relative to the
+    /// crate root.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub test_targets: BTreeMap<String, TestTargetConfig>,
     /// Static libraries in this list will instead be added as whole_static_libs.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub whole_static_libs: Vec<String>,
     /// Directories with headers to export for C usage.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub exported_c_header_dir: Vec<PathBuf>,
+    /// Generate a `rust_bindgen` module for the bindings which the build script generates with
+    /// bindgen, and add it to the `srcs` of the package's crates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bindgen: Option<BindgenConfig>,
+    /// Generate a module for the code which the build script generates from `.proto` files, and
+    /// add it to the `srcs` of the package's crates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protobuf: Option<ProtobufConfig>,
+    /// Generate `rust_fuzz` modules for the cargo-fuzz targets in the package's `fuzz` directory.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fuzzers: bool,
+    /// Generate a C header for the package's `cdylib` or `staticlib` with cbindgen, and export it
+    /// from the `rust_ffi` modules. This is done with the default options if the package has a
+    /// `cbindgen.toml`, even if this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cbindgen: Option<CbindgenConfig>,
 }
 
 impl Default for PackageVariantConfig {
@@ -448,12 +564,120 @@ impl Default for PackageVariantConfig {
             no_std: false,
             copy_out: false,
             test_data: Default::default(),
+            test_targets: Default::default(),
             whole_static_libs: Default::default(),
             exported_c_header_dir: Default::default(),
+            bindgen: None,
+            protobuf: None,
+            fuzzers: false,
+            cbindgen: None,
         }
     }
 }
 
+/// Options for the `rust_bindgen` module generated for a package.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BindgenConfig {
+    /// The header to generate bindings for, relative to the package directory.
+    pub wrapper_src: String,
+    /// The name of the generated file without the `.rs` extension, as included from `OUT_DIR`.
+    /// Defaults to "bindings".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_stem: Option<String>,
+    /// Functions to generate bindings for, as regular expressions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowlist_functions: Vec<String>,
+    /// Types to generate bindings for, as regular expressions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowlist_types: Vec<String>,
+    /// Variables to generate bindings for, as regular expressions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowlist_vars: Vec<String>,
+    /// Other flags to pass to bindgen, e.g. "--no-layout-tests".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bindgen_flags: Vec<String>,
+    /// Flags to pass to clang, e.g. "-DFOO".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cflags: Vec<String>,
+    /// Header libraries which the wrapper header needs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub header_libs: Vec<String>,
+    /// Static libraries which the wrapper header needs the headers of.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub static_libs: Vec<String>,
+    /// Shared libraries which the wrapper header needs the headers of.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_libs: Vec<String>,
+}
+
+/// Options for the module generated for a package's `.proto` files.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtobufConfig {
+    /// The code generator which the build script uses.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub generator: ProtobufGenerator,
+    /// The `.proto` files to generate code for, relative to the package directory.
+    pub protos: Vec<String>,
+    /// The name of the generated file without the `.rs` extension, as included from `OUT_DIR`.
+    /// Only used for the `protobuf` generator. Defaults to "protos".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_stem: Option<String>,
+    /// Extra flags to pass to protoc, e.g. "-I external/foo/proto".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub proto_flags: Vec<String>,
+}
+
+/// A Rust code generator for protobufs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtobufGenerator {
+    /// The protobuf crate's code generator, used by `protobuf-codegen` and `protoc-rust`. A
+    /// `rust_protobuf` module is generated.
+    #[default]
+    Protobuf,
+    /// prost, used by `prost-build` and `tonic-build`. A genrule running `protoc-gen-prost` is
+    /// generated.
+    Prost,
+}
+
+/// Options for the genrule which generates a C header for a package with cbindgen.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CbindgenConfig {
+    /// The cbindgen config file, relative to the package directory. Defaults to "cbindgen.toml".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+    /// The name of the generated header. Defaults to the library crate name with `.h`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// A checked-in copy of the header, relative to the package directory, for `check-headers` to
+    /// compare with the generated one. Defaults to the header in any of `exported_c_header_dir`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checked_in_header: Option<PathBuf>,
+}
+
+/// Options for a single test target within a package.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestTargetConfig {
+    /// Whether to run the test in presubmit, i.e. the value of `unit_test`. Defaults to the
+    /// opposite of the package's `no_presubmit`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_test: Option<bool>,
+    /// Test suites to add the test to. Defaults to `["general-tests"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub test_suites: Vec<String>,
+    /// Timeout for the test binary, e.g. "10m".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    /// Names of individual tests not to run, e.g. because they need network access or root. These
+    /// may be globs, which are matched against the tests found by `cargo test -- --list`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_tests: Vec<String>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -581,6 +805,7 @@ mod tests {
                 ]
                 .into_iter()
                 .collect(),
+                provenance: false,
             }
         );
     }
@@ -632,6 +857,7 @@ mod tests {
             )]
             .into_iter()
             .collect(),
+            provenance: false,
         };
 
         assert_eq!(
@@ -675,6 +901,7 @@ mod tests {
                 ..Default::default()
             }],
             package: Default::default(),
+            provenance: false,
         };
 
         assert_eq!(
@@ -684,4 +911,73 @@ mod tests {
 }"#
         );
     }
+
+    /// Tests that options from `Cargo.toml` metadata are used where the JSON config doesn't set them.
+    #[test]
+    fn config_with_defaults() {
+        let defaults = serde_json::from_str(
+            r#"{
+                "tests": true,
+                "features": ["std"],
+                "package": {
+                    "argh": {
+                        "device_supported": false,
+                        "patch": "patches/Android.bp.patch"
+                    },
+                    "another": {
+                        "force_rlib": true
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let config = Config::from_json_str_with_defaults(
+            r#"{
+                "features": [],
+                "package": {
+                    "argh": {
+                        "device_supported": true
+                    }
+                }
+            }"#,
+            &defaults,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                variants: vec![VariantConfig {
+                    tests: true,
+                    features: Some(vec![]),
+                    package: [
+                        (
+                            "argh".to_string(),
+                            PackageVariantConfig { device_supported: true, ..Default::default() },
+                        ),
+                        (
+                            "another".to_string(),
+                            PackageVariantConfig { force_rlib: true, ..Default::default() },
+                        ),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    ..Default::default()
+                }],
+                package: [
+                    (
+                        "argh".to_string(),
+                        PackageConfig {
+                            patch: Some("patches/Android.bp.patch".into()),
+                            ..Default::default()
+                        },
+                    ),
+                    ("another".to_string(), PackageConfig::default()),
+                ]
+                .into_iter()
+                .collect(),
+                provenance: false,
+            }
+        );
+    }
 }
@@ -0,0 +1,220 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that the dependencies of generated modules exist in the tree and are the right kind of
+//! module, so that problems show up when generating rather than in a later Soong build.
+
+use crate::bp::{BpModule, BpProperties, BpValue};
+use crate::rename_registry::rename_registry;
+use crate::tree_index::TreeIndex;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+/// Properties listing dependencies, and the prefixes of module types which they may refer to.
+const DEP_PROPERTIES: [(&str, &[&str]); 5] = [
+    (
+        "rustlibs",
+        &[
+            "rust_library",
+            "rust_ffi_rlib",
+            "rust_bindgen",
+            "rust_protobuf",
+            "rust_prebuilt_library",
+            "rust_prebuilt_rlib",
+            "rust_prebuilt_dylib",
+        ],
+    ),
+    ("proc_macros", &["rust_proc_macro", "rust_prebuilt_proc_macro"]),
+    ("static_libs", &["cc_library", "cc_prebuilt_library", "rust_ffi"]),
+    ("whole_static_libs", &["cc_library", "cc_prebuilt_library", "rust_ffi"]),
+    ("shared_libs", &["cc_library", "cc_prebuilt_library", "rust_ffi", "ndk_library"]),
+];
+
+/// A problem with a dependency of a generated module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DepProblem {
+    /// The name of the generated module.
+    pub module_name: String,
+    /// The property listing the dependency, e.g. `rustlibs`.
+    pub property: String,
+    /// The name of the dependency.
+    pub dep: String,
+    pub kind: DepProblemKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DepProblemKind {
+    /// There is no module with the given name, but there are some with similar names.
+    Missing { suggestions: Vec<String> },
+    /// The module is of a type which can't be used in the property.
+    WrongType { module_type: String },
+    /// The module can only be built for the host, but the generated module is also for device.
+    HostOnly,
+}
+
+impl Display for DepProblem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {} entry \"{}\" ", self.module_name, self.property, self.dep)?;
+        match &self.kind {
+            DepProblemKind::Missing { suggestions } if suggestions.is_empty() => {
+                write!(f, "doesn't exist in the tree")
+            }
+            DepProblemKind::Missing { suggestions } => write!(
+                f,
+                "doesn't exist in the tree, consider a module_name_overrides entry to use {}",
+                suggestions.join(" or ")
+            ),
+            DepProblemKind::WrongType { module_type } => {
+                write!(f, "is a {module_type}, which can't be used in {}", self.property)
+            }
+            DepProblemKind::HostOnly => write!(f, "is host-only, but the module is for device"),
+        }
+    }
+}
+
+/// Checks the dependencies of the given generated modules.
+///
+/// Dependencies on any of `modules` themselves are allowed even if they aren't in the tree yet.
+pub fn check_deps(modules: &[BpModule], tree_index: &TreeIndex) -> Vec<DepProblem> {
+    let local_modules: BTreeMap<&str, &BpModule> =
+        modules.iter().map(|module| (module.props.get_string("name"), module)).collect();
+    let mut problems = Vec::new();
+    for module in modules {
+        let module_name = module.props.get_string("name");
+        let device = !module.module_type.ends_with("_host")
+            && module.module_type != "rust_proc_macro"
+            && !is_false(module.props.map.get("device_supported"));
+        for (property, allowed_types) in DEP_PROPERTIES {
+            for dep in deps_in(&module.props, property) {
+                let (module_type, host_only) = if let Some(local) = local_modules.get(dep) {
+                    (local.module_type.as_str(), local.module_type.ends_with("_host"))
+                } else if let Some(tree_module) = tree_index.get(dep) {
+                    (tree_module.module_type.as_str(), tree_module.host_only)
+                } else {
+                    problems.push(DepProblem {
+                        module_name: module_name.to_string(),
+                        property: property.to_string(),
+                        dep: dep.to_string(),
+                        kind: DepProblemKind::Missing {
+                            suggestions: suggest_renames(dep, tree_index),
+                        },
+                    });
+                    continue;
+                };
+                let kind = if !allowed_types.iter().any(|prefix| module_type.starts_with(prefix))
+                    || (property.ends_with("static_libs") && module_type.ends_with("_shared"))
+                    || (property == "shared_libs" && module_type.ends_with("_static"))
+                {
+                    DepProblemKind::WrongType { module_type: module_type.to_string() }
+                } else if device && host_only && property != "proc_macros" {
+                    DepProblemKind::HostOnly
+                } else {
+                    continue;
+                };
+                problems.push(DepProblem {
+                    module_name: module_name.to_string(),
+                    property: property.to_string(),
+                    dep: dep.to_string(),
+                    kind,
+                });
+            }
+        }
+    }
+    problems
+}
+
+fn is_false(value: Option<&BpValue>) -> bool {
+    matches!(value, Some(BpValue::Bool(false)))
+}
+
+/// Returns the dependencies listed in the given property, including in nested `target` and `arch`
+/// blocks.
+fn deps_in<'a>(props: &'a BpProperties, property: &str) -> Vec<&'a str> {
+    let mut deps = Vec::new();
+    for (key, value) in &props.map {
+        match value {
+            BpValue::List(values) if key == property => {
+                deps.extend(values.iter().filter_map(|value| match value {
+                    BpValue::String(dep) => Some(dep.as_str()),
+                    _ => None,
+                }));
+            }
+            BpValue::Object(nested) => deps.extend(deps_in(nested, property)),
+            _ => {}
+        }
+    }
+    deps
+}
+
+/// Returns names of modules in the tree which the given missing module might have been renamed to,
+/// based on the rename registry and its `_rust` suffix convention, and on versioned module names.
+fn suggest_renames(dep: &str, tree_index: &TreeIndex) -> Vec<String> {
+    let mut candidates = vec![format!("{dep}_rust")];
+    for (original, renamed) in rename_registry().rename_map.iter() {
+        if *original == dep {
+            candidates.push(renamed.to_string());
+        } else if *renamed == dep {
+            candidates.push(original.to_string());
+        }
+    }
+    candidates.extend(tree_index.versions_of(dep).into_iter().map(str::to_string));
+    candidates.retain(|candidate| tree_index.get(candidate).is_some());
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn module(module_type: &str, name: &str, rustlibs: &[&str], proc_macros: &[&str]) -> BpModule {
+        let mut module = BpModule::new(module_type.to_string());
+        module.props.set("name", name);
+        module.props.set_if_nonempty("rustlibs", rustlibs.to_vec());
+        module.props.set_if_nonempty("proc_macros", proc_macros.to_vec());
+        module
+    }
+
+    #[test]
+    fn check() {
+        let mut tree_index = TreeIndex::default();
+        tree_index.add_blueprint(
+            Path::new("external/rust/crates"),
+            "rust_library {\n    name: \"libbar\",\n}\n\n\
+             rust_proc_macro {\n    name: \"libbaz\",\n}\n\n\
+             rust_library_host {\n    name: \"libhost\",\n}\n\n\
+             rust_library {\n    name: \"liblog_rust\",\n}\n",
+        );
+        let modules = [
+            module("rust_library", "libfoo", &["libbar", "libbaz", "libhost", "liblog"], &[]),
+            module("rust_test", "foo_test", &["libfoo"], &["libbaz"]),
+        ];
+
+        assert_eq!(
+            check_deps(&modules, &tree_index)
+                .into_iter()
+                .map(|problem| problem.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "libfoo: rustlibs entry \"libbaz\" is a rust_proc_macro, which can't be used in \
+                 rustlibs",
+                "libfoo: rustlibs entry \"libhost\" is host-only, but the module is for device",
+                "libfoo: rustlibs entry \"liblog\" doesn't exist in the tree, consider a \
+                 module_name_overrides entry to use liblog_rust",
+            ]
+        );
+    }
+}
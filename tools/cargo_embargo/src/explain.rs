@@ -0,0 +1,482 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Explanations of where the property values of generated modules came from, for
+//! `cargo_embargo explain`.
+//!
+//! These are derived from the finished modules, along with the origins recorded on the crates they
+//! were generated from and the config, so that they always match what is actually generated.
+
+use crate::bp::{BpModule, BpProperties, BpValue};
+use crate::cargo::{Crate, CrateType};
+use crate::config::{PackageVariantConfig, VariantConfig};
+use crate::extern_library_name;
+use crate::rename_registry::rename_registry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// What a generated module was generated from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ModuleSource<'a> {
+    /// A target of the given crates, of the given type. There is more than one crate if modules
+    /// from several rustc invocations were merged.
+    Crates(CrateType, Vec<&'a Crate>),
+    /// The given config option, e.g. the genrule generated for `bindgen`.
+    ConfigOption(&'static str),
+}
+
+/// Where a single value of a module property came from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Explanation {
+    /// The name of the module property, e.g. "rustlibs".
+    pub property: String,
+    /// The value, or the element of the value if it is a list. If the value was filtered out then
+    /// this is the value which would otherwise have been used.
+    pub value: String,
+    /// Where the value came from, e.g. a rustc flag, a Cargo.toml dependency or a config option.
+    pub origin: String,
+    /// What renamed or filtered out the value, if anything.
+    pub fate: Option<String>,
+}
+
+/// Explanations for all the properties of a module.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Explanations(Vec<Explanation>);
+
+impl Explanations {
+    /// Records where the given value of the given property came from, and what renamed or filtered
+    /// it out, if anything.
+    pub fn add(
+        &mut self,
+        property: &str,
+        value: impl Into<String>,
+        origin: impl Into<String>,
+        fate: Option<String>,
+    ) {
+        self.0.push(Explanation {
+            property: property.to_string(),
+            value: value.into(),
+            origin: origin.into(),
+            fate,
+        });
+    }
+
+    /// Returns the explanations for the given property, or all properties if `None`. Properties
+    /// within objects such as `test_options` match either their own name or the name of the
+    /// object.
+    pub fn get<'a>(&'a self, property: Option<&'a str>) -> impl Iterator<Item = &'a Explanation> {
+        self.0.iter().filter(move |explanation| {
+            let Some(property) = property else {
+                return true;
+            };
+            let path = explanation.property.as_str();
+            path == property
+                || path.ends_with(&format!(".{property}"))
+                || path.starts_with(&format!("{property}."))
+        })
+    }
+
+    /// Returns a human-readable description of the explanations for the given property, or all
+    /// properties if `None`, grouped by property.
+    pub fn describe(&self, property: Option<&str>) -> String {
+        let mut by_property: BTreeMap<&str, Vec<&Explanation>> = BTreeMap::new();
+        for explanation in self.get(property) {
+            by_property.entry(&explanation.property).or_default().push(explanation);
+        }
+        let mut description = String::new();
+        for (property, explanations) in by_property {
+            writeln!(description, "{property}:").unwrap();
+            for explanation in explanations {
+                writeln!(description, "  {:?}", explanation.value).unwrap();
+                writeln!(description, "    from {}", explanation.origin).unwrap();
+                if let Some(fate) = &explanation.fate {
+                    writeln!(description, "    {fate}").unwrap();
+                }
+            }
+        }
+        description
+    }
+}
+
+/// Explains where the values of the properties of the given generated module came from.
+///
+/// `source` is what the module was generated from, and `option_modules` maps the names of the
+/// modules generated for config options in the same package to the options, for references to
+/// them.
+pub fn explain_module(
+    module: &BpModule,
+    source: &ModuleSource,
+    option_modules: &BTreeMap<String, &'static str>,
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+) -> Explanations {
+    let mut explainer = Explainer {
+        source,
+        option_modules,
+        cfg,
+        package_cfg,
+        explanations: Explanations::default(),
+    };
+    explainer.explain_properties("", &module.props);
+    explainer.explain_removed();
+    explainer.explanations
+}
+
+/// State for explaining a single module.
+struct Explainer<'a> {
+    source: &'a ModuleSource<'a>,
+    option_modules: &'a BTreeMap<String, &'static str>,
+    cfg: &'a VariantConfig,
+    package_cfg: &'a PackageVariantConfig,
+    explanations: Explanations,
+}
+
+impl Explainer<'_> {
+    /// Explains each value of the given properties, and of any objects within them. `prefix` is
+    /// the path of the object containing them, e.g. "test_options.".
+    fn explain_properties(&mut self, prefix: &str, props: &BpProperties) {
+        for (property, value) in &props.map {
+            let path = format!("{prefix}{property}");
+            match value {
+                BpValue::Object(object) => self.explain_properties(&format!("{path}."), object),
+                BpValue::List(values) => {
+                    for value in values {
+                        self.explain_value(property, &path, value);
+                    }
+                }
+                _ => self.explain_value(property, &path, value),
+            }
+        }
+    }
+
+    /// Explains a single value of a property, or element of a list property.
+    fn explain_value(&mut self, property: &str, path: &str, value: &BpValue) {
+        let text = value_text(value);
+        let (origin, fate) = self.origin(property, &text, value);
+        self.explanations.add(path, text, origin, fate);
+    }
+
+    /// Returns where the given value of the given property came from, and what renamed it, if
+    /// anything.
+    fn origin(&self, property: &str, text: &str, value: &BpValue) -> (String, Option<String>) {
+        let (crate_type, crates) = match self.source {
+            ModuleSource::ConfigOption(option) => {
+                let fate = if property == "name" { self.renamed_from(text).1 } else { None };
+                return (format!("the module generated for the `{option}` config option"), fate);
+            }
+            ModuleSource::Crates(crate_type, crates) => (crate_type, crates),
+        };
+        let Some(first_crate) = crates.first() else {
+            return ("unknown".to_string(), None);
+        };
+        let test_cfg = self
+            .package_cfg
+            .test_targets
+            .get(first_crate.main_src.to_string_lossy().as_ref())
+            .cloned()
+            .unwrap_or_default();
+        let origin = match property {
+            "name" => {
+                let mut origin = format!(
+                    "the {crate_type:?} target {} of {}",
+                    first_crate.name, first_crate.package_name
+                );
+                if crate_type.is_library() && self.cfg.versioned_crates.contains(&first_crate.name)
+                {
+                    origin += ", with a version suffix because it is in `versioned_crates`";
+                }
+                return (origin, self.renamed_from(text).1);
+            }
+            "rustlibs" | "proc_macros" | "static_libs" | "whole_static_libs" | "shared_libs" => {
+                let (original, fate) = self.renamed_from(text);
+                let origin = self
+                    .dependency_origin(property, &original)
+                    .unwrap_or_else(|| unrecorded_origin("dependencies", first_crate));
+                return (origin, fate);
+            }
+            "defaults" => config_option("global_defaults"),
+            "host_supported" => "the `host_supported` and `device_supported` config options".into(),
+            "compile_multilib" => config_option("host_first_multilib"),
+            "include_dirs" => config_option("exported_c_header_dir"),
+            "generated_headers" | "export_generated_headers" => {
+                "the header generated with cbindgen for the `cbindgen` config option or \
+                 cbindgen.toml"
+                    .into()
+            }
+            "crate_name" => format!(
+                "the name of the {crate_type:?} target in the Cargo.toml of {}",
+                first_crate.package_name
+            ),
+            "cargo_pkg_version" => {
+                format!("the version of {} in cargo metadata", first_crate.package_name)
+            }
+            "crate_root" => {
+                format!("the source path of the {crate_type:?} target in cargo metadata")
+            }
+            "srcs" => match text.strip_prefix(':').and_then(|name| self.option_modules.get(name)) {
+                Some(option) => format!("the module generated for the `{option}` config option"),
+                None => "unknown".into(),
+            },
+            "test_suites" if test_cfg.test_suites.is_empty() => "the default test suite".into(),
+            "test_suites" => "the `test_suites` option in `test_targets`".into(),
+            "unit_test" if test_cfg.unit_test.is_some() => {
+                "the `unit_test` option in `test_targets`".into()
+            }
+            "unit_test" => config_option("no_presubmit"),
+            "test_runner_options" => match value {
+                BpValue::Object(option) if option.get_string("name") == "test-timeout" => {
+                    "the `timeout` option in `test_targets`".into()
+                }
+                _ => "the `exclude_tests` option in `test_targets`, with globs matched against \
+                      the tests listed by `cargo test -- --list`"
+                    .into(),
+            },
+            "edition" | "features" | "cfgs" => crate_origin(crates, property, text)
+                .unwrap_or_else(|| unrecorded_origin(property, first_crate)),
+            "flags" => match text.strip_prefix("-C ") {
+                Some(codegen) => crate_origin(crates, "codegens", codegen),
+                None => crate_origin(crates, "cap_lints", text),
+            }
+            .unwrap_or_else(|| unrecorded_origin("rustc flags", first_crate)),
+            "aliases" => match text.split_once(':') {
+                Some((_, name)) => crate_origin(crates, "externs", name),
+                None => None,
+            }
+            .unwrap_or_else(|| unrecorded_origin("dependencies", first_crate)),
+            "native_bridge_supported"
+            | "product_available"
+            | "ramdisk_available"
+            | "recovery_available"
+            | "vendor_available"
+            | "vendor_ramdisk_available"
+            | "apex_available"
+            | "min_sdk_version" => config_option(property),
+            "visibility" => config_option("module_visibility"),
+            "data" => config_option("test_data"),
+            "corpus" => "the cargo-fuzz corpus of the fuzz target".into(),
+            "dictionary" => "the dictionary of the fuzz target in the cargo-fuzz package".into(),
+            "prefer_rlib" | "no_stdlibs" => config_option("no_std"),
+            "stdlibs" if text == "liballoc.rust_sysroot" => {
+                "the `no_std` and `alloc` config options".into()
+            }
+            "stdlibs" => config_option("no_std"),
+            _ => format!("the defaults for {crate_type:?} targets"),
+        };
+        (origin, None)
+    }
+
+    /// Returns where the dependency on the given library module, before renaming, came from, if
+    /// it was recorded.
+    fn dependency_origin(&self, property: &str, module_name: &str) -> Option<String> {
+        let ModuleSource::Crates(_, crates) = self.source else {
+            return None;
+        };
+        let lib = module_name.strip_prefix("lib")?;
+        for crate_ in crates {
+            let origin = match property {
+                "rustlibs" | "proc_macros" => crate_
+                    .externs
+                    .iter()
+                    .find(|extern_dep| extern_library_name(extern_dep) == lib)
+                    .and_then(|extern_dep| {
+                        let mut origin =
+                            crate_.origins.get("externs", &extern_dep.name)?.to_string();
+                        if extern_dep.version.is_some() {
+                            origin += &format!(
+                                ", with a version suffix because {} is in `versioned_crates`",
+                                extern_dep.lib_name
+                            );
+                        }
+                        Some(origin)
+                    }),
+                "static_libs" | "whole_static_libs" => crate_
+                    .static_libs
+                    .iter()
+                    .find(|static_lib| *static_lib == lib)
+                    .and_then(|static_lib| crate_.origins.get("static_libs", static_lib))
+                    .map(str::to_string),
+                _ => crate_
+                    .shared_libs
+                    .iter()
+                    .find(|shared_lib| *shared_lib == lib)
+                    .and_then(|shared_lib| crate_.origins.get("shared_libs", shared_lib))
+                    .map(str::to_string),
+            };
+            if origin.is_some() {
+                return origin;
+            }
+        }
+        None
+    }
+
+    /// Returns the name which the given module name was renamed from by `module_name_overrides`,
+    /// the rename registry or `module_suffix`, and a description of what renamed it, if anything.
+    fn renamed_from(&self, name: &str) -> (String, Option<String>) {
+        let mut fates = Vec::new();
+        let mut unsuffixed = name;
+        if let Some(suffix) = &self.cfg.module_suffix {
+            if let Some(stripped) = name.strip_suffix(suffix.as_str()) {
+                unsuffixed = stripped;
+                fates.push(format!("suffixed with {suffix:?} by `module_suffix`"));
+            }
+        }
+        let overrides = &self.cfg.module_name_overrides;
+        let renamed = |rename_map: &BTreeMap<String, String>, is_override: bool| {
+            rename_map
+                .iter()
+                .filter(|(name, _)| is_override || !overrides.contains_key(*name))
+                .find(|(name, renamed)| *renamed == unsuffixed && name != renamed)
+                .map(|(name, _)| name.clone())
+        };
+        let original = if let Some(original) = renamed(overrides, true) {
+            fates.insert(0, format!("renamed from {original} by `module_name_overrides`"));
+            original
+        } else if let Some(original) = renamed(&rename_registry().rename_map, false) {
+            fates.insert(0, format!("renamed from {original} by the rename registry"));
+            original
+        } else {
+            unsuffixed.to_string()
+        };
+        (original, (!fates.is_empty()).then(|| fates.join(", ")))
+    }
+
+    /// Explains the values which would have been in the module, but were removed by the config.
+    fn explain_removed(&mut self) {
+        let ModuleSource::Crates(_, crates) = self.source else {
+            return;
+        };
+        let mut removed = BTreeSet::new();
+        for crate_ in crates {
+            for crate_cfg in &crate_.cfgs {
+                if self.cfg.cfg_blocklist.contains(crate_cfg) {
+                    removed.insert((
+                        "cfgs",
+                        crate_cfg.clone(),
+                        crate_
+                            .origins
+                            .get("cfgs", crate_cfg)
+                            .map_or_else(|| unrecorded_origin("cfgs", crate_), str::to_string),
+                        "cfg_blocklist",
+                    ));
+                }
+            }
+            let mut deps: Vec<(&str, String, String)> = crate_
+                .externs
+                .iter()
+                .map(|extern_dep| {
+                    let property = match extern_dep.extern_type {
+                        crate::cargo::ExternType::Rust => "rustlibs",
+                        crate::cargo::ExternType::ProcMacro => "proc_macros",
+                    };
+                    let origin = crate_
+                        .origins
+                        .get("externs", &extern_dep.name)
+                        .map_or_else(|| unrecorded_origin("dependencies", crate_), str::to_string);
+                    (property, extern_library_name(extern_dep), origin)
+                })
+                .collect();
+            for (property, libs) in
+                [("static_libs", &crate_.static_libs), ("shared_libs", &crate_.shared_libs)]
+            {
+                deps.extend(libs.iter().map(|lib| {
+                    let origin = crate_
+                        .origins
+                        .get(property, lib)
+                        .map_or_else(|| unrecorded_origin("dependencies", crate_), str::to_string);
+                    (property, lib.clone(), origin)
+                }));
+            }
+            for (property, lib, origin) in deps {
+                let module_name = format!("lib{lib}");
+                if self.package_cfg.dep_blocklist.contains(&module_name) {
+                    removed.insert((property, module_name, origin, "dep_blocklist"));
+                }
+            }
+        }
+        for (property, value, origin, option) in removed {
+            self.explanations.add(property, value, origin, Some(format!("removed by `{option}`")));
+        }
+    }
+}
+
+/// Returns the origin of the given value of the given field of any of the crates, if it was
+/// recorded.
+fn crate_origin(crates: &[&Crate], field: &str, value: &str) -> Option<String> {
+    crates.iter().find_map(|crate_| crate_.origins.get(field, value)).map(str::to_string)
+}
+
+/// Describes the origin of a value of the given crate which has no recorded origin, such as one
+/// from a `crates.json` file.
+fn unrecorded_origin(what: &str, crate_: &Crate) -> String {
+    format!("the {what} of {}, with no recorded origin", crate_.name)
+}
+
+/// Returns the origin of a value which comes from the given config option.
+fn config_option(option: &str) -> String {
+    format!("the `{option}` config option")
+}
+
+/// Returns a single line describing the given value.
+fn value_text(value: &BpValue) -> String {
+    match value {
+        BpValue::Bool(b) => b.to_string(),
+        BpValue::String(s) => s.clone(),
+        BpValue::List(values) => values.iter().map(value_text).collect::<Vec<_>>().join(", "),
+        BpValue::Object(props) => props
+            .map
+            .iter()
+            .map(|(key, value)| format!("{key}: {}", value_text(value)))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe() {
+        let mut explanations = Explanations::default();
+        explanations.add(
+            "rustlibs",
+            "libbar",
+            "dependency on bar ^1 in the Cargo.toml of foo",
+            None,
+        );
+        explanations.add("cfgs", "baz", "`--cfg baz` in the rustc invocation", None);
+        explanations.add(
+            "rustlibs",
+            "libqux",
+            "dependency on qux ^2 in the Cargo.toml of foo",
+            Some("removed by `dep_blocklist`".to_string()),
+        );
+        assert_eq!(
+            explanations.describe(None),
+            "\
+cfgs:
+  \"baz\"
+    from `--cfg baz` in the rustc invocation
+rustlibs:
+  \"libbar\"
+    from dependency on bar ^1 in the Cargo.toml of foo
+  \"libqux\"
+    from dependency on qux ^2 in the Cargo.toml of foo
+    removed by `dep_blocklist`
+"
+        );
+        assert_eq!(explanations.get(Some("cfgs")).count(), 1);
+        assert_eq!(explanations.describe(Some("flags")), "");
+    }
+}
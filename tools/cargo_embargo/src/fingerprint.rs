@@ -0,0 +1,100 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stable content hashing, used to detect when the inputs to cargo_embargo have changed.
+//!
+//! This uses 128-bit FNV-1a rather than `std::hash::DefaultHasher`, because the latter is not
+//! guaranteed to give the same results between Rust releases, and the hashes may be stored on disk
+//! and compared by a different build of cargo_embargo.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Incrementally computes a hash of some data.
+#[derive(Clone, Debug)]
+pub struct Fingerprint {
+    state: u128,
+}
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Self { state: FNV_OFFSET_BASIS }
+    }
+}
+
+impl Fingerprint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the given bytes to the hash.
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= u128::from(*byte);
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// Adds the given string to the hash, followed by a terminator so that adjacent strings can't
+    /// run together.
+    pub fn update_str(&mut self, s: &str) {
+        self.update(s.as_bytes());
+        self.update(&[0]);
+    }
+
+    /// Adds the name and contents of the given file to the hash. A missing file is hashed
+    /// differently to an empty one.
+    pub fn update_file(&mut self, path: &Path) -> Result<()> {
+        self.update_str(&path.to_string_lossy());
+        match std::fs::read(path) {
+            Ok(contents) => {
+                self.update_str("present");
+                self.update(&(contents.len() as u64).to_le_bytes());
+                self.update(&contents);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.update_str("missing"),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
+        }
+        Ok(())
+    }
+
+    /// Returns the hash as a hex string.
+    pub fn finish(&self) -> String {
+        format!("{:032x}", self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_hash_is_offset_basis() {
+        assert_eq!(Fingerprint::new().finish(), "6c62272e07bb014262b821756295c58d");
+    }
+
+    #[test]
+    fn strings_are_separated() {
+        let mut a = Fingerprint::new();
+        a.update_str("ab");
+        a.update_str("c");
+        let mut b = Fingerprint::new();
+        b.update_str("a");
+        b.update_str("bc");
+        assert_ne!(a.finish(), b.finish());
+    }
+}
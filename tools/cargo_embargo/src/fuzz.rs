@@ -0,0 +1,290 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Discovery of the cargo-fuzz targets of a package, for `rust_fuzz` modules.
+
+use crate::cargo::metadata::{parse_cargo_metadata_str, WorkspaceMetadata};
+use crate::cargo::{Crate, CrateType};
+use crate::cargo_profile::CargoProfile;
+use crate::config::VariantConfig;
+use crate::run_cargo;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::read_dir;
+use std::path::Path;
+
+/// The directory of the cargo-fuzz package, relative to the package it fuzzes.
+const FUZZ_DIR: &str = "fuzz";
+
+/// Directories in which cargo-fuzz packages commonly keep the dictionary for each fuzz target,
+/// relative to the cargo-fuzz package.
+const DICTIONARY_DIRS: [&str; 4] = ["", "dict", "dicts", "fuzz_targets"];
+
+/// Runs `cargo metadata` with the given profile for the cargo-fuzz package of each package in the
+/// given `cargo metadata` output which has `fuzzers` set, and returns the output for each, keyed by
+/// the name of the package which it fuzzes.
+pub fn fuzz_metadata(
+    cfg: &VariantConfig,
+    profile: &CargoProfile,
+    cargo_metadata: &str,
+) -> Result<BTreeMap<String, String>> {
+    let metadata: WorkspaceMetadata =
+        serde_json::from_str(cargo_metadata).context("failed to parse cargo metadata")?;
+    let mut fuzz_metadata = BTreeMap::new();
+    for package in metadata.packages {
+        if !cfg.package.get(&package.name).is_some_and(|package_cfg| package_cfg.fuzzers) {
+            continue;
+        }
+        let fuzz_dir = Path::new(&package.manifest_path).parent().unwrap().join(FUZZ_DIR);
+        let manifest_path = fuzz_dir.join("Cargo.toml");
+        if !manifest_path.try_exists()? {
+            bail!("fuzzers is set for {} but {manifest_path:?} doesn't exist", package.name);
+        }
+        // As for the package itself, cargo can't create a lockfile in the read-only source tree.
+        if profile.is_sandboxed() && !fuzz_dir.join("Cargo.lock").exists() {
+            run_cargo(
+                profile
+                    .unsandboxed_command()
+                    .arg("generate-lockfile")
+                    .arg("--manifest-path")
+                    .arg(&manifest_path),
+            )
+            .with_context(|| format!("Running cargo generate-lockfile for {manifest_path:?}"))?;
+        }
+        // The cargo-fuzz package is usually excluded from the workspace, so needs its own metadata.
+        let output = run_cargo(
+            profile
+                .command()?
+                .args(["metadata", "-q", "--format-version", "1", "--manifest-path"])
+                .arg(&manifest_path),
+        )
+        .with_context(|| format!("Running cargo metadata for {manifest_path:?}"))?;
+        fuzz_metadata.insert(package.name, output);
+    }
+    Ok(fuzz_metadata)
+}
+
+/// Returns crates for the fuzz targets of the packages of the given crates which have `fuzzers`
+/// set, as part of the package which they fuzz, from the output of `fuzz_metadata`.
+pub fn fuzz_crates(
+    cfg: &VariantConfig,
+    crates: &[Crate],
+    fuzz_metadata: &BTreeMap<String, String>,
+) -> Result<Vec<Crate>> {
+    let packages: BTreeMap<&str, &Path> = crates
+        .iter()
+        .filter(|crate_| {
+            cfg.package.get(&crate_.package_name).is_some_and(|package_cfg| package_cfg.fuzzers)
+        })
+        .map(|crate_| (crate_.package_name.as_str(), crate_.package_dir.as_path()))
+        .collect();
+    let mut fuzz_crates = Vec::new();
+    for (package_name, package_dir) in packages {
+        let Some(cargo_metadata) = fuzz_metadata.get(package_name) else {
+            bail!("fuzzers is set for {package_name} but its cargo-fuzz package has no metadata");
+        };
+        fuzz_crates.extend(fuzz_targets(cfg, package_name, package_dir, cargo_metadata)?);
+    }
+    Ok(fuzz_crates)
+}
+
+/// Parses the given `cargo metadata` output for the cargo-fuzz package of the given package, and
+/// returns a crate for each of its fuzz targets.
+fn fuzz_targets(
+    cfg: &VariantConfig,
+    package_name: &str,
+    package_dir: &Path,
+    cargo_metadata: &str,
+) -> Result<Vec<Crate>> {
+    // The variant's features are for the package being fuzzed, not the cargo-fuzz package.
+    let fuzz_cfg = VariantConfig { features: None, tests: false, ..cfg.clone() };
+    let mut crates = Vec::new();
+    for mut crate_ in parse_cargo_metadata_str(cargo_metadata, &fuzz_cfg, &BTreeMap::new())? {
+        if crate_.types != [CrateType::Bin] {
+            continue;
+        }
+        let Ok(fuzz_dir) = crate_.package_dir.strip_prefix(package_dir) else {
+            bail!("{:?} is not within {package_dir:?}", crate_.package_dir);
+        };
+        crate_.main_src = fuzz_dir.join(&crate_.main_src);
+        crate_.package_name = package_name.to_string();
+        crate_.package_dir = package_dir.to_path_buf();
+        crate_.types = vec![CrateType::Fuzz];
+        // Soong adds libfuzzer-sys to every `rust_fuzz` module.
+        crate_.externs.retain(|extern_dep| extern_dep.lib_name != "libfuzzer_sys");
+        crates.push(crate_);
+    }
+    Ok(crates)
+}
+
+/// The corpus and dictionary of a fuzz target, relative to the package directory.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FuzzData {
+    /// A glob for the files of the corpus, if it has any.
+    pub corpus: Option<String>,
+    /// The dictionary, if there is one.
+    pub dictionary: Option<String>,
+}
+
+/// Finds the corpus and dictionary of the given fuzz target in the places where cargo-fuzz
+/// packages keep them, named after the target's source file.
+pub fn fuzz_data(crate_: &Crate) -> Result<FuzzData> {
+    let mut data = FuzzData::default();
+    let Some(target) = crate_.main_src.file_stem().and_then(|stem| stem.to_str()) else {
+        return Ok(data);
+    };
+    let corpus_dir = Path::new(FUZZ_DIR).join("corpus").join(target);
+    let corpus_path = crate_.package_dir.join(&corpus_dir);
+    if corpus_path.is_dir()
+        && read_dir(&corpus_path)
+            .with_context(|| format!("failed to read {corpus_path:?}"))?
+            .next()
+            .is_some()
+    {
+        data.corpus = Some(corpus_dir.join("*").to_string_lossy().into_owned());
+    }
+    data.dictionary = DICTIONARY_DIRS
+        .iter()
+        .map(|dir| Path::new(FUZZ_DIR).join(dir).join(format!("{target}.dict")))
+        .find(|dictionary| crate_.package_dir.join(dictionary).is_file())
+        .map(|dictionary| dictionary.to_string_lossy().into_owned());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::{Extern, ExternType};
+    use std::fs::{create_dir_all, write};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn targets_from_metadata() -> Result<()> {
+        fn package(name: &str, dir: &str, targets: serde_json::Value) -> serde_json::Value {
+            serde_json::json!({
+                "name": name,
+                "version": "0.1.0",
+                "edition": "2021",
+                "manifest_path": format!("{dir}/Cargo.toml"),
+                "dependencies": [],
+                "features": {},
+                "id": format!("path+file://{dir}#0.1.0"),
+                "targets": targets,
+            })
+        }
+        fn target(kind: &str, name: &str, src_path: &str) -> serde_json::Value {
+            serde_json::json!({
+                "crate_types": [kind],
+                "doc": false,
+                "doctest": false,
+                "edition": "2021",
+                "kind": [kind],
+                "name": name,
+                "src_path": src_path,
+                "test": false,
+            })
+        }
+        let mut fuzz = package(
+            "foo-fuzz",
+            "/ws/foo/fuzz",
+            serde_json::json!([
+                target("bin", "parse", "/ws/foo/fuzz/fuzz_targets/parse.rs"),
+                target("bin", "round-trip", "/ws/foo/fuzz/fuzz_targets/round_trip.rs"),
+            ]),
+        );
+        fuzz["dependencies"] = serde_json::json!([
+            { "name": "foo", "kind": null, "optional": false, "req": "*" },
+            { "name": "libfuzzer-sys", "kind": null, "optional": false, "req": "0.4" },
+        ]);
+        let cargo_metadata = serde_json::json!({
+            "packages": [
+                fuzz,
+                package(
+                    "foo",
+                    "/ws/foo",
+                    serde_json::json!([target("lib", "foo", "/ws/foo/src/lib.rs")]),
+                ),
+                package(
+                    "libfuzzer-sys",
+                    "/registry/libfuzzer-sys",
+                    serde_json::json!([target(
+                        "lib",
+                        "libfuzzer-sys",
+                        "/registry/libfuzzer-sys/src/lib.rs"
+                    )]),
+                ),
+            ],
+            "workspace_members": ["path+file:///ws/foo/fuzz#0.1.0"],
+        })
+        .to_string();
+        let cfg = VariantConfig { features: Some(vec!["std".to_string()]), ..Default::default() };
+
+        let crates = fuzz_targets(&cfg, "foo", Path::new("/ws/foo"), &cargo_metadata)?;
+        assert_eq!(
+            crates
+                .iter()
+                .map(|crate_| (crate_.name.as_str(), &crate_.main_src))
+                .collect::<Vec<_>>(),
+            vec![
+                ("parse", &PathBuf::from("fuzz/fuzz_targets/parse.rs")),
+                ("round_trip", &PathBuf::from("fuzz/fuzz_targets/round_trip.rs")),
+            ]
+        );
+        for crate_ in &crates {
+            assert_eq!(crate_.package_name, "foo");
+            assert_eq!(crate_.package_dir, PathBuf::from("/ws/foo"));
+            assert_eq!(crate_.types, vec![CrateType::Fuzz]);
+            assert_eq!(crate_.features, Vec::<String>::new());
+            assert_eq!(
+                crate_.externs,
+                vec![Extern {
+                    name: "foo".to_string(),
+                    lib_name: "foo".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: None,
+                }]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn find_fuzz_data() -> Result<()> {
+        let package_dir = tempdir()?;
+        let fuzz_dir = package_dir.path().join(FUZZ_DIR);
+        create_dir_all(fuzz_dir.join("corpus/parse"))?;
+        create_dir_all(fuzz_dir.join("corpus/decode"))?;
+        create_dir_all(fuzz_dir.join("dict"))?;
+        write(fuzz_dir.join("corpus/parse/seed"), "1 + 2")?;
+        write(fuzz_dir.join("dict/parse.dict"), "\"+\"\n")?;
+        let crate_ = |main_src: &str| Crate {
+            types: vec![CrateType::Fuzz],
+            package_dir: package_dir.path().to_path_buf(),
+            main_src: main_src.into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            fuzz_data(&crate_("fuzz/fuzz_targets/parse.rs"))?,
+            FuzzData {
+                corpus: Some("fuzz/corpus/parse/*".to_string()),
+                dictionary: Some("fuzz/dict/parse.dict".to_string()),
+            }
+        );
+        // An empty corpus directory is ignored.
+        assert_eq!(fuzz_data(&crate_("fuzz/fuzz_targets/decode.rs"))?, FuzzData::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversion of legacy `cargo2android.json` and `cargo2rulesmk.json` configs, as used by
+//! `cargo2android.py` and `cargo2rulesmk.py`, to a cargo_embargo `Config`.
+//!
+//! The legacy configs are the command-line options of those scripts, written as a JSON object with
+//! the option names as keys.
+
+use crate::config::{Config, PackageConfig, PackageVariantConfig, VariantConfig};
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Legacy options which don't affect the generated files, or which cargo_embargo always behaves as
+/// if they were set.
+const IGNORED_OPTIONS: [&str; 8] = [
+    "cargo-bin",
+    "debug",
+    "dependencies",
+    "ignore-cargo-errors",
+    "run",
+    "skipcargo",
+    "verbose",
+    "vv",
+];
+
+/// Which legacy script a config was for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LegacyFormat {
+    /// `cargo2android.json`, for generating `Android.bp` files.
+    Cargo2Android,
+    /// `cargo2rulesmk.json`, for generating `rules.mk` files.
+    Cargo2Rulesmk,
+}
+
+impl LegacyFormat {
+    /// Returns the filename of legacy configs in this format.
+    pub fn filename(self) -> &'static str {
+        match self {
+            Self::Cargo2Android => "cargo2android.json",
+            Self::Cargo2Rulesmk => "cargo2rulesmk.json",
+        }
+    }
+}
+
+/// Reads the legacy configs in the given package directory, and converts them to a config with a
+/// variant for each.
+///
+/// Returns the config and warnings about options which couldn't be converted.
+pub fn import_legacy_configs(package_dir: &Path) -> Result<(Config, Vec<String>)> {
+    let package_name = package_name(package_dir)?;
+    let mut config =
+        Config { variants: Vec::new(), package: Default::default(), provenance: false };
+    let mut warnings = Vec::new();
+    for format in [LegacyFormat::Cargo2Android, LegacyFormat::Cargo2Rulesmk] {
+        let path = package_dir.join(format.filename());
+        if !path.try_exists()? {
+            continue;
+        }
+        let json = read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+        let legacy: Map<String, Value> =
+            serde_json::from_str(&json).with_context(|| format!("failed to parse {path:?}"))?;
+        let (variant, package_config) =
+            import_legacy_config(&legacy, format, &package_name, &mut warnings)
+                .with_context(|| format!("failed to import {path:?}"))?;
+        config.variants.push(variant);
+        let merged_package_config = config.package.entry(package_name.clone()).or_default();
+        if package_config.add_toplevel_block.is_some() {
+            merged_package_config.add_toplevel_block = package_config.add_toplevel_block;
+        }
+        if package_config.patch.is_some() {
+            merged_package_config.patch = package_config.patch;
+        }
+        if package_config.rulesmk_patch.is_some() {
+            merged_package_config.rulesmk_patch = package_config.rulesmk_patch;
+        }
+    }
+    if config.variants.is_empty() {
+        bail!(
+            "No {} or {} found in {package_dir:?}",
+            LegacyFormat::Cargo2Android.filename(),
+            LegacyFormat::Cargo2Rulesmk.filename()
+        );
+    }
+    config.package.retain(|_, package_config| *package_config != PackageConfig::default());
+    Ok((config, warnings))
+}
+
+/// Converts a single legacy config for the given package to a variant config and package config.
+///
+/// Adds a warning to `warnings` for each option which has no equivalent.
+pub fn import_legacy_config(
+    legacy: &Map<String, Value>,
+    format: LegacyFormat,
+    package_name: &str,
+    warnings: &mut Vec<String>,
+) -> Result<(VariantConfig, PackageConfig)> {
+    let mut variant = VariantConfig::default();
+    let mut package_variant = PackageVariantConfig::default();
+    let mut package = PackageConfig::default();
+    match format {
+        LegacyFormat::Cargo2Android => {
+            // The legacy script only generated device modules and these properties when asked to.
+            package_variant.device_supported = false;
+            variant.product_available = false;
+            variant.vendor_available = false;
+        }
+        LegacyFormat::Cargo2Rulesmk => {
+            variant.generate_androidbp = false;
+            variant.generate_rulesmk = true;
+        }
+    }
+
+    for (option, value) in legacy {
+        // The legacy scripts accept both hyphens and underscores in option names.
+        let option = option.replace('_', "-");
+        let option = option.as_str();
+        match option {
+            "add-module-block" => {
+                package_variant.add_module_block = Some(path_value(option, value)?)
+            }
+            "add-toplevel-block" => package.add_toplevel_block = Some(path_value(option, value)?),
+            "alloc" => package_variant.alloc = bool_value(option, value)?,
+            "apex-available" => variant.apex_available = strings_value(option, value)?,
+            "cfg-blocklist" => variant.cfg_blocklist = strings_value(option, value)?,
+            "copy-out" => package_variant.copy_out = bool_value(option, value)?,
+            "dependency-blocklist" => {
+                package_variant.dep_blocklist = strings_value(option, value)?
+                    .into_iter()
+                    .map(|dependency| format!("lib{dependency}"))
+                    .collect();
+            }
+            "device" => package_variant.device_supported = bool_value(option, value)?,
+            "exported-c-header-dir" => {
+                package_variant.exported_c_header_dir =
+                    strings_value(option, value)?.into_iter().map(PathBuf::from).collect();
+            }
+            "features" => {
+                // An empty string means no features, not even the default ones.
+                let features = string_value(option, value)?;
+                variant.features = Some(
+                    features
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|feature| !feature.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                );
+            }
+            "force-rlib" => package_variant.force_rlib = bool_value(option, value)?,
+            "global-defaults" => variant.global_defaults = Some(string_value(option, value)?),
+            "host-first-multilib" => {
+                package_variant.host_first_multilib = bool_value(option, value)?
+            }
+            "min-sdk-version" => variant.min_sdk_version = Some(string_value(option, value)?),
+            "native-bridge-supported" => {
+                variant.native_bridge_supported = bool_value(option, value)?
+            }
+            "no-host" => package_variant.host_supported = !bool_value(option, value)?,
+            "no-presubmit" => package_variant.no_presubmit = bool_value(option, value)?,
+            "no-std" => package_variant.no_std = bool_value(option, value)?,
+            "patch" => {
+                let patch = Some(path_value(option, value)?);
+                match format {
+                    LegacyFormat::Cargo2Android => package.patch = patch,
+                    LegacyFormat::Cargo2Rulesmk => package.rulesmk_patch = patch,
+                }
+            }
+            "product-available" => variant.product_available = bool_value(option, value)?,
+            "ramdisk-available" => variant.ramdisk_available = bool_value(option, value)?,
+            "recovery-available" => variant.recovery_available = bool_value(option, value)?,
+            "rustflags" => {
+                variant.rustflags =
+                    string_value(option, value)?.split_whitespace().map(str::to_string).collect();
+            }
+            "test-blocklist" => {
+                // Tests are blocked by source path in the legacy config, but by module name here.
+                variant.module_blocklist.extend(
+                    strings_value(option, value)?
+                        .into_iter()
+                        .map(|test_path| test_module_name(package_name, &test_path)),
+                );
+            }
+            "test-data" => {
+                for entry in strings_value(option, value)? {
+                    let Some((test_path, data_path)) = entry.split_once('=') else {
+                        bail!("invalid test-data entry {entry:?}, expected test-path=data-path");
+                    };
+                    package_variant
+                        .test_data
+                        .entry(test_path.to_string())
+                        .or_default()
+                        .push(data_path.to_string());
+                }
+            }
+            "tests" => variant.tests = bool_value(option, value)?,
+            "vendor-available" => variant.vendor_available = bool_value(option, value)?,
+            "vendor-ramdisk-available" => {
+                variant.vendor_ramdisk_available = bool_value(option, value)?
+            }
+            "workspace" => variant.workspace = bool_value(option, value)?,
+            _ if IGNORED_OPTIONS.contains(&option) => {}
+            _ => warnings.push(format!(
+                "{} option {option:?} ({value}) has no equivalent in cargo_embargo, so it was \
+                 ignored.",
+                format.filename()
+            )),
+        }
+    }
+
+    if package_variant != PackageVariantConfig::default() {
+        variant.package.insert(package_name.to_string(), package_variant);
+    }
+    Ok((variant, package))
+}
+
+/// Returns the name of the module which cargo_embargo generates for the test with the given source
+/// path.
+fn test_module_name(package_name: &str, test_path: &str) -> String {
+    format!("{package_name}_test_{}", test_path.replace('/', "_").replace(".rs", ""))
+}
+
+/// Returns the package name from the `Cargo.toml` in the given directory.
+fn package_name(package_dir: &Path) -> Result<String> {
+    static PACKAGE_NAME: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"^name\s*=\s*"([^"]+)""#).unwrap());
+
+    let manifest_path = package_dir.join("Cargo.toml");
+    let manifest = read_to_string(&manifest_path)
+        .with_context(|| format!("failed to read {manifest_path:?}"))?;
+    manifest
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "[package]")
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .find_map(|line| PACKAGE_NAME.captures(line))
+        .map(|captures| captures[1].to_string())
+        .with_context(|| format!("no package name found in {manifest_path:?}"))
+}
+
+fn bool_value(option: &str, value: &Value) -> Result<bool> {
+    value.as_bool().with_context(|| format!("{option} should be a boolean, was {value}"))
+}
+
+fn string_value(option: &str, value: &Value) -> Result<String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .with_context(|| format!("{option} should be a string, was {value}"))
+}
+
+fn strings_value(option: &str, value: &Value) -> Result<Vec<String>> {
+    match value {
+        // Some options were sometimes given a single value rather than a list.
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| {
+                value.as_str().map(str::to_string).with_context(|| {
+                    format!("{option} should be a list of strings, but contained {value}")
+                })
+            })
+            .collect(),
+        _ => bail!("{option} should be a list of strings, was {value}"),
+    }
+}
+
+fn path_value(option: &str, value: &Value) -> Result<PathBuf> {
+    string_value(option, value).map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(json: &str, format: LegacyFormat) -> (VariantConfig, PackageConfig, Vec<String>) {
+        let mut warnings = Vec::new();
+        let (variant, package) = import_legacy_config(
+            &serde_json::from_str(json).unwrap(),
+            format,
+            "foo",
+            &mut warnings,
+        )
+        .unwrap();
+        (variant, package, warnings)
+    }
+
+    #[test]
+    fn import_cargo2android() {
+        let (variant, package, warnings) = import(
+            r#"{
+                "apex-available": ["//apex_available:platform", "com.android.virt"],
+                "dependencies": true,
+                "dependency-blocklist": ["criterion"],
+                "device": true,
+                "features": "std,alloc",
+                "min-sdk-version": "29",
+                "no-subdir": true,
+                "patch": "patches/Android.bp.patch",
+                "run": true,
+                "test-blocklist": ["tests/slow.rs"],
+                "test-data": ["src/lib.rs=testdata/a.txt", "src/lib.rs=testdata/b.txt"],
+                "tests": true,
+                "vendor-available": true
+            }"#,
+            LegacyFormat::Cargo2Android,
+        );
+        assert_eq!(
+            variant,
+            VariantConfig {
+                apex_available: vec![
+                    "//apex_available:platform".to_string(),
+                    "com.android.virt".to_string()
+                ],
+                features: Some(vec!["std".to_string(), "alloc".to_string()]),
+                min_sdk_version: Some("29".to_string()),
+                module_blocklist: vec!["foo_test_tests_slow".to_string()],
+                product_available: false,
+                tests: true,
+                package: [(
+                    "foo".to_string(),
+                    PackageVariantConfig {
+                        dep_blocklist: vec!["libcriterion".to_string()],
+                        test_data: [(
+                            "src/lib.rs".to_string(),
+                            vec!["testdata/a.txt".to_string(), "testdata/b.txt".to_string()]
+                        )]
+                        .into(),
+                        ..Default::default()
+                    }
+                )]
+                .into(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            package,
+            PackageConfig { patch: Some("patches/Android.bp.patch".into()), ..Default::default() }
+        );
+        assert_eq!(
+            warnings,
+            vec![
+                "cargo2android.json option \"no-subdir\" (true) has no equivalent in \
+                 cargo_embargo, so it was ignored."
+            ]
+        );
+    }
+
+    #[test]
+    fn import_cargo2rulesmk() {
+        let (variant, package, warnings) = import(
+            r#"{"features": "", "patch": "patches/rules.mk.patch", "run": true}"#,
+            LegacyFormat::Cargo2Rulesmk,
+        );
+        assert_eq!(
+            variant,
+            VariantConfig {
+                features: Some(vec![]),
+                generate_androidbp: false,
+                generate_rulesmk: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            package,
+            PackageConfig {
+                rulesmk_patch: Some("patches/rules.mk.patch".into()),
+                ..Default::default()
+            }
+        );
+        assert!(warnings.is_empty());
+    }
+}
@@ -0,0 +1,621 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Infers a config from an existing hand-written `Android.bp`.
+//!
+//! Options which correspond directly to properties of the existing modules are derived from them,
+//! then a greedy search toggles other options while that reduces the difference between the
+//! generated modules and the existing ones.
+
+use crate::bp::{parse_blueprint, BpModule, BpProperties, BpValue};
+use crate::cargo::Crate;
+use crate::config::{PackageVariantConfig, VariantConfig};
+use crate::crate_to_bp_modules;
+use crate::rename_registry::rename_registry;
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Boolean package options which the search tries toggling.
+const PACKAGE_TOGGLES: [&str; 6] =
+    ["alloc", "device_supported", "force_rlib", "host_first_multilib", "host_supported", "no_std"];
+
+/// Boolean variant options which the search tries toggling.
+const VARIANT_TOGGLES: [&str; 6] = [
+    "native_bridge_supported",
+    "product_available",
+    "ramdisk_available",
+    "recovery_available",
+    "vendor_available",
+    "vendor_ramdisk_available",
+];
+
+/// The number of unchanged lines to show around each change in the residual diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// Returns the features of the existing library module for the given crates, if they are different
+/// to the features the crates were built with.
+pub fn existing_features(existing: &[BpModule], crates: &[Crate]) -> Option<Vec<String>> {
+    let library = crates.iter().find(|crate_| crate_.types.iter().any(|t| t.is_library()))?;
+    let module = rust_modules(existing).into_iter().find(|module| {
+        string_prop(&module.props, "crate_name") == Some(&library.name)
+            && !module.module_type.starts_with("rust_test")
+    })?;
+    let mut features = strings_prop(&module.props, "features");
+    features.sort();
+    let mut current = library.features.clone();
+    current.sort();
+    (features != current).then_some(features)
+}
+
+/// Sets options in `cfg` so that the modules generated for `crates` are as close as possible to
+/// the `existing` modules.
+pub fn infer_options(
+    existing: &[BpModule],
+    crates: &[Crate],
+    cfg: &mut VariantConfig,
+    notes: &mut Vec<String>,
+) -> Result<()> {
+    let package_name = package_name(crates)?;
+    derive_options(existing, crates, &package_name, cfg, notes);
+    derive_renames(existing, crates, &package_name, cfg, notes)?;
+
+    let mut best_distance = distance(existing, &generate_modules(cfg, crates)?);
+    loop {
+        let mut best_candidate = None;
+        let candidates = PACKAGE_TOGGLES
+            .iter()
+            .map(|option| (true, *option))
+            .chain(VARIANT_TOGGLES.iter().map(|option| (false, *option)));
+        for (package_option, option) in candidates {
+            let mut candidate = cfg.clone();
+            if package_option {
+                toggle_package_option(package_entry(&mut candidate, &package_name), option);
+            } else {
+                toggle_variant_option(&mut candidate, option);
+            }
+            // Some combinations of options aren't valid, so just skip them.
+            let Ok(modules) = generate_modules(&candidate, crates) else {
+                continue;
+            };
+            let candidate_distance = distance(existing, &modules);
+            if candidate_distance < best_distance {
+                best_distance = candidate_distance;
+                best_candidate = Some((candidate, option));
+            }
+        }
+        let Some((candidate, option)) = best_candidate else {
+            break;
+        };
+        notes.push(format!(
+            "Toggling {option} makes the generated modules closer to the existing ones."
+        ));
+        *cfg = candidate;
+    }
+    Ok(())
+}
+
+/// Returns properties which all the existing modules have but the generated ones don't, formatted
+/// for `add_module_block`, if there are any.
+pub fn residual_module_block(
+    existing: &[BpModule],
+    crates: &[Crate],
+    cfg: &VariantConfig,
+) -> Result<Option<String>> {
+    let generated = generate_modules(cfg, crates)?;
+    let existing_by_name = modules_by_name(rust_modules(existing));
+    let mut common: Option<BTreeMap<String, BpValue>> = None;
+    for generated_module in &generated {
+        let Some(existing_module) = existing_by_name.get(generated_module.props.get_string("name"))
+        else {
+            return Ok(None);
+        };
+        let extra: BTreeMap<String, BpValue> = existing_module
+            .props
+            .map
+            .iter()
+            .filter(|(key, _)| !generated_module.props.map.contains_key(*key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if common.as_ref().is_some_and(|common| *common != extra) {
+            return Ok(None);
+        }
+        common = Some(extra);
+    }
+    let Some(common) = common.filter(|common| !common.is_empty()) else {
+        return Ok(None);
+    };
+    let mut block = String::new();
+    BpProperties { map: common, raw_block: None }.write(&mut block)?;
+    // Remove the braces and the final comma, as `add_module_block` is inserted as a raw block.
+    let block = block.trim_start_matches("{\n").trim_end_matches("}").trim_end_matches(",\n");
+    Ok(Some(block.to_string()))
+}
+
+/// Generates the modules for the given crates with the given config, in the same way as for an
+/// `Android.bp`.
+pub fn generate_modules(cfg: &VariantConfig, crates: &[Crate]) -> Result<Vec<BpModule>> {
+    let default_package_cfg = PackageVariantConfig::default();
+    let mut modules = Vec::new();
+    for crate_ in crates {
+        if !cfg.tests && crate_.types.iter().any(|t| t.is_test()) {
+            continue;
+        }
+        let package_cfg = cfg.package.get(&crate_.package_name).unwrap_or(&default_package_cfg);
+        modules.extend(crate_to_bp_modules(crate_, cfg, package_cfg, &[])?);
+    }
+    modules.sort();
+    modules.dedup();
+    modules.sort_by_key(|module| module.props.get_string("name").to_string());
+    Ok(modules)
+}
+
+/// Returns a diff from the existing Rust modules to the generated modules, with both written in
+/// the same format so that only real differences show up.
+pub fn residual_diff(existing: &[BpModule], generated: &[BpModule]) -> Result<String> {
+    let mut existing_modules = rust_modules(existing);
+    existing_modules.sort_by_key(|module| string_prop(&module.props, "name"));
+    let mut existing_text = String::new();
+    for module in existing_modules {
+        module.write(&mut existing_text)?;
+    }
+    // Round trip the generated modules through the parser, so that properties from
+    // `add_module_block` are sorted in the same way as the others.
+    let mut generated_text = String::new();
+    for module in generated {
+        module.write(&mut generated_text)?;
+    }
+    let generated = parse_blueprint(&generated_text)?;
+    let mut generated_text = String::new();
+    for module in &generated {
+        module.write(&mut generated_text)?;
+    }
+
+    let existing_lines: Vec<&str> = existing_text.lines().collect();
+    let generated_lines: Vec<&str> = generated_text.lines().collect();
+    let ops = diff_lines(&existing_lines, &generated_lines);
+    if ops.iter().all(|(op, _)| *op == ' ') {
+        return Ok(String::new());
+    }
+    let mut diff = "--- Android.bp\n+++ generated\n".to_string();
+    let changed: Vec<usize> =
+        ops.iter().enumerate().filter(|(_, (op, _))| *op != ' ').map(|(i, _)| i).collect();
+    let mut last_written = None;
+    for (i, (op, line)) in ops.iter().enumerate() {
+        let near_change =
+            changed.iter().any(|changed_index| changed_index.abs_diff(i) <= DIFF_CONTEXT);
+        if !near_change {
+            continue;
+        }
+        if last_written.is_some_and(|last| last + 1 != i) {
+            diff += "@@\n";
+        }
+        diff += &format!("{op}{line}\n");
+        last_written = Some(i);
+    }
+    Ok(diff)
+}
+
+/// Returns the minimal list of lines to keep (' '), remove ('-') or add ('+') to turn `a` into
+/// `b`, based on their longest common subsequence.
+fn diff_lines<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(char, &'a str)> {
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] =
+                if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut ops = Vec::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            ops.push(('+', b[j]));
+            j += 1;
+        } else {
+            ops.push(('-', a[i]));
+            i += 1;
+        }
+    }
+    ops
+}
+
+/// Returns the name of the single package which the crates belong to.
+fn package_name(crates: &[Crate]) -> Result<String> {
+    let package_names: BTreeSet<&str> =
+        crates.iter().map(|crate_| crate_.package_name.as_str()).collect();
+    if package_names.len() != 1 {
+        bail!("Expected crates from a single package, but found {package_names:?}");
+    }
+    Ok(package_names.into_iter().next().unwrap().to_string())
+}
+
+/// Sets options which correspond directly to properties of the existing modules.
+fn derive_options(
+    existing: &[BpModule],
+    crates: &[Crate],
+    package_name: &str,
+    cfg: &mut VariantConfig,
+    notes: &mut Vec<String>,
+) {
+    let existing = rust_modules(existing);
+    cfg.tests = existing.iter().any(|module| module.module_type.starts_with("rust_test"));
+    if !cfg.tests {
+        notes.push("There are no existing tests, so tests are disabled.".to_string());
+    }
+
+    let defaults: BTreeSet<Vec<String>> =
+        existing.iter().map(|module| strings_prop(&module.props, "defaults")).collect();
+    if let [defaults] = Vec::from_iter(defaults).as_slice() {
+        if let [global_defaults] = defaults.as_slice() {
+            cfg.global_defaults = Some(global_defaults.clone());
+        }
+    }
+
+    for module in &existing {
+        if let (Some(name), Some(BpValue::List(_))) =
+            (string_prop(&module.props, "name"), module.props.map.get("visibility"))
+        {
+            cfg.module_visibility
+                .insert(name.to_string(), strings_prop(&module.props, "visibility"));
+        }
+    }
+
+    let library_names: BTreeSet<&str> = crates
+        .iter()
+        .filter(|crate_| crate_.types.iter().any(|t| t.is_library()))
+        .map(|crate_| crate_.name.as_str())
+        .collect();
+    let Some(library) = existing.iter().find(|module| {
+        module.module_type.starts_with("rust_library")
+            && string_prop(&module.props, "crate_name")
+                .is_some_and(|name| library_names.contains(name))
+    }) else {
+        return;
+    };
+    let props = &library.props;
+    let host_only = library.module_type.ends_with("_host");
+    let package_cfg = package_entry(cfg, package_name);
+    package_cfg.device_supported = !host_only;
+    package_cfg.host_supported = host_only || bool_prop(props, "host_supported");
+    package_cfg.force_rlib = library.module_type.ends_with("_rlib");
+    package_cfg.host_first_multilib = string_prop(props, "compile_multilib") == Some("first");
+    package_cfg.no_std = bool_prop(props, "no_stdlibs");
+    package_cfg.alloc =
+        strings_prop(props, "stdlibs").iter().any(|lib| lib.starts_with("liballoc"));
+    if host_only {
+        return;
+    }
+    cfg.apex_available = strings_prop(props, "apex_available");
+    cfg.min_sdk_version = string_prop(props, "min_sdk_version").map(str::to_string);
+    cfg.native_bridge_supported = bool_prop(props, "native_bridge_supported");
+    cfg.product_available = bool_prop(props, "product_available");
+    cfg.ramdisk_available = bool_prop(props, "ramdisk_available");
+    cfg.recovery_available = bool_prop(props, "recovery_available");
+    cfg.vendor_available = bool_prop(props, "vendor_available");
+    cfg.vendor_ramdisk_available = bool_prop(props, "vendor_ramdisk_available");
+}
+
+/// Adds module name overrides and blocklist entries for generated modules and dependencies which
+/// have different names to the existing ones, or don't exist.
+fn derive_renames(
+    existing: &[BpModule],
+    crates: &[Crate],
+    package_name: &str,
+    cfg: &mut VariantConfig,
+    notes: &mut Vec<String>,
+) -> Result<()> {
+    let existing_by_name = modules_by_name(rust_modules(existing));
+
+    // Match up generated modules with existing ones for the same crate and source file.
+    for generated in generate_modules(cfg, crates)? {
+        let name = generated.props.get_string("name");
+        if existing_by_name.contains_key(name) {
+            continue;
+        }
+        let counterpart = existing_by_name.iter().find(|(_, module)| {
+            module.module_type == generated.module_type
+                && module.props.map.get("crate_name") == generated.props.map.get("crate_name")
+                && module.props.map.get("crate_root") == generated.props.map.get("crate_root")
+        });
+        if let Some((existing_name, _)) = counterpart {
+            cfg.module_name_overrides.insert(original_name(name), existing_name.to_string());
+        } else {
+            notes.push(format!("There is no existing module like {name}, so it is blocklisted."));
+            cfg.module_blocklist.push(original_name(name));
+        }
+    }
+
+    // Then look at the dependencies of modules which match.
+    let mut dep_blocklist = BTreeSet::new();
+    for generated in generate_modules(cfg, crates)? {
+        let Some(existing_module) = existing_by_name.get(generated.props.get_string("name")) else {
+            continue;
+        };
+        for property in ["rustlibs", "proc_macros"] {
+            let existing_deps = strings_prop(&existing_module.props, property);
+            let generated_deps = strings_prop(&generated.props, property);
+            for dep in generated_deps.iter().filter(|dep| !existing_deps.contains(dep)) {
+                if let Some(renamed) = existing_deps.iter().find(|existing_dep| {
+                    existing_dep.strip_prefix(dep.as_str()).is_some_and(is_rename_suffix)
+                        && !generated_deps.contains(existing_dep)
+                }) {
+                    cfg.module_name_overrides.insert(original_name(dep), renamed.clone());
+                } else {
+                    dep_blocklist.insert(original_name(dep));
+                }
+            }
+        }
+    }
+    if !dep_blocklist.is_empty() {
+        notes.push(format!(
+            "The existing modules don't depend on {}, so they are blocklisted.",
+            Vec::from_iter(dep_blocklist.iter().map(String::as_str)).join(", ")
+        ));
+        package_entry(cfg, package_name).dep_blocklist.extend(dep_blocklist);
+    }
+    Ok(())
+}
+
+/// Returns whether a dependency with the given suffix added is likely to be a renamed version of
+/// the dependency, rather than a different crate such as `libfoo_derive` for `libfoo`. This is the
+/// case for version suffixes such as `_1` or `_0_7`, `_rust`, and suffixes which the rename
+/// registry adds.
+fn is_rename_suffix(suffix: &str) -> bool {
+    let is_version = suffix.strip_prefix('_').is_some_and(|version| {
+        version.split('_').all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    });
+    is_version
+        || suffix == "_rust"
+        || rename_registry()
+            .rename_map
+            .iter()
+            .any(|(original, renamed)| renamed.strip_prefix(original.as_str()) == Some(suffix))
+}
+
+/// Returns the name which a generated module would have had before being renamed by the rename
+/// registry.
+fn original_name(name: &str) -> String {
+    rename_registry()
+        .rename_map
+        .iter()
+        .find(|(_, renamed)| *renamed == name)
+        .map_or(name, |(original, _)| original)
+        .to_string()
+}
+
+fn toggle_package_option(package_cfg: &mut PackageVariantConfig, option: &str) {
+    let value = match option {
+        "alloc" => &mut package_cfg.alloc,
+        "device_supported" => &mut package_cfg.device_supported,
+        "force_rlib" => &mut package_cfg.force_rlib,
+        "host_first_multilib" => &mut package_cfg.host_first_multilib,
+        "host_supported" => &mut package_cfg.host_supported,
+        "no_std" => &mut package_cfg.no_std,
+        _ => unreachable!("unknown package option {option}"),
+    };
+    *value = !*value;
+}
+
+fn toggle_variant_option(cfg: &mut VariantConfig, option: &str) {
+    let value = match option {
+        "native_bridge_supported" => &mut cfg.native_bridge_supported,
+        "product_available" => &mut cfg.product_available,
+        "ramdisk_available" => &mut cfg.ramdisk_available,
+        "recovery_available" => &mut cfg.recovery_available,
+        "vendor_available" => &mut cfg.vendor_available,
+        "vendor_ramdisk_available" => &mut cfg.vendor_ramdisk_available,
+        _ => unreachable!("unknown variant option {option}"),
+    };
+    *value = !*value;
+}
+
+/// Returns a measure of how different the generated modules are from the existing ones: the number
+/// of properties or list entries which differ, plus one for each module which only exists on one
+/// side.
+fn distance(existing: &[BpModule], generated: &[BpModule]) -> usize {
+    let existing = modules_by_name(rust_modules(existing));
+    let generated = modules_by_name(generated.iter().collect());
+    let names: BTreeSet<&str> = existing.keys().chain(generated.keys()).copied().collect();
+    names
+        .into_iter()
+        .map(|name| match (existing.get(name), generated.get(name)) {
+            (Some(a), Some(b)) => {
+                usize::from(a.module_type != b.module_type) + props_distance(&a.props, &b.props)
+            }
+            (Some(module), None) | (None, Some(module)) => 1 + props_size(&module.props),
+            (None, None) => 0,
+        })
+        .sum()
+}
+
+fn props_distance(a: &BpProperties, b: &BpProperties) -> usize {
+    let keys: BTreeSet<&String> = a.map.keys().chain(b.map.keys()).collect();
+    keys.into_iter()
+        .map(|key| match (a.map.get(key), b.map.get(key)) {
+            (Some(a), Some(b)) => value_distance(a, b),
+            (Some(value), None) | (None, Some(value)) => value_size(value),
+            (None, None) => 0,
+        })
+        .sum()
+}
+
+fn value_distance(a: &BpValue, b: &BpValue) -> usize {
+    match (a, b) {
+        (BpValue::List(a), BpValue::List(b)) => {
+            a.iter().filter(|value| !b.contains(value)).count()
+                + b.iter().filter(|value| !a.contains(value)).count()
+        }
+        (BpValue::Object(a), BpValue::Object(b)) => props_distance(a, b),
+        _ => usize::from(a != b),
+    }
+}
+
+fn props_size(props: &BpProperties) -> usize {
+    props.map.values().map(value_size).sum()
+}
+
+fn value_size(value: &BpValue) -> usize {
+    match value {
+        BpValue::List(values) => values.len().max(1),
+        BpValue::Object(props) => props_size(props).max(1),
+        _ => 1,
+    }
+}
+
+/// Returns the Rust modules, which are the only ones cargo_embargo might generate, except for
+/// `rust_defaults` which it never generates.
+fn rust_modules(modules: &[BpModule]) -> Vec<&BpModule> {
+    modules
+        .iter()
+        .filter(|module| {
+            module.module_type.starts_with("rust_")
+                && module.module_type != "rust_defaults"
+                && string_prop(&module.props, "name").is_some()
+        })
+        .collect()
+}
+
+fn modules_by_name(modules: Vec<&BpModule>) -> BTreeMap<&str, &BpModule> {
+    modules
+        .into_iter()
+        .filter_map(|module| Some((string_prop(&module.props, "name")?, module)))
+        .collect()
+}
+
+fn string_prop<'a>(props: &'a BpProperties, key: &str) -> Option<&'a str> {
+    match props.map.get(key) {
+        Some(BpValue::String(value)) => Some(value),
+        _ => None,
+    }
+}
+
+fn strings_prop(props: &BpProperties, key: &str) -> Vec<String> {
+    match props.map.get(key) {
+        Some(BpValue::List(values)) => values
+            .iter()
+            .filter_map(|value| match value {
+                BpValue::String(value) => Some(value.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn bool_prop(props: &BpProperties, key: &str) -> bool {
+    matches!(props.map.get(key), Some(BpValue::Bool(true)))
+}
+
+/// Returns the config for the given package in the variant, adding a default one if necessary.
+fn package_entry<'a>(
+    cfg: &'a mut VariantConfig,
+    package_name: &str,
+) -> &'a mut PackageVariantConfig {
+    cfg.package.entry(package_name.to_string()).or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::{CrateType, Extern, ExternType};
+
+    fn make_extern(name: &str) -> Extern {
+        Extern {
+            name: name.to_string(),
+            lib_name: name.to_string(),
+            extern_type: ExternType::Rust,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn infer() {
+        let crates = [Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            version: Some("1.2.3".to_string()),
+            types: vec![CrateType::Lib],
+            externs: vec![make_extern("bar"), make_extern("baz"), make_extern("log")],
+            edition: "2021".to_string(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        }];
+        let existing = parse_blueprint(
+            r#"
+rust_library_host {
+    name: "libfoo_rust",
+    crate_name: "foo",
+    cargo_env_compat: true,
+    cargo_pkg_version: "1.2.3",
+    crate_root: "src/lib.rs",
+    edition: "2021",
+    rustlibs: ["libbar_1", "liblog_rust"],
+    visibility: ["//external/foo"],
+    lints: "none",
+}
+"#,
+        )
+        .unwrap();
+        let mut cfg = VariantConfig { tests: true, ..Default::default() };
+        let mut notes = Vec::new();
+
+        infer_options(&existing, &crates, &mut cfg, &mut notes).unwrap();
+
+        assert!(!cfg.tests);
+        assert!(!cfg.package["foo"].device_supported);
+        assert!(cfg.package["foo"].host_supported);
+        assert_eq!(cfg.package["foo"].dep_blocklist, vec!["libbaz"]);
+        assert_eq!(
+            cfg.module_name_overrides,
+            [
+                ("libbar".to_string(), "libbar_1".to_string()),
+                ("libfoo".to_string(), "libfoo_rust".to_string())
+            ]
+            .into()
+        );
+        assert_eq!(cfg.module_visibility["libfoo_rust"], vec!["//external/foo"]);
+
+        assert_eq!(
+            residual_module_block(&existing, &crates, &cfg).unwrap(),
+            Some("lints: \"none\"".to_string())
+        );
+        let generated = generate_modules(&cfg, &crates).unwrap();
+        assert_eq!(
+            residual_diff(&existing, &generated).unwrap(),
+            "--- Android.bp\n\
+             +++ generated\n \
+             edition: \"2021\",\n \
+             rustlibs: [\"libbar_1\", \"liblog_rust\"],\n \
+             visibility: [\"//external/foo\"],\n\
+             -lints: \"none\",\n \
+             }\n"
+        );
+    }
+
+    #[test]
+    fn rename_suffixes() {
+        assert!(is_rename_suffix("_1"));
+        assert!(is_rename_suffix("_0_7"));
+        assert!(is_rename_suffix("_rust"));
+        assert!(!is_rename_suffix("_derive"));
+        assert!(!is_rename_suffix("_0_"));
+        assert!(!is_rename_suffix("1"));
+        assert!(!is_rename_suffix(""));
+    }
+}
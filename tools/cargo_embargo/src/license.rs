@@ -0,0 +1,652 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! License modules, `MODULE_LICENSE_*` files and `METADATA` stubs generated from the SPDX license
+//! expression in the `Cargo.toml` of a package.
+
+use crate::bp::BpModule;
+use crate::cargo::PackageInfo;
+use anyhow::{bail, Context, Result};
+use std::fmt::{self, Display, Formatter, Write};
+use std::fs::{read_dir, write};
+use std::path::Path;
+
+/// A license which cargo_embargo knows how to describe.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct KnownLicense {
+    /// The SPDX identifier.
+    spdx: &'static str,
+    /// The suffix of the `MODULE_LICENSE_*` marker file.
+    marker: &'static str,
+    /// The `license_type` in the `METADATA` file.
+    license_type: LicenseType,
+    /// Words in the name of a license file which show that it is for this license, e.g. `APACHE`
+    /// for `LICENSE-APACHE`.
+    file_keywords: &'static [&'static str],
+}
+
+/// The `license_type` of a `METADATA` file, from least to most restrictive.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum LicenseType {
+    Unencumbered,
+    Notice,
+    Reciprocal,
+}
+
+impl Display for LicenseType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Unencumbered => f.write_str("UNENCUMBERED"),
+            Self::Notice => f.write_str("NOTICE"),
+            Self::Reciprocal => f.write_str("RECIPROCAL"),
+        }
+    }
+}
+
+/// The licenses which cargo_embargo knows, in order of preference when a package offers a choice.
+const KNOWN_LICENSES: [KnownLicense; 12] = [
+    KnownLicense {
+        spdx: "Apache-2.0",
+        marker: "APACHE2",
+        license_type: LicenseType::Notice,
+        file_keywords: &["APACHE"],
+    },
+    KnownLicense {
+        spdx: "MIT",
+        marker: "MIT",
+        license_type: LicenseType::Notice,
+        file_keywords: &["MIT"],
+    },
+    KnownLicense {
+        spdx: "BSD-3-Clause",
+        marker: "BSD",
+        license_type: LicenseType::Notice,
+        file_keywords: &["BSD"],
+    },
+    KnownLicense {
+        spdx: "BSD-2-Clause",
+        marker: "BSD",
+        license_type: LicenseType::Notice,
+        file_keywords: &["BSD"],
+    },
+    KnownLicense {
+        spdx: "ISC",
+        marker: "ISC",
+        license_type: LicenseType::Notice,
+        file_keywords: &["ISC"],
+    },
+    KnownLicense {
+        spdx: "Zlib",
+        marker: "ZLIB",
+        license_type: LicenseType::Notice,
+        file_keywords: &["ZLIB"],
+    },
+    KnownLicense {
+        spdx: "BSL-1.0",
+        marker: "BOOST",
+        license_type: LicenseType::Notice,
+        file_keywords: &["BOOST", "BSL"],
+    },
+    KnownLicense {
+        spdx: "Unicode-DFS-2016",
+        marker: "UNICODE",
+        license_type: LicenseType::Notice,
+        file_keywords: &["UNICODE"],
+    },
+    KnownLicense {
+        spdx: "Unicode-3.0",
+        marker: "UNICODE",
+        license_type: LicenseType::Notice,
+        file_keywords: &["UNICODE"],
+    },
+    KnownLicense {
+        spdx: "0BSD",
+        marker: "PERMISSIVE",
+        license_type: LicenseType::Unencumbered,
+        file_keywords: &["0BSD"],
+    },
+    KnownLicense {
+        spdx: "Unlicense",
+        marker: "UNLICENSE",
+        license_type: LicenseType::Unencumbered,
+        file_keywords: &["UNLICENSE"],
+    },
+    KnownLicense {
+        spdx: "MPL-2.0",
+        marker: "MPL",
+        license_type: LicenseType::Reciprocal,
+        file_keywords: &["MPL", "MOZILLA"],
+    },
+];
+
+/// Prefixes of the names of license files.
+const LICENSE_FILE_PREFIXES: [&str; 4] = ["LICENSE", "LICENCE", "COPYING", "UNLICENSE"];
+
+fn known_license(spdx: &str) -> Option<&'static KnownLicense> {
+    KNOWN_LICENSES.iter().find(|license| license.spdx == spdx)
+}
+
+/// Returns the position of the given license in order of preference, with unknown licenses last.
+fn preference(spdx: &str) -> usize {
+    KNOWN_LICENSES.iter().position(|license| license.spdx == spdx).unwrap_or(KNOWN_LICENSES.len())
+}
+
+/// A parsed SPDX license expression.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LicenseExpr {
+    /// A single license identifier, e.g. `MIT`.
+    License(String),
+    /// A license with an exception, e.g. `Apache-2.0 WITH LLVM-exception`.
+    With(String, String),
+    /// All of the given licenses apply.
+    And(Vec<LicenseExpr>),
+    /// Any one of the given licenses may be chosen.
+    Or(Vec<LicenseExpr>),
+}
+
+impl Display for LicenseExpr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let write_all = |f: &mut Formatter, parts: &[LicenseExpr], operator: &str| {
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {operator} ")?;
+                }
+                if matches!(part, Self::And(_) | Self::Or(_)) {
+                    write!(f, "({part})")?;
+                } else {
+                    write!(f, "{part}")?;
+                }
+            }
+            Ok(())
+        };
+        match self {
+            Self::License(license) => f.write_str(license),
+            Self::With(license, exception) => write!(f, "{license} WITH {exception}"),
+            Self::And(parts) => write_all(f, parts, "AND"),
+            Self::Or(parts) => write_all(f, parts, "OR"),
+        }
+    }
+}
+
+impl LicenseExpr {
+    /// Parses an SPDX license expression, also accepting the deprecated `/` separator for
+    /// alternatives as used by older crates, e.g. `MIT/Apache-2.0`.
+    pub fn parse(expression: &str) -> Result<Self> {
+        let spaced = expression.replace('(', " ( ").replace(')', " ) ").replace('/', " OR ");
+        let mut tokens: Vec<&str> = spaced.split_whitespace().collect();
+        tokens.reverse();
+        let expr = Self::parse_or(&mut tokens)
+            .with_context(|| format!("failed to parse license expression {expression:?}"))?;
+        if let Some(token) = tokens.last() {
+            bail!("unexpected {token:?} in license expression {expression:?}");
+        }
+        Ok(expr)
+    }
+
+    /// Parses alternatives from the given tokens, which are in reverse order.
+    fn parse_or(tokens: &mut Vec<&str>) -> Result<Self> {
+        let mut parts = vec![Self::parse_and(tokens)?];
+        while tokens.last().is_some_and(|token| token.eq_ignore_ascii_case("OR")) {
+            tokens.pop();
+            parts.push(Self::parse_and(tokens)?);
+        }
+        Ok(if parts.len() == 1 { parts.remove(0) } else { Self::Or(parts) })
+    }
+
+    /// Parses conjunctions from the given tokens, which are in reverse order.
+    fn parse_and(tokens: &mut Vec<&str>) -> Result<Self> {
+        let mut parts = vec![Self::parse_with(tokens)?];
+        while tokens.last().is_some_and(|token| token.eq_ignore_ascii_case("AND")) {
+            tokens.pop();
+            parts.push(Self::parse_with(tokens)?);
+        }
+        Ok(if parts.len() == 1 { parts.remove(0) } else { Self::And(parts) })
+    }
+
+    /// Parses a license with an optional exception, or a parenthesised expression, from the given
+    /// tokens, which are in reverse order.
+    fn parse_with(tokens: &mut Vec<&str>) -> Result<Self> {
+        match tokens.pop() {
+            Some("(") => {
+                let expr = Self::parse_or(tokens)?;
+                if tokens.pop() != Some(")") {
+                    bail!("missing `)`");
+                }
+                Ok(expr)
+            }
+            Some(token) if is_operator(token) || token == ")" => bail!("unexpected {token:?}"),
+            Some(license) => {
+                if tokens.last().is_some_and(|token| token.eq_ignore_ascii_case("WITH")) {
+                    tokens.pop();
+                    match tokens.pop() {
+                        Some(exception) if !is_operator(exception) && exception != "(" => {
+                            Ok(Self::With(license.to_string(), exception.to_string()))
+                        }
+                        _ => bail!("missing exception after `WITH`"),
+                    }
+                } else {
+                    Ok(Self::License(license.to_string()))
+                }
+            }
+            None => bail!("unexpected end"),
+        }
+    }
+
+    /// Chooses the licenses to use for a package with this license expression, preferring known
+    /// licenses when there is a choice. Returns the SPDX identifiers of the chosen licenses, and a
+    /// description of each reason that the choice needs to be reviewed.
+    fn choose(&self) -> (Vec<String>, Vec<String>) {
+        match self {
+            Self::License(license) => {
+                let problems = if known_license(license).is_some() {
+                    vec![]
+                } else {
+                    vec![format!("`{license}` is not a license which cargo_embargo knows")]
+                };
+                (vec![license.clone()], problems)
+            }
+            Self::With(license, _) => {
+                let (licenses, mut problems) = Self::License(license.clone()).choose();
+                problems.push(format!("`{self}` has an exception"));
+                (licenses, problems)
+            }
+            Self::And(parts) => {
+                let mut licenses = Vec::new();
+                let mut problems = vec![format!("`{self}` requires several licenses")];
+                for part in parts {
+                    let (part_licenses, part_problems) = part.choose();
+                    licenses.extend(part_licenses);
+                    problems.extend(part_problems);
+                }
+                licenses.sort_by_key(|license| preference(license));
+                licenses.dedup();
+                (licenses, problems)
+            }
+            Self::Or(parts) => parts
+                .iter()
+                .map(Self::choose)
+                .min_by_key(|(licenses, problems)| {
+                    (
+                        !problems.is_empty(),
+                        licenses.iter().map(|license| preference(license)).max(),
+                        licenses.len(),
+                    )
+                })
+                .expect("alternatives can't be empty"),
+        }
+    }
+}
+
+fn is_operator(token: &str) -> bool {
+    ["AND", "OR", "WITH"].iter().any(|operator| token.eq_ignore_ascii_case(operator))
+}
+
+/// The licenses chosen for a package and their license files.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PackageLicense {
+    /// The SPDX identifiers of the licenses which apply to the package.
+    pub licenses: Vec<String>,
+    /// The license files, relative to the package directory.
+    pub files: Vec<String>,
+    /// Reasons why a human needs to review the license, if any.
+    pub problems: Vec<String>,
+}
+
+impl PackageLicense {
+    /// Chooses the licenses for the package with the given info, and finds their license files in
+    /// `package_dir`.
+    pub fn new(info: &PackageInfo, package_dir: &Path) -> Result<Self> {
+        let mut license = Self::default();
+        match &info.license {
+            Some(expression) => match LicenseExpr::parse(expression) {
+                Ok(expr) => (license.licenses, license.problems) = expr.choose(),
+                Err(e) => license.problems.push(format!("{e:#}")),
+            },
+            None if info.license_file.is_some() => {
+                license.problems.push("`Cargo.toml` has a `license-file` but no `license`".into())
+            }
+            None => license.problems.push("`Cargo.toml` has no `license`".into()),
+        }
+        license.files = find_license_files(&license.licenses, info, package_dir)?;
+        if license.files.is_empty() {
+            license.problems.push("no license file was found".into());
+        }
+        Ok(license)
+    }
+
+    /// Returns the `package` and `license` modules for the package, with the given name for the
+    /// `license` module.
+    pub fn modules(&self, license_module_name: &str) -> Vec<BpModule> {
+        let mut package = BpModule::new("package".to_string());
+        package.props.set("default_applicable_licenses", vec![license_module_name]);
+
+        let mut license = BpModule::new("license".to_string());
+        license.props.set("name", license_module_name);
+        license.props.set("visibility", vec![":__subpackages__"]);
+        license.props.set(
+            "license_kinds",
+            self.licenses
+                .iter()
+                .map(|license| format!("SPDX-license-identifier-{license}"))
+                .collect::<Vec<_>>(),
+        );
+        license.props.set("license_text", self.files.clone());
+        vec![package, license]
+    }
+
+    /// Returns the license header for a new `Android.bp`, with a comment for each problem which
+    /// needs to be reviewed followed by the `package` and `license` modules.
+    pub fn android_bp_header(&self, license_module_name: &str) -> Result<String> {
+        let mut header = String::new();
+        for problem in &self.problems {
+            writeln!(header, "// DO NOT SUBMIT: Review license before submitting: {problem}.")?;
+        }
+        for module in self.modules(license_module_name) {
+            header += "\n";
+            module.write(&mut header)?;
+        }
+        Ok(header)
+    }
+
+    /// Creates a `MODULE_LICENSE_*` file for each license and a `METADATA` stub in `package_dir`,
+    /// unless they already exist.
+    pub fn write_metadata_files(
+        &self,
+        info: &PackageInfo,
+        package_name: &str,
+        version: Option<&str>,
+        package_dir: &Path,
+    ) -> Result<()> {
+        if !has_file_with_prefix(package_dir, "MODULE_LICENSE_")? {
+            for license in self.licenses.iter().filter_map(|license| known_license(license)) {
+                let path = package_dir.join(format!("MODULE_LICENSE_{}", license.marker));
+                write(&path, "").with_context(|| format!("failed to create {path:?}"))?;
+            }
+        }
+        let metadata_path = package_dir.join("METADATA");
+        if !metadata_path.exists() {
+            write(&metadata_path, self.metadata(info, package_name, version)?)
+                .with_context(|| format!("failed to write {metadata_path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the contents of a `METADATA` stub for the package.
+    fn metadata(
+        &self,
+        info: &PackageInfo,
+        package_name: &str,
+        version: Option<&str>,
+    ) -> Result<String> {
+        let mut metadata = String::new();
+        writeln!(metadata, "# This METADATA stub was generated by cargo_embargo. Review it.")?;
+        writeln!(metadata, "name: {package_name:?}")?;
+        if let Some(description) = &info.description {
+            writeln!(metadata, "description: {:?}", description.trim())?;
+        }
+        writeln!(metadata, "third_party {{")?;
+        writeln!(metadata, "  identifier {{")?;
+        writeln!(metadata, "    type: \"crates.io\"")?;
+        writeln!(metadata, "    value: \"https://crates.io/crates/{package_name}\"")?;
+        writeln!(metadata, "  }}")?;
+        if let Some(version) = version {
+            writeln!(metadata, "  identifier {{")?;
+            writeln!(metadata, "    type: \"Archive\"")?;
+            writeln!(
+                metadata,
+                "    value: \"https://static.crates.io/crates/{package_name}/{package_name}-{version}.crate\""
+            )?;
+            writeln!(metadata, "    primary_source: true")?;
+            writeln!(metadata, "  }}")?;
+        }
+        if let Some(repository) = &info.repository {
+            writeln!(metadata, "  identifier {{")?;
+            writeln!(metadata, "    type: \"Git\"")?;
+            writeln!(metadata, "    value: {repository:?}")?;
+            writeln!(metadata, "  }}")?;
+        }
+        if let Some(version) = version {
+            writeln!(metadata, "  version: {version:?}")?;
+        }
+        let license_types: Option<Vec<LicenseType>> = self
+            .licenses
+            .iter()
+            .map(|license| known_license(license).map(|license| license.license_type))
+            .collect();
+        match license_types.and_then(|license_types| license_types.into_iter().max()) {
+            Some(license_type) => writeln!(metadata, "  license_type: {license_type}")?,
+            None => writeln!(metadata, "  # DO NOT SUBMIT: Add license_type before submitting.")?,
+        }
+        writeln!(metadata, "}}")?;
+        Ok(metadata)
+    }
+}
+
+/// Finds the license files in `package_dir` for the given licenses.
+///
+/// Files whose names mention one of the licenses (e.g. `LICENSE-MIT`) are preferred. If there are
+/// none then files which don't mention any known license (e.g. `LICENSE`) are used. The
+/// `license_file` from `Cargo.toml` is always included.
+fn find_license_files(
+    licenses: &[String],
+    info: &PackageInfo,
+    package_dir: &Path,
+) -> Result<Vec<String>> {
+    let mut candidates = Vec::new();
+    for entry in read_dir(package_dir).with_context(|| format!("failed to read {package_dir:?}"))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let upper_name = name.to_ascii_uppercase();
+        if entry.path().is_file()
+            && LICENSE_FILE_PREFIXES.iter().any(|prefix| upper_name.starts_with(prefix))
+        {
+            candidates.push(name);
+        }
+    }
+    candidates.sort();
+
+    let mentions = |name: &str, license: &KnownLicense| {
+        let upper_name = name.to_ascii_uppercase();
+        license.file_keywords.iter().any(|keyword| upper_name.contains(keyword))
+    };
+    let mut files: Vec<String> = candidates
+        .iter()
+        .filter(|name| {
+            licenses
+                .iter()
+                .filter_map(|license| known_license(license))
+                .any(|license| mentions(name, license))
+        })
+        .cloned()
+        .collect();
+    if files.is_empty() {
+        files = candidates
+            .into_iter()
+            .filter(|name| !KNOWN_LICENSES.iter().any(|license| mentions(name, license)))
+            .collect();
+    }
+    if let Some(license_file) = &info.license_file {
+        if !files.contains(license_file) {
+            files.push(license_file.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Returns whether `dir` contains a file whose name starts with the given prefix.
+fn has_file_with_prefix(dir: &Path, prefix: &str) -> Result<bool> {
+    for entry in read_dir(dir).with_context(|| format!("failed to read {dir:?}"))? {
+        if entry?.file_name().to_string_lossy().starts_with(prefix) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the name to use for the `license` module of the package in `package_dir`.
+///
+/// This follows the Android convention of naming it after the path of the package within the tree,
+/// e.g. `external_rust_crates_either_license`, if `tree_root` is given and contains the package.
+/// Otherwise it is named after the package.
+pub fn license_module_name(
+    package_name: &str,
+    package_dir: &Path,
+    tree_root: Option<&Path>,
+) -> String {
+    let relative_dir = tree_root
+        .and_then(|tree_root| tree_root.canonicalize().ok())
+        .and_then(|tree_root| package_dir.strip_prefix(tree_root).ok().map(Path::to_owned));
+    let base = match relative_dir {
+        Some(relative_dir) if relative_dir.components().next().is_some() => {
+            relative_dir.to_string_lossy().into_owned()
+        }
+        _ => package_name.to_string(),
+    };
+    base.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect::<String>()
+        + "_license"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir;
+    use tempfile::tempdir;
+
+    fn license(id: &str) -> LicenseExpr {
+        LicenseExpr::License(id.to_string())
+    }
+
+    #[test]
+    fn parse_expressions() {
+        assert_eq!(LicenseExpr::parse("MIT").unwrap(), license("MIT"));
+        assert_eq!(
+            LicenseExpr::parse("MIT OR Apache-2.0").unwrap(),
+            LicenseExpr::Or(vec![license("MIT"), license("Apache-2.0")])
+        );
+        assert_eq!(
+            LicenseExpr::parse("MIT/Apache-2.0").unwrap(),
+            LicenseExpr::Or(vec![license("MIT"), license("Apache-2.0")])
+        );
+        assert_eq!(
+            LicenseExpr::parse("(MIT OR Apache-2.0) AND Unicode-DFS-2016").unwrap(),
+            LicenseExpr::And(vec![
+                LicenseExpr::Or(vec![license("MIT"), license("Apache-2.0")]),
+                license("Unicode-DFS-2016"),
+            ])
+        );
+        assert_eq!(
+            LicenseExpr::parse("Apache-2.0 WITH LLVM-exception OR MIT AND ISC").unwrap(),
+            LicenseExpr::Or(vec![
+                LicenseExpr::With("Apache-2.0".to_string(), "LLVM-exception".to_string()),
+                LicenseExpr::And(vec![license("MIT"), license("ISC")]),
+            ])
+        );
+        assert_eq!(
+            LicenseExpr::parse("(MIT OR Apache-2.0) AND Unicode-DFS-2016").unwrap().to_string(),
+            "(MIT OR Apache-2.0) AND Unicode-DFS-2016"
+        );
+        assert!(LicenseExpr::parse("MIT OR").is_err());
+        assert!(LicenseExpr::parse("(MIT").is_err());
+        assert!(LicenseExpr::parse("MIT Apache-2.0").is_err());
+    }
+
+    #[test]
+    fn choose_licenses() {
+        let choose = |expression: &str| LicenseExpr::parse(expression).unwrap().choose();
+        assert_eq!(choose("MIT OR Apache-2.0"), (vec!["Apache-2.0".to_string()], vec![]));
+        assert_eq!(choose("Unlicense OR MIT"), (vec!["MIT".to_string()], vec![]));
+        assert_eq!(choose("Foo OR BSD-2-Clause"), (vec!["BSD-2-Clause".to_string()], vec![]));
+        assert_eq!(
+            choose("Foo"),
+            (
+                vec!["Foo".to_string()],
+                vec!["`Foo` is not a license which cargo_embargo knows".to_string()]
+            )
+        );
+        assert_eq!(
+            choose("(MIT OR Apache-2.0) AND Unicode-DFS-2016"),
+            (
+                vec!["Apache-2.0".to_string(), "Unicode-DFS-2016".to_string()],
+                vec!["`(MIT OR Apache-2.0) AND Unicode-DFS-2016` requires several licenses"
+                    .to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn package_license() {
+        let package_dir = tempdir().unwrap();
+        for name in ["LICENSE-APACHE", "LICENSE-MIT", "README.md"] {
+            write(package_dir.path().join(name), "").unwrap();
+        }
+        create_dir(package_dir.path().join("LICENSES")).unwrap();
+        let info = PackageInfo {
+            license: Some("MIT OR Apache-2.0".to_string()),
+            description: Some("A crate.\n".to_string()),
+            ..Default::default()
+        };
+
+        let license = PackageLicense::new(&info, package_dir.path()).unwrap();
+        assert_eq!(
+            license,
+            PackageLicense {
+                licenses: vec!["Apache-2.0".to_string()],
+                files: vec!["LICENSE-APACHE".to_string()],
+                problems: vec![],
+            }
+        );
+        let mut header = license.android_bp_header("foo_license").unwrap();
+        header.retain(|c| c != '\n');
+        assert_eq!(
+            header,
+            "package {default_applicable_licenses: [\"foo_license\"],}\
+license {name: \"foo_license\",visibility: [\":__subpackages__\"],\
+license_kinds: [\"SPDX-license-identifier-Apache-2.0\"],license_text: [\"LICENSE-APACHE\"],}"
+        );
+
+        license.write_metadata_files(&info, "foo", Some("1.2.3"), package_dir.path()).unwrap();
+        assert!(package_dir.path().join("MODULE_LICENSE_APACHE2").exists());
+        let metadata = std::fs::read_to_string(package_dir.path().join("METADATA")).unwrap();
+        assert!(metadata.contains("description: \"A crate.\"\n"));
+        assert!(metadata.contains("  version: \"1.2.3\"\n"));
+        assert!(metadata.contains("  license_type: NOTICE\n"));
+
+        let info = PackageInfo { license: Some("Foo".to_string()), ..Default::default() };
+        let license = PackageLicense::new(&info, package_dir.path()).unwrap();
+        assert_eq!(
+            license.problems,
+            vec![
+                "`Foo` is not a license which cargo_embargo knows".to_string(),
+                "no license file was found".to_string(),
+            ]
+        );
+        assert!(license
+            .android_bp_header("foo_license")
+            .unwrap()
+            .starts_with("// DO NOT SUBMIT: Review license before submitting: `Foo` is not"));
+    }
+
+    #[test]
+    fn module_name() {
+        let tree_root = tempdir().unwrap();
+        let package_dir = tree_root.path().join("external/rust/crates/foo-bar");
+        std::fs::create_dir_all(&package_dir).unwrap();
+        let package_dir = package_dir.canonicalize().unwrap();
+        assert_eq!(
+            license_module_name("foo-bar", &package_dir, Some(tree_root.path())),
+            "external_rust_crates_foo_bar_license"
+        );
+        assert_eq!(license_module_name("foo-bar", &package_dir, None), "foo_bar_license");
+    }
+}
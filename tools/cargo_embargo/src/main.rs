@@ -26,29 +26,63 @@
 //! The last step often involves messy, project specific business logic, so many options are
 //! available to tweak it via a config file.
 
+mod autoconfig;
+mod bindgen;
 mod bp;
+mod build_script;
+mod cache;
 mod cargo;
+mod cargo_profile;
+mod cbindgen;
 mod config;
-
+mod dep_check;
+mod explain;
+mod fingerprint;
+mod fuzz;
+mod import;
+mod infer_config;
+mod license;
+mod merge;
+mod probe;
+mod protobuf;
+mod provenance;
+mod rename_registry;
+mod sandbox;
+mod tree_index;
+
+use crate::build_script::{check_config, classify_build_scripts, static_cfgs, BuildScript};
+use crate::cache::{cache_key, is_cacheable, toolchain_version, CargoCache};
+use crate::cargo_profile::CargoProfile;
 use crate::config::Config;
 use crate::config::PackageConfig;
 use crate::config::PackageVariantConfig;
+use crate::config::TestTargetConfig;
 use crate::config::VariantConfig;
+use crate::explain::{explain_module, ModuleSource};
+use crate::fuzz::{fuzz_crates, fuzz_data, fuzz_metadata};
+use crate::license::{license_module_name, PackageLicense};
+use crate::probe::RustcVersion;
+use crate::provenance::Provenance;
+use crate::rename_registry::{init_rename_registry, rename_registry};
+use crate::sandbox::{find_violations, Sandbox};
+use crate::tree_index::TreeIndex;
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use bp::*;
 use cargo::{
-    cargo_out::parse_cargo_out, metadata::parse_cargo_metadata_str, Crate, CrateType, ExternType,
+    cargo_out::parse_cargo_out,
+    metadata::{config_from_cargo_metadata, parse_cargo_metadata_str},
+    Crate, CrateType, Extern, ExternType,
 };
 use clap::Parser;
 use clap::Subcommand;
 use log::debug;
 use nix::fcntl::OFlag;
 use nix::unistd::pipe2;
-use once_cell::sync::Lazy;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::VecDeque;
 use std::env;
 use std::fs::{read_to_string, write, File};
@@ -58,57 +92,23 @@ use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use tempfile::tempdir;
 
+/// The file to write properties for `add_module_block` to when inferring a config.
+const MODULE_BLOCK_FILENAME: &str = "add_module_block.bp";
+
+/// The target triple to build for when running cargo.
+const DEFAULT_TARGET: &str = "x86_64-unknown-linux-gnu";
+
 // Major TODOs
 //  * handle errors, esp. in cargo.out parsing. they should fail the program with an error code
 //  * handle warnings. put them in comments in the android.bp, some kind of report section
 
-/// Rust modules which shouldn't use the default generated names, to avoid conflicts or confusion.
-pub static RENAME_MAP: Lazy<BTreeMap<&str, &str>> = Lazy::new(|| {
-    [
-        ("libash", "libash_rust"),
-        ("libatomic", "libatomic_rust"),
-        ("libbacktrace", "libbacktrace_rust"),
-        ("libbase", "libbase_rust"),
-        ("libbase64", "libbase64_rust"),
-        ("libfuse", "libfuse_rust"),
-        ("libgcc", "libgcc_rust"),
-        ("liblog", "liblog_rust"),
-        ("libminijail", "libminijail_rust"),
-        ("libsync", "libsync_rust"),
-        ("libx86_64", "libx86_64_rust"),
-        ("libxml", "libxml_rust"),
-        ("protoc_gen_rust", "protoc-gen-rust"),
-    ]
-    .into_iter()
-    .collect()
-});
-
-/// This map tracks Rust crates that have special rules.mk modules that were not
-/// generated automatically by this script. Examples include compiler builtins
-/// and other foundational libraries. It also tracks the location of rules.mk
-/// build files for crates that are not under external/rust/crates.
-pub static RULESMK_RENAME_MAP: Lazy<BTreeMap<&str, &str>> = Lazy::new(|| {
-    [
-        ("liballoc", "trusty/user/base/lib/liballoc-rust"),
-        ("libcompiler_builtins", "trusty/user/base/lib/libcompiler_builtins-rust"),
-        ("libcore", "trusty/user/base/lib/libcore-rust"),
-        ("libhashbrown", "trusty/user/base/lib/libhashbrown-rust"),
-        ("libpanic_abort", "trusty/user/base/lib/libpanic_abort-rust"),
-        ("libstd", "trusty/user/base/lib/libstd-rust"),
-        ("libstd_detect", "trusty/user/base/lib/libstd_detect-rust"),
-        ("libunwind", "trusty/user/base/lib/libunwind-rust"),
-    ]
-    .into_iter()
-    .collect()
-});
-
 /// Given a proposed module name, returns `None` if it is blocked by the given config, or
 /// else apply any name overrides and returns the name to use.
 fn override_module_name(
     module_name: &str,
     blocklist: &[String],
     module_name_overrides: &BTreeMap<String, String>,
-    rename_map: &BTreeMap<&str, &str>,
+    rename_map: &BTreeMap<String, String>,
 ) -> Option<String> {
     if blocklist.iter().any(|blocked_name| blocked_name == module_name) {
         None
@@ -121,6 +121,119 @@ fn override_module_name(
     }
 }
 
+/// Returns the given crate name with a suffix for the given version, e.g. "syn_1" for version
+/// "1.0.109", or "nom_0_7" for version "0.7.1".
+///
+/// The suffix includes parts of the version up to and including the first non-zero part, as those
+/// are the parts which Cargo treats as incompatible.
+fn versioned_name(name: &str, version: &str) -> String {
+    let parts: Vec<&str> = version.split(['-', '+']).next().unwrap().split('.').collect();
+    let significant = parts.iter().position(|part| *part != "0").map_or(parts.len(), |i| i + 1);
+    format!("{name}_{}", parts[..significant].join("_"))
+}
+
+/// Returns the name to use for the library modules of the given crate, with a version suffix if it
+/// is one of the `versioned_crates`.
+fn library_crate_name(crate_: &Crate, cfg: &VariantConfig) -> String {
+    match &crate_.version {
+        Some(version) if cfg.versioned_crates.contains(&crate_.name) => {
+            versioned_name(&crate_.name, version)
+        }
+        _ => crate_.name.clone(),
+    }
+}
+
+/// Returns the names of the libraries generated for the given crates of a variant, without the
+/// "lib" prefix.
+fn sibling_libraries(crates: &[Crate], cfg: &VariantConfig) -> BTreeSet<String> {
+    crates
+        .iter()
+        .filter(|crate_| {
+            crate_
+                .types
+                .iter()
+                .any(|crate_type| crate_type.is_library() || *crate_type == CrateType::ProcMacro)
+        })
+        .map(|crate_| library_crate_name(crate_, cfg))
+        .collect()
+}
+
+/// Adds the variant's `module_suffix`, if any, to the names of the given modules generated for the
+/// variant, and to references to them and to the `siblings` libraries generated for other packages
+/// in the same variant.
+fn apply_module_suffix(modules: &mut [BpModule], cfg: &VariantConfig, siblings: &BTreeSet<String>) {
+    let Some(suffix) = &cfg.module_suffix else {
+        return;
+    };
+    let mut renames = BTreeMap::new();
+    for sibling in siblings {
+        if let Some(module_name) = override_module_name(
+            &format!("lib{sibling}"),
+            &[],
+            &cfg.module_name_overrides,
+            &rename_registry().rename_map,
+        ) {
+            renames.insert(module_name.clone(), module_name + suffix);
+        }
+    }
+    for m in modules.iter() {
+        let name = m.props.get_string("name");
+        renames.insert(format!(":{name}"), format!(":{name}{suffix}"));
+        renames.insert(name.to_string(), format!("{name}{suffix}"));
+    }
+    for m in modules {
+        let name = m.props.get_string("name").to_string() + suffix;
+        m.props.set("name", name);
+        rename_references(&mut m.props, &renames);
+    }
+}
+
+/// Properties which may refer to other modules generated in the same variant.
+const REFERENCE_PROPERTIES: [&str; 8] = [
+    "rustlibs",
+    "proc_macros",
+    "static_libs",
+    "whole_static_libs",
+    "shared_libs",
+    "generated_headers",
+    "export_generated_headers",
+    "srcs",
+];
+
+/// Renames references to other modules in the dependency and source properties of the given
+/// properties, including those in `arch` and `target` blocks.
+fn rename_references(props: &mut BpProperties, renames: &BTreeMap<String, String>) {
+    for (property, value) in &mut props.map {
+        match value {
+            BpValue::List(values) if REFERENCE_PROPERTIES.contains(&property.as_str()) => {
+                for value in values {
+                    if let BpValue::String(reference) = value {
+                        if let Some(renamed) = renames.get(reference) {
+                            reference.clone_from(renamed);
+                        }
+                    }
+                }
+            }
+            BpValue::Object(block) if property == "arch" || property == "target" => {
+                for block_props in block.map.values_mut() {
+                    if let BpValue::Object(block_props) = block_props {
+                        rename_references(block_props, renames);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the name of the library which the given extern refers to, without the "lib" prefix.
+fn extern_library_name(extern_dep: &Extern) -> String {
+    match &extern_dep.version {
+        Some(version) => versioned_name(&extern_dep.lib_name, version),
+        None => extern_dep.lib_name.clone(),
+    }
+}
+
 /// Command-line parameters for `cargo_embargo`.
 #[derive(Parser, Debug)]
 struct Args {
@@ -134,6 +247,25 @@ struct Args {
     /// available. Requires setting --cargo_out_dir.
     #[clap(long)]
     reuse_cargo_out: bool,
+    /// Cache the output of cargo in this directory, keyed by a hash of its inputs, and reuse it
+    /// rather than running cargo again if the inputs haven't changed.
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// Run cargo with no network access and a read-only view of the source tree, in new user,
+    /// mount and network namespaces. Requires unprivileged user namespaces.
+    #[clap(long)]
+    sandbox: bool,
+    /// Check that the dependencies of generated modules exist in the Android tree, and warn about
+    /// any which are missing or the wrong kind of module.
+    #[clap(long)]
+    check_deps: bool,
+    /// Root of the Android tree to look for modules in. Defaults to `ANDROID_BUILD_TOP`.
+    #[clap(long)]
+    tree_root: Option<PathBuf>,
+    /// Rename registry JSON file to use in addition to the built-in one. Defaults to the nearest
+    /// `cargo_embargo_renames.json` in the current directory or its parents.
+    #[clap(long)]
+    rename_registry: Option<PathBuf>,
     #[command(subcommand)]
     mode: Mode,
 }
@@ -159,6 +291,47 @@ enum Mode {
         /// `cargo_embargo.json` config file to create.
         config: PathBuf,
     },
+    /// Tries to infer a `cargo_embargo.json` config file for the package in the current directory
+    /// from its existing hand-written `Android.bp`, and writes the remaining differences to a
+    /// `.diff` file next to it.
+    InferConfig {
+        /// `cargo_embargo.json` config file to create.
+        config: PathBuf,
+        /// Overwrite the config file and module block file if they already exist.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Converts the legacy `cargo2android.json` and/or `cargo2rulesmk.json` config files in the
+    /// current directory to a `cargo_embargo.json` config file.
+    Import {
+        /// `cargo_embargo.json` config file to create.
+        config: PathBuf,
+        /// Overwrite the config file if it already exists.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Checks whether the generated files under the current directory are up to date with the
+    /// given config, using their provenance stamps. Exits with status 1 if any are stale.
+    Status {
+        /// `cargo_embargo.json` config file to use.
+        config: PathBuf,
+    },
+    /// Explains where the values of the properties of the given generated module came from, and
+    /// what renamed or filtered them out.
+    Explain {
+        /// `cargo_embargo.json` config file to use.
+        config: PathBuf,
+        /// Name of the generated `Android.bp` module to explain, e.g. `libfoo`.
+        module: String,
+        /// Only explain this property of the module, e.g. `rustlibs`.
+        property: Option<String>,
+    },
+    /// Generates the C headers of the packages' FFI libraries with cbindgen, and checks that their
+    /// checked-in copies are up to date. Exits with status 1 if any are out of date.
+    CheckHeaders {
+        /// `cargo_embargo.json` config file to use.
+        config: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
@@ -168,6 +341,7 @@ fn main() -> Result<()> {
     if args.reuse_cargo_out && args.cargo_out_dir.is_none() {
         return Err(anyhow!("Must specify --cargo_out_dir with --reuse_cargo_out"));
     }
+    init_rename_registry(args.rename_registry.as_deref(), &env::current_dir()?)?;
     let tempdir = tempdir()?;
     let intermediates_dir = args.cargo_out_dir.as_deref().unwrap_or(tempdir.path());
 
@@ -181,8 +355,212 @@ fn main() -> Result<()> {
         Mode::Autoconfig { config } => {
             autoconfig(&args, config, intermediates_dir)?;
         }
+        Mode::InferConfig { config, force } => {
+            infer_config(&args, config, *force, intermediates_dir)?;
+        }
+        Mode::Import { config, force } => {
+            import(config, *force)?;
+        }
+        Mode::Status { config } => {
+            if !status(&args, config)? {
+                std::process::exit(1);
+            }
+        }
+        Mode::Explain { config, module, property } => {
+            explain(&args, config, module, property.as_deref(), intermediates_dir)?;
+        }
+        Mode::CheckHeaders { config } => {
+            if !check_headers(&args, config, intermediates_dir)? {
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns an error if the given file exists and `force` isn't set, so that subcommands which
+/// create files don't overwrite them by accident.
+fn check_overwrite(path: &Path, force: bool) -> Result<()> {
+    if !force && path.try_exists()? {
+        bail!("{path:?} already exists; pass --force to overwrite it");
+    }
+    Ok(())
+}
+
+/// Converts the legacy configs in the current directory to a `cargo_embargo.json` config file.
+fn import(config_filename: &Path, force: bool) -> Result<()> {
+    check_overwrite(config_filename, force)?;
+    let (config, warnings) = import::import_legacy_configs(&env::current_dir()?)?;
+    for warning in &warnings {
+        eprintln!("WARNING: {warning}");
+    }
+    let comments: String = warnings.iter().map(|warning| format!("// {warning}\n")).collect();
+    write(config_filename, format!("{comments}{}\n", config.to_json_string()?))?;
+    println!(
+        "Wrote config to {0}. Run `cargo_embargo generate {0}` to use it.",
+        config_filename.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Compares the provenance stamps of the generated files under the current directory with the
+/// current inputs, and prints whether each is up to date. Returns whether they all are.
+fn status(args: &Args, config_filename: &Path) -> Result<bool> {
+    // Compare against the toolchain which would be used to generate, if it can be found.
+    if let Err(e) = add_cargo_to_path(args) {
+        eprintln!("WARNING: {e:#}; comparing against the toolchain on the PATH");
+    }
+    let current_dir = env::current_dir()?;
+    let current = Provenance::current(config_filename, &current_dir)?;
+
+    let mut generated_files = Vec::new();
+    find_generated_files(&current_dir, &mut generated_files)?;
+    if generated_files.is_empty() {
+        bail!("No files generated by cargo_embargo found under {current_dir:?}");
+    }
+    let mut up_to_date = true;
+    for (path, contents) in generated_files {
+        let path = path.strip_prefix(&current_dir).unwrap_or(&path);
+        let Some(stamp) = Provenance::from_generated_file(&contents) else {
+            println!("{}: stale: no provenance stamp", path.display());
+            up_to_date = false;
+            continue;
+        };
+        let reasons = stamp.stale_reasons(&current);
+        if reasons.is_empty() {
+            println!("{}: up to date", path.display());
+        } else {
+            println!("{}: stale: {}", path.display(), reasons.join(", "));
+            up_to_date = false;
+        }
+    }
+    Ok(up_to_date)
+}
+
+/// Generates the C headers of the packages' FFI libraries with cbindgen, and compares them with their
+/// checked-in copies. Returns whether they are all up to date.
+fn check_headers(args: &Args, config_filename: &Path, intermediates_dir: &Path) -> Result<bool> {
+    let cfg = load_config(args, config_filename, intermediates_dir)?;
+    let crates = make_all_crates(args, &cfg, intermediates_dir)?;
+    cbindgen::check_headers(&cfg, &crates)
+}
+
+/// Appends the paths and contents of all `Android.bp` and `rules.mk` files generated by
+/// cargo_embargo in package directories under `dir` to `files`.
+fn find_generated_files(dir: &Path, files: &mut Vec<(PathBuf, String)>) -> Result<()> {
+    if dir.join("Cargo.toml").exists() {
+        for file_name in ["Android.bp", "rules.mk"] {
+            let path = dir.join(file_name);
+            if let Ok(contents) = read_to_string(&path) {
+                if contents
+                    .lines()
+                    .next()
+                    .is_some_and(|line| line.ends_with("This file is generated by cargo_embargo."))
+                {
+                    files.push((path, contents));
+                }
+            }
+        }
     }
+    for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read {dir:?}"))? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if entry.file_type()?.is_dir()
+            && ![".git", "out", "target", "target.tmp"]
+                .contains(&file_name.to_string_lossy().as_ref())
+        {
+            find_generated_files(&entry.path(), files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints where the values of the properties of the given generated module came from, for each
+/// variant which generates it.
+fn explain(
+    args: &Args,
+    config_filename: &Path,
+    module_name: &str,
+    property: Option<&str>,
+    intermediates_dir: &Path,
+) -> Result<()> {
+    let cfg = load_config(args, config_filename, intermediates_dir)?;
+    let crates = make_all_crates(args, &cfg, intermediates_dir)?;
+    let package_out_files = find_package_out_files(&cfg, intermediates_dir)?;
+    let siblings: Vec<BTreeSet<String>> = cfg
+        .variants
+        .iter()
+        .zip(&crates)
+        .map(|(variant_cfg, variant_crates)| sibling_libraries(variant_crates, variant_cfg))
+        .collect();
 
+    let mut found = false;
+    let mut other_modules = BTreeSet::new();
+    let def = PackageVariantConfig::default();
+    let empty_out_files = vec![vec![]; cfg.variants.len()];
+    for crates in group_by_package(crates).into_values() {
+        let package_name = &crates.iter().flatten().next().unwrap().package_name;
+        let out_files = package_out_files.get(package_name).unwrap_or(&empty_out_files);
+        for (variant_index, variant_cfg) in cfg.variants.iter().enumerate() {
+            if !variant_cfg.generate_androidbp || crates[variant_index].is_empty() {
+                continue;
+            }
+            let package_cfg = variant_cfg.package.get(package_name).unwrap_or(&def);
+            let modules = generate_android_bp_modules_with_sources(
+                variant_cfg,
+                package_cfg,
+                package_name,
+                &crates[variant_index],
+                &out_files[variant_index],
+                &siblings[variant_index],
+            )?;
+            let option_modules: BTreeMap<String, &'static str> = modules
+                .iter()
+                .filter_map(|(module, source)| match source {
+                    ModuleSource::ConfigOption(option) => {
+                        Some((module.props.get_string("name").to_string(), *option))
+                    }
+                    ModuleSource::Crates(..) => None,
+                })
+                .collect();
+            for (module, source) in &modules {
+                let name = module.props.get_string("name");
+                if name != module_name {
+                    other_modules.insert(name.to_string());
+                    continue;
+                }
+                found = true;
+                println!(
+                    "{module_name} ({}) in variant {}:",
+                    module.module_type,
+                    variant_index + 1
+                );
+                let explanations =
+                    explain_module(module, source, &option_modules, variant_cfg, package_cfg);
+                let description = explanations.describe(property);
+                if description.is_empty() {
+                    println!("  no explanation for {}", property.unwrap_or("any property"));
+                }
+                for line in description.lines() {
+                    println!("  {line}");
+                }
+            }
+        }
+    }
+    if !found {
+        if cfg
+            .variants
+            .iter()
+            .any(|variant| variant.module_blocklist.iter().any(|m| m == module_name))
+        {
+            bail!("Module {module_name:?} is removed by `module_blocklist`");
+        }
+        bail!(
+            "No module {module_name:?} is generated. Generated modules are: {}",
+            other_modules.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
     Ok(())
 }
 
@@ -194,8 +572,7 @@ fn dump_crates(
     crates_filename: &Path,
     intermediates_dir: &Path,
 ) -> Result<()> {
-    let cfg = Config::from_file(config_filename)?;
-    let crates = make_all_crates(args, &cfg, intermediates_dir)?;
+    let (_, crates) = load_crates(args, config_filename, intermediates_dir)?;
     serde_json::to_writer(
         File::create(crates_filename)
             .with_context(|| format!("Failed to create {:?}", crates_filename))?,
@@ -204,13 +581,49 @@ fn dump_crates(
     Ok(())
 }
 
+/// Loads the config from the given file and makes the crates for each of its variants, warning
+/// about problems with the config and, with `--check-deps`, with the dependencies of the modules
+/// which will be generated.
+fn load_crates(
+    args: &Args,
+    config_filename: &Path,
+    intermediates_dir: &Path,
+) -> Result<(Config, Vec<Vec<Crate>>)> {
+    let cfg = load_config(args, config_filename, intermediates_dir)?;
+    for variant_cfg in &cfg.variants {
+        rename_registry().check_overrides(&variant_cfg.module_name_overrides)?;
+    }
+    let crates = make_all_crates(args, &cfg, intermediates_dir)?;
+
+    let build_scripts = read_build_scripts(intermediates_dir)?;
+    for variant_cfg in &cfg.variants {
+        for problem in check_config(variant_cfg, &build_scripts) {
+            eprintln!("WARNING: {problem}");
+        }
+    }
+
+    if args.check_deps {
+        let tree_root = tree_root(args)
+            .context("--check-deps needs --tree-root or ANDROID_BUILD_TOP to be set")?;
+        let tree_index = TreeIndex::from_tree(&tree_root)?;
+        for problem in check_generated_deps(&cfg, &crates, &tree_index) {
+            eprintln!("WARNING: {problem}");
+        }
+    }
+    Ok((cfg, crates))
+}
+
 /// Tries to automatically generate a suitable `cargo_embargo.json` for the package in the current
 /// directory.
 fn autoconfig(args: &Args, config_filename: &Path, intermediates_dir: &Path) -> Result<()> {
+    // Reasons for the choices made, to be written as comments in the config file.
+    let mut notes = Vec::new();
+
     println!("Trying default config with tests...");
     let mut config_with_build = Config {
         variants: vec![VariantConfig { tests: true, ..Default::default() }],
         package: Default::default(),
+        provenance: false,
     };
     let mut crates_with_build = make_all_crates(args, &config_with_build, intermediates_dir)?;
 
@@ -218,8 +631,12 @@ fn autoconfig(args: &Args, config_filename: &Path, intermediates_dir: &Path) ->
         crates_with_build[0].iter().any(|c| c.types.contains(&CrateType::Test) && !c.empty_test);
     if !has_tests {
         println!("No tests, removing from config.");
-        config_with_build =
-            Config { variants: vec![Default::default()], package: Default::default() };
+        notes.push("There are no tests, so tests are disabled.".to_string());
+        config_with_build = Config {
+            variants: vec![Default::default()],
+            package: Default::default(),
+            provenance: false,
+        };
         crates_with_build = make_all_crates(args, &config_with_build, intermediates_dir)?;
     }
 
@@ -227,19 +644,86 @@ fn autoconfig(args: &Args, config_filename: &Path, intermediates_dir: &Path) ->
     let config_no_build = Config {
         variants: vec![VariantConfig { run_cargo: false, tests: has_tests, ..Default::default() }],
         package: Default::default(),
+        provenance: false,
     };
     let crates_without_build = make_all_crates(args, &config_no_build, intermediates_dir)?;
 
-    let config = if crates_with_build == crates_without_build {
+    let build_scripts = read_build_scripts(intermediates_dir)?;
+    let build_scripts_needing_cargo: Vec<_> = build_scripts
+        .iter()
+        .filter(|(_, build_script)| {
+            build_script.needs_cargo() && !build_script.replaceable_by_modules()
+        })
+        .collect();
+
+    let (mut config, crates) = if !build_scripts_needing_cargo.is_empty() {
+        println!(
+            "Build scripts have effects which cargo metadata misses. Need to run cargo build."
+        );
+        for (package_name, build_script) in build_scripts_needing_cargo {
+            notes.push(format!(
+                "The build script of {package_name} {}, so cargo build is run.",
+                build_script.describe()
+            ));
+        }
+        (config_with_build, crates_with_build)
+    } else if same_crates(&crates_with_build, &crates_without_build) {
         println!("Output without build was the same, using that.");
-        config_no_build
+        notes.push(
+            "The output from cargo metadata is the same as from cargo build, so cargo build isn't \
+             run."
+                .to_string(),
+        );
+        (config_no_build, crates_without_build)
     } else {
         println!("Output without build was different. Need to run cargo build.");
         println!("With build: {}", serde_json::to_string_pretty(&crates_with_build)?);
         println!("Without build: {}", serde_json::to_string_pretty(&crates_without_build)?);
-        config_with_build
+        notes.push(
+            "The output from cargo metadata is different to cargo build, so cargo build is run."
+                .to_string(),
+        );
+        (config_with_build, crates_with_build)
     };
-    write(config_filename, format!("{}\n", config.to_json_string()?))?;
+
+    let tree_index = if let Some(tree_root) = tree_root(args) {
+        println!("Indexing modules under {tree_root:?}...");
+        Some(TreeIndex::from_tree(&tree_root)?)
+    } else {
+        notes.push(
+            "ANDROID_BUILD_TOP isn't set, so dependencies weren't checked against the tree."
+                .to_string(),
+        );
+        None
+    };
+
+    let variant = &mut config.variants[0];
+    let crates = &crates[0];
+    autoconfig::configure_bindgen(variant, &build_scripts, &mut notes);
+    autoconfig::configure_protobuf(variant, &build_scripts, &mut notes);
+    autoconfig::configure_copy_out(variant, crates, &build_scripts, &mut notes)?;
+    autoconfig::configure_host_only(variant, crates, tree_index.as_ref(), &mut notes);
+    if let Some(tree_index) = &tree_index {
+        autoconfig::configure_deps(variant, crates, tree_index, &mut notes);
+    }
+    autoconfig::configure_test_data(variant, crates, &mut notes)?;
+    if let Some(no_std_variant) = autoconfig::configure_no_std(variant, crates, &mut notes)? {
+        println!("Trying no_std variant...");
+        match make_crates(args, &no_std_variant, intermediates_dir) {
+            Ok(_) => config.variants.push(no_std_variant),
+            Err(e) => {
+                println!("no_std variant failed: {e:#}");
+                notes.push(
+                    "The no_std variant failed to build, so it was left out. It may need a \
+                     different set of features."
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    let comments: String = notes.iter().map(|note| format!("// {note}\n")).collect();
+    write(config_filename, format!("{comments}{}\n", config.to_json_string()?))?;
     println!(
         "Wrote config to {0}. Run `cargo_embargo generate {0}` to use it.",
         config_filename.to_string_lossy()
@@ -248,6 +732,77 @@ fn autoconfig(args: &Args, config_filename: &Path, intermediates_dir: &Path) ->
     Ok(())
 }
 
+/// Tries to infer a `cargo_embargo.json` for the package in the current directory from its existing
+/// `Android.bp`. Existing files are only overwritten if `force` is set.
+fn infer_config(
+    args: &Args,
+    config_filename: &Path,
+    force: bool,
+    intermediates_dir: &Path,
+) -> Result<()> {
+    let diff_filename = config_filename.with_extension("diff");
+    check_overwrite(config_filename, force)?;
+    check_overwrite(&diff_filename, force)?;
+    let existing = parse_blueprint(
+        &read_to_string("Android.bp").context("failed to read Android.bp in current directory")?,
+    )
+    .context("failed to parse Android.bp")?;
+    // Reasons for the choices made, to be written as comments in the config file.
+    let mut notes = Vec::new();
+
+    println!("Running cargo...");
+    let mut config = Config {
+        variants: vec![VariantConfig { tests: true, ..Default::default() }],
+        package: Default::default(),
+        provenance: false,
+    };
+    let mut crates = make_all_crates(args, &config, intermediates_dir)?.remove(0);
+    if let Some(features) = infer_config::existing_features(&existing, &crates) {
+        println!("Running cargo again with features {features:?}...");
+        config.variants[0].features = Some(features);
+        crates = make_all_crates(args, &config, intermediates_dir)?.remove(0);
+        notes.push("The features are taken from the existing library module.".to_string());
+    }
+
+    println!("Searching for options...");
+    let variant = &mut config.variants[0];
+    infer_config::infer_options(&existing, &crates, variant, &mut notes)?;
+    if let Some(module_block) = infer_config::residual_module_block(&existing, &crates, variant)? {
+        let module_block_path = PathBuf::from(MODULE_BLOCK_FILENAME);
+        check_overwrite(&module_block_path, force)?;
+        write(&module_block_path, module_block + "\n")?;
+        let package_name = &crates[0].package_name;
+        variant.package.entry(package_name.clone()).or_default().add_module_block =
+            Some(module_block_path);
+        notes.push(format!(
+            "Properties of all the existing modules which can't be expressed by other options are \
+             in {MODULE_BLOCK_FILENAME}."
+        ));
+    }
+    let generated = infer_config::generate_modules(variant, &crates)?;
+    let diff = infer_config::residual_diff(&existing, &generated)?;
+
+    let comments: String = notes.iter().map(|note| format!("// {note}\n")).collect();
+    write(config_filename, format!("{comments}{}\n", config.to_json_string()?))?;
+    println!("Wrote config to {config_filename:?}.");
+    if diff.is_empty() {
+        // Don't leave the differences from an earlier run behind.
+        if diff_filename.try_exists()? {
+            std::fs::remove_file(&diff_filename)?;
+        }
+        println!("The generated modules match the existing Android.bp.");
+    } else {
+        write(&diff_filename, diff)?;
+        println!("Wrote remaining differences from the existing Android.bp to {diff_filename:?}.");
+    }
+    Ok(())
+}
+
+/// Returns the root of the Android tree, from the command line or environment, if known.
+fn tree_root(args: &Args) -> Option<PathBuf> {
+    args.tree_root.clone().or_else(|| env::var_os("ANDROID_BUILD_TOP").map(PathBuf::from))
+}
+
 /// Finds the path to the directory containing the Android prebuilt Rust toolchain.
 fn find_android_rust_toolchain() -> Result<PathBuf> {
     let platform_rustfmt = if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
@@ -285,60 +840,196 @@ fn make_all_crates(args: &Args, cfg: &Config, intermediates_dir: &Path) -> Resul
     cfg.variants.iter().map(|variant| make_crates(args, variant, intermediates_dir)).collect()
 }
 
-fn make_crates(args: &Args, cfg: &VariantConfig, intermediates_dir: &Path) -> Result<Vec<Crate>> {
-    if !Path::new("Cargo.toml").try_exists().context("when checking Cargo.toml")? {
-        bail!("Cargo.toml missing. Run in a directory with a Cargo.toml file.");
+/// Returns whether the given crates for each variant have the same values, regardless of where they
+/// came from.
+fn same_crates(a: &[Vec<Crate>], b: &[Vec<Crate>]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.same_values(b)))
+}
+
+/// Classifies the build scripts of the packages in the `cargo metadata` output written to the
+/// intermediates directory by the last call to `make_crates`.
+fn read_build_scripts(intermediates_dir: &Path) -> Result<BTreeMap<String, BuildScript>> {
+    let cargo_metadata_path = intermediates_dir.join("cargo.metadata");
+    let cargo_metadata = read_to_string(&cargo_metadata_path)
+        .with_context(|| format!("failed to read {cargo_metadata_path:?}"))?;
+    classify_build_scripts(&cargo_metadata)
+}
+
+/// Returns the directory containing the cargo and rustc binaries to use.
+fn toolchain_dir(args: &Args) -> Result<PathBuf> {
+    if let Some(cargo_bin) = &args.cargo_bin {
+        Ok(cargo_bin.to_owned())
+    } else {
+        // Find the Android prebuilt.
+        find_android_rust_toolchain()
     }
+}
 
-    // Add the custom cargo to PATH.
+/// Adds the directory containing the cargo binary to use to the `PATH`.
+fn add_cargo_to_path(args: &Args) -> Result<()> {
     // NOTE: If the directory with cargo has more binaries, this could have some unpredictable side
     // effects. That is partly intended though, because we want to use that cargo binary's
     // associated rustc.
-    let cargo_bin = if let Some(cargo_bin) = &args.cargo_bin {
-        cargo_bin.to_owned()
+    add_to_path(toolchain_dir(args)?)
+}
+
+/// Loads the config from the given JSON file, with defaults from any `cargo_embargo` tables in the
+/// `Cargo.toml` metadata of the crate in the current directory.
+///
+/// With `--reuse-cargo-out`, the metadata is read from the `cargo metadata` output saved in the
+/// intermediates directory rather than running cargo.
+fn load_config(args: &Args, config_filename: &Path, intermediates_dir: &Path) -> Result<Config> {
+    let saved_metadata_path = intermediates_dir.join("cargo.metadata");
+    let defaults = if args.reuse_cargo_out && saved_metadata_path.exists() {
+        config_from_cargo_metadata(&read_to_string(saved_metadata_path)?)?
+    } else if Path::new("Cargo.toml").try_exists().context("when checking Cargo.toml")? {
+        add_cargo_to_path(args)?;
+        let cargo_metadata = run_cargo(Command::new("cargo").args([
+            "metadata",
+            "-q",
+            "--format-version",
+            "1",
+            "--no-deps",
+        ]))?;
+        config_from_cargo_metadata(&cargo_metadata)?
     } else {
-        // Find the Android prebuilt.
-        find_android_rust_toolchain()?
+        serde_json::Map::new()
     };
-    add_to_path(cargo_bin)?;
+    Config::from_file_with_defaults(config_filename, &defaults)
+}
+
+fn make_crates(args: &Args, cfg: &VariantConfig, intermediates_dir: &Path) -> Result<Vec<Crate>> {
+    if !Path::new("Cargo.toml").try_exists().context("when checking Cargo.toml")? {
+        bail!("Cargo.toml missing. Run in a directory with a Cargo.toml file.");
+    }
 
     let cargo_out_path = intermediates_dir.join("cargo.out");
     let cargo_metadata_path = intermediates_dir.join("cargo.metadata");
+    let fuzz_metadata_path = intermediates_dir.join("cargo.fuzz_metadata");
     let cargo_output = if args.reuse_cargo_out && cargo_out_path.exists() {
         CargoOutput {
             cargo_out: read_to_string(cargo_out_path)?,
             cargo_metadata: read_to_string(cargo_metadata_path)?,
+            fuzz_metadata: read_fuzz_metadata(&fuzz_metadata_path)?,
         }
     } else {
-        let cargo_output =
-            generate_cargo_out(cfg, intermediates_dir).context("generate_cargo_out failed")?;
+        add_cargo_to_path(args)?;
+        let mut profile = CargoProfile::new(cfg, intermediates_dir, DEFAULT_TARGET)?;
+        if args.sandbox {
+            let source_dir = env::current_dir()?;
+            let mut read_only_dirs = vec![source_dir.as_path()];
+            if let Some(vendored_crates_dir) = &cfg.vendored_crates_dir {
+                read_only_dirs.push(vendored_crates_dir);
+            }
+            profile = profile.sandboxed(Sandbox::new(&read_only_dirs, &[intermediates_dir])?);
+        }
+        let cache = match &args.cache_dir {
+            Some(cache_dir) if is_cacheable(cfg) => {
+                let key = cache_key(
+                    cfg,
+                    &env::current_dir()?.canonicalize()?,
+                    &toolchain_version()?,
+                    profile.effective_env(),
+                )?;
+                Some((CargoCache::new(cache_dir), key))
+            }
+            _ => None,
+        };
+        let cached_output = if let Some((cache, key)) = &cache { cache.get(key)? } else { None };
+        let cargo_output = if let Some(cargo_output) = cached_output {
+            debug!("Using cached cargo output");
+            cargo_output
+        } else {
+            let cargo_output = generate_cargo_out(cfg, &profile, intermediates_dir);
+            if profile.is_sandboxed() {
+                report_sandbox_violations(&cargo_output);
+            }
+            let cargo_output = cargo_output.context("generate_cargo_out failed")?;
+            if let Some((cache, key)) = &cache {
+                cache.put(key, &cargo_output)?;
+            }
+            cargo_output
+        };
         if cfg.run_cargo {
             write(cargo_out_path, &cargo_output.cargo_out)?;
         }
         write(cargo_metadata_path, &cargo_output.cargo_metadata)?;
+        write(fuzz_metadata_path, serde_json::to_string(&cargo_output.fuzz_metadata)?)?;
         cargo_output
     };
 
-    if cfg.run_cargo {
-        parse_cargo_out(&cargo_output).context("parse_cargo_out failed")
+    let mut crates = if cfg.run_cargo {
+        parse_cargo_out(&cargo_output, &cfg.versioned_crates).context("parse_cargo_out failed")?
     } else {
-        parse_cargo_metadata_str(&cargo_output.cargo_metadata, cfg)
+        let static_cfgs = static_cfgs(&cargo_output.cargo_metadata, || {
+            RustcVersion::of_toolchain(&toolchain_dir(args)?)
+        })?;
+        parse_cargo_metadata_str(&cargo_output.cargo_metadata, cfg, &static_cfgs)?
+    };
+    crates.extend(fuzz_crates(cfg, &crates, &cargo_output.fuzz_metadata)?);
+    Ok(crates)
+}
+
+/// Reads the fuzz metadata written to the given file by an earlier run, or returns none if there is
+/// no such file, e.g. because it was written by an older version of cargo_embargo.
+fn read_fuzz_metadata(path: &Path) -> Result<BTreeMap<String, String>> {
+    match read_to_string(path) {
+        Ok(contents) => {
+            serde_json::from_str(&contents).with_context(|| format!("failed to parse {path:?}"))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {path:?}")),
     }
 }
 
 /// Runs cargo_embargo with the given JSON configuration file.
 fn run_embargo(args: &Args, config_filename: &Path, intermediates_dir: &Path) -> Result<()> {
+    let (cfg, crates) = load_crates(args, config_filename, intermediates_dir)?;
+
+    let package_out_files = find_package_out_files(&cfg, intermediates_dir)?;
+
+    // If we were configured to run cargo, check whether we could have got away without it.
+    if cfg.variants.iter().any(|variant| variant.run_cargo) && package_out_files.is_empty() {
+        let mut cfg_no_cargo = cfg.clone();
+        for variant in &mut cfg_no_cargo.variants {
+            variant.run_cargo = false;
+        }
+        let crates_no_cargo = make_all_crates(args, &cfg_no_cargo, intermediates_dir)?;
+        if same_crates(&crates_no_cargo, &crates) {
+            eprintln!("Running cargo appears to be unnecessary for this crate, consider adding `\"run_cargo\": false` to your cargo_embargo.json.");
+        }
+    }
+
+    let provenance = if cfg.provenance {
+        Some(Provenance::current(config_filename, &env::current_dir()?)?)
+    } else {
+        None
+    };
+    write_all_build_files(
+        &cfg,
+        crates,
+        &package_out_files,
+        provenance.as_ref(),
+        tree_root(args).as_deref(),
+    )
+}
+
+/// Finds the files generated by build scripts under the given intermediates directory, for packages
+/// with `copy_out` enabled, grouped by package name and variant.
+fn find_package_out_files(
+    cfg: &Config,
+    intermediates_dir: &Path,
+) -> Result<BTreeMap<String, Vec<Vec<PathBuf>>>> {
     let intermediates_glob = intermediates_dir
         .to_str()
         .ok_or(anyhow!("Failed to convert intermediate dir path to string"))?
         .to_string()
         + "target.tmp/**/build/*/out/*";
 
-    let cfg = Config::from_file(config_filename)?;
-    let crates = make_all_crates(args, &cfg, intermediates_dir)?;
-
     // TODO: Use different directories for different variants.
-    // Find out files.
     // Example: target.tmp/x86_64-unknown-linux-gnu/debug/build/metrics-d2dd799cebf1888d/out/event_details.rs
     let num_variants = cfg.variants.len();
     let mut package_out_files: BTreeMap<String, Vec<Vec<PathBuf>>> = BTreeMap::new();
@@ -362,20 +1053,40 @@ fn run_embargo(args: &Args, config_filename: &Path, intermediates_dir: &Path) ->
             }
         }
     }
+    Ok(package_out_files)
+}
 
-    // If we were configured to run cargo, check whether we could have got away without it.
-    if cfg.variants.iter().any(|variant| variant.run_cargo) && package_out_files.is_empty() {
-        let mut cfg_no_cargo = cfg.clone();
-        for variant in &mut cfg_no_cargo.variants {
-            variant.run_cargo = false;
-        }
-        let crates_no_cargo = make_all_crates(args, &cfg_no_cargo, intermediates_dir)?;
-        if crates_no_cargo == crates {
-            eprintln!("Running cargo appears to be unnecessary for this crate, consider adding `\"run_cargo\": false` to your cargo_embargo.json.");
+/// Checks the dependencies of the Android.bp modules which will be generated for the given crates
+/// against the tree, and returns a description of each problem found.
+fn check_generated_deps(
+    cfg: &Config,
+    crates: &[Vec<Crate>],
+    tree_index: &TreeIndex,
+) -> BTreeSet<String> {
+    let mut problems = BTreeSet::new();
+    for (variant_cfg, variant_crates) in cfg.variants.iter().zip(crates) {
+        if !variant_cfg.generate_androidbp {
+            continue;
         }
+        let def = PackageVariantConfig::default();
+        let mut modules: Vec<BpModule> = variant_crates
+            .iter()
+            .flat_map(|c| {
+                let package_cfg = variant_cfg.package.get(&c.package_name).unwrap_or(&def);
+                // Errors will be reported when the modules are generated for real.
+                crate_to_bp_modules(c, variant_cfg, package_cfg, &[]).unwrap_or_default()
+            })
+            .collect();
+        apply_module_suffix(
+            &mut modules,
+            variant_cfg,
+            &sibling_libraries(variant_crates, variant_cfg),
+        );
+        problems.extend(
+            dep_check::check_deps(&modules, tree_index).iter().map(|problem| problem.to_string()),
+        );
     }
-
-    write_all_build_files(&cfg, crates, &package_out_files)
+    problems
 }
 
 /// Input is indexed by variant, then all crates for that variant.
@@ -400,7 +1111,15 @@ fn write_all_build_files(
     cfg: &Config,
     crates: Vec<Vec<Crate>>,
     package_out_files: &BTreeMap<String, Vec<Vec<PathBuf>>>,
+    provenance: Option<&Provenance>,
+    tree_root: Option<&Path>,
 ) -> Result<()> {
+    let siblings: Vec<BTreeSet<String>> = cfg
+        .variants
+        .iter()
+        .zip(&crates)
+        .map(|(variant_cfg, variant_crates)| sibling_libraries(variant_crates, variant_cfg))
+        .collect();
     // Group by package.
     let module_by_package = group_by_package(crates);
 
@@ -416,6 +1135,9 @@ fn write_all_build_files(
             package_dir,
             &crates,
             package_out_files.get(package_name).unwrap_or(&empty_package_out_files),
+            &siblings,
+            provenance,
+            tree_root,
         ) {
             // print the error, but continue to accumulate all of the errors
             eprintln!("ERROR: {:#}", e);
@@ -461,18 +1183,43 @@ fn run_cargo(cmd: &mut Command) -> Result<String> {
 pub struct CargoOutput {
     cargo_metadata: String,
     cargo_out: String,
+    /// The `cargo metadata` output for the cargo-fuzz package of each package with `fuzzers` set,
+    /// keyed by package name.
+    fuzz_metadata: BTreeMap<String, String>,
+}
+
+/// Prints a warning for each line of cargo output which looks like the result of a sandbox
+/// violation, whether or not cargo failed.
+fn report_sandbox_violations(cargo_output: &Result<CargoOutput>) {
+    let output = match cargo_output {
+        Ok(cargo_output) => cargo_output.cargo_out.clone(),
+        Err(e) => format!("{e:#}"),
+    };
+    for violation in find_violations(&output) {
+        eprintln!("WARNING: sandbox violation: {violation}");
+    }
 }
 
 /// Run various cargo commands and returns the output.
-fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<CargoOutput> {
+fn generate_cargo_out(
+    cfg: &VariantConfig,
+    profile: &CargoProfile,
+    intermediates_dir: &Path,
+) -> Result<CargoOutput> {
     let verbose_args = ["-v"];
     let target_dir = intermediates_dir.join("target.tmp");
 
+    // The source tree is read-only in the sandbox, so cargo can't create a lockfile there.
+    // Resolving dependencies doesn't run any build scripts, so it is done outside.
+    if profile.is_sandboxed() && !Path::new("Cargo.lock").exists() {
+        run_cargo(profile.unsandboxed_command().arg("generate-lockfile"))
+            .context("Running cargo generate-lockfile")?;
+    }
+
     // cargo clean
-    run_cargo(Command::new("cargo").arg("clean").arg("--target-dir").arg(&target_dir))
+    run_cargo(profile.command()?.arg("clean").arg("--target-dir").arg(&target_dir))
         .context("Running cargo clean")?;
 
-    let default_target = "x86_64-unknown-linux-gnu";
     let feature_args = if let Some(features) = &cfg.features {
         if features.is_empty() {
             vec!["--no-default-features".to_string()]
@@ -498,7 +1245,8 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
 
     // cargo metadata
     let cargo_metadata = run_cargo(
-        Command::new("cargo")
+        profile
+            .command()?
             .arg("metadata")
             .arg("-q") // don't output warnings to stderr
             .arg("--format-version")
@@ -506,27 +1254,15 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
             .args(&feature_args),
     )
     .context("Running cargo metadata")?;
+    let fuzz_metadata = fuzz_metadata(cfg, profile, &cargo_metadata)?;
 
     let mut cargo_out = String::new();
     if cfg.run_cargo {
-        let envs = if cfg.extra_cfg.is_empty() {
-            vec![]
-        } else {
-            vec![(
-                "RUSTFLAGS",
-                cfg.extra_cfg
-                    .iter()
-                    .map(|cfg_flag| format!("--cfg {}", cfg_flag))
-                    .collect::<Vec<_>>()
-                    .join(" "),
-            )]
-        };
-
         // cargo build
         cargo_out += &run_cargo(
-            Command::new("cargo")
-                .envs(envs.clone())
-                .args(["build", "--target", default_target])
+            profile
+                .command()?
+                .args(["build", "--target", DEFAULT_TARGET])
                 .args(verbose_args)
                 .arg("--target-dir")
                 .arg(&target_dir)
@@ -537,9 +1273,9 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
         if cfg.tests {
             // cargo build --tests
             cargo_out += &run_cargo(
-                Command::new("cargo")
-                    .envs(envs.clone())
-                    .args(["build", "--target", default_target, "--tests"])
+                profile
+                    .command()?
+                    .args(["build", "--target", DEFAULT_TARGET, "--tests"])
                     .args(verbose_args)
                     .arg("--target-dir")
                     .arg(&target_dir)
@@ -548,9 +1284,9 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
             )?;
             // cargo test -- --list
             cargo_out += &run_cargo(
-                Command::new("cargo")
-                    .envs(envs)
-                    .args(["test", "--target", default_target])
+                profile
+                    .command()?
+                    .args(["test", "--target", DEFAULT_TARGET])
                     .arg("--target-dir")
                     .arg(&target_dir)
                     .args(&workspace_args)
@@ -560,48 +1296,58 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
         }
     }
 
-    Ok(CargoOutput { cargo_metadata, cargo_out })
+    Ok(CargoOutput { cargo_metadata, cargo_out, fuzz_metadata })
 }
 
+/// Placeholder license TODO line for new `rules.mk` files, which have no license modules.
+const LICENSE_PLACEHOLDER: &str = "// DO NOT SUBMIT: Add license before submitting.\n";
+
 /// Read and return license and other header lines from a build file.
 ///
 /// Skips initial comment lines, then returns all lines before the first line
 /// starting with `rust_`, `genrule {`, or `LOCAL_DIR`.
 ///
-/// If `path` could not be read, return a placeholder license TODO line.
-fn read_license_header(path: &Path) -> Result<String> {
+/// If `path` doesn't exist, return `None`.
+fn read_license_header(path: &Path) -> Result<Option<String>> {
     // Keep the old license header.
     match std::fs::read_to_string(path) {
-        Ok(s) => Ok(s
-            .lines()
-            .skip_while(|l| l.starts_with("//") || l.starts_with('#'))
-            .take_while(|l| {
-                !l.starts_with("rust_")
-                    && !l.starts_with("genrule {")
-                    && !l.starts_with("LOCAL_DIR")
-            })
-            .collect::<Vec<&str>>()
-            .join("\n")),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            Ok("// DO NOT SUBMIT: Add license before submitting.\n".to_string())
-        }
+        Ok(s) => Ok(Some(
+            s.lines()
+                .skip_while(|l| l.starts_with("//") || l.starts_with('#'))
+                .take_while(|l| {
+                    !l.starts_with("rust_")
+                        && !l.starts_with("genrule {")
+                        && !l.starts_with("LOCAL_DIR")
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
         Err(e) => Err(anyhow!("error when reading {path:?}: {e}")),
     }
 }
 
 /// Create the build file for `package_dir`.
 ///
-/// `crates` and `out_files` are both indexed by variant.
+/// `crates`, `out_files` and `siblings` are all indexed by variant. `siblings` are the names of the
+/// libraries generated for all packages in each variant. If `provenance` is given then it is
+/// stamped in the header comment. `tree_root` is used to name the license module of a new
+/// `Android.bp`.
+#[allow(clippy::too_many_arguments)]
 fn write_build_files(
     cfg: &Config,
     package_name: &str,
     package_dir: PathBuf,
     crates: &[Vec<Crate>],
     out_files: &[Vec<PathBuf>],
+    siblings: &[BTreeSet<String>],
+    provenance: Option<&Provenance>,
+    tree_root: Option<&Path>,
 ) -> Result<()> {
     assert_eq!(crates.len(), out_files.len());
+    let first_crate = crates.iter().flatten().next().context("package has no crates")?;
 
-    let mut bp_contents = String::new();
+    let mut bp_modules = Vec::new();
     let mut mk_contents = String::new();
     for (variant_index, variant_config) in cfg.variants.iter().enumerate() {
         let variant_crates = &crates[variant_index];
@@ -623,13 +1369,15 @@ fn write_build_files(
         }
 
         if variant_config.generate_androidbp {
-            bp_contents += &generate_android_bp(
+            let modules = generate_android_bp_modules(
                 variant_config,
                 package_cfg,
                 package_name,
                 variant_crates,
                 &out_files[variant_index],
+                &siblings[variant_index],
             )?;
+            merge::add_variant_modules(&mut bp_modules, modules, variant_index)?;
         }
         if variant_config.generate_rulesmk {
             mk_contents += &generate_rules_mk(
@@ -642,6 +1390,11 @@ fn write_build_files(
         }
     }
 
+    let mut bp_contents = String::new();
+    for (m, _) in bp_modules {
+        m.write(&mut bp_contents)?;
+        bp_contents += "\n";
+    }
     let def = PackageConfig::default();
     let package_cfg = cfg.package.get(package_name).unwrap_or(&def);
     if let Some(path) = &package_cfg.add_toplevel_block {
@@ -649,13 +1402,28 @@ fn write_build_files(
             &std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
         bp_contents += "\n";
     }
+    if package_cfg.add_metadata_files {
+        PackageLicense::new(&first_crate.package_info, &package_dir)?.write_metadata_files(
+            &first_crate.package_info,
+            package_name,
+            first_crate.version.as_deref(),
+            &package_dir,
+        )?;
+    }
     if !bp_contents.is_empty() {
         let output_path = package_dir.join("Android.bp");
+        let license_header = match read_license_header(&output_path)? {
+            Some(license_header) => license_header,
+            None => PackageLicense::new(&first_crate.package_info, &package_dir)?
+                .android_bp_header(&license_module_name(package_name, &package_dir, tree_root))?,
+        };
         let bp_contents = "// This file is generated by cargo_embargo.\n".to_owned()
             + "// Do not modify this file after the first \"rust_*\" or \"genrule\" module\n"
             + "// because the changes will be overridden on upgrade.\n"
-            + "// Content before the first \"rust_*\" or \"genrule\" module is preserved.\n\n"
-            + read_license_header(&output_path)?.trim()
+            + "// Content before the first \"rust_*\" or \"genrule\" module is preserved.\n"
+            + &provenance.map(|provenance| provenance.to_comment("//")).unwrap_or_default()
+            + "\n"
+            + license_header.trim()
             + "\n"
             + &bp_contents;
         write_format_android_bp(&output_path, &bp_contents, package_cfg.patch.as_deref())?;
@@ -666,7 +1434,8 @@ fn write_build_files(
             + "# Do not modify this file after the LOCAL_DIR line\n"
             + "# because the changes will be overridden on upgrade.\n"
             + "# Content before the first line starting with LOCAL_DIR is preserved.\n"
-            + read_license_header(&output_path)?.trim()
+            + &provenance.map(|provenance| provenance.to_comment("#")).unwrap_or_default()
+            + read_license_header(&output_path)?.as_deref().unwrap_or(LICENSE_PLACEHOLDER).trim()
             + "\n"
             + &mk_contents;
         File::create(&output_path)?.write_all(mk_contents.as_bytes())?;
@@ -678,20 +1447,45 @@ fn write_build_files(
     Ok(())
 }
 
-/// Generates and returns a Soong Blueprint for the given set of crates, for a single variant of a
-/// package.
-fn generate_android_bp(
+/// Generates the Soong modules for the given set of crates, for a single variant of a package,
+/// sorted by name.
+///
+/// `siblings` are the names of the libraries generated for all packages in the variant.
+fn generate_android_bp_modules(
     cfg: &VariantConfig,
     package_cfg: &PackageVariantConfig,
     package_name: &str,
     crates: &[Crate],
     out_files: &[PathBuf],
-) -> Result<String> {
-    let mut bp_contents = String::new();
+    siblings: &BTreeSet<String>,
+) -> Result<Vec<BpModule>> {
+    Ok(generate_android_bp_modules_with_sources(
+        cfg,
+        package_cfg,
+        package_name,
+        crates,
+        out_files,
+        siblings,
+    )?
+    .into_iter()
+    .map(|(module, _)| module)
+    .collect())
+}
 
+/// Like `generate_android_bp_modules`, but also returns what each module was generated from, for
+/// `cargo_embargo explain`.
+fn generate_android_bp_modules_with_sources<'a>(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_name: &str,
+    crates: &'a [Crate],
+    out_files: &[PathBuf],
+    siblings: &BTreeSet<String>,
+) -> Result<Vec<(BpModule, ModuleSource<'a>)>> {
     let mut modules = Vec::new();
+    let mut sources = Vec::new();
 
-    let extra_srcs = if package_cfg.copy_out && !out_files.is_empty() {
+    let mut extra_srcs = if package_cfg.copy_out && !out_files.is_empty() {
         let outs: Vec<String> = out_files
             .iter()
             .map(|f| f.file_name().unwrap().to_str().unwrap().to_string())
@@ -702,13 +1496,14 @@ fn generate_android_bp(
             &format!("copy_{}_build_out", package_name),
             &cfg.module_blocklist,
             &cfg.module_name_overrides,
-            &RENAME_MAP,
+            &rename_registry().rename_map,
         ) {
             m.props.set("name", module_name.clone());
             m.props.set("srcs", vec!["out/*"]);
             m.props.set("cmd", "cp $(in) $(genDir)");
             m.props.set("out", outs);
             modules.push(m);
+            sources.push(ModuleSource::ConfigOption("copy_out"));
 
             vec![":".to_string() + &module_name]
         } else {
@@ -717,30 +1512,57 @@ fn generate_android_bp(
     } else {
         vec![]
     };
+    if let Some(m) = bindgen::bindgen_module(cfg, package_cfg, package_name) {
+        extra_srcs.push(":".to_string() + m.props.get_string("name"));
+        modules.push(m);
+        sources.push(ModuleSource::ConfigOption("bindgen"));
+    }
+    if let Some(m) = cbindgen::header_module(cfg, package_cfg, crates) {
+        modules.push(m);
+        sources.push(ModuleSource::ConfigOption("cbindgen"));
+    }
+    if let Some(first_crate) = crates.first() {
+        if let Some(m) =
+            protobuf::protobuf_module(cfg, package_cfg, package_name, &first_crate.package_dir)?
+        {
+            extra_srcs.push(":".to_string() + m.props.get_string("name"));
+            modules.push(m);
+            sources.push(ModuleSource::ConfigOption("protobuf"));
+        }
+    }
 
+    let mut crate_modules = Vec::new();
+    let mut crate_sources: BTreeMap<String, (CrateType, Vec<&Crate>)> = BTreeMap::new();
     for c in crates {
-        modules.extend(crate_to_bp_modules(c, cfg, package_cfg, &extra_srcs).with_context(
-            || {
+        let crate_modules_for_crate = crate_to_bp_modules_by_type(c, cfg, package_cfg, &extra_srcs)
+            .with_context(|| {
                 format!(
                     "failed to generate bp module for crate \"{}\" with package name \"{}\"",
                     c.name, c.package_name
                 )
-            },
-        )?);
+            })?;
+        for (crate_type, m) in crate_modules_for_crate {
+            let name = m.props.get_string("name").to_string();
+            crate_sources.entry(name).or_insert_with(|| (crate_type, Vec::new())).1.push(c);
+            crate_modules.push((m, c));
+        }
     }
 
-    // In some cases there are nearly identical rustc invocations that that get processed into
-    // identical BP modules. So far, dedup'ing them is a good enough fix. At some point we might
-    // need something more complex, maybe like cargo2android's logic for merging crates.
-    modules.sort();
-    modules.dedup();
-
-    modules.sort_by_key(|m| m.props.get_string("name").to_string());
-    for m in modules {
-        m.write(&mut bp_contents)?;
-        bp_contents += "\n";
+    // In some cases there are nearly identical rustc invocations that get processed into identical
+    // BP modules. Dedup them so that there is only one module with each name, and report any which
+    // conflict.
+    for m in merge::merge_modules(crate_modules)? {
+        let (crate_type, module_crates) = crate_sources[m.props.get_string("name")].clone();
+        modules.push(m);
+        sources.push(ModuleSource::Crates(crate_type, module_crates));
     }
-    Ok(bp_contents)
+    apply_module_suffix(&mut modules, cfg, siblings);
+
+    let mut modules: Vec<_> = modules.into_iter().zip(sources).collect();
+    modules.sort_by(|(a, _), (b, _)| a.cmp(b));
+    modules.dedup_by(|(a, _), (b, _)| a == b);
+    modules.sort_by_key(|(m, _)| m.props.get_string("name").to_string());
+    Ok(modules)
 }
 
 /// Generates and returns a Trusty rules.mk file for the given set of crates.
@@ -763,6 +1585,9 @@ fn generate_rules_mk(
             if c.types.contains(&CrateType::Bin) {
                 eprintln!("WARNING: skipped generation of rules.mk for binary crate: {}", c.name);
                 false
+            } else if c.types.contains(&CrateType::Fuzz) {
+                eprintln!("WARNING: skipped generation of rules.mk for fuzz target: {}", c.name);
+                false
             } else if c.types.iter().any(|t| t.is_test()) {
                 // Test build file generation is not yet implemented
                 eprintln!("WARNING: skipped generation of rules.mk for test crate: {}", c.name);
@@ -842,15 +1667,80 @@ fn write_format_android_bp(
     Ok(())
 }
 
-/// Convert a `Crate` into `BpModule`s.
+/// Returns the Tradefed options for the test runner of the given test crate, to set its timeout
+/// and exclude tests.
 ///
-/// If messy business logic is necessary, prefer putting it here.
+/// Globs in `exclude_tests` are expanded using the test names found by `cargo test -- --list`, so
+/// are an error if cargo isn't run.
+fn test_runner_options(
+    crate_: &Crate,
+    cfg: &VariantConfig,
+    test_cfg: &TestTargetConfig,
+) -> Result<Vec<BpProperties>> {
+    let mut excluded_tests = BTreeSet::new();
+    for exclude in &test_cfg.exclude_tests {
+        if !exclude.contains(['*', '?', '[']) {
+            excluded_tests.insert(exclude.clone());
+            continue;
+        }
+        if !cfg.run_cargo {
+            bail!(
+                "exclude_tests pattern {exclude:?} for {:?} is a glob, which needs `run_cargo` to \
+                 list the tests; list the tests to exclude by name instead",
+                crate_.main_src
+            );
+        }
+        let pattern = glob::Pattern::new(exclude)
+            .with_context(|| format!("invalid exclude_tests pattern {exclude:?}"))?;
+        let matching: Vec<&String> =
+            crate_.test_names.iter().filter(|name| pattern.matches(name)).collect();
+        if matching.is_empty() {
+            eprintln!(
+                "WARNING: exclude_tests pattern {exclude:?} for {:?} doesn't match any tests",
+                crate_.main_src
+            );
+        }
+        excluded_tests.extend(matching.into_iter().cloned());
+    }
+
+    let mut options = Vec::new();
+    let mut add_option = |name: &str, value: String| {
+        let mut option = BpProperties::new();
+        option.set("name", name);
+        option.set("value", value);
+        options.push(option);
+    };
+    if let Some(timeout) = &test_cfg.timeout {
+        add_option("test-timeout", timeout.clone());
+    }
+    for test in excluded_tests {
+        add_option("exclude-filter", test);
+    }
+    Ok(options)
+}
+
+/// Convert a `Crate` into `BpModule`s.
 fn crate_to_bp_modules(
     crate_: &Crate,
     cfg: &VariantConfig,
     package_cfg: &PackageVariantConfig,
     extra_srcs: &[String],
 ) -> Result<Vec<BpModule>> {
+    Ok(crate_to_bp_modules_by_type(crate_, cfg, package_cfg, extra_srcs)?
+        .into_iter()
+        .map(|(_, module)| module)
+        .collect())
+}
+
+/// Convert a `Crate` into `BpModule`s, along with the crate type which each was generated for.
+///
+/// If messy business logic is necessary, prefer putting it here.
+fn crate_to_bp_modules_by_type(
+    crate_: &Crate,
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    extra_srcs: &[String],
+) -> Result<Vec<(CrateType, BpModule)>> {
     let mut modules = Vec::new();
     for crate_type in &crate_.types {
         let host = if package_cfg.device_supported { "" } else { "_host" };
@@ -858,23 +1748,23 @@ fn crate_to_bp_modules(
         let (module_type, module_name) = match crate_type {
             CrateType::Bin => ("rust_binary".to_string() + host, crate_.name.clone()),
             CrateType::Lib | CrateType::RLib => {
-                let stem = "lib".to_string() + &crate_.name;
+                let stem = "lib".to_string() + &library_crate_name(crate_, cfg);
                 ("rust_library".to_string() + host + rlib, stem)
             }
             CrateType::DyLib => {
-                let stem = "lib".to_string() + &crate_.name;
+                let stem = "lib".to_string() + &library_crate_name(crate_, cfg);
                 ("rust_library".to_string() + host + "_dylib", stem + "_dylib")
             }
             CrateType::CDyLib => {
-                let stem = "lib".to_string() + &crate_.name;
+                let stem = "lib".to_string() + &library_crate_name(crate_, cfg);
                 ("rust_ffi".to_string() + host + "_shared", stem + "_shared")
             }
             CrateType::StaticLib => {
-                let stem = "lib".to_string() + &crate_.name;
+                let stem = "lib".to_string() + &library_crate_name(crate_, cfg);
                 ("rust_ffi".to_string() + host + "_static", stem + "_static")
             }
             CrateType::ProcMacro => {
-                let stem = "lib".to_string() + &crate_.name;
+                let stem = "lib".to_string() + &library_crate_name(crate_, cfg);
                 ("rust_proc_macro".to_string(), stem)
             }
             CrateType::Test | CrateType::TestNoHarness => {
@@ -893,6 +1783,10 @@ fn crate_to_bp_modules(
                 }
                 ("rust_test".to_string() + host, stem)
             }
+            CrateType::Fuzz => {
+                let stem = crate_.package_name.clone() + "_fuzz_" + &crate_.name;
+                ("rust_fuzz".to_string() + host, stem)
+            }
         };
 
         let mut m = BpModule::new(module_type.clone());
@@ -900,7 +1794,7 @@ fn crate_to_bp_modules(
             &module_name,
             &cfg.module_blocklist,
             &cfg.module_name_overrides,
-            &RENAME_MAP,
+            &rename_registry().rename_map,
         ) else {
             continue;
         };
@@ -933,6 +1827,10 @@ fn crate_to_bp_modules(
         }
         if crate_type.is_c_library() {
             m.props.set_if_nonempty("include_dirs", package_cfg.exported_c_header_dir.clone());
+            if let Some(header_module) = cbindgen::header_module_name(cfg, package_cfg, crate_) {
+                m.props.set("generated_headers", vec![header_module.clone()]);
+                m.props.set("export_generated_headers", vec![header_module]);
+            }
         }
 
         m.props.set("crate_name", crate_.name.clone());
@@ -943,10 +1841,25 @@ fn crate_to_bp_modules(
         }
 
         if crate_.types.contains(&CrateType::Test) {
-            m.props.set("test_suites", vec!["general-tests"]);
+            let test_cfg = package_cfg
+                .test_targets
+                .get(crate_.main_src.to_string_lossy().as_ref())
+                .cloned()
+                .unwrap_or_default();
+            if test_cfg.test_suites.is_empty() {
+                m.props.set("test_suites", vec!["general-tests"]);
+            } else {
+                m.props.set("test_suites", test_cfg.test_suites.clone());
+            }
             m.props.set("auto_gen_config", true);
-            if package_cfg.host_supported {
-                m.props.object("test_options").set("unit_test", !package_cfg.no_presubmit);
+            if package_cfg.host_supported || test_cfg.unit_test.is_some() {
+                m.props
+                    .object("test_options")
+                    .set("unit_test", test_cfg.unit_test.unwrap_or(!package_cfg.no_presubmit));
+            }
+            let runner_options = test_runner_options(crate_, cfg, &test_cfg)?;
+            if !runner_options.is_empty() {
+                m.props.object("test_options").set("test_runner_options", runner_options);
             }
         }
 
@@ -977,8 +1890,8 @@ fn crate_to_bp_modules(
         let mut aliases = Vec::new();
         for extern_dep in &crate_.externs {
             match extern_dep.extern_type {
-                ExternType::Rust => rust_libs.push(extern_dep.lib_name.clone()),
-                ExternType::ProcMacro => proc_macro_libs.push(extern_dep.lib_name.clone()),
+                ExternType::Rust => rust_libs.push(extern_library_name(extern_dep)),
+                ExternType::ProcMacro => proc_macro_libs.push(extern_library_name(extern_dep)),
             }
             if extern_dep.name != extern_dep.lib_name {
                 aliases.push(format!("{}:{}", extern_dep.lib_name, extern_dep.name));
@@ -994,7 +1907,7 @@ fn crate_to_bp_modules(
                     &module_name,
                     &package_cfg.dep_blocklist,
                     &cfg.module_name_overrides,
-                    &RENAME_MAP,
+                    &rename_registry().rename_map,
                 ) {
                     result.push(module_name);
                 }
@@ -1014,7 +1927,7 @@ fn crate_to_bp_modules(
         m.props.set_if_nonempty("aliases", aliases);
 
         if package_cfg.device_supported {
-            if !crate_type.is_test() {
+            if !crate_type.is_test() && *crate_type != CrateType::Fuzz {
                 if cfg.native_bridge_supported {
                     m.props.set("native_bridge_supported", true);
                 }
@@ -1047,6 +1960,14 @@ fn crate_to_bp_modules(
             {
                 m.props.set("data", data.clone());
             }
+        } else if *crate_type == CrateType::Fuzz {
+            let fuzz_data = fuzz_data(crate_)?;
+            if let Some(corpus) = fuzz_data.corpus {
+                m.props.set("corpus", vec![corpus]);
+            }
+            if let Some(dictionary) = fuzz_data.dictionary {
+                m.props.set("dictionary", dictionary);
+            }
         } else if package_cfg.no_std {
             m.props.set("prefer_rlib", true);
             m.props.set("no_stdlibs", true);
@@ -1068,7 +1989,7 @@ fn crate_to_bp_modules(
             m.props.raw_block = Some(content);
         }
 
-        modules.push(m);
+        modules.push((*crate_type, m));
     }
     Ok(modules)
 }
@@ -1082,6 +2003,12 @@ fn crate_to_rulesmk(
     package_cfg: &PackageVariantConfig,
     out_files: &[String],
 ) -> Result<String> {
+    if cfg.module_suffix.is_some() {
+        bail!(
+            "`module_suffix` can't be used with `generate_rulesmk`, as rules.mk modules are named \
+             by directory so the variant's libraries would depend on those of other variants"
+        );
+    }
     let mut contents = String::new();
 
     contents += "LOCAL_DIR := $(GET_LOCAL_DIR)\n";
@@ -1118,7 +2045,7 @@ fn crate_to_rulesmk(
     }
 
     // crate dependencies without lib- prefix
-    let mut library_deps: Vec<_> = crate_.externs.iter().map(|dep| dep.lib_name.clone()).collect();
+    let mut library_deps: Vec<_> = crate_.externs.iter().map(extern_library_name).collect();
     if package_cfg.no_std {
         contents += "MODULE_ADD_IMPLICIT_DEPS := false\n";
         library_deps.push("compiler_builtins".to_string());
@@ -1152,12 +2079,13 @@ fn crate_to_rulesmk(
     let mut library_deps: Vec<String> = library_deps
         .into_iter()
         .flat_map(|dep| {
-            override_module_name(
+            let module_name = override_module_name(
                 &format!("lib{dep}"),
                 &package_cfg.dep_blocklist,
                 &cfg.module_name_overrides,
-                &RULESMK_RENAME_MAP,
-            )
+                &rename_registry().rulesmk_rename_map,
+            )?;
+            Some(module_name)
         })
         .map(|dep| {
             // Rewrite dependency name to module path for Trusty build system
@@ -1254,14 +2182,19 @@ mod tests {
                 let def = PackageVariantConfig::default();
                 let package_variant_cfg = variant_cfg.package.get(package_name).unwrap_or(&def);
 
-                output += &generate_android_bp(
+                for m in generate_android_bp_modules(
                     variant_cfg,
                     package_variant_cfg,
                     package_name,
                     variant_crates,
                     &Vec::new(),
+                    &sibling_libraries(variant_crates, variant_cfg),
                 )
-                .unwrap();
+                .unwrap()
+                {
+                    m.write(&mut output).unwrap();
+                    output += "\n";
+                }
             }
 
             assert_that!(output, eq(expected_output));
@@ -1372,6 +2305,420 @@ mod tests {
         );
     }
 
+    #[test]
+    fn crate_to_bp_test_options() {
+        let c = Crate {
+            name: "test".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Test],
+            main_src: "tests/test.rs".into(),
+            test_names: vec![
+                "network::fetch".to_string(),
+                "network::resolve".to_string(),
+                "parse".to_string(),
+            ],
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig {
+            test_targets: [(
+                "tests/test.rs".to_string(),
+                TestTargetConfig {
+                    unit_test: Some(false),
+                    test_suites: vec!["general-tests".to_string(), "mts".to_string()],
+                    timeout: Some("10m".to_string()),
+                    exclude_tests: vec!["network::*".to_string(), "needs_root".to_string()],
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+        let mut bp = String::new();
+        modules[0].write(&mut bp).unwrap();
+
+        assert!(bp.contains("test_suites: [\"general-tests\", \"mts\"],\n"));
+        assert!(bp.contains(
+            "test_options: {\n\
+             test_runner_options: [{\nname: \"test-timeout\",\nvalue: \"10m\",\n}, \
+             {\nname: \"exclude-filter\",\nvalue: \"needs_root\",\n}, \
+             {\nname: \"exclude-filter\",\nvalue: \"network::fetch\",\n}, \
+             {\nname: \"exclude-filter\",\nvalue: \"network::resolve\",\n}],\n\
+             unit_test: false,\n\
+             }"
+        ));
+
+        let cfg = VariantConfig { run_cargo: false, ..Default::default() };
+        let error = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap_err();
+        assert!(format!("{error:#}").contains("exclude_tests pattern \"network::*\""));
+    }
+
+    #[test]
+    fn crate_to_bp_fuzz() -> Result<()> {
+        let package_dir = tempfile::tempdir()?;
+        fs::create_dir_all(package_dir.path().join("fuzz/corpus/parse"))?;
+        fs::write(package_dir.path().join("fuzz/corpus/parse/seed"), "1 + 2")?;
+        fs::write(package_dir.path().join("fuzz/parse.dict"), "\"+\"\n")?;
+        let c = Crate {
+            name: "parse".to_string(),
+            package_name: "calc".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Fuzz],
+            externs: vec![Extern {
+                name: "calc".to_string(),
+                lib_name: "calc".to_string(),
+                extern_type: ExternType::Rust,
+                version: None,
+            }],
+            package_dir: package_dir.path().to_path_buf(),
+            main_src: "fuzz/fuzz_targets/parse.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig { fuzzers: true, ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[])?;
+        let mut bp = String::new();
+        modules[0].write(&mut bp)?;
+
+        assert_eq!(
+            bp,
+            "rust_fuzz {\n\
+             name: \"calc_fuzz_parse\",\n\
+             host_supported: true,\n\
+             crate_name: \"parse\",\n\
+             cargo_env_compat: true,\n\
+             crate_root: \"fuzz/fuzz_targets/parse.rs\",\n\
+             edition: \"2021\",\n\
+             rustlibs: [\"libcalc\"],\n\
+             corpus: [\"fuzz/corpus/parse/*\"],\n\
+             dictionary: \"fuzz/parse.dict\",\n\
+             }\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn crate_to_bp_versioned() {
+        let c = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            version: Some("0.4.2".to_string()),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            main_src: "src/lib.rs".into(),
+            externs: vec![
+                Extern {
+                    name: "syn".to_string(),
+                    lib_name: "syn".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: Some("1.0.109".to_string()),
+                },
+                Extern {
+                    name: "foo_derive".to_string(),
+                    lib_name: "foo_derive".to_string(),
+                    extern_type: ExternType::ProcMacro,
+                    version: Some("0.0.3".to_string()),
+                },
+                Extern {
+                    name: "bar".to_string(),
+                    lib_name: "bar".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let cfg = VariantConfig { versioned_crates: vec!["foo".to_string()], ..Default::default() };
+        let package_cfg = PackageVariantConfig::default();
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+
+        assert_eq!(modules[0].props.get_string("name"), "libfoo_0_4");
+        assert_eq!(modules[0].props.get_string("crate_name"), "foo");
+        let mut bp = String::new();
+        modules[0].write(&mut bp).unwrap();
+        assert!(bp.contains("rustlibs: [\"libbar\", \"libsyn_1\"],\n"));
+        assert!(bp.contains("proc_macros: [\"libfoo_derive_0_0_3\"],\n"));
+
+        let rulesmk = crate_to_rulesmk(&c, &cfg, &package_cfg, &[]).unwrap();
+        assert!(rulesmk.contains("external/rust/crates/syn_1"));
+    }
+
+    #[test]
+    fn crate_to_bp_explained() {
+        let mut c = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            main_src: "src/lib.rs".into(),
+            cfgs: vec!["bar".to_string(), "baz".to_string()],
+            externs: vec![
+                Extern {
+                    name: "log".to_string(),
+                    lib_name: "log".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: None,
+                },
+                Extern {
+                    name: "qux".to_string(),
+                    lib_name: "qux".to_string(),
+                    extern_type: ExternType::Rust,
+                    version: None,
+                },
+            ],
+            ..Default::default()
+        };
+        c.origins.record("cfgs", "bar", "`--cfg bar` in the rustc invocation");
+        c.origins.record("externs", "log", "dependency on log ^0.4 in the Cargo.toml of foo");
+        let cfg = VariantConfig {
+            cfg_blocklist: vec!["baz".to_string()],
+            module_name_overrides: [("libfoo".to_string(), "libfoo_rust".to_string())].into(),
+            ..Default::default()
+        };
+        let package_cfg = PackageVariantConfig {
+            dep_blocklist: vec!["libqux".to_string()],
+            ..Default::default()
+        };
+        let crates = [c];
+        let modules = generate_android_bp_modules_with_sources(
+            &cfg,
+            &package_cfg,
+            "foo",
+            &crates,
+            &[],
+            &BTreeSet::new(),
+        )
+        .unwrap();
+        assert_eq!(modules.len(), 1);
+        let (module, source) = &modules[0];
+        assert_eq!(module.props.get_string("name"), "libfoo_rust");
+        let explanations = explain_module(module, source, &BTreeMap::new(), &cfg, &package_cfg);
+
+        assert_eq!(
+            explanations.describe(Some("name")),
+            "\
+name:
+  \"libfoo_rust\"
+    from the Lib target foo of foo
+    renamed from libfoo by `module_name_overrides`
+"
+        );
+        assert_eq!(
+            explanations.describe(Some("cfgs")),
+            "\
+cfgs:
+  \"bar\"
+    from `--cfg bar` in the rustc invocation
+  \"baz\"
+    from the cfgs of foo, with no recorded origin
+    removed by `cfg_blocklist`
+"
+        );
+        assert_eq!(
+            explanations.describe(Some("rustlibs")),
+            "\
+rustlibs:
+  \"liblog_rust\"
+    from dependency on log ^0.4 in the Cargo.toml of foo
+    renamed from liblog by the rename registry
+  \"libqux\"
+    from the dependencies of foo, with no recorded origin
+    removed by `dep_blocklist`
+"
+        );
+    }
+
+    #[test]
+    fn explain_suffixed_test_module() {
+        let c = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            version: Some("1.2.3".to_string()),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Test],
+            main_src: "tests/foo.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig { module_suffix: Some("_nostd".to_string()), ..Default::default() };
+        let package_cfg = PackageVariantConfig {
+            no_presubmit: true,
+            bindgen: Some(Default::default()),
+            ..Default::default()
+        };
+        let crates = [c];
+        let modules = generate_android_bp_modules_with_sources(
+            &cfg,
+            &package_cfg,
+            "foo",
+            &crates,
+            &[],
+            &BTreeSet::new(),
+        )
+        .unwrap();
+        let option_modules: BTreeMap<String, &'static str> = modules
+            .iter()
+            .filter_map(|(module, source)| match source {
+                ModuleSource::ConfigOption(option) => {
+                    Some((module.props.get_string("name").to_string(), *option))
+                }
+                ModuleSource::Crates(..) => None,
+            })
+            .collect();
+        let (module, source) =
+            modules.iter().find(|(module, _)| module.module_type.starts_with("rust_test")).unwrap();
+        assert_eq!(module.props.get_string("name"), "foo_test_tests_foo_nostd");
+        let explanations = explain_module(module, source, &option_modules, &cfg, &package_cfg);
+
+        assert_eq!(
+            explanations.describe(Some("name")),
+            "\
+name:
+  \"foo_test_tests_foo_nostd\"
+    from the Test target foo of foo
+    suffixed with \"_nostd\" by `module_suffix`
+"
+        );
+        assert_eq!(
+            explanations.describe(Some("test_options")),
+            "\
+test_options.unit_test:
+  \"false\"
+    from the `no_presubmit` config option
+"
+        );
+        assert!(explanations
+            .describe(Some("srcs"))
+            .contains("from the module generated for the `bindgen` config option"));
+        assert!(explanations.describe(Some("auto_gen_config")).contains("from the defaults"));
+    }
+
+    #[test]
+    fn module_suffix() {
+        let rust_extern = |name: &str| Extern {
+            name: name.to_string(),
+            lib_name: name.to_string(),
+            extern_type: ExternType::Rust,
+            version: None,
+        };
+        let lib = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            main_src: "src/lib.rs".into(),
+            externs: vec![rust_extern("bar"), rust_extern("log")],
+            ..Default::default()
+        };
+        let bin = Crate {
+            name: "foo_cli".to_string(),
+            types: vec![CrateType::Bin],
+            main_src: "src/main.rs".into(),
+            externs: vec![rust_extern("foo")],
+            ..lib.clone()
+        };
+        let other_package = Crate {
+            name: "bar".to_string(),
+            package_name: "bar".to_string(),
+            externs: vec![],
+            ..lib.clone()
+        };
+        let c_package = Crate {
+            name: "baz".to_string(),
+            package_name: "baz".to_string(),
+            types: vec![CrateType::StaticLib],
+            externs: vec![],
+            ..lib.clone()
+        };
+        let lib = Crate {
+            static_libs: vec!["baz".to_string(), "z".to_string()],
+            shared_libs: vec!["baz".to_string()],
+            ..lib
+        };
+        let cfg = VariantConfig { module_suffix: Some("_nostd".to_string()), ..Default::default() };
+        let package_cfg = PackageVariantConfig::default();
+        let siblings =
+            sibling_libraries(&[lib.clone(), bin.clone(), other_package, c_package], &cfg);
+        assert_eq!(siblings, ["bar".to_string(), "baz".to_string(), "foo".to_string()].into());
+
+        let modules = generate_android_bp_modules(
+            &cfg,
+            &package_cfg,
+            "foo",
+            &[lib.clone(), bin],
+            &[],
+            &siblings,
+        )
+        .unwrap();
+        let mut bp = String::new();
+        for m in &modules {
+            m.write(&mut bp).unwrap();
+        }
+        assert!(bp.contains("name: \"foo_cli_nostd\",\n"));
+        assert!(bp.contains("rustlibs: [\"libfoo_nostd\"],\n"));
+        assert!(bp.contains("name: \"libfoo_nostd\",\n"));
+        assert!(bp.contains("rustlibs: [\"libbar_nostd\", \"liblog_rust\"],\n"));
+        assert!(bp.contains("static_libs: [\"libbaz_nostd\", \"libz\"],\n"));
+        assert!(bp.contains("shared_libs: [\"libbaz_nostd\"],\n"));
+
+        // Trusty modules are named by directory, so there are no suffixed siblings to depend on.
+        assert!(crate_to_rulesmk(&lib, &cfg, &package_cfg, &[])
+            .unwrap_err()
+            .to_string()
+            .contains("`module_suffix` can't be used with `generate_rulesmk`"));
+
+        let package_cfg = PackageVariantConfig {
+            whole_static_libs: vec!["libbaz".to_string()],
+            ..Default::default()
+        };
+        let modules = generate_android_bp_modules(
+            &cfg,
+            &package_cfg,
+            "foo",
+            std::slice::from_ref(&lib),
+            &[],
+            &siblings,
+        )
+        .unwrap();
+        let mut bp = String::new();
+        modules[0].write(&mut bp).unwrap();
+        assert!(bp.contains("whole_static_libs: [\"libbaz_nostd\"],\n"));
+    }
+
+    #[test]
+    fn module_suffix_generated_header() -> Result<()> {
+        let package_dir = tempfile::tempdir()?;
+        fs::write(package_dir.path().join("cbindgen.toml"), "language = \"C\"\n")?;
+        let c = Crate {
+            name: "foo_ffi".to_string(),
+            package_name: "foo".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::StaticLib],
+            package_dir: package_dir.path().to_path_buf(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig { module_suffix: Some("_nostd".to_string()), ..Default::default() };
+        let siblings = sibling_libraries(std::slice::from_ref(&c), &cfg);
+        let modules = generate_android_bp_modules(
+            &cfg,
+            &PackageVariantConfig::default(),
+            "foo",
+            &[c],
+            &[],
+            &siblings,
+        )?;
+
+        let names: Vec<_> = modules.iter().map(|m| m.props.get_string("name")).collect();
+        assert_eq!(names, vec!["gen_foo_ffi_header_nostd", "libfoo_ffi_static_nostd"]);
+        let mut bp = String::new();
+        modules[1].write(&mut bp)?;
+        assert!(bp.contains("generated_headers: [\"gen_foo_ffi_header_nostd\"],\n"));
+        assert!(bp.contains("export_generated_headers: [\"gen_foo_ffi_header_nostd\"],\n"));
+        Ok(())
+    }
+
     /// Returns a list of directories containing test data.
     ///
     /// Each directory under `testdata/` contains a single test case.
@@ -0,0 +1,220 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Merging of modules with the same name, which are generated from identical rustc invocations,
+//! and from different variants.
+
+use crate::bp::BpModule;
+use crate::cargo::Crate;
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Deduplicates modules generated from different crates which have the same name, so that there is
+/// only one module with each name.
+///
+/// Identical modules are only kept once, in the order they were first generated. Distinct modules
+/// with the same name are an error naming the invocations which they came from and a property in
+/// which they differ.
+pub fn merge_modules(modules: Vec<(BpModule, &Crate)>) -> Result<Vec<BpModule>> {
+    let mut by_name: BTreeMap<String, (BpModule, &Crate)> = BTreeMap::new();
+    let mut merged = Vec::new();
+    for (module, crate_) in modules {
+        let name = module.props.get_string("name").to_string();
+        match by_name.get(&name) {
+            Some((other, _)) if *other == module => {}
+            Some((other, other_crate)) => bail!(
+                "Conflicting modules named {name:?}: {} and {} differ in {}",
+                describe_invocation(other_crate),
+                describe_invocation(crate_),
+                describe_difference(other, &module),
+            ),
+            None => {
+                merged.push(module.clone());
+                by_name.insert(name, (module, crate_));
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Returns a description of a difference between the given distinct modules, for error messages.
+fn describe_difference(a: &BpModule, b: &BpModule) -> String {
+    if a.module_type != b.module_type {
+        return format!("module type ({} and {})", a.module_type, b.module_type);
+    }
+    if a.props.raw_block != b.props.raw_block {
+        return "module block".to_string();
+    }
+    let properties: BTreeSet<&String> = a.props.map.keys().chain(b.props.map.keys()).collect();
+    properties
+        .into_iter()
+        .find(|property| a.props.map.get(*property) != b.props.map.get(*property))
+        .map_or_else(|| "properties".to_string(), |property| format!("{property:?}"))
+}
+
+/// Returns a description of the rustc invocation which the given crate came from, for error
+/// messages.
+fn describe_invocation(crate_: &Crate) -> String {
+    format!(
+        "the invocation for {} ({}) for {}",
+        crate_.name,
+        crate_.main_src.display(),
+        crate_.target.as_deref().unwrap_or("the host"),
+    )
+}
+
+/// Adds the modules generated for a variant to those generated for previous variants.
+///
+/// Modules which are identical to one generated by a previous variant are skipped, and the order
+/// of the rest is preserved. It is an error for a variant to generate a different module with the
+/// same name as a previous variant.
+pub fn add_variant_modules(
+    all_modules: &mut Vec<(BpModule, usize)>,
+    modules: Vec<BpModule>,
+    variant_index: usize,
+) -> Result<()> {
+    for module in modules {
+        let name = module.props.get_string("name");
+        match all_modules.iter().find(|(other, _)| other.props.get_string("name") == name) {
+            Some((other, _)) if *other == module => {}
+            Some((_, other_variant)) => bail!(
+                "Variants {} and {} both generate a module named {name:?} but with different \
+                 properties. Use `module_name_overrides` to rename one of them.",
+                other_variant + 1,
+                variant_index + 1,
+            ),
+            None => all_modules.push((module, variant_index)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(name: &str, features: &[&str], rustlibs: &[&str]) -> BpModule {
+        let mut module = BpModule::new("rust_library".to_string());
+        module.props.set("name", name);
+        module.props.set("crate_name", "foo");
+        module.props.set_if_nonempty("features", features.to_vec());
+        module.props.set_if_nonempty("rustlibs", rustlibs.to_vec());
+        module
+    }
+
+    fn crate_for(target: Option<&str>) -> Crate {
+        Crate {
+            name: "foo".to_string(),
+            main_src: "src/lib.rs".into(),
+            target: target.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn write(modules: &[BpModule]) -> String {
+        let mut bp = String::new();
+        for module in modules {
+            module.write(&mut bp).unwrap();
+        }
+        bp
+    }
+
+    #[test]
+    fn dedup() {
+        let host = crate_for(None);
+        let default_target = crate_for(Some("x86_64-unknown-linux-gnu"));
+        let merged = merge_modules(vec![
+            (module("libfoo", &["a"], &["libbar"]), &default_target),
+            (module("libbaz", &[], &[]), &default_target),
+            (module("libfoo", &["a"], &["libbar"]), &host),
+        ])
+        .unwrap();
+        assert_eq!(
+            write(&merged),
+            "\
+rust_library {
+name: \"libfoo\",
+crate_name: \"foo\",
+features: [\"a\"],
+rustlibs: [\"libbar\"],
+}
+rust_library {
+name: \"libbaz\",
+crate_name: \"foo\",
+}
+"
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        // E.g. a crate built both as a normal dependency and as a build dependency with different
+        // features.
+        let host = crate_for(None);
+        let default_target = crate_for(Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(
+            merge_modules(vec![
+                (module("libfoo", &["a", "b"], &[]), &default_target),
+                (module("libfoo", &["a"], &[]), &host),
+            ])
+            .unwrap_err()
+            .to_string(),
+            "Conflicting modules named \"libfoo\": the invocation for foo (src/lib.rs) for \
+             x86_64-unknown-linux-gnu and the invocation for foo (src/lib.rs) for the host differ \
+             in \"features\""
+        );
+
+        let mut other = module("libfoo", &[], &[]);
+        other.module_type = "rust_library_host".to_string();
+        assert_eq!(
+            merge_modules(vec![(module("libfoo", &[], &[]), &host), (other, &host)])
+                .unwrap_err()
+                .to_string(),
+            "Conflicting modules named \"libfoo\": the invocation for foo (src/lib.rs) for the \
+             host and the invocation for foo (src/lib.rs) for the host differ in module type \
+             (rust_library and rust_library_host)"
+        );
+    }
+
+    #[test]
+    fn variant_modules() {
+        let mut all_modules = Vec::new();
+        add_variant_modules(
+            &mut all_modules,
+            vec![module("libfoo", &[], &[]), module("libbar", &[], &[])],
+            0,
+        )
+        .unwrap();
+        add_variant_modules(
+            &mut all_modules,
+            vec![module("libfoo", &[], &[]), module("libfoo_nostd", &[], &[])],
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            all_modules
+                .iter()
+                .map(|(module, variant)| (module.props.get_string("name"), *variant))
+                .collect::<Vec<_>>(),
+            vec![("libfoo", 0), ("libbar", 0), ("libfoo_nostd", 1)]
+        );
+        assert_eq!(
+            add_variant_modules(&mut all_modules, vec![module("libbar", &["std"], &[])], 2)
+                .unwrap_err()
+                .to_string(),
+            "Variants 1 and 3 both generate a module named \"libbar\" but with different \
+             properties. Use `module_name_overrides` to rename one of them."
+        );
+    }
+}
@@ -0,0 +1,793 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Static evaluation of build scripts which only probe the rustc version to set cfgs, so that the
+//! cfgs can be found without running cargo.
+//!
+//! This understands the common patterns used with autocfg, version_check and rustversion, and
+//! comparisons of a `minor` version variable. Anything else makes the build script unsupported, as
+//! does anything which may return or exit early before or around a statement which sets a cfg.
+
+use crate::provenance::tool_version;
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+/// A version of rustc, against which probes are evaluated.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct RustcVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl RustcVersion {
+    /// Returns the version of the rustc in the given toolchain directory.
+    pub fn of_toolchain(toolchain_dir: &Path) -> Result<Self> {
+        let rustc = toolchain_dir.join("rustc");
+        let version = tool_version(&rustc.to_string_lossy())?;
+        version
+            .strip_prefix("rustc ")
+            .and_then(|version| Self::parse(version.split(' ').next()?))
+            .ok_or_else(|| anyhow!("failed to get the version of {rustc:?}: {version:?}"))
+    }
+
+    /// Parses a version like "1.80", "1.80.1" or "1.80.1-nightly".
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.split(['-', '+']).next()?;
+        let mut parts = version.split('.').map(str::parse);
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { major, minor, patch })
+    }
+}
+
+impl Display for RustcVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// A statement in a build script, as far as is needed to find which cfgs it sets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Statement {
+    /// A statement without a block at the top level, e.g. `println!("cargo:rustc-cfg=foo");`.
+    Simple(String),
+    /// An `if` statement, with its condition and the statements of each branch.
+    If { condition: String, then: Vec<Statement>, otherwise: Vec<Statement> },
+    /// Any other statement with a block, e.g. a function or a `match`, with the text before it.
+    Block { header: String, body: Vec<Statement> },
+}
+
+/// Matches anything in a build script which might set a cfg.
+static EMISSION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"rustc-cfg|autocfg::emit|\.emit_\w+\s*\(").unwrap());
+
+/// Matches anything in a build script which might stop it early, so that later statements aren't
+/// run. This may also match unrelated `?`s in strings, which only makes it more conservative.
+static DIVERGENCE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"\breturn\b|\?|\bexit\s*\(|\babort\s*\(|\b(?:panic|unreachable|todo|unimplemented)!",
+    )
+    .unwrap()
+});
+
+/// Evaluates the probes in the given build script source against the given rustc version.
+///
+/// Returns the cfgs which the build script sets, each with the probe which set it if it isn't set
+/// unconditionally, or `None` if the build script does something which can't be evaluated
+/// statically.
+pub fn evaluate_probes(
+    source: &str,
+    version: RustcVersion,
+) -> Option<BTreeMap<String, Option<String>>> {
+    let statements = parse_statements(&strip_comments(source))?;
+    let mut evaluator = Evaluator { version, cfgs: BTreeMap::new() };
+    let mut found_main = false;
+    for statement in &statements {
+        match statement {
+            Statement::Block { header, body } if header.starts_with("fn main(") => {
+                found_main = true;
+                evaluator.evaluate(body, true, &[])?;
+            }
+            // The effects of other functions can't be followed.
+            statement if contains_emission(std::slice::from_ref(statement)) => return None,
+            _ => {}
+        }
+    }
+    found_main.then_some(evaluator.cfgs)
+}
+
+/// A cfg which a statement in a build script may set.
+struct Emission {
+    cfg: String,
+    /// Whether the cfg is set for the rustc version being evaluated against.
+    enabled: bool,
+    /// The probe which decides whether the cfg is set, if it isn't the enclosing conditions.
+    probe: Option<String>,
+}
+
+struct Evaluator {
+    version: RustcVersion,
+    /// The cfgs found so far, with the probe which set each if any.
+    cfgs: BTreeMap<String, Option<String>>,
+}
+
+impl Evaluator {
+    /// Evaluates the given statements, given the conditions of the enclosing `if` statements.
+    ///
+    /// Inactive statements are still checked, so that whether a build script is supported doesn't
+    /// depend on the rustc version.
+    fn evaluate(
+        &mut self,
+        statements: &[Statement],
+        active: bool,
+        conditions: &[&str],
+    ) -> Option<()> {
+        // Whether an earlier statement may have stopped the build script, in which case whether
+        // any later cfg is set depends on more than the rustc version.
+        let mut may_have_stopped = false;
+        for statement in statements {
+            let statement_slice = std::slice::from_ref(statement);
+            let may_stop = contains_divergence(statement_slice);
+            if contains_emission(statement_slice) && (may_have_stopped || may_stop) {
+                return None;
+            }
+            may_have_stopped |= may_stop;
+            match statement {
+                Statement::Simple(text) => {
+                    for Emission { cfg, enabled, probe } in self.emissions(text)? {
+                        if active && enabled {
+                            let probe = probe.or_else(|| {
+                                (!conditions.is_empty()).then(|| conditions.join(" && "))
+                            });
+                            self.cfgs.insert(cfg, probe);
+                        }
+                    }
+                }
+                Statement::If { condition, then, otherwise } => {
+                    let Some(value) = self.condition(condition) else {
+                        if contains_emission(then) || contains_emission(otherwise) {
+                            return None;
+                        }
+                        continue;
+                    };
+                    let negated = format!("!({condition})");
+                    self.evaluate(then, active && value, &[conditions, &[condition]].concat())?;
+                    self.evaluate(
+                        otherwise,
+                        active && !value,
+                        &[conditions, &[negated.as_str()]].concat(),
+                    )?;
+                }
+                Statement::Block { body, .. } => {
+                    if contains_emission(body) {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Returns the cfgs which the given statement may set, or `None` if it can't be evaluated.
+    fn emissions(&self, text: &str) -> Option<Vec<Emission>> {
+        static CARGO_CFG: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r#"cargo::?rustc-cfg=([^"\s]*)"#).unwrap());
+        static AUTOCFG_EMIT: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r#"^autocfg::emit\(\s*"(\w+)"\s*\)$"#).unwrap());
+        static EMIT_RUSTC_VERSION: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^\S+?\.emit_rustc_version\(\s*(\d+)\s*,\s*(\d+)\s*\)$").unwrap()
+        });
+        static CFG_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\w+$").unwrap());
+
+        if !EMISSION.is_match(text) {
+            return Some(vec![]);
+        }
+        if let Some(captures) = EMIT_RUSTC_VERSION.captures(text) {
+            let major = captures[1].parse().ok()?;
+            let minor = captures[2].parse().ok()?;
+            let enabled = self.version >= RustcVersion { major, minor, patch: 0 };
+            return Some(vec![Emission {
+                cfg: format!("rustc_{major}_{minor}"),
+                enabled,
+                probe: Some(text.to_string()),
+            }]);
+        }
+        if let Some(captures) = AUTOCFG_EMIT.captures(text) {
+            return Some(vec![Emission {
+                cfg: captures[1].to_string(),
+                enabled: true,
+                probe: None,
+            }]);
+        }
+        let cfgs = CARGO_CFG
+            .captures_iter(text)
+            .map(|captures| {
+                CFG_NAME.is_match(&captures[1]).then(|| Emission {
+                    cfg: captures[1].to_string(),
+                    enabled: true,
+                    probe: None,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        // Anything else, e.g. `ac.emit_has_path(...)`, depends on more than the rustc version.
+        if cfgs.is_empty() {
+            None
+        } else {
+            Some(cfgs)
+        }
+    }
+
+    /// Evaluates the given `if` condition, or returns `None` if it isn't a supported probe.
+    fn condition(&self, condition: &str) -> Option<bool> {
+        static PROBE_RUSTC_VERSION: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^\S+?\.probe_rustc_version\(\s*(\d+)\s*,\s*(\d+)\s*\)$").unwrap()
+        });
+        static VERSION_CHECK: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(
+                r#"^version_check::is_(min|max|exact)_version\(\s*"([^"]+)"\s*\)\s*(\.unwrap\(\)|\.unwrap_or\((?:true|false)\)|\.unwrap_or_default\(\)|==\s*Some\(true\))$"#,
+            )
+            .unwrap()
+        });
+        static RUSTVERSION: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"^rustversion::cfg!\s*\((.*)\)$").unwrap());
+        static MINOR: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^(?:\w+\.)?(?:rustc_)?minor(?:_version)?\s*(>=|<=|==|!=|>|<)\s*(\d+)$")
+                .unwrap()
+        });
+
+        let condition = strip_parens(condition.trim());
+        if let Some(alternatives) = split_top_level(condition, "||") {
+            return alternatives
+                .iter()
+                .try_fold(false, |value, alternative| Some(self.condition(alternative)? || value));
+        }
+        if let Some(terms) = split_top_level(condition, "&&") {
+            return terms.iter().try_fold(true, |value, term| Some(self.condition(term)? && value));
+        }
+        if let Some(negated) = condition.strip_prefix('!') {
+            return self.condition(negated).map(|value| !value);
+        }
+        if let Some(captures) = PROBE_RUSTC_VERSION.captures(condition) {
+            let major = captures[1].parse().ok()?;
+            let minor = captures[2].parse().ok()?;
+            return Some(self.version >= RustcVersion { major, minor, patch: 0 });
+        }
+        if let Some(captures) = VERSION_CHECK.captures(condition) {
+            let version = RustcVersion::parse(&captures[2])?;
+            return Some(match &captures[1] {
+                "min" => self.version >= version,
+                "max" => self.version <= version,
+                _ => self.version == version,
+            });
+        }
+        if let Some(captures) = RUSTVERSION.captures(condition) {
+            return self.rustversion(captures[1].trim());
+        }
+        if let Some(captures) = MINOR.captures(condition) {
+            let minor: u64 = captures[2].parse().ok()?;
+            return Some(match &captures[1] {
+                ">=" => self.version.minor >= minor,
+                "<=" => self.version.minor <= minor,
+                "==" => self.version.minor == minor,
+                "!=" => self.version.minor != minor,
+                ">" => self.version.minor > minor,
+                _ => self.version.minor < minor,
+            });
+        }
+        None
+    }
+
+    /// Evaluates the given `rustversion::cfg!` expression. The Android toolchain is always a
+    /// stable release.
+    fn rustversion(&self, expression: &str) -> Option<bool> {
+        let (name, arguments) = match expression.split_once('(') {
+            Some((name, arguments)) => (name.trim(), Some(arguments.strip_suffix(')')?)),
+            None => (expression, None),
+        };
+        match (name, arguments) {
+            ("stable", None) => Some(true),
+            ("stable", Some(version)) => Some(self.matches(version)?),
+            ("beta" | "nightly", _) => Some(false),
+            ("since", Some(version)) => Some(self.version >= RustcVersion::parse(version.trim())?),
+            ("before", Some(version)) => Some(self.version < RustcVersion::parse(version.trim())?),
+            ("not", Some(argument)) => self.rustversion(argument.trim()).map(|value| !value),
+            ("all", Some(arguments)) => split_arguments(arguments)
+                .iter()
+                .try_fold(true, |value, argument| Some(self.rustversion(argument)? && value)),
+            ("any", Some(arguments)) => split_arguments(arguments)
+                .iter()
+                .try_fold(false, |value, argument| Some(self.rustversion(argument)? || value)),
+            (version, None) => self.matches(version),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the rustc version matches the given version, which may omit the patch.
+    fn matches(&self, version: &str) -> Option<bool> {
+        let version = version.trim();
+        let parsed = RustcVersion::parse(version)?;
+        Some(if version.matches('.').count() == 1 {
+            (self.version.major, self.version.minor) == (parsed.major, parsed.minor)
+        } else {
+            self.version == parsed
+        })
+    }
+}
+
+/// Returns whether any of the given statements might set a cfg.
+fn contains_emission(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match statement {
+        Statement::Simple(text) => EMISSION.is_match(text),
+        Statement::If { condition, then, otherwise } => {
+            EMISSION.is_match(condition) || contains_emission(then) || contains_emission(otherwise)
+        }
+        Statement::Block { header, body } => EMISSION.is_match(header) || contains_emission(body),
+    })
+}
+
+/// Returns whether any of the given statements might stop the build script early.
+fn contains_divergence(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match statement {
+        Statement::Simple(text) => DIVERGENCE.is_match(text),
+        Statement::If { condition, then, otherwise } => {
+            DIVERGENCE.is_match(condition)
+                || contains_divergence(then)
+                || contains_divergence(otherwise)
+        }
+        Statement::Block { header, body } => {
+            DIVERGENCE.is_match(header) || contains_divergence(body)
+        }
+    })
+}
+
+/// Returns the index just after the string literal, character literal or comment starting at the
+/// given index, or `None` if there isn't one there.
+fn skip_literal(text: &str, start: usize) -> Option<usize> {
+    let rest = &text[start..];
+    let preceded_by_identifier =
+        text[..start].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+    if rest.starts_with("//") {
+        Some(rest.find('\n').map_or(text.len(), |end| start + end))
+    } else if let Some(comment) = rest.strip_prefix("/*") {
+        Some(comment.find("*/").map_or(text.len(), |end| start + 2 + end + 2))
+    } else if rest.starts_with('"') {
+        let mut escaped = false;
+        for (index, c) in rest.char_indices().skip(1) {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => return Some(start + index + 1),
+                _ => escaped = false,
+            }
+        }
+        Some(text.len())
+    } else if !preceded_by_identifier && (rest.starts_with("r\"") || rest.starts_with("r#")) {
+        let hashes = rest[1..].chars().take_while(|&c| c == '#').count();
+        if !rest[1 + hashes..].starts_with('"') {
+            return None;
+        }
+        let terminator = format!("\"{}", "#".repeat(hashes));
+        let body_start = 1 + hashes + 1;
+        Some(
+            rest[body_start..]
+                .find(&terminator)
+                .map_or(text.len(), |end| start + body_start + end + terminator.len()),
+        )
+    } else if let Some(after_quote) = rest.strip_prefix('\'') {
+        // A character literal, rather than a lifetime.
+        if let Some(escape) = after_quote.strip_prefix('\\') {
+            let end = escape.get(1..)?.find('\'')?;
+            Some(start + 3 + end + 1)
+        } else {
+            let c = after_quote.chars().next()?;
+            after_quote[c.len_utf8()..].starts_with('\'').then(|| start + 1 + c.len_utf8() + 1)
+        }
+    } else {
+        None
+    }
+}
+
+/// Replaces comments in the given Rust source with spaces.
+fn strip_comments(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut index = 0;
+    while index < source.len() {
+        if let Some(end) = skip_literal(source, index) {
+            let literal = &source[index..end];
+            if literal.starts_with("//") || literal.starts_with("/*") {
+                stripped.push(' ');
+            } else {
+                stripped.push_str(literal);
+            }
+            index = end;
+        } else {
+            let c = source[index..].chars().next().unwrap();
+            stripped.push(c);
+            index += c.len_utf8();
+        }
+    }
+    stripped
+}
+
+/// Returns the index of the `}` matching the `{` at the given index.
+fn matching_brace(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut index = open;
+    while index < text.len() {
+        if let Some(end) = skip_literal(text, index) {
+            index = end;
+            continue;
+        }
+        match text.as_bytes()[index] {
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+/// Splits the given Rust source into statements, or returns `None` if the braces don't match.
+fn parse_statements(text: &str) -> Option<Vec<Statement>> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    let mut depth = 0usize;
+    while index < text.len() {
+        if let Some(end) = skip_literal(text, index) {
+            index = end;
+            continue;
+        }
+        match text.as_bytes()[index] {
+            b'(' | b'[' => depth += 1,
+            b')' | b']' => depth = depth.saturating_sub(1),
+            b';' if depth == 0 => {
+                push_simple(&mut statements, &text[start..index]);
+                start = index + 1;
+            }
+            b'{' => {
+                let close = matching_brace(text, index)?;
+                if depth == 0 {
+                    let header = text[start..index].trim();
+                    let body = parse_statements(&text[index + 1..close])?;
+                    push_block(&mut statements, header, body);
+                    start = close + 1;
+                }
+                index = close + 1;
+                continue;
+            }
+            b'}' => return None,
+            _ => {}
+        }
+        index += 1;
+    }
+    push_simple(&mut statements, &text[start..]);
+    Some(statements)
+}
+
+fn push_simple(statements: &mut Vec<Statement>, text: &str) {
+    let text = text.trim();
+    if !text.is_empty() {
+        statements.push(Statement::Simple(text.to_string()));
+    }
+}
+
+/// Adds a statement with the given header and block, attaching `else` blocks to the preceding `if`.
+fn push_block(statements: &mut Vec<Statement>, header: &str, body: Vec<Statement>) {
+    let (header, is_else) = match header.strip_prefix("else") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            (rest.trim(), true)
+        }
+        _ => (header, false),
+    };
+    let statement = match header.strip_prefix("if") {
+        Some(condition) if condition.starts_with(char::is_whitespace) => {
+            Statement::If { condition: condition.trim().to_string(), then: body, otherwise: vec![] }
+        }
+        _ if is_else && header.is_empty() => {
+            if let Some(otherwise) = last_otherwise(statements) {
+                *otherwise = body;
+                return;
+            }
+            Statement::Block { header: "else".to_string(), body }
+        }
+        _ => Statement::Block { header: header.to_string(), body },
+    };
+    match last_otherwise(statements) {
+        Some(otherwise) if is_else => otherwise.push(statement),
+        _ => statements.push(statement),
+    }
+}
+
+/// Returns the empty `else` branch at the end of the chain of `if` statements ending the given
+/// statements, if any.
+fn last_otherwise(statements: &mut [Statement]) -> Option<&mut Vec<Statement>> {
+    let Some(Statement::If { otherwise, .. }) = statements.last_mut() else {
+        return None;
+    };
+    if otherwise.is_empty() {
+        Some(otherwise)
+    } else {
+        last_otherwise(otherwise)
+    }
+}
+
+/// Removes parentheses around the whole of the given expression.
+fn strip_parens(expression: &str) -> &str {
+    if expression.starts_with('(') {
+        let mut depth = 0;
+        for (index, c) in expression.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        if index == expression.len() - 1 {
+                            return strip_parens(expression[1..index].trim());
+                        }
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    expression
+}
+
+/// Splits the given expression at the given operator outside of parentheses and strings, or
+/// returns `None` if it doesn't contain it.
+fn split_top_level<'a>(expression: &'a str, operator: &str) -> Option<Vec<&'a str>> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut index = 0;
+    while index < expression.len() {
+        if let Some(end) = skip_literal(expression, index) {
+            index = end;
+            continue;
+        }
+        match expression.as_bytes()[index] {
+            b'(' | b'[' => depth += 1,
+            b')' | b']' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && expression[index..].starts_with(operator) => {
+                parts.push(expression[start..index].trim());
+                index += operator.len();
+                start = index;
+                continue;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    parts.push(expression[start..].trim());
+    Some(parts)
+}
+
+/// Splits the given comma-separated arguments outside of parentheses.
+fn split_arguments(arguments: &str) -> Vec<&str> {
+    split_top_level(arguments, ",").unwrap_or_else(|| vec![arguments.trim()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION: RustcVersion = RustcVersion { major: 1, minor: 80, patch: 1 };
+
+    fn cfgs(source: &str) -> Option<BTreeMap<String, Option<String>>> {
+        evaluate_probes(source, VERSION)
+    }
+
+    fn expected(cfgs: &[(&str, Option<&str>)]) -> Option<BTreeMap<String, Option<String>>> {
+        Some(cfgs.iter().map(|(cfg, probe)| (cfg.to_string(), probe.map(str::to_string))).collect())
+    }
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(
+            RustcVersion::parse("1.80"),
+            Some(RustcVersion { major: 1, minor: 80, patch: 0 })
+        );
+        assert_eq!(RustcVersion::parse("1.80.1-nightly"), Some(VERSION));
+        assert_eq!(RustcVersion::parse("1.80.1.2"), None);
+        assert_eq!(RustcVersion::parse("one"), None);
+        assert_eq!(VERSION.to_string(), "1.80.1");
+    }
+
+    #[test]
+    fn autocfg() {
+        let source = r#"
+            // Probe for features.
+            fn main() {
+                let ac = autocfg::new();
+                ac.emit_rustc_version(1, 60);
+                ac.emit_rustc_version(1, 90);
+                if ac.probe_rustc_version(1, 77) {
+                    autocfg::emit("has_c_str_literals");
+                } else {
+                    autocfg::emit("no_c_str_literals");
+                }
+                if !ac.probe_rustc_version(1, 81) {
+                    println!("cargo:rustc-cfg=no_error_in_core");
+                }
+                autocfg::rerun_path("build.rs");
+            }
+        "#;
+        assert_eq!(
+            cfgs(source),
+            expected(&[
+                ("has_c_str_literals", Some("ac.probe_rustc_version(1, 77)")),
+                ("no_error_in_core", Some("!ac.probe_rustc_version(1, 81)")),
+                ("rustc_1_60", Some("ac.emit_rustc_version(1, 60)")),
+            ])
+        );
+
+        // Probing paths depends on more than the version.
+        let source = r#"
+            fn main() {
+                let ac = autocfg::new();
+                ac.emit_has_path("std::ffi::c_str");
+            }
+        "#;
+        assert_eq!(cfgs(source), None);
+    }
+
+    #[test]
+    fn version_check_and_rustversion() {
+        let source = r#"
+            fn main() {
+                if version_check::is_min_version("1.70.0").unwrap_or(false) {
+                    println!("cargo:rustc-cfg=has_once_cell");
+                } else if version_check::is_min_version("1.60.0").unwrap_or(false) {
+                    println!("cargo:rustc-cfg=old");
+                }
+                if rustversion::cfg!(all(since(1.75), not(nightly))) {
+                    println!("cargo::rustc-cfg=has_async_fn");
+                }
+                if rustversion::cfg!(before(1.70)) || version_check::is_max_version("1.50.0") == Some(true) {
+                    println!("cargo:rustc-cfg=ancient");
+                }
+            }
+        "#;
+        assert_eq!(
+            cfgs(source),
+            expected(&[
+                ("has_async_fn", Some("rustversion::cfg!(all(since(1.75), not(nightly)))")),
+                (
+                    "has_once_cell",
+                    Some("version_check::is_min_version(\"1.70.0\").unwrap_or(false)")
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn minor_version() {
+        let source = r#"
+            use std::process::Command;
+
+            fn main() {
+                let minor = rustc_minor_version().unwrap_or(0);
+                if minor < 36 {
+                    println!("cargo:rustc-cfg=no_alloc_crate");
+                }
+                if minor >= 80 {
+                    println!("cargo:rustc-cfg=has_lazy_cell");
+                }
+            }
+
+            fn rustc_minor_version() -> Option<u32> {
+                let rustc = std::env::var_os("RUSTC")?;
+                let output = Command::new(rustc).arg("--version").output().ok()?;
+                let version = std::str::from_utf8(&output.stdout).ok()?;
+                let mut pieces = version.split('.');
+                if pieces.next() != Some("rustc 1") {
+                    return None;
+                }
+                pieces.next()?.parse().ok()
+            }
+        "#;
+        assert_eq!(cfgs(source), expected(&[("has_lazy_cell", Some("minor >= 80"))]));
+    }
+
+    #[test]
+    fn early_exit() {
+        // A cfg after a return under a probe which is true.
+        assert_eq!(
+            cfgs(
+                "fn main() { if version_check::is_min_version(\"1.20\").unwrap_or(false) { \
+                 return; } println!(\"cargo:rustc-cfg=old_rustc\"); }"
+            ),
+            None
+        );
+        // A cfg after a `match` which may return.
+        assert_eq!(
+            cfgs(
+                "fn main() { let minor = match rustc_minor_version() { Some(minor) => minor, \
+                 None => return, }; if minor >= 80 { println!(\"cargo:rustc-cfg=lazy\"); } }"
+            ),
+            None
+        );
+        // A cfg after a `let ... else` or `?`.
+        assert_eq!(
+            cfgs(
+                "fn main() { let Some(x) = f() else { std::process::exit(0) }; \
+                 println!(\"cargo:rustc-cfg=foo\"); }"
+            ),
+            None
+        );
+        assert_eq!(
+            cfgs("fn main() -> Result<()> { f()?; println!(\"cargo:rustc-cfg=foo\"); Ok(()) }"),
+            None
+        );
+        // A cfg in a branch which may panic.
+        assert_eq!(
+            cfgs(
+                "fn main() { if rustversion::cfg!(since(1.70)) { \
+                 println!(\"cargo:rustc-cfg=foo\"); panic!(\"unsupported\"); } }"
+            ),
+            None
+        );
+        // Returning after all cfgs are set is fine.
+        assert_eq!(
+            cfgs("fn main() { println!(\"cargo:rustc-cfg=foo\"); if f() { return; } }"),
+            expected(&[("foo", None)])
+        );
+    }
+
+    #[test]
+    fn unsupported() {
+        // A cfg set from a helper function.
+        assert_eq!(
+            cfgs("fn main() { emit(); }\nfn emit() { println!(\"cargo:rustc-cfg=foo\"); }"),
+            None
+        );
+        // A cfg under an unknown condition.
+        assert_eq!(
+            cfgs(
+                "fn main() { if std::env::var(\"TARGET\").is_ok() { \
+                 println!(\"cargo:rustc-cfg=foo\"); } }"
+            ),
+            None
+        );
+        // A formatted cfg.
+        assert_eq!(cfgs("fn main() { println!(\"cargo:rustc-cfg={}\", name()); }"), None);
+        // Unconditional cfgs and unknown conditions without cfgs are fine.
+        assert_eq!(
+            cfgs(
+                "fn main() { println!(\"cargo:rustc-cfg=foo\"); \
+                 if let Ok(x) = std::env::var(\"X\") { println!(\"cargo:rerun-if-env-changed=X\"); } }"
+            ),
+            expected(&[("foo", None)])
+        );
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Modules to generate the code which a package's build script would otherwise generate from
+//! `.proto` files with protobuf-codegen or prost-build.
+
+use crate::bindgen::source_module;
+use crate::bp::BpModule;
+use crate::config::{PackageVariantConfig, ProtobufConfig, ProtobufGenerator, VariantConfig};
+use crate::override_module_name;
+use crate::rename_registry::rename_registry;
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// The default `source_stem` for the `protobuf` generator.
+const DEFAULT_SOURCE_STEM: &str = "protos";
+
+/// Generates the module for the `.proto` files of the given package, if it has a `protobuf` config
+/// and the module isn't blocklisted.
+///
+/// This is a `rust_protobuf` module for the `protobuf` generator, or a genrule running protoc with
+/// `protoc-gen-prost` for the `prost` generator.
+pub fn protobuf_module(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_name: &str,
+    package_dir: &Path,
+) -> Result<Option<BpModule>> {
+    let Some(protobuf_cfg) = &package_cfg.protobuf else {
+        return Ok(None);
+    };
+    let stem = package_name.replace('-', "_");
+    let module_name = match protobuf_cfg.generator {
+        ProtobufGenerator::Protobuf => format!("lib{stem}_protos"),
+        ProtobufGenerator::Prost => format!("gen_{stem}_protos"),
+    };
+    let Some(module_name) = override_module_name(
+        &module_name,
+        &cfg.module_blocklist,
+        &cfg.module_name_overrides,
+        &rename_registry().rename_map,
+    ) else {
+        return Ok(None);
+    };
+    let m = match protobuf_cfg.generator {
+        ProtobufGenerator::Protobuf => {
+            let mut m = source_module("rust_protobuf", module_name, cfg, package_cfg);
+            m.props.set("crate_name", format!("{stem}_protos"));
+            m.props.set("protos", protobuf_cfg.protos.clone());
+            m.props.set(
+                "source_stem",
+                protobuf_cfg.source_stem.clone().unwrap_or_else(|| DEFAULT_SOURCE_STEM.to_string()),
+            );
+            m.props.set_if_nonempty("proto_flags", protobuf_cfg.proto_flags.clone());
+            m
+        }
+        ProtobufGenerator::Prost => {
+            let mut m = BpModule::new("genrule".to_string());
+            m.props.set("name", module_name);
+            m.props.set("tools", vec!["aprotoc", "protoc-gen-prost"]);
+            let mut cmd = "$(location aprotoc) --plugin=protoc-gen-prost=$(location \
+                           protoc-gen-prost) --prost_out=$(genDir)"
+                .to_string();
+            for flag in &protobuf_cfg.proto_flags {
+                cmd += " ";
+                cmd += flag;
+            }
+            cmd += " $(in)";
+            m.props.set("cmd", cmd);
+            m.props.set("srcs", protobuf_cfg.protos.clone());
+            m.props.set(
+                "out",
+                prost_outputs(package_dir, &protobuf_cfg.protos)?.into_iter().collect::<Vec<_>>(),
+            );
+            m
+        }
+    };
+    Ok(Some(m))
+}
+
+/// Returns the names of the files which prost generates for the given `.proto` files: one for each
+/// protobuf package, named after the package.
+fn prost_outputs(package_dir: &Path, protos: &[String]) -> Result<BTreeSet<String>> {
+    static PACKAGE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?m)^\s*package\s+([\w.]+)\s*;").unwrap());
+
+    let mut outputs = BTreeSet::new();
+    for proto in protos {
+        let path = package_dir.join(proto);
+        let contents = read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+        let package =
+            PACKAGE.captures(&contents).map_or("_", |captures| captures.get(1).unwrap().as_str());
+        outputs.insert(format!("{package}.rs"));
+    }
+    Ok(outputs)
+}
+
+/// Finds the `.proto` files which the given build script source generates code for with the given
+/// generator, or returns `None` if there aren't any given as string literals which exist.
+pub fn protobuf_config_from_build_script(
+    source: &str,
+    package_dir: &Path,
+    generator: ProtobufGenerator,
+) -> Option<ProtobufConfig> {
+    static PROTO: Lazy<Regex> = Lazy::new(|| Regex::new(r#""([^"\s*]+\.proto)""#).unwrap());
+
+    let protos: BTreeSet<String> = PROTO
+        .captures_iter(source)
+        .map(|captures| captures[1].to_string())
+        .filter(|proto| package_dir.join(proto).is_file())
+        .collect();
+    if protos.is_empty() {
+        return None;
+    }
+    Some(ProtobufConfig { generator, protos: protos.into_iter().collect(), ..Default::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn config_from_build_script() -> Result<()> {
+        let package_dir = tempdir()?;
+        create_dir(package_dir.path().join("proto"))?;
+        write(package_dir.path().join("proto/foo.proto"), "syntax = \"proto3\";\n")?;
+        let source = r#"
+            fn main() {
+                prost_build::compile_protos(&["proto/foo.proto", "proto/missing.proto"], &["proto/"])
+                    .unwrap();
+            }
+        "#;
+        assert_eq!(
+            protobuf_config_from_build_script(source, package_dir.path(), ProtobufGenerator::Prost),
+            Some(ProtobufConfig {
+                generator: ProtobufGenerator::Prost,
+                protos: vec!["proto/foo.proto".to_string()],
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            protobuf_config_from_build_script(
+                "fn main() { prost_build::compile_protos(&protos(), &[\"proto\"]).unwrap(); }",
+                package_dir.path(),
+                ProtobufGenerator::Prost
+            ),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn generate_modules() -> Result<()> {
+        let package_dir = tempdir()?;
+        write(package_dir.path().join("a.proto"), "syntax = \"proto3\";\npackage foo.v1;\n")?;
+        write(package_dir.path().join("b.proto"), "syntax = \"proto3\";\npackage foo.v1;\n")?;
+        write(package_dir.path().join("c.proto"), "syntax = \"proto3\";\n")?;
+        let cfg = VariantConfig { apex_available: vec![], ..Default::default() };
+        let mut package_cfg =
+            PackageVariantConfig { device_supported: false, ..Default::default() };
+        assert_eq!(protobuf_module(&cfg, &package_cfg, "foo-proto", package_dir.path())?, None);
+
+        package_cfg.protobuf = Some(ProtobufConfig {
+            protos: vec!["a.proto".to_string()],
+            proto_flags: vec!["--experimental_allow_proto3_optional".to_string()],
+            ..Default::default()
+        });
+        let mut contents = String::new();
+        protobuf_module(&cfg, &package_cfg, "foo-proto", package_dir.path())?
+            .unwrap()
+            .write(&mut contents)?;
+        assert_eq!(
+            contents,
+            r#"rust_protobuf_host {
+name: "libfoo_proto_protos",
+crate_name: "foo_proto_protos",
+protos: ["a.proto"],
+source_stem: "protos",
+proto_flags: ["--experimental_allow_proto3_optional"],
+}
+"#
+        );
+
+        package_cfg.protobuf = Some(ProtobufConfig {
+            generator: ProtobufGenerator::Prost,
+            protos: vec!["a.proto".to_string(), "b.proto".to_string(), "c.proto".to_string()],
+            ..Default::default()
+        });
+        let mut contents = String::new();
+        protobuf_module(&cfg, &package_cfg, "foo-proto", package_dir.path())?
+            .unwrap()
+            .write(&mut contents)?;
+        assert_eq!(
+            contents,
+            r#"genrule {
+name: "gen_foo_proto_protos",
+srcs: ["a.proto", "b.proto", "c.proto"],
+cmd: "$(location aprotoc) --plugin=protoc-gen-prost=$(location protoc-gen-prost) --prost_out=$(genDir) $(in)",
+out: ["_.rs", "foo.v1.rs"],
+tools: ["aprotoc", "protoc-gen-prost"],
+}
+"#
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Provenance stamps recording the inputs which a generated file was generated from, so that files
+//! which are out of date can be found without running cargo.
+
+use crate::config::Config;
+use crate::fingerprint::Fingerprint;
+use anyhow::{Context, Result};
+use serde_json::Map;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+/// The version of cargo_embargo. Bump this when changes to cargo_embargo change its output, so
+/// that files generated by older versions are reported as stale.
+pub const CARGO_EMBARGO_VERSION: &str = "1";
+
+/// The first line of a provenance stamp, after the comment prefix.
+const STAMP_HEADER: &str = "cargo_embargo provenance:";
+
+/// The inputs which a generated file was generated from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Provenance {
+    /// The version of cargo_embargo.
+    pub tool_version: String,
+    /// A hash of the config file, ignoring comments and formatting, or "none" if there isn't one.
+    /// Config defaults from `Cargo.toml` metadata are covered by `manifest_hash` instead, so that
+    /// this can be found without running cargo.
+    pub config_hash: String,
+    /// The output of `cargo --version`.
+    pub cargo_version: String,
+    /// The output of `rustc --version`.
+    pub rustc_version: String,
+    /// A hash of the `Cargo.toml`, which covers the package version and any config defaults in
+    /// its metadata, or "none" if there isn't one.
+    pub manifest_hash: String,
+    /// A hash of the `Cargo.lock`, or "none" if there isn't one.
+    pub lockfile_hash: String,
+}
+
+impl Provenance {
+    /// Returns the provenance for generating files from the given config file, the toolchain on
+    /// the `PATH` and the `Cargo.toml` and `Cargo.lock` in `package_dir`.
+    pub fn current(config_filename: &Path, package_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            tool_version: CARGO_EMBARGO_VERSION.to_string(),
+            config_hash: config_hash(config_filename)?,
+            cargo_version: tool_version("cargo")?,
+            rustc_version: tool_version("rustc")?,
+            manifest_hash: file_hash(&package_dir.join("Cargo.toml"))?,
+            lockfile_hash: file_hash(&package_dir.join("Cargo.lock"))?,
+        })
+    }
+
+    /// Returns the fields of the stamp, in the order they are written.
+    fn fields(&self) -> [(&'static str, &str); 6] {
+        [
+            ("cargo_embargo", &self.tool_version),
+            ("config", &self.config_hash),
+            ("cargo", &self.cargo_version),
+            ("rustc", &self.rustc_version),
+            ("Cargo.toml", &self.manifest_hash),
+            ("Cargo.lock", &self.lockfile_hash),
+        ]
+    }
+
+    /// Returns the stamp as comment lines starting with the given comment prefix, e.g. "//".
+    pub fn to_comment(&self, comment_prefix: &str) -> String {
+        let mut comment = format!("{comment_prefix} {STAMP_HEADER}\n");
+        for (key, value) in self.fields() {
+            comment += &format!("{comment_prefix}   {key}: {value}\n");
+        }
+        comment
+    }
+
+    /// Finds and parses a stamp in the initial comment lines of the given generated file contents.
+    pub fn from_generated_file(contents: &str) -> Option<Self> {
+        let mut lines = contents
+            .lines()
+            .map_while(|line| line.strip_prefix("//").or_else(|| line.strip_prefix('#')))
+            .map(str::trim)
+            .skip_while(|line| *line != STAMP_HEADER)
+            .skip(1);
+        let mut provenance = Self::default();
+        for (key, value) in [
+            ("cargo_embargo", &mut provenance.tool_version),
+            ("config", &mut provenance.config_hash),
+            ("cargo", &mut provenance.cargo_version),
+            ("rustc", &mut provenance.rustc_version),
+            ("Cargo.toml", &mut provenance.manifest_hash),
+            ("Cargo.lock", &mut provenance.lockfile_hash),
+        ] {
+            *value = lines.next()?.strip_prefix(key)?.strip_prefix(": ")?.to_string();
+        }
+        Some(provenance)
+    }
+
+    /// Returns the reasons why a file with this stamp would be different if it was generated from
+    /// the `current` inputs, or an empty list if it is up to date.
+    pub fn stale_reasons(&self, current: &Self) -> Vec<&'static str> {
+        let mut reasons = Vec::new();
+        if self.tool_version != current.tool_version {
+            reasons.push("cargo_embargo changed");
+        }
+        if self.config_hash != current.config_hash {
+            reasons.push("config changed");
+        }
+        if self.cargo_version != current.cargo_version
+            || self.rustc_version != current.rustc_version
+        {
+            reasons.push("toolchain changed");
+        }
+        if self.manifest_hash != current.manifest_hash {
+            reasons.push("Cargo.toml changed");
+        }
+        if self.lockfile_hash != current.lockfile_hash {
+            reasons.push("Cargo.lock changed");
+        }
+        reasons
+    }
+}
+
+/// Returns a hash of the given config file, or "none" if it doesn't exist. This is based on the
+/// parsed config rather than the file contents, so that changes to comments or formatting don't
+/// make generated files stale.
+fn config_hash(config_filename: &Path) -> Result<String> {
+    if !config_filename.try_exists()? {
+        return Ok("none".to_string());
+    }
+    let cfg = Config::from_file_with_defaults(config_filename, &Map::new())?;
+    let mut fingerprint = Fingerprint::new();
+    fingerprint.update_str(&serde_json::to_string(&cfg)?);
+    Ok(fingerprint.finish())
+}
+
+/// Returns a hash of the contents of the given file, or "none" if it doesn't exist.
+fn file_hash(path: &Path) -> Result<String> {
+    if !path.try_exists()? {
+        return Ok("none".to_string());
+    }
+    let mut fingerprint = Fingerprint::new();
+    fingerprint.update(&std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?);
+    Ok(fingerprint.finish())
+}
+
+/// Returns the first line of `<tool> --version`, or "not found" if the tool isn't on the `PATH`.
+pub fn tool_version(tool: &str) -> Result<String> {
+    let output = match Command::new(tool).arg("--version").output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok("not found".to_string()),
+        Err(e) => return Err(e).with_context(|| format!("failed to run {tool} --version")),
+    };
+    Ok(String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provenance() -> Provenance {
+        Provenance {
+            tool_version: "1".to_string(),
+            config_hash: "0123".to_string(),
+            cargo_version: "cargo 1.78.0 (54d8815d0 2024-03-26)".to_string(),
+            rustc_version: "rustc 1.78.0 (9b00956e5 2024-04-29)".to_string(),
+            manifest_hash: "89ab".to_string(),
+            lockfile_hash: "none".to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let provenance = provenance();
+        for prefix in ["//", "#"] {
+            let contents = format!(
+                "{prefix} This file is generated by cargo_embargo.\n{}\n{}\nrust_library {{",
+                provenance.to_comment(prefix),
+                prefix,
+            );
+            assert_eq!(Provenance::from_generated_file(&contents), Some(provenance.clone()));
+        }
+        assert_eq!(
+            Provenance::from_generated_file("// This file is generated by cargo_embargo.\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn stale() {
+        let stamp = provenance();
+        assert!(stamp.stale_reasons(&stamp).is_empty());
+        let current = Provenance {
+            config_hash: "4567".to_string(),
+            rustc_version: "rustc 1.79.0 (129f3b996 2024-06-10)".to_string(),
+            ..provenance()
+        };
+        assert_eq!(stamp.stale_reasons(&current), vec!["config changed", "toolchain changed"]);
+        let current = Provenance { manifest_hash: "cdef".to_string(), ..provenance() };
+        assert_eq!(stamp.stale_reasons(&current), vec!["Cargo.toml changed"]);
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Registry of module names which shouldn't be used as generated, to avoid conflicts with other
+//! modules in the tree.
+//!
+//! A default registry is built in. It can be extended by a `cargo_embargo_renames.json` file in the
+//! current directory or any parent of it, or by a file given on the command line.
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::OnceCell;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::{self, Formatter};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// The name of registry files to look for in the current directory and its parents.
+const REGISTRY_FILE_NAME: &str = "cargo_embargo_renames.json";
+
+/// The built-in registry.
+const DEFAULT_REGISTRY: &str = include_str!("../cargo_embargo_renames.json");
+
+static RENAME_REGISTRY: OnceCell<RenameRegistry> = OnceCell::new();
+
+/// Maps of renames for generated module names.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RenameRegistry {
+    /// Rust modules which shouldn't use the default generated names, to avoid conflicts or
+    /// confusion.
+    #[serde(default, deserialize_with = "deserialize_unique_map")]
+    pub rename_map: BTreeMap<String, String>,
+    /// Rust crates that have special rules.mk modules that were not generated automatically by
+    /// cargo_embargo, such as compiler builtins and other foundational libraries, and the location
+    /// of rules.mk build files for crates that are not under external/rust/crates.
+    #[serde(default, deserialize_with = "deserialize_unique_map")]
+    pub rulesmk_rename_map: BTreeMap<String, String>,
+}
+
+impl RenameRegistry {
+    /// Returns the built-in registry.
+    pub fn builtin() -> Self {
+        Self::from_json_str(DEFAULT_REGISTRY).expect("built-in rename registry is invalid")
+    }
+
+    /// Parses and validates a registry from the given JSON string.
+    pub fn from_json_str(json: &str) -> Result<Self> {
+        let registry: Self = serde_json::from_str(json)?;
+        registry.validate()?;
+        Ok(registry)
+    }
+
+    /// Reads and validates a registry from the given JSON file.
+    pub fn from_file(filename: &Path) -> Result<Self> {
+        let json = read_to_string(filename)
+            .with_context(|| format!("failed to read rename registry {filename:?}"))?;
+        Self::from_json_str(&json).with_context(|| format!("invalid rename registry {filename:?}"))
+    }
+
+    /// Loads the built-in registry, extended by the given registry file if any, or else the
+    /// nearest `cargo_embargo_renames.json` in `dir` or its parents.
+    pub fn load(filename: Option<&Path>, dir: &Path) -> Result<Self> {
+        let mut registry = Self::builtin();
+        let filename = filename.map(Path::to_owned).or_else(|| find_registry_file(dir));
+        if let Some(filename) = filename {
+            registry.extend(Self::from_file(&filename)?);
+            registry.validate().with_context(|| {
+                format!("rename registry {filename:?} conflicts with the built-in registry")
+            })?;
+        }
+        Ok(registry)
+    }
+
+    /// Adds the entries of the given registry, replacing any existing entries for the same names.
+    fn extend(&mut self, other: Self) {
+        self.rename_map.extend(other.rename_map);
+        self.rulesmk_rename_map.extend(other.rulesmk_rename_map);
+    }
+
+    fn validate(&self) -> Result<()> {
+        check_renames(&self.rename_map).context("invalid rename_map")?;
+        check_renames(&self.rulesmk_rename_map).context("invalid rulesmk_rename_map")?;
+        Ok(())
+    }
+
+    /// Checks that the given per-config `module_name_overrides` don't conflict with the registry.
+    ///
+    /// An override replaces the registry entry for the same name, so an identity override such as
+    /// `"libfoo": "libfoo"` undoes the registry's rename of `libfoo`.
+    pub fn check_overrides(&self, module_name_overrides: &BTreeMap<String, String>) -> Result<()> {
+        let effective: BTreeMap<String, String> = self
+            .rename_map
+            .iter()
+            .filter(|(name, _)| !module_name_overrides.contains_key(*name))
+            .chain(module_name_overrides)
+            .map(|(name, renamed)| (name.clone(), renamed.clone()))
+            .collect();
+        check_renames(&effective).context("module_name_overrides conflict with the rename registry")
+    }
+}
+
+/// Initialises the global rename registry. This must be called before `rename_registry`, or it
+/// will have no effect.
+pub fn init_rename_registry(filename: Option<&Path>, dir: &Path) -> Result<()> {
+    let registry = RenameRegistry::load(filename, dir)?;
+    if RENAME_REGISTRY.set(registry).is_err() {
+        bail!("rename registry already initialised");
+    }
+    Ok(())
+}
+
+/// Returns the global rename registry, or the built-in one if it hasn't been initialised.
+pub fn rename_registry() -> &'static RenameRegistry {
+    RENAME_REGISTRY.get_or_init(RenameRegistry::builtin)
+}
+
+/// Returns the path of the nearest registry file in `dir` or its parents, if any.
+fn find_registry_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().map(|dir| dir.join(REGISTRY_FILE_NAME)).find(|path| path.is_file())
+}
+
+/// Checks that no two names are renamed to the same name, and that following renames never leads
+/// back to a name already seen. Identity entries, which keep a name unchanged, are ignored.
+fn check_renames(renames: &BTreeMap<String, String>) -> Result<()> {
+    let renames: BTreeMap<&str, &str> = renames
+        .iter()
+        .filter(|(name, renamed)| name != renamed)
+        .map(|(name, renamed)| (name.as_str(), renamed.as_str()))
+        .collect();
+    let mut sources: BTreeMap<&str, &str> = BTreeMap::new();
+    for (&name, &renamed) in &renames {
+        if let Some(other) = sources.insert(renamed, name) {
+            bail!("both {other:?} and {name:?} are renamed to {renamed:?}");
+        }
+    }
+    for &name in renames.keys() {
+        let mut chain = vec![name];
+        let mut current = name;
+        while let Some(&renamed) = renames.get(current) {
+            if chain.contains(&renamed) {
+                chain.push(renamed);
+                bail!("renames form a cycle: {}", chain.join(" -> "));
+            }
+            chain.push(renamed);
+            current = renamed;
+        }
+    }
+    Ok(())
+}
+
+/// Deserializes a map of strings, failing if any key appears more than once rather than silently
+/// using the last value.
+fn deserialize_unique_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    struct UniqueMapVisitor;
+
+    impl<'de> Visitor<'de> for UniqueMapVisitor {
+        type Value = BTreeMap<String, String>;
+
+        fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "a map of strings")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let mut map = BTreeMap::new();
+            while let Some((key, value)) = access.next_entry::<String, String>()? {
+                if map.contains_key(&key) {
+                    return Err(serde::de::Error::custom(format!("duplicate entry for {key:?}")));
+                }
+                map.insert(key, value);
+            }
+            Ok(map)
+        }
+    }
+
+    deserializer.deserialize_map(UniqueMapVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_is_valid() {
+        let registry = RenameRegistry::builtin();
+        assert_eq!(registry.rename_map.get("liblog").map(String::as_str), Some("liblog_rust"));
+        assert_eq!(
+            registry.rulesmk_rename_map.get("libcore").map(String::as_str),
+            Some("trusty/user/base/lib/libcore-rust")
+        );
+    }
+
+    #[test]
+    fn invalid_registries() {
+        assert!(RenameRegistry::from_json_str(r#"{"rename_map": {"liba": "libb"}}"#).is_ok());
+        assert_eq!(
+            RenameRegistry::from_json_str(r#"{"rename_map": {"liba": "libb", "liba": "libc"}}"#)
+                .unwrap_err()
+                .to_string(),
+            "duplicate entry for \"liba\" at line 1 column 47"
+        );
+        assert_eq!(
+            format!(
+                "{:#}",
+                RenameRegistry::from_json_str(
+                    r#"{"rename_map": {"liba": "libc", "libb": "libc"}}"#
+                )
+                .unwrap_err()
+            ),
+            "invalid rename_map: both \"liba\" and \"libb\" are renamed to \"libc\""
+        );
+        assert_eq!(
+            format!(
+                "{:#}",
+                RenameRegistry::from_json_str(
+                    r#"{"rename_map": {"liba": "libb", "libb": "liba"}}"#
+                )
+                .unwrap_err()
+            ),
+            "invalid rename_map: renames form a cycle: liba -> libb -> liba"
+        );
+        assert!(RenameRegistry::from_json_str(r#"{"rename_map": {"liba": "liba"}}"#).is_ok());
+    }
+
+    #[test]
+    fn overrides() {
+        let registry = RenameRegistry::builtin();
+        assert!(registry
+            .check_overrides(&[("liblog".to_string(), "liblog_foo".to_string())].into())
+            .is_ok());
+        assert!(registry
+            .check_overrides(&[("libfoo".to_string(), "libbase_rust".to_string())].into())
+            .is_err());
+        // An identity override undoes the registry's rename.
+        assert!(registry
+            .check_overrides(&[("liblog".to_string(), "liblog".to_string())].into())
+            .is_ok());
+        // An override replaces the registry's entry for the same name, so it may rename to a name
+        // which the registry would otherwise have used.
+        assert!(registry
+            .check_overrides(
+                &[
+                    ("liblog".to_string(), "liblog".to_string()),
+                    ("libfoo".to_string(), "liblog_rust".to_string()),
+                ]
+                .into()
+            )
+            .is_ok());
+    }
+}
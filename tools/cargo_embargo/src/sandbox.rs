@@ -0,0 +1,307 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs cargo in a sandbox using Linux namespaces.
+//!
+//! The sandboxed process gets new user, mount and network namespaces. The network namespace has no
+//! interfaces other than a loopback device which is down, so nothing can be downloaded. The source
+//! tree is bind mounted read-only over itself, except for any scratch directories inside it. The
+//! rest of the filesystem is left as it is.
+//!
+//! This needs unprivileged user namespaces to be enabled, but no other privileges.
+
+use anyhow::{Context, Result};
+use nix::libc::{self, c_int, c_ulong};
+use nix::mount::MsFlags;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::unistd::{getgid, getuid};
+use std::env;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+/// Substrings of cargo or build script output which indicate that something tried to do
+/// something which isn't allowed in the sandbox.
+const VIOLATION_PATTERNS: [&str; 7] = [
+    "Read-only file system",
+    "Could not resolve host",
+    "Couldn't resolve host",
+    "failed to lookup address information",
+    "Temporary failure in name resolution",
+    "Network is unreachable",
+    "failed to download",
+];
+
+/// Mount flags which the kernel doesn't allow to be changed from within a user namespace, so must
+/// be kept when remounting, along with the corresponding `statvfs` flags.
+const LOCKED_FLAGS: [(FsFlags, MsFlags); 6] = [
+    (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+    (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+    (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+    (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+    (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+    (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+];
+
+/// A sandbox in which to run commands.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sandbox {
+    /// Directories to make read-only, along with the flags of the mount they are on.
+    read_only_dirs: Vec<(PathBuf, MsFlags)>,
+    /// Directories which should stay writable even if they are inside a read-only directory.
+    writable_dirs: Vec<PathBuf>,
+    /// Contents for `/proc/self/uid_map`.
+    uid_map: String,
+    /// Contents for `/proc/self/gid_map`.
+    gid_map: String,
+}
+
+impl Sandbox {
+    /// Creates a sandbox where `read_only_dirs` are read-only, except for `writable_dirs` and
+    /// their contents.
+    ///
+    /// Read-only directories inside writable ones are left writable.
+    pub fn new(read_only_dirs: &[&Path], writable_dirs: &[&Path]) -> Result<Self> {
+        let mut writable_dirs = writable_dirs
+            .iter()
+            .map(|dir| dir.canonicalize().with_context(|| format!("failed to find {dir:?}")))
+            .collect::<Result<Vec<_>>>()?;
+        let mut sandbox_read_only_dirs = Vec::new();
+        for dir in read_only_dirs {
+            let dir = dir.canonicalize().with_context(|| format!("failed to find {dir:?}"))?;
+            if writable_dirs.iter().any(|writable_dir| dir.starts_with(writable_dir)) {
+                continue;
+            }
+            let fs_flags =
+                statvfs(&dir).with_context(|| format!("failed to stat {dir:?}"))?.flags();
+            let locked_flags = LOCKED_FLAGS
+                .iter()
+                .filter(|(fs_flag, _)| fs_flags.contains(*fs_flag))
+                .fold(MsFlags::empty(), |flags, (_, ms_flag)| flags | *ms_flag);
+            sandbox_read_only_dirs.push((dir, locked_flags));
+        }
+        // Writable directories elsewhere don't need to be touched.
+        writable_dirs.retain(|writable_dir| {
+            sandbox_read_only_dirs.iter().any(|(dir, _)| writable_dir.starts_with(dir))
+        });
+        // Map our own user and group to themselves, so that file ownership looks the same inside
+        // the sandbox.
+        let uid = getuid();
+        let gid = getgid();
+        Ok(Self {
+            read_only_dirs: sandbox_read_only_dirs,
+            writable_dirs,
+            uid_map: format!("{uid} {uid} 1"),
+            gid_map: format!("{gid} {gid} 1"),
+        })
+    }
+
+    /// Configures the given command to run inside the sandbox.
+    pub fn apply(&self, command: &mut Command) -> Result<()> {
+        let current_dir = env::current_dir()?;
+        let current_dir = match command.get_current_dir() {
+            Some(dir) => current_dir.join(dir),
+            None => current_dir,
+        };
+        let entry = SandboxEntry {
+            read_only_dirs: self
+                .read_only_dirs
+                .iter()
+                .map(|(dir, locked_flags)| Ok((path_to_cstring(dir)?, locked_flags.bits())))
+                .collect::<Result<_>>()?,
+            writable_dirs: self
+                .writable_dirs
+                .iter()
+                .map(|dir| path_to_cstring(dir))
+                .collect::<Result<_>>()?,
+            uid_map: self.uid_map.clone(),
+            gid_map: self.gid_map.clone(),
+            current_dir: path_to_cstring(&current_dir)?,
+        };
+        // SAFETY: `enter` only makes raw system calls with arguments prepared in advance, and
+        // doesn't allocate, so it is safe to call in the child process between fork and exec.
+        unsafe {
+            command.pre_exec(move || entry.enter());
+        }
+        Ok(())
+    }
+}
+
+/// The arguments for the system calls which move a process into a `Sandbox`, prepared before
+/// forking.
+struct SandboxEntry {
+    /// Directories to make read-only, along with the locked flags of the mount they are on.
+    read_only_dirs: Vec<(CString, c_ulong)>,
+    /// Directories which should stay writable.
+    writable_dirs: Vec<CString>,
+    /// Contents for `/proc/self/uid_map`.
+    uid_map: String,
+    /// Contents for `/proc/self/gid_map`.
+    gid_map: String,
+    /// The directory to change to once the mounts are in place.
+    current_dir: CString,
+}
+
+impl SandboxEntry {
+    /// Moves the current process into the sandbox, and then changes to `current_dir` so that it
+    /// is seen through the new mounts.
+    ///
+    /// This runs between fork and exec, so it must not allocate.
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: These system calls are only passed valid NUL-terminated strings or null
+        // pointers, and don't keep any of them.
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET))?;
+            // An unprivileged process must give up setgroups before it can write a GID map.
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+            // Don't propagate any changes back to the parent namespace.
+            mount(None, c"/", libc::MS_REC | libc::MS_PRIVATE)?;
+            for (dir, _) in &self.read_only_dirs {
+                mount(Some(dir), dir, libc::MS_BIND | libc::MS_REC)?;
+            }
+            // Remounting read-only only affects the top mount at each point, so these stay
+            // writable.
+            for dir in &self.writable_dirs {
+                mount(Some(dir), dir, libc::MS_BIND | libc::MS_REC)?;
+            }
+            for (dir, locked_flags) in &self.read_only_dirs {
+                mount(
+                    None,
+                    dir,
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | *locked_flags,
+                )?;
+            }
+            check(libc::chdir(self.current_dir.as_ptr()))
+        }
+    }
+}
+
+/// Converts the given path to a `CString` for passing to system calls.
+fn path_to_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).with_context(|| format!("invalid path {path:?}"))
+}
+
+/// Converts the result of a system call to an `io::Result`, without allocating.
+fn check(result: c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Bind mounts or remounts `target` with the given flags, without allocating.
+///
+/// # Safety
+///
+/// This changes the mounts of the current mount namespace.
+unsafe fn mount(source: Option<&CStr>, target: &CStr, flags: c_ulong) -> io::Result<()> {
+    // SAFETY: The strings are valid and NUL-terminated, and null is allowed for the others.
+    check(unsafe {
+        libc::mount(
+            source.map_or(ptr::null(), CStr::as_ptr),
+            target.as_ptr(),
+            ptr::null(),
+            flags,
+            ptr::null(),
+        )
+    })
+}
+
+/// Writes the given contents to the given file in a single `write` call, without allocating, as
+/// is needed for files in `/proc/self`.
+///
+/// # Safety
+///
+/// This writes to an arbitrary file.
+unsafe fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    // SAFETY: The path is valid and NUL-terminated, and the buffer is valid for its length.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        let result = if written == -1 {
+            Err(io::Error::last_os_error())
+        } else if written as usize != contents.len() {
+            Err(io::ErrorKind::WriteZero.into())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+/// Returns the lines of the given output which indicate that a sandboxed command tried to access
+/// the network or write to the source tree.
+pub fn find_violations(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .filter(|line| VIOLATION_PATTERNS.iter().any(|pattern| line.contains(pattern)))
+        .map(str::trim)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    #[test]
+    fn violations() {
+        let output = "   Compiling foo v0.1.0\n\
+                      error: failed to write `src/generated.rs`: Read-only file system (os error 30)\n\
+                      warning: spurious network error: Could not resolve host: example.com\n";
+        assert_eq!(
+            find_violations(output),
+            vec![
+                "error: failed to write `src/generated.rs`: Read-only file system (os error 30)",
+                "warning: spurious network error: Could not resolve host: example.com",
+            ]
+        );
+        assert!(find_violations("   Compiling foo v0.1.0\n").is_empty());
+    }
+
+    #[test]
+    fn source_read_only_scratch_writable() -> Result<()> {
+        let source_dir = tempdir()?;
+        let scratch_dir = source_dir.path().join("scratch");
+        std::fs::create_dir(&scratch_dir)?;
+        let sandbox = Sandbox::new(&[source_dir.path()], &[&scratch_dir])?;
+
+        // Unprivileged user namespaces may be disabled where the tests run.
+        let mut command = Command::new("true");
+        sandbox.apply(&mut command)?;
+        if command.status().is_err() {
+            return Ok(());
+        }
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo scratch > scratch/file && echo source > source");
+        command.current_dir(source_dir.path());
+        sandbox.apply(&mut command)?;
+        assert!(!command.status()?.success());
+        assert!(!source_dir.path().join("source").exists());
+        assert_eq!(read_to_string(scratch_dir.join("file"))?, "scratch\n");
+        Ok(())
+    }
+}
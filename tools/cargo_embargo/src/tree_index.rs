@@ -0,0 +1,196 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An index of the Soong modules defined in an Android source tree.
+//!
+//! This doesn't evaluate Blueprint files, it just looks for module blocks at the top level with a
+//! literal `name`, which is how almost all Rust modules are defined.
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+/// Directories which are never searched for `Android.bp` files.
+const SKIPPED_DIRS: [&str; 3] = [".git", ".repo", "out"];
+
+/// A module defined somewhere in the tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeModule {
+    /// The module type, e.g. `rust_library`.
+    pub module_type: String,
+    /// The directory containing the `Android.bp` file which defines the module, relative to the
+    /// root of the tree.
+    pub dir: PathBuf,
+    /// Whether the module can only be built for the host.
+    pub host_only: bool,
+}
+
+impl TreeModule {
+    /// Returns whether the module is a Rust library which another Rust module could depend on.
+    pub fn is_rust_library(&self) -> bool {
+        self.module_type.starts_with("rust_library")
+    }
+}
+
+/// The modules defined in a tree, by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TreeIndex {
+    modules: BTreeMap<String, TreeModule>,
+}
+
+impl TreeIndex {
+    /// Indexes all the `Android.bp` files under the given root directory.
+    pub fn from_tree(root: &Path) -> Result<Self> {
+        let mut index = Self::default();
+        index.add_dir(root, Path::new(""))?;
+        Ok(index)
+    }
+
+    fn add_dir(&mut self, root: &Path, dir: &Path) -> Result<()> {
+        let full_dir = root.join(dir);
+        let entries = match read_dir(&full_dir) {
+            Ok(entries) => entries,
+            // Skip directories we can't read, rather than failing entirely.
+            Err(_) => return Ok(()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if !SKIPPED_DIRS.contains(&file_name) {
+                    self.add_dir(root, &dir.join(file_name))?;
+                }
+            } else if file_name == "Android.bp" {
+                let contents = read_to_string(entry.path())
+                    .with_context(|| format!("failed to read {:?}", entry.path()))?;
+                self.add_blueprint(dir, &contents);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the modules defined in the given `Android.bp` contents, from the given directory.
+    pub fn add_blueprint(&mut self, dir: &Path, contents: &str) {
+        static MODULE_START: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(\w+)\s*\{").unwrap());
+        static NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^\s+name:\s*"([^"]+)",?$"#).unwrap());
+
+        let mut current: Option<(String, Option<String>, bool)> = None;
+        for line in contents.lines() {
+            if let Some((module_type, name, host_only)) = &mut current {
+                if line.starts_with('}') {
+                    if let Some(name) = name.take() {
+                        let module_type = module_type.clone();
+                        let host_only = *host_only || module_type.ends_with("_host");
+                        self.modules.insert(
+                            name,
+                            TreeModule { module_type, dir: dir.to_owned(), host_only },
+                        );
+                    }
+                    current = None;
+                } else if let Some(captures) = NAME.captures(line) {
+                    // Only take the first name, in case of nested blocks with names.
+                    name.get_or_insert_with(|| captures[1].to_string());
+                } else if line.trim() == "device_supported: false," {
+                    *host_only = true;
+                }
+            } else if let Some(captures) = MODULE_START.captures(line) {
+                current = Some((captures[1].to_string(), None, false));
+            }
+        }
+    }
+
+    /// Returns the module with the given name, if it exists.
+    pub fn get(&self, name: &str) -> Option<&TreeModule> {
+        self.modules.get(name)
+    }
+
+    /// Returns the names of Rust library modules which look like other versions of the given
+    /// module name, such as `libfoo_1` or `libfoo_0_4` for `libfoo`.
+    pub fn versions_of(&self, name: &str) -> Vec<&str> {
+        let prefix = format!("{name}_");
+        self.modules
+            .range(prefix.clone()..)
+            .take_while(|(module_name, _)| module_name.starts_with(&prefix))
+            .filter(|(module_name, module)| {
+                module.is_rust_library()
+                    && module_name[prefix.len()..]
+                        .split('_')
+                        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+            })
+            .map(|(module_name, _)| module_name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLUEPRINT: &str = r#"
+package {
+    default_applicable_licenses: ["external_rust_crates_foo_license"],
+}
+
+rust_library {
+    name: "libfoo",
+    host_supported: true,
+    crate_name: "foo",
+}
+
+rust_library_host {
+    name: "libfoo_host",
+}
+
+rust_library {
+    name: "libfoo_0_4",
+    device_supported: false,
+    target: {
+        android: {
+            name: "libnested",
+        },
+    },
+}
+
+rust_test {
+    name: "libfoo_1",
+}
+"#;
+
+    #[test]
+    fn parse_blueprint() {
+        let mut index = TreeIndex::default();
+        index.add_blueprint(Path::new("external/rust/crates/foo"), BLUEPRINT);
+
+        assert_eq!(
+            index.get("libfoo"),
+            Some(&TreeModule {
+                module_type: "rust_library".to_string(),
+                dir: PathBuf::from("external/rust/crates/foo"),
+                host_only: false,
+            })
+        );
+        assert!(index.get("libfoo_host").unwrap().host_only);
+        assert!(index.get("libfoo_0_4").unwrap().host_only);
+        assert_eq!(index.get("libnested"), None);
+        assert_eq!(index.get("external_rust_crates_foo_license"), None);
+        assert_eq!(index.versions_of("libfoo"), vec!["libfoo_0_4"]);
+    }
+}